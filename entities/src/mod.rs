@@ -15,6 +15,7 @@ pub mod revert_log;
 pub mod rpc_accounting;
 pub mod rpc_accounting_v2;
 pub mod rpc_key;
+pub mod rpc_lifetime_stats;
 pub mod sea_orm_active_enums;
 pub mod secondary_user;
 pub mod serialization;
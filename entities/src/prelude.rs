@@ -13,6 +13,7 @@ pub use super::revert_log::Entity as RevertLog;
 pub use super::rpc_accounting::Entity as RpcAccounting;
 pub use super::rpc_accounting_v2::Entity as RpcAccountingV2;
 pub use super::rpc_key::Entity as RpcKey;
+pub use super::rpc_lifetime_stats::Entity as RpcLifetimeStats;
 pub use super::secondary_user::Entity as SecondaryUser;
 pub use super::stripe_increase_balance_receipt::Entity as StripeIncreaseBalanceReceipt;
 pub use super::user::Entity as User;
@@ -27,6 +27,10 @@ pub struct Model {
     pub allowed_referers: Option<String>,
     #[sea_orm(column_type = "Text", nullable)]
     pub allowed_user_agents: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub allowed_methods: Option<String>,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub denied_methods: Option<String>,
     #[sea_orm(column_type = "Double")]
     pub log_revert_chance: f64,
 }
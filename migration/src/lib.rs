@@ -39,6 +39,8 @@ mod m20230708_151756_rpc_accounting_free_usage_credits;
 mod m20230708_152131_referral_track_one_time_bonus_bonus;
 mod m20230713_144446_stripe_default_date_created;
 mod m20230713_210511_deposit_add_date_created;
+mod m20230725_181500_rpc_lifetime_stats;
+mod m20230801_000000_rpc_key_methods;
 
 pub struct Migrator;
 
@@ -85,6 +87,8 @@ impl MigratorTrait for Migrator {
             Box::new(m20230708_152131_referral_track_one_time_bonus_bonus::Migration),
             Box::new(m20230713_144446_stripe_default_date_created::Migration),
             Box::new(m20230713_210511_deposit_add_date_created::Migration),
+            Box::new(m20230725_181500_rpc_lifetime_stats::Migration),
+            Box::new(m20230801_000000_rpc_key_methods::Migration),
         ]
     }
 }
@@ -0,0 +1,91 @@
+use sea_orm_migration::prelude::*;
+
+#[derive(DeriveMigrationName)]
+pub struct Migration;
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(RpcLifetimeStats::Table)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::Id)
+                            .big_unsigned()
+                            .not_null()
+                            .auto_increment()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::ChainId)
+                            .big_unsigned()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::RpcName)
+                            .string()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::Requests)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::Errors)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::ResponseBytes)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::SumLatencyUs)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .col(
+                        ColumnDef::new(RpcLifetimeStats::ConsensusHeads)
+                            .big_unsigned()
+                            .not_null()
+                            .default(0),
+                    )
+                    .index(
+                        sea_query::Index::create()
+                            .col(RpcLifetimeStats::ChainId)
+                            .col(RpcLifetimeStats::RpcName)
+                            .unique(),
+                    )
+                    .to_owned(),
+            )
+            .await
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .drop_table(Table::drop().table(RpcLifetimeStats::Table).to_owned())
+            .await
+    }
+}
+
+/// Learn more at https://docs.rs/sea-query#iden
+#[derive(Iden)]
+enum RpcLifetimeStats {
+    Table,
+    Id,
+    ChainId,
+    RpcName,
+    Requests,
+    Errors,
+    ResponseBytes,
+    SumLatencyUs,
+    ConsensusHeads,
+}
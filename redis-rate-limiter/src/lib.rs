@@ -1,7 +1,10 @@
 //#![warn(missing_docs)]
 use anyhow::Context;
+use std::collections::HashMap;
 use std::ops::Add;
+use std::sync::{Arc, Mutex};
 use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
 
 pub use deadpool_redis::redis;
 pub use deadpool_redis::{
@@ -17,6 +20,15 @@ pub struct RedisRateLimiter {
     /// seconds
     pub period: f32,
     pool: RedisPool,
+    /// counts for while redis is unreachable. they are only shared by clones of this limiter
+    local_counts: Arc<Mutex<LocalCounts>>,
+}
+
+/// the counts for the current period. older periods are cleared
+#[derive(Default)]
+struct LocalCounts {
+    period_id: u32,
+    counts: HashMap<String, u64>,
 }
 
 pub enum RedisRateLimitResult {
@@ -28,6 +40,11 @@ pub enum RedisRateLimitResult {
 }
 
 impl RedisRateLimiter {
+    /// `app` should include the chain id so that chains never share counts.
+    ///
+    /// Keys are `{app}:rrl:{label}:{period}:...`. Older versions left out the period (and the chain id for logins),
+    /// so while old and new versions run side by side, each counts separately and a caller can get the limit from both.
+    /// Keys expire after a period, so nothing needs to be cleaned up once every proxy is upgraded.
     pub fn new(
         app: &str,
         label: &str,
@@ -35,13 +52,15 @@ impl RedisRateLimiter {
         period: f32,
         pool: RedisPool,
     ) -> Self {
-        let key_prefix = format!("{}:rrl:{}", app, label);
+        // the period is part of the key so that limiters with different periods never share counts
+        let key_prefix = format!("{}:rrl:{}:{}", app, label, period);
 
         Self {
             pool,
             key_prefix,
             max_requests_per_period,
             period,
+            local_counts: Default::default(),
         }
     }
 
@@ -82,6 +101,28 @@ impl RedisRateLimiter {
         // TODO: include max per period in the throttle key?
         let throttle_key = format!("{}:{}:{}", self.key_prefix, label, period_id);
 
+        let new_count = match self.incr(&throttle_key, count).await {
+            Ok(x) => x,
+            Err(err) => {
+                // limit this process on its own instead of blocking every request while redis is down
+                debug!(?err, "unable to rate limit with redis");
+
+                self.local_incr(period_id, throttle_key, count)
+            }
+        };
+
+        if new_count > max_per_period {
+            // TODO: this might actually be early if we are way over the count
+            let retry_at = self.next_period(now);
+
+            Ok(RedisRateLimitResult::RetryAt(retry_at, new_count))
+        } else {
+            Ok(RedisRateLimitResult::Allowed(new_count))
+        }
+    }
+
+    /// increment the count in redis
+    async fn incr(&self, throttle_key: &str, count: u64) -> anyhow::Result<u64> {
         let mut conn = self
             .pool
             .get()
@@ -95,9 +136,9 @@ impl RedisRateLimiter {
         let x: Vec<_> = redis::pipe()
             .atomic()
             // we could get the key first, but that means an extra redis call for every check. this seems better
-            .incr(&throttle_key, count)
+            .incr(throttle_key, count)
             // set expiration each time we set the key. ignore the result
-            .expire(&throttle_key, 1 + self.period as usize)
+            .expire(throttle_key, 1 + self.period as usize)
             // TODO: NX will make it only set the expiration the first time. works in redis, but not elasticache
             // .arg("NX")
             .ignore()
@@ -108,14 +149,29 @@ impl RedisRateLimiter {
 
         let new_count: u64 = *x.first().expect("check redis");
 
-        if new_count > max_per_period {
-            // TODO: this might actually be early if we are way over the count
-            let retry_at = self.next_period(now);
+        Ok(new_count)
+    }
 
-            Ok(RedisRateLimitResult::RetryAt(retry_at, new_count))
-        } else {
-            Ok(RedisRateLimitResult::Allowed(new_count))
+    /// increment the count in this process. only used while redis is unreachable
+    fn local_incr(&self, period_id: f32, throttle_key: String, count: u64) -> u64 {
+        let mut local_counts = self.local_counts.lock().unwrap();
+
+        // compare the bits so that float rounding can't keep an old period around
+        let period_id = period_id.to_bits();
+
+        if local_counts.period_id != period_id {
+            // only once per period. every request would be too noisy
+            warn!(key_prefix = %self.key_prefix, "redis is unreachable. using local rate limits");
+
+            local_counts.period_id = period_id;
+            local_counts.counts.clear();
         }
+
+        let x = local_counts.counts.entry(throttle_key).or_default();
+
+        *x += count;
+
+        *x
     }
 
     #[inline]
@@ -123,3 +179,55 @@ impl RedisRateLimiter {
         self.throttle_label("", None, 1).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_local_incr_fallback() {
+        // nothing listens on this port, so every redis call fails
+        let pool = RedisConfig::from_url("redis://127.0.0.1:1/")
+            .create_pool(Some(DeadpoolRuntime::Tokio1))
+            .unwrap();
+
+        let rrl = RedisRateLimiter::new("test:1", "fallback", 2, 60.0, pool);
+
+        let mut results = vec![];
+        for _ in 0..3 {
+            results.push(rrl.throttle_label("a", None, 1).await.unwrap());
+        }
+
+        // the limit still applies, counted in this process
+        assert!(matches!(results[0], RedisRateLimitResult::Allowed(1)));
+        assert!(matches!(results[1], RedisRateLimitResult::Allowed(2)));
+        assert!(matches!(results[2], RedisRateLimitResult::RetryAt(_, 3)));
+
+        // labels are counted separately
+        assert!(matches!(
+            rrl.throttle_label("b", None, 1).await.unwrap(),
+            RedisRateLimitResult::Allowed(1)
+        ));
+
+        // clones share the counts
+        assert!(matches!(
+            rrl.clone().throttle_label("b", None, 1).await.unwrap(),
+            RedisRateLimitResult::Allowed(2)
+        ));
+    }
+
+    #[test]
+    fn test_local_counts_reset_each_period() {
+        let pool = RedisConfig::from_url("redis://127.0.0.1:1/")
+            .create_pool(Some(DeadpoolRuntime::Tokio1))
+            .unwrap();
+
+        let rrl = RedisRateLimiter::new("test:1", "fallback", 2, 60.0, pool);
+
+        assert_eq!(rrl.local_incr(1.0, "a".to_string(), 2), 2);
+        assert_eq!(rrl.local_incr(1.0, "a".to_string(), 1), 3);
+
+        // a new period starts over
+        assert_eq!(rrl.local_incr(2.0, "a".to_string(), 1), 1);
+    }
+}
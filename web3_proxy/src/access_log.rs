@@ -0,0 +1,336 @@
+//! One json line for every request with who sent it, where it went, and how long it took. Useful for debugging a customer's requests.
+use crate::errors::Web3ProxyResult;
+use crate::frontend::authorization::{method_matches, RequestMetadata};
+use crate::response_cache::CacheStatus;
+use chrono::Utc;
+use ethers::types::U64;
+use hashbrown::HashSet;
+use nanorand::Rng;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+use tracing::{error, info, warn};
+
+/// how many lines can wait for the writer. once it is full, new lines are dropped instead of using more memory
+pub const ACCESS_LOG_BUFFER: usize = 10_000;
+
+#[derive(Debug, Serialize)]
+pub struct AccessLogLine {
+    /// unix timestamp in milliseconds of when the response was ready
+    pub timestamp: i64,
+    pub request_ulid: String,
+    pub rpc_key_id: Option<u64>,
+    pub method: String,
+    /// None for methods that match `access_log_redacted_methods`
+    pub params: Option<serde_json::Value>,
+    /// the rpc that answered. None if the response was cached or no rpc was tried
+    pub rpc: Option<String>,
+    /// the block that the request needed, after tags like "latest" were turned into a number
+    pub block_num: Option<U64>,
+    pub response_millis: u64,
+    pub cache: Option<CacheStatus>,
+    pub retries: u32,
+    pub error: bool,
+}
+
+pub struct AccessLog {
+    /// only 1 out of this many requests is logged
+    sample_rate: u32,
+    /// the params of methods matching these are never logged. a trailing `*` matches any suffix
+    redacted_methods: HashSet<String>,
+    /// lines are written by `access_log_loop` so that requests never wait on the disk
+    sender: mpsc::Sender<AccessLogLine>,
+    /// lines that were not logged because the writer fell too far behind
+    dropped_lines: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn new(
+        sample_rate: u32,
+        redacted_methods: HashSet<String>,
+    ) -> (Self, mpsc::Receiver<AccessLogLine>) {
+        let (sender, receiver) = mpsc::channel(ACCESS_LOG_BUFFER);
+
+        let x = Self {
+            sample_rate: sample_rate.max(1),
+            redacted_methods,
+            sender,
+            dropped_lines: AtomicU64::new(0),
+        };
+
+        (x, receiver)
+    }
+
+    pub fn dropped_lines(&self) -> u64 {
+        self.dropped_lines.load(Ordering::Relaxed)
+    }
+
+    fn is_redacted(&self, method: &str) -> bool {
+        self.redacted_methods
+            .iter()
+            .any(|x| method_matches(x, method))
+    }
+
+    /// the line for a finished request. None if the request was not sampled
+    pub fn line(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+        request_metadata: &RequestMetadata,
+    ) -> Option<AccessLogLine> {
+        if self.sample_rate > 1 && nanorand::tls_rng().generate_range(0..self.sample_rate) != 0 {
+            return None;
+        }
+
+        let provenance = request_metadata.provenance.lock();
+
+        let params = (!self.is_redacted(method)).then(|| params.clone());
+
+        Some(AccessLogLine {
+            timestamp: Utc::now().timestamp_millis(),
+            request_ulid: request_metadata.request_ulid.to_string(),
+            rpc_key_id: request_metadata
+                .authorization
+                .as_ref()
+                .and_then(|x| x.checks.rpc_secret_key_id)
+                .map(|x| x.get()),
+            method: method.to_string(),
+            params,
+            rpc: provenance.chosen.clone(),
+            block_num: provenance.block_num,
+            response_millis: request_metadata.response_millis.load(Ordering::Acquire),
+            cache: provenance.cache,
+            retries: provenance.retries,
+            error: request_metadata.error_response.load(Ordering::Acquire),
+        })
+    }
+
+    pub fn log(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+        request_metadata: &RequestMetadata,
+    ) {
+        if let Some(line) = self.line(method, params, request_metadata) {
+            // requests never wait on the log. the receiver only stops during shutdown
+            if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(line) {
+                let dropped = self.dropped_lines.fetch_add(1, Ordering::Relaxed);
+
+                if dropped % ACCESS_LOG_BUFFER as u64 == 0 {
+                    warn!(%dropped, "access log is behind. dropping lines");
+                }
+            }
+        }
+    }
+}
+
+/// write the access log lines to the file at `path` until shutdown. None writes to stdout.
+/// lines that are already queued when shutdown starts are still written
+pub async fn access_log_loop(
+    path: Option<PathBuf>,
+    mut receiver: mpsc::Receiver<AccessLogLine>,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    let writer: Box<dyn AsyncWrite + Send + Unpin> = match path.as_ref() {
+        Some(path) => Box::new(
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .await?,
+        ),
+        None => Box::new(tokio::io::stdout()),
+    };
+
+    let mut writer = BufWriter::new(writer);
+
+    info!(?path, "writing access log");
+
+    loop {
+        let line = select! {
+            x = receiver.recv() => x,
+            _ = shutdown_receiver.recv() => {
+                // no new lines are accepted. the queued ones are drained below
+                receiver.close();
+                receiver.recv().await
+            }
+        };
+
+        let Some(line) = line else {
+            break;
+        };
+
+        write_line(&mut writer, &line, path.as_ref()).await?;
+
+        // write everything that is already waiting before flushing
+        while let Ok(line) = receiver.try_recv() {
+            write_line(&mut writer, &line, path.as_ref()).await?;
+        }
+
+        if let Err(err) = writer.flush().await {
+            error!(?err, ?path, "unable to flush access log");
+        }
+    }
+
+    writer.flush().await?;
+
+    Ok(())
+}
+
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    line: &AccessLogLine,
+    path: Option<&PathBuf>,
+) -> Web3ProxyResult<()> {
+    let mut x = serde_json::to_vec(line)?;
+
+    x.push(b'\n');
+
+    if let Err(err) = writer.write_all(&x).await {
+        error!(?err, ?path, "unable to write access log line");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_log_line() {
+        let redacted_methods = ["eth_sendRawTransaction".to_string()].into_iter().collect();
+
+        let (access_log, _receiver) = AccessLog::new(1, redacted_methods);
+
+        let request_metadata = RequestMetadata::default();
+
+        request_metadata
+            .error_response
+            .store(true, Ordering::Release);
+
+        {
+            let mut provenance = request_metadata.provenance.lock();
+
+            provenance.chosen = Some("b".into());
+            provenance.retries = 1;
+            provenance.block_num = Some(100.into());
+        }
+
+        let params = serde_json::json!(["0x1234"]);
+
+        let line = access_log
+            .line("eth_getBalance", &params, &request_metadata)
+            .unwrap();
+
+        assert_eq!(line.params, Some(params.clone()));
+        assert_eq!(line.rpc.as_deref(), Some("b"));
+        assert_eq!(line.block_num, Some(100.into()));
+        assert_eq!(line.retries, 1);
+        assert!(line.error);
+
+        // params can be sensitive
+        let line = access_log
+            .line("eth_sendRawTransaction", &params, &request_metadata)
+            .unwrap();
+
+        assert_eq!(line.params, None);
+    }
+
+    #[test]
+    fn test_access_log_default_redactions() {
+        let config = crate::config::AppConfig::default();
+
+        let (access_log, _receiver) = AccessLog::new(1, config.access_log_redacted_methods);
+
+        let request_metadata = RequestMetadata::default();
+
+        let params = serde_json::json!(["0x1234"]);
+
+        for method in [
+            "eth_sendRawTransaction",
+            "eth_sign",
+            "eth_signTypedData_v4",
+            "personal_sign",
+            "personal_unlockAccount",
+        ] {
+            let line = access_log.line(method, &params, &request_metadata).unwrap();
+
+            assert_eq!(line.params, None, "{}", method);
+        }
+
+        let line = access_log
+            .line("eth_call", &params, &request_metadata)
+            .unwrap();
+
+        assert_eq!(line.params, Some(params));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_access_log_full() {
+        let (access_log, mut receiver) = AccessLog::new(1, Default::default());
+
+        let request_metadata = RequestMetadata::default();
+
+        for _ in 0..ACCESS_LOG_BUFFER + 5 {
+            access_log.log("eth_chainId", &serde_json::Value::Null, &request_metadata);
+        }
+
+        // requests did not wait. the extra lines were dropped instead
+        assert_eq!(access_log.dropped_lines(), 5);
+
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+
+        assert_eq!(queued, ACCESS_LOG_BUFFER);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_access_log_drains_on_shutdown() {
+        let path = std::env::temp_dir().join(format!("access_log_{}.log", ulid::Ulid::new()));
+
+        let (access_log, receiver) = AccessLog::new(1, Default::default());
+
+        let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+
+        let request_metadata = RequestMetadata::default();
+
+        for _ in 0..100 {
+            access_log.log("eth_chainId", &serde_json::Value::Null, &request_metadata);
+        }
+
+        // shut down before the loop has even started. the queued lines are still written
+        shutdown_sender.send(()).unwrap();
+
+        access_log_loop(Some(path.clone()), receiver, shutdown_receiver)
+            .await
+            .unwrap();
+
+        let written = tokio::fs::read_to_string(&path).await.unwrap();
+
+        tokio::fs::remove_file(&path).await.unwrap();
+
+        assert_eq!(written.lines().count(), 100);
+    }
+
+    #[test]
+    fn test_access_log_sampling() {
+        let (access_log, _receiver) = AccessLog::new(10, Default::default());
+
+        let request_metadata = RequestMetadata::default();
+
+        let logged = (0..10_000)
+            .filter_map(|_| {
+                access_log.line("eth_chainId", &serde_json::Value::Null, &request_metadata)
+            })
+            .count();
+
+        // about 1 in 10
+        assert!((500..2_000).contains(&logged), "{}", logged);
+    }
+}
@@ -1,35 +1,56 @@
 mod ws;
 
-use crate::block_number::CacheMode;
-use crate::caches::{RegisteredUserRateLimitKey, RpcSecretKeyCache, UserBalanceCache};
-use crate::config::{AppConfig, TopConfig};
+use crate::access_log::{access_log_loop, AccessLog};
+use crate::block_number::{check_logs_block_range, logs_block_range, CacheMode};
+use crate::caches::{
+    subscribe_rpc_secret_key_invalidations, RegisteredUserRateLimitKey, RpcSecretKeyCache,
+    UserBalanceCache,
+};
+use crate::config::{average_block_interval, AppConfig, TopConfig};
+use crate::dead_letters::DeadLetters;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::authorization::{
-    Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes,
+    method_matches, Authorization, RequestMetadata, RequestOrMethod, ResponseOrBytes,
 };
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::globals::{global_db_conn, DatabaseError, DB_CONN, DB_REPLICA};
 use crate::jsonrpc::{
-    JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum, JsonRpcId,
-    JsonRpcParams, JsonRpcRequest, JsonRpcRequestEnum, JsonRpcResultData,
+    JsonRpcBatchEntry, JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcForwardedResponseEnum,
+    JsonRpcId, JsonRpcParams, JsonRpcRequest, JsonRpcRequestEnum, JsonRpcResultData,
+    StreamOrBuffered,
 };
+use crate::otel;
+use crate::param_metrics::{ParamMetrics, TopParams};
 use crate::relational_db::{connect_db, migrate_db};
 use crate::response_cache::{
-    JsonRpcQueryCacheKey, JsonRpcResponseCache, JsonRpcResponseEnum, JsonRpcResponseWeigher,
+    CacheStatus, JsonRpcQueryCacheKey, JsonRpcResponseBlockIndex, JsonRpcResponseCache,
+    JsonRpcResponseCacheCounts, JsonRpcResponseCacheStats, JsonRpcResponseEnum,
+    JsonRpcResponseWeigher,
 };
+use crate::rpcs::block_snapshot::block_snapshot_loop;
 use crate::rpcs::blockchain::Web3ProxyBlock;
+use crate::rpcs::circuit_breaker::CircuitState;
 use crate::rpcs::consensus::RankedRpcs;
-use crate::rpcs::many::Web3Rpcs;
+use crate::rpcs::groups::{RpcGroup, RpcGroups};
+use crate::rpcs::many::{new_pending_tx_rpcs, new_session_rpcs, Web3Rpcs, Web3RpcsOptions};
 use crate::rpcs::one::Web3Rpc;
 use crate::rpcs::provider::{connect_http, EthersHttpProvider};
-use crate::stats::{AppStat, FlushedStats, StatBuffer};
+use crate::rpcs::request::OpenRequestResult;
+use crate::rpcs::subscriptions::{
+    LogsSubscriptions, PendingFullTxSubscriptions, PendingTxSubscriptions, LOGS_BUFFER,
+    PENDING_TX_BUFFER,
+};
+use crate::stats::{
+    save_rpc_head_lag_loop, save_rpc_lifetime_stats_loop, FlushedStats, StatBuffer, StatSender,
+};
 use anyhow::Context;
+use arc_swap::ArcSwapOption;
 use axum::http::StatusCode;
 use chrono::Utc;
 use deferred_rate_limiter::DeferredRateLimiter;
 use entities::user;
 use ethers::core::utils::keccak256;
-use ethers::prelude::{Address, Bytes, Transaction, H256, U64};
+use ethers::prelude::{Bytes, Transaction, H256, U64};
 use ethers::types::U256;
 use ethers::utils::rlp::{Decodable, Rlp};
 use futures::future::join_all;
@@ -47,14 +68,14 @@ use std::fmt;
 use std::net::IpAddr;
 use std::num::NonZeroU64;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering};
 use std::sync::{atomic, Arc};
 use std::time::Duration;
 use tokio::select;
 use tokio::sync::{broadcast, mpsc, oneshot, watch, Semaphore};
 use tokio::task::JoinHandle;
-use tokio::time::{sleep, timeout};
-use tracing::{error, info, trace, warn, Level};
+use tokio::time::{sleep, timeout, Instant};
+use tracing::{error, info, trace, warn, Level, Span};
 
 // TODO: make this customizable?
 // TODO: include GIT_REF in here. i had trouble getting https://docs.rs/vergen/latest/vergen/ to work with a workspace. also .git is in .dockerignore
@@ -68,12 +89,31 @@ pub static APP_USER_AGENT: &str = concat!(
 /// aggregate across 1 week
 pub const BILLING_PERIOD_SECONDS: i64 = 60 * 60 * 24 * 7;
 
+/// admin methods are never forwarded, no matter what the key allows
+pub const ADMIN_METHODS: &str = "admin_*";
+
+/// the proxy is shared and has no keys. these are never forwarded, no matter what the key allows
+pub const SIGNING_METHODS: [&str; 10] = [
+    "eth_coinbase",
+    "eth_sendTransaction",
+    "eth_sign",
+    "eth_signTransaction",
+    "eth_signTypedData",
+    "eth_signTypedData_v3",
+    "eth_signTypedData_v4",
+    "personal_sendTransaction",
+    "personal_sign",
+    "personal_signTypedData",
+];
+
 /// Convenience type
 pub type Web3ProxyJoinHandle<T> = JoinHandle<Web3ProxyResult<T>>;
 
 /// The application
 // TODO: i'm sure this is more arcs than necessary, but spawning futures makes references hard
 pub struct Web3ProxyApp {
+    /// one json line per request. None if not configured
+    pub access_log: Option<AccessLog>,
     /// Send requests to the best server available
     pub balanced_rpcs: Arc<Web3Rpcs>,
     /// Send 4337 Abstraction Bundler requests to one of these servers
@@ -81,9 +121,15 @@ pub struct Web3ProxyApp {
     /// application config
     /// TODO: this will need a large refactor to handle reloads while running. maybe use a watch::Receiver?
     pub config: AppConfig,
+    /// the most recent requests that failed on every backend rpc
+    pub dead_letters: DeadLetters,
     pub http_client: Option<reqwest::Client>,
     /// track JSONRPC responses
     pub jsonrpc_response_cache: JsonRpcResponseCache,
+    /// responses that depend on blocks that might be reorged. a reorg drops them from the cache
+    pub jsonrpc_response_block_index: Arc<JsonRpcResponseBlockIndex>,
+    /// count cache hits and requests collapsed into an identical in-flight request
+    pub jsonrpc_response_cache_stats: JsonRpcResponseCacheStats,
     /// rpc clients that subscribe to newHeads use this channel
     /// don't drop this or the sender will stop working
     /// TODO: broadcast channel instead?
@@ -98,6 +144,18 @@ pub struct Web3ProxyApp {
     /// concurrent/parallel request limits for anonymous users
     pub ip_semaphores: Cache<IpAddr, Arc<Semaphore>>,
     pub kafka_producer: Option<rdkafka::producer::FutureProducer>,
+    /// clients that subscribe to logs with the same filter share an upstream subscription
+    pub logs_subscriptions: LogsSubscriptions,
+    /// while this is set, rpc requests get this message in an error instead of being proxied
+    pub maintenance: ArcSwapOption<String>,
+    /// requests by method for prometheus. clients can send any method name, so unknown methods are counted as "other"
+    pub method_counts: Cache<String, Arc<AtomicU64>>,
+    /// sampled counts of configured params. None if not configured
+    pub param_metrics: Option<ParamMetrics>,
+    /// clients that subscribe to newPendingTransactions share an upstream subscription
+    pub pending_txs_subscriptions: PendingTxSubscriptions,
+    /// clients that subscribe to full pending transactions share one upstream that fetches each transaction once
+    pub pending_full_txs_subscriptions: PendingFullTxSubscriptions,
     /// rate limit the login endpoint
     /// we do this because each pending login is a row in the database
     pub login_rate_limiter: Option<RedisRateLimiter>,
@@ -105,9 +163,15 @@ pub struct Web3ProxyApp {
     /// TODO: include another type so that we can use private miner relays that do not use JSONRPC requests
     pub private_rpcs: Option<Arc<Web3Rpcs>>,
     pub prometheus_port: Arc<AtomicU16>,
+    /// hashes of the transactions that were recently sent through this proxy. reads of them might need to wait for an rpc to see them
+    pub recent_writes: Cache<H256, ()>,
+    /// rpcs that keep their own consensus. `config.rpc_group` picks the one that serves requests
+    pub rpc_groups: Arc<RpcGroups>,
     /// cache authenticated users so that we don't have to query the database on the hot path
     // TODO: should the key be our RpcSecretKey class instead of Ulid?
     pub rpc_secret_key_cache: RpcSecretKeyCache,
+    /// responses to recently broadcast raw transactions. retries of the same transaction get the same hash without sending it again
+    pub sent_transactions: Cache<H256, Box<RawValue>>,
     /// cache user balances so we don't have to check downgrade logic every single time
     pub user_balance_cache: UserBalanceCache,
     /// concurrent/parallel RPC request limits for authenticated users
@@ -116,7 +180,9 @@ pub struct Web3ProxyApp {
     /// TODO: i think i might just delete this entirely. instead use local-only concurrency limits.
     pub vredis_pool: Option<RedisPool>,
     /// channel for sending stats in a background task
-    pub stat_sender: Option<mpsc::UnboundedSender<AppStat>>,
+    pub stat_sender: Option<StatSender>,
+    /// false until the configured warmup requests are done
+    warmed_up: AtomicBool,
 
     /// Optional time series database for making pretty graphs that load quickly
     influxdb_client: Option<influxdb2::Client>,
@@ -157,6 +223,8 @@ pub struct Web3ProxyAppSpawn {
     pub app_handles: FuturesUnordered<Web3ProxyJoinHandle<()>>,
     /// these are important and must be allowed to finish
     pub background_handles: FuturesUnordered<Web3ProxyJoinHandle<()>>,
+    /// stops the consensus loops. app_handles finish once this is sent
+    pub consensus_shutdown: broadcast::Sender<()>,
     /// config changes are sent here
     pub new_top_config: Arc<watch::Sender<TopConfig>>,
     /// watch this to know when the app is ready to serve requests
@@ -210,6 +278,9 @@ impl Web3ProxyApp {
             );
         }
 
+        // the rpcs stop following the chain when this is sent. this is separate from shutdown_sender so that it can happen before stats are flushed
+        let (consensus_shutdown_sender, _) = broadcast::channel(1);
+
         // these futures are key parts of the app. if they stop running, the app has encountered an irrecoverable error
         // TODO: this is a small enough group, that a vec with try_join_all is probably fine
         let app_handles: FuturesUnordered<Web3ProxyJoinHandle<()>> = FuturesUnordered::new();
@@ -330,6 +401,7 @@ impl Web3ProxyApp {
             BILLING_PERIOD_SECONDS,
             top_config.app.chain_id,
             30,
+            top_config.app.stat_flush_frontend_requests,
             top_config.app.influxdb_bucket.clone(),
             influxdb_client.clone(),
             rpc_secret_key_cache.clone(),
@@ -388,7 +460,7 @@ impl Web3ProxyApp {
 
             // login rate limiter
             login_rate_limiter = Some(RedisRateLimiter::new(
-                "web3_proxy",
+                &format!("web3_proxy:{}", top_config.app.chain_id),
                 "login",
                 top_config.app.login_rate_limit_per_period,
                 60.0,
@@ -412,6 +484,24 @@ impl Web3ProxyApp {
                 .weigher(move |k, v| jsonrpc_weigher.weigh(k, v))
                 .build();
 
+        let jsonrpc_response_block_index = Arc::new(JsonRpcResponseBlockIndex::new(
+            jsonrpc_response_cache.clone(),
+        ));
+
+        let method_counts = CacheBuilder::new(1_000).name("method_counts").build();
+
+        // transactions should be visible on every rpc well before this expires
+        let recent_writes = CacheBuilder::new(10_000)
+            .name("recent_writes")
+            .time_to_live(Duration::from_secs(60))
+            .build();
+
+        // wallets retry a transaction if it is slow to send. this only needs to cover the retries
+        let sent_transactions = CacheBuilder::new(10_000)
+            .name("sent_transactions")
+            .time_to_live(Duration::from_secs(10))
+            .build();
+
         // create semaphores for concurrent connection limits
         // TODO: time-to-idle on these. need to make sure the arcs aren't anywhere though. so maybe arc isn't correct and it should be refs
         let ip_semaphores = CacheBuilder::new(max_users).name("ip_semaphores").build();
@@ -419,20 +509,126 @@ impl Web3ProxyApp {
 
         let chain_id = top_config.app.chain_id;
 
+        // transactions can go out through private_rpcs, so every group shares this
+        let pending_tx_rpcs = new_pending_tx_rpcs();
+
+        // reads move between balanced_rpcs and the rpc_group as the group syncs, so they share sessions too.
+        // private and bundler rpcs broadcast their requests, so they don't get sessions
+        let session_rpcs = top_config
+            .app
+            .sticky_session_ttl_ms
+            .map(|x| new_session_rpcs(Duration::from_millis(x)));
+
         // TODO: remove this. it should only be done by apply_top_config
         let (balanced_rpcs, balanced_handle, consensus_connections_watcher) = Web3Rpcs::spawn(
-            chain_id,
-            top_config.app.max_head_block_lag,
-            top_config.app.min_synced_rpcs,
-            top_config.app.min_sum_soft_limit,
-            "balanced rpcs".into(),
-            Some(watch_consensus_head_sender),
+            Web3RpcsOptions {
+                pending_tx_rpcs: Some(pending_tx_rpcs.clone()),
+                session_rpcs: session_rpcs.clone(),
+                watch_consensus_head_sender: Some(watch_consensus_head_sender),
+                ..Web3RpcsOptions::from_app_config(&top_config.app, "balanced rpcs".into())
+            },
+            consensus_shutdown_sender.subscribe(),
         )
         .await
         .web3_context("spawning balanced rpcs")?;
 
+        balanced_rpcs.add_reorg_observer(jsonrpc_response_block_index.clone());
+
+        // rotated keys are only invalidated locally by the instance that handled the rotation. listen for the others
+        if let Some(redis_url) = top_config.app.volatile_redis_url.clone() {
+            let handle = tokio::spawn(subscribe_rpc_secret_key_invalidations(
+                redis_url,
+                rpc_secret_key_cache.clone(),
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+        }
+
         app_handles.push(balanced_handle);
 
+        if let (Some(influxdb_client), Some(influxdb_bucket)) = (
+            influxdb_client.clone(),
+            top_config.app.influxdb_bucket.clone(),
+        ) {
+            let handle = tokio::spawn(save_rpc_head_lag_loop(
+                balanced_rpcs.clone(),
+                chain_id,
+                influxdb_client,
+                influxdb_bucket,
+                top_config.app.influxdb_id.to_string(),
+                Duration::from_secs(60),
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+        }
+
+        if let Some(path) = top_config.app.block_snapshot_path.as_ref() {
+            // the snapshot is loaded in the background. startup doesn't wait for it
+            let handle = tokio::spawn(block_snapshot_loop(
+                balanced_rpcs.clone(),
+                path.into(),
+                top_config.app.block_snapshot_depth,
+                Duration::from_secs(60),
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+        }
+
+        if top_config.app.db_url.is_some() {
+            let handle = tokio::spawn(save_rpc_lifetime_stats_loop(
+                balanced_rpcs.clone(),
+                chain_id,
+                Duration::from_secs(60),
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+        }
+
+        // every rpc group keeps its own consensus. they are compared to find forks between the groups
+        // TODO: remove this. it should only be done by apply_top_config
+        let mut rpc_groups = HashMap::new();
+
+        for name in top_config.rpc_groups.keys() {
+            let (watch_group_head_sender, watch_group_head_receiver) = watch::channel(None);
+
+            let (group_rpcs, group_handle, _) = Web3Rpcs::spawn(
+                Web3RpcsOptions {
+                    pending_tx_rpcs: Some(pending_tx_rpcs.clone()),
+                    session_rpcs: session_rpcs.clone(),
+                    watch_consensus_head_sender: Some(watch_group_head_sender),
+                    ..Web3RpcsOptions::from_app_config(
+                        &top_config.app,
+                        format!("{} rpcs", name).into(),
+                    )
+                },
+                consensus_shutdown_sender.subscribe(),
+            )
+            .await
+            .web3_context(format!("spawning {} rpcs", name))?;
+
+            app_handles.push(group_handle);
+
+            rpc_groups.insert(
+                name.clone(),
+                RpcGroup::new(group_rpcs, watch_group_head_receiver),
+            );
+        }
+
+        let rpc_groups = Arc::new(RpcGroups::new(rpc_groups));
+
+        if !rpc_groups.is_empty() {
+            let handle = tokio::spawn(rpc_groups.clone().reconcile_loop(
+                average_block_interval(chain_id),
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+        }
+
         // prepare a Web3Rpcs to hold all our private connections
         // only some chains have this, so this is optional
         // TODO: remove this. it should only be done by apply_top_config
@@ -442,17 +638,19 @@ impl Web3ProxyApp {
         } else {
             // TODO: do something with the spawn handle
             let (private_rpcs, private_handle, _) = Web3Rpcs::spawn(
-                chain_id,
-                // private rpcs don't get subscriptions, so no need for max_head_block_lag
-                None,
-                0,
-                0,
-                "protected rpcs".into(),
+                // private rpcs don't get subscriptions, so they don't need any consensus settings
+                // transactions are broadcast to every private rpc, so there is no need for fastest_rpc_methods or sticky sessions
                 // subscribing to new heads here won't work well. if they are fast, they might be ahead of balanced_rpcs
                 // they also often have low rate limits
                 // however, they are well connected to miners/validators. so maybe using them as a safety check would be good
                 // TODO: but maybe we could include privates in the "backup" tier
-                None,
+                Web3RpcsOptions {
+                    chain_id,
+                    name: "protected rpcs".into(),
+                    pending_tx_rpcs: Some(pending_tx_rpcs.clone()),
+                    ..Default::default()
+                },
+                consensus_shutdown_sender.subscribe(),
             )
             .await
             .web3_context("spawning private_rpcs")?;
@@ -471,13 +669,13 @@ impl Web3ProxyApp {
         } else {
             // TODO: do something with the spawn handle
             let (bundler_4337_rpcs, bundler_4337_rpcs_handle, _) = Web3Rpcs::spawn(
-                chain_id,
-                // bundler_4337_rpcs don't get subscriptions, so no need for max_head_block_lag
-                None,
-                0,
-                0,
-                "eip4337 rpcs".into(),
-                None,
+                // bundler_4337_rpcs don't get subscriptions, so they don't need any consensus settings
+                Web3RpcsOptions {
+                    chain_id,
+                    name: "eip4337 rpcs".into(),
+                    ..Default::default()
+                },
+                consensus_shutdown_sender.subscribe(),
             )
             .await
             .web3_context("spawning bundler_4337_rpcs")?;
@@ -491,10 +689,43 @@ impl Web3ProxyApp {
             .ok()
             .and_then(|x| x.to_str().map(|x| x.to_string()));
 
+        let access_log = if let Some(path) = top_config.app.access_log_path.as_ref() {
+            let (access_log, access_log_receiver) = AccessLog::new(
+                top_config.app.access_log_sample_rate,
+                top_config.app.access_log_redacted_methods.clone(),
+            );
+
+            let path = (path != "-").then(|| path.into());
+
+            let handle = tokio::spawn(access_log_loop(
+                path,
+                access_log_receiver,
+                consensus_shutdown_sender.subscribe(),
+            ));
+
+            app_handles.push(handle);
+
+            Some(access_log)
+        } else {
+            None
+        };
+
+        let param_metrics = ParamMetrics::new(
+            top_config.app.param_metrics.clone(),
+            top_config.app.param_metrics_sample_rate,
+        );
+
         let app = Self {
+            access_log,
             balanced_rpcs,
             bundler_4337_rpcs,
             config: top_config.app.clone(),
+            dead_letters: DeadLetters::new(
+                top_config.app.max_dead_letters,
+                top_config.app.dead_letter_raw_params,
+                top_config.app.chain_id,
+                vredis_pool.clone(),
+            ),
             frontend_port: frontend_port.clone(),
             frontend_ip_rate_limiter,
             frontend_registered_user_rate_limiter,
@@ -503,16 +734,31 @@ impl Web3ProxyApp {
             influxdb_client,
             internal_provider: Default::default(),
             ip_semaphores,
+            jsonrpc_response_block_index,
             jsonrpc_response_cache,
+            jsonrpc_response_cache_stats: Default::default(),
             kafka_producer,
             login_rate_limiter,
+            logs_subscriptions: LogsSubscriptions::new(
+                top_config.app.max_upstream_logs_subscriptions,
+                LOGS_BUFFER,
+            ),
+            maintenance: Default::default(),
+            method_counts,
+            param_metrics,
+            pending_txs_subscriptions: PendingTxSubscriptions::new(1, PENDING_TX_BUFFER),
+            pending_full_txs_subscriptions: PendingFullTxSubscriptions::new(1, PENDING_TX_BUFFER),
             private_rpcs,
             prometheus_port: prometheus_port.clone(),
+            recent_writes,
+            rpc_groups,
             rpc_secret_key_cache,
+            sent_transactions,
             stat_sender,
             user_balance_cache,
             user_semaphores,
             vredis_pool,
+            warmed_up: AtomicBool::new(top_config.app.warmup_requests.is_empty()),
             watch_consensus_head_receiver,
         };
 
@@ -523,6 +769,13 @@ impl Web3ProxyApp {
 
         let app = Arc::new(app);
 
+        // populate the response cache before /health is ok. this does not block startup
+        if !app.warmed_up() {
+            let app = app.clone();
+
+            tokio::spawn(async move { app.warm_up().await });
+        }
+
         // watch for config changes
         // TODO: move this to its own function/struct
         {
@@ -575,6 +828,7 @@ impl Web3ProxyApp {
             app,
             app_handles,
             background_handles: important_background_handles,
+            consensus_shutdown: consensus_shutdown_sender,
             new_top_config: Arc::new(new_top_config_sender),
             ranked_rpcs: consensus_connections_watcher,
         })
@@ -635,10 +889,29 @@ impl Web3ProxyApp {
                 Ok(())
             };
 
+        let mut groups = Ok(());
+
+        for (name, rpc_configs) in new_top_config.rpc_groups.iter() {
+            if let Some(group_rpcs) = self.rpc_groups.get(name) {
+                let x = group_rpcs
+                    .apply_server_configs(self, rpc_configs.clone())
+                    .await
+                    .web3_context(format!("updating {} rpcs", name));
+
+                if x.is_err() {
+                    groups = x;
+                }
+            } else {
+                // TODO: handle adding groups
+                warn!(%name, "new rpc groups are only added on restart");
+            }
+        }
+
         // TODO: log all the errors if there are multiple
         balanced?;
         private?;
         bundler_4337?;
+        groups?;
 
         Ok(())
     }
@@ -928,18 +1201,87 @@ impl Web3ProxyApp {
             }
         };
 
+        // TODO: configurable k?
+        let top_params = self
+            .param_metrics
+            .as_ref()
+            .map(|x| x.top_k(10))
+            .unwrap_or_default();
+
+        #[derive(Serialize)]
+        struct RpcMetrics {
+            active_requests: usize,
+            max_active_requests: Option<usize>,
+            /// 0 = closed, 1 = half open, 2 = open
+            circuit_state: u8,
+            head_lag_blocks: u64,
+            rate_limited: u64,
+        }
+
+        let rpcs: HashMap<String, RpcMetrics> = self
+            .balanced_rpcs
+            .by_name
+            .read()
+            .iter()
+            .map(|(name, rpc)| {
+                let circuit_state = match rpc.circuit_state() {
+                    CircuitState::Closed => 0,
+                    CircuitState::HalfOpen => 1,
+                    CircuitState::Open => 2,
+                };
+
+                let x = RpcMetrics {
+                    active_requests: rpc.active_requests(),
+                    max_active_requests: rpc.max_active_requests(),
+                    circuit_state,
+                    head_lag_blocks: rpc.head_lag_blocks(),
+                    rate_limited: rpc.rate_limited_count(),
+                };
+
+                (name.clone(), x)
+            })
+            .collect();
+
+        let method_counts: HashMap<String, u64> = self
+            .method_counts
+            .iter()
+            .map(|(method, count)| (method.to_string(), count.load(Ordering::Relaxed)))
+            .collect();
+
         #[derive(Serialize)]
         struct CombinedMetrics {
+            access_log_dropped_lines: u64,
+            block_cache_repairs: u64,
+            method_counts: HashMap<String, u64>,
+            min_sum_soft_limit: u32,
+            orphans_dropped: u64,
             recent_ip_counts: RecentCounts,
             recent_user_id_counts: RecentCounts,
             recent_tx_counts: RecentCounts,
+            response_cache: JsonRpcResponseCacheCounts,
+            rpcs: HashMap<String, RpcMetrics>,
+            synced_soft_limit: u32,
+            top_params: TopParams,
             user_count: UserCount,
         }
 
         let metrics = CombinedMetrics {
+            access_log_dropped_lines: self
+                .access_log
+                .as_ref()
+                .map(|x| x.dropped_lines())
+                .unwrap_or_default(),
+            block_cache_repairs: self.balanced_rpcs.block_cache_repairs(),
+            method_counts,
+            min_sum_soft_limit: self.balanced_rpcs.min_sum_soft_limit(),
+            orphans_dropped: self.balanced_rpcs.orphans_dropped(),
             recent_ip_counts,
             recent_user_id_counts,
             recent_tx_counts,
+            response_cache: self.jsonrpc_response_cache_stats.counts(),
+            rpcs,
+            synced_soft_limit: self.balanced_rpcs.synced_soft_limit(),
+            top_params,
             user_count,
         };
 
@@ -948,6 +1290,46 @@ impl Web3ProxyApp {
             .expect("prometheus metrics should always serialize")
     }
 
+    /// true once the configured warmup requests are done (or if there are none)
+    pub fn warmed_up(&self) -> bool {
+        self.warmed_up.load(Ordering::Relaxed)
+    }
+
+    /// send the configured warmup requests through the response cache.
+    /// failures are only logged. a broken warmup request should not keep the proxy unhealthy
+    async fn warm_up(self: &Arc<Self>) {
+        let mut head_block_receiver = self.watch_consensus_head_receiver.clone();
+
+        // most responses depend on the head block, so wait for one
+        while head_block_receiver.borrow_and_update().is_none() {
+            if head_block_receiver.changed().await.is_err() {
+                return;
+            }
+        }
+
+        for request in self.config.warmup_requests.iter() {
+            let params = if request.params.is_null() {
+                json!([])
+            } else {
+                request.params.clone()
+            };
+
+            if let Err(err) = self
+                .internal_request::<_, Box<RawValue>>(&request.method, params)
+                .await
+            {
+                warn!(method=%request.method, ?err, "warmup request failed");
+            }
+        }
+
+        info!(
+            num = self.config.warmup_requests.len(),
+            "finished warmup requests"
+        );
+
+        self.warmed_up.store(true, Ordering::Relaxed);
+    }
+
     /// make an internal request with stats and caching
     pub async fn internal_request<P: JsonRpcParams, R: JsonRpcResultData>(
         self: &Arc<Self>,
@@ -969,7 +1351,11 @@ impl Web3ProxyApp {
         // TODO: proper ids
         let request = JsonRpcRequest::new(JsonRpcId::Number(1), method.to_string(), json!(params))?;
 
-        let (_, response, _) = self.proxy_request(request, authorization, None).await;
+        let (_, response, _) = self
+            .proxy_request(request, authorization, None, false)
+            .await;
+
+        let response = response.buffered()?;
 
         if let Some(result) = response.result {
             let result = serde_json::from_str(result.get())?;
@@ -983,27 +1369,57 @@ impl Web3ProxyApp {
         }
     }
 
+    /// turn maintenance mode on or off. the message defaults to the configured maintenance_message
+    pub fn set_maintenance(&self, enabled: bool, message: Option<String>) -> Option<Arc<String>> {
+        let msg = enabled
+            .then(|| Arc::new(message.unwrap_or_else(|| self.config.maintenance_message.clone())));
+
+        self.maintenance.store(msg.clone());
+
+        msg
+    }
+
     /// send the request or batch of requests to the approriate RPCs
+    /// `stream` allows a single request to stream a big response from the backend rpc. batches are never streamed
     pub async fn proxy_web3_rpc(
         self: &Arc<Self>,
         authorization: Arc<Authorization>,
         request: JsonRpcRequestEnum,
-    ) -> Web3ProxyResult<(StatusCode, JsonRpcForwardedResponseEnum, Vec<Arc<Web3Rpc>>)> {
+        stream: bool,
+    ) -> Web3ProxyResult<(
+        StatusCode,
+        StreamOrBuffered<JsonRpcForwardedResponseEnum>,
+        Vec<Arc<Web3Rpc>>,
+    )> {
         // trace!(?request, "proxy_web3_rpc");
 
+        if let Some(msg) = self.maintenance.load_full() {
+            return Err(Web3ProxyError::Maintenance(msg));
+        }
+
         let response = match request {
             JsonRpcRequestEnum::Single(request) => {
                 let (status_code, response, rpcs) = self
-                    .proxy_request(request, authorization.clone(), None)
+                    .proxy_request(request, authorization.clone(), None, stream)
                     .await;
 
                 (
                     status_code,
-                    JsonRpcForwardedResponseEnum::Single(response),
+                    response.map(JsonRpcForwardedResponseEnum::Single),
                     rpcs,
                 )
             }
             JsonRpcRequestEnum::Batch(requests) => {
+                if requests.len() > self.config.max_batch_size {
+                    return Err(Web3ProxyError::BadRequest(
+                        format!(
+                            "batches are limited to {} requests",
+                            self.config.max_batch_size
+                        )
+                        .into(),
+                    ));
+                }
+
                 let (responses, rpcs) = self
                     .proxy_web3_rpc_requests(&authorization, requests)
                     .await?;
@@ -1011,7 +1427,7 @@ impl Web3ProxyApp {
                 // TODO: real status code. if an error happens, i don't think we are following the spec here
                 (
                     StatusCode::OK,
-                    JsonRpcForwardedResponseEnum::Batch(responses),
+                    StreamOrBuffered::Buffered(JsonRpcForwardedResponseEnum::Batch(responses)),
                     rpcs,
                 )
             }
@@ -1021,11 +1437,11 @@ impl Web3ProxyApp {
     }
 
     /// cut up the request and send to potentually different servers
-    /// TODO: make sure this isn't a problem
+    /// every entry is rate limited and gets its own stat. a bad entry gets an error response without failing the rest of the batch
     async fn proxy_web3_rpc_requests(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
-        requests: Vec<JsonRpcRequest>,
+        requests: Vec<JsonRpcBatchEntry>,
     ) -> Web3ProxyResult<(Vec<JsonRpcForwardedResponse>, Vec<Arc<Web3Rpc>>)> {
         // TODO: we should probably change ethers-rs to support this directly. they pushed this off to v2 though
         let num_requests = requests.len();
@@ -1046,9 +1462,8 @@ impl Web3ProxyApp {
         let responses = join_all(
             requests
                 .into_iter()
-                .map(|request| {
-                    self.proxy_request(request, authorization.clone(), Some(&head_block))
-                })
+                .enumerate()
+                .map(|(i, request)| self.proxy_batch_entry(i, request, authorization, &head_block))
                 .collect::<Vec<_>>(),
         )
         .await;
@@ -1060,7 +1475,7 @@ impl Web3ProxyApp {
             // TODO: any way to attach the tried rpcs to the error? it is likely helpful
             let (_status_code, response, rpcs) = response;
 
-            collected.push(response);
+            collected.push(response.buffered()?);
             collected_rpcs.extend(rpcs.into_iter().filter(|x| {
                 if collected_rpc_names.contains(&x.name) {
                     false
@@ -1076,6 +1491,67 @@ impl Web3ProxyApp {
         Ok((collected, collected_rpcs))
     }
 
+    /// the first entry was already counted against the rate limit when the batch was authorized
+    async fn proxy_batch_entry(
+        self: &Arc<Self>,
+        i: usize,
+        entry: JsonRpcBatchEntry,
+        authorization: &Arc<Authorization>,
+        head_block: &Web3ProxyBlock,
+    ) -> (
+        StatusCode,
+        StreamOrBuffered<JsonRpcForwardedResponse>,
+        Vec<Arc<Web3Rpc>>,
+    ) {
+        if i > 0 {
+            if let Err(err) = self.rate_limit_again(authorization).await {
+                let (status_code, response_data) = err.as_response_parts();
+
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(response_data, entry.id());
+
+                return (status_code, StreamOrBuffered::Buffered(response), vec![]);
+            }
+        }
+
+        let request = match entry {
+            JsonRpcBatchEntry::Request(request) if request.validate_method() => request,
+            entry => {
+                let size = serde_json::to_string(&entry)
+                    .expect("JsonRpcBatchEntry should always serialize")
+                    .len();
+
+                let request_metadata = RequestMetadata::new(
+                    self,
+                    authorization.clone(),
+                    RequestOrMethod::Method("invalid_request", size),
+                    Some(head_block),
+                )
+                .await;
+
+                request_metadata
+                    .user_error_response
+                    .store(true, Ordering::Release);
+
+                let err = Web3ProxyError::BadRequest("request failed validation".into());
+
+                request_metadata.add_response(&err);
+
+                let (status_code, response_data) = err.as_response_parts();
+
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(response_data, entry.id());
+
+                let _ = request_metadata.try_send_arc_stat();
+
+                return (status_code, StreamOrBuffered::Buffered(response), vec![]);
+            }
+        };
+
+        self.proxy_request(request, authorization.clone(), Some(head_block), false)
+            .await
+    }
+
     pub async fn redis_conn(&self) -> Web3ProxyResult<redis_rate_limiter::RedisConnection> {
         match self.vredis_pool.as_ref() {
             None => Err(Web3ProxyError::NoDatabaseConfigured),
@@ -1107,7 +1583,7 @@ impl Web3ProxyApp {
                         None,
                         Some(Duration::from_secs(30)),
                         Some(Level::TRACE.into()),
-                        None,
+                        self.config.max_fanout(None),
                     )
                     .await;
 
@@ -1115,20 +1591,19 @@ impl Web3ProxyApp {
             }
         }
 
+        // one rpc with poor mempool peering would make the transaction slow to propagate. send it to every synced rpc
         let num_public_rpcs = match request_metadata.proxy_mode() {
-            // TODO: how many balanced rpcs should we send to? configurable? percentage of total?
-            ProxyMode::Best | ProxyMode::Debug => Some(4),
-            ProxyMode::Fastest(0) => None,
-            // TODO: how many balanced rpcs should we send to? configurable? percentage of total?
+            ProxyMode::Best | ProxyMode::Debug | ProxyMode::Versus | ProxyMode::Fastest(0) => None,
             // TODO: what if we do 2 per tier? we want to blast the third party rpcs
             // TODO: maybe having the third party rpcs in their own Web3Rpcs would be good for this
             ProxyMode::Fastest(x) => Some(x * 4),
-            ProxyMode::Versus => None,
         };
 
-        // no private rpcs to send to. send to a few public rpcs
+        // no private rpcs to send to. send to the public rpcs. the rpc_group is used while it is synced, the same as reads
         // try_send_all_upstream_servers puts the request id into the response. no need to do that ourselves here.
-        self.balanced_rpcs
+        let (public_rpcs, _) = self.read_rpcs(None);
+
+        public_rpcs
             .try_send_all_synced_connections(
                 method,
                 params,
@@ -1137,18 +1612,27 @@ impl Web3ProxyApp {
                 None,
                 Some(Duration::from_secs(30)),
                 Some(Level::TRACE.into()),
-                num_public_rpcs,
+                self.config.max_fanout(num_public_rpcs),
             )
             .await
     }
 
     /// proxy request with up to 3 tries.
+    /// if `stream` is true, a big response to one of the `stream_methods` is passed through instead of being read into memory
     async fn proxy_request(
         self: &Arc<Self>,
         mut request: JsonRpcRequest,
         authorization: Arc<Authorization>,
         head_block: Option<&Web3ProxyBlock>,
-    ) -> (StatusCode, JsonRpcForwardedResponse, Vec<Arc<Web3Rpc>>) {
+        stream: bool,
+    ) -> (
+        StatusCode,
+        StreamOrBuffered<JsonRpcForwardedResponse>,
+        Vec<Arc<Web3Rpc>>,
+    ) {
+        // rpc keys can limit which methods they are allowed to call
+        let method_allowed = authorization.check_method(&request.method);
+
         let request_metadata = RequestMetadata::new(
             self,
             authorization,
@@ -1159,18 +1643,46 @@ impl Web3ProxyApp {
 
         let response_id = request.id;
 
-        // TODO: trace/kafka log request.params before we send them to _proxy_request_with_caching which might modify them
+        if let Some(param_metrics) = self.param_metrics.as_ref() {
+            param_metrics.sample(&request.method, &request.params);
+        }
 
-        let (code, response_data) = match self
-            ._proxy_request_with_caching(
-                &request.method,
-                &mut request.params,
-                head_block,
-                Some(2),
-                &request_metadata,
-            )
+        self.method_counts
+            .get_with_by_ref(self.config.counted_method(&request.method), async {
+                Default::default()
+            })
             .await
-        {
+            .fetch_add(1, Ordering::Relaxed);
+
+        // TODO: trace/kafka log request.params before we send them to _proxy_request_with_caching which might modify them
+        // dead letters need the params that the client sent
+        let original_params = self
+            .dead_letters
+            .is_enabled()
+            .then(|| request.params.clone());
+
+        // paid requests hold their cost on the balance first. keys that can't cover it get a 402
+        let reserved = match method_allowed {
+            Ok(()) => request_metadata.reserve_paid_credits().await,
+            Err(err) => Err(err),
+        };
+
+        let response = match reserved {
+            Ok(()) => {
+                self._proxy_request_after_write(
+                    &request.method,
+                    &mut request.params,
+                    head_block,
+                    Some(2),
+                    &request_metadata,
+                    stream.then_some(&*response_id),
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+
+        let (code, response_data) = match response {
             Ok(response_data) => {
                 request_metadata
                     .error_response
@@ -1183,23 +1695,148 @@ impl Web3ProxyApp {
                     .error_response
                     .store(true, Ordering::Release);
 
-                err.as_response_parts()
+                if let Some(params) = original_params.as_ref() {
+                    self.dead_letters
+                        .push(&request.method, params, &request_metadata, &err);
+                }
+
+                let (status_code, response_data) = err.as_response_parts_for(&request_metadata);
+
+                (
+                    self.config.error_status(status_code),
+                    StreamOrBuffered::Buffered(response_data),
+                )
             }
         };
 
-        let response = JsonRpcForwardedResponse::from_response_data(response_data, response_id);
+        let response = match response_data {
+            StreamOrBuffered::Buffered(response_data) => {
+                let response =
+                    JsonRpcForwardedResponse::from_response_data(response_data, response_id);
+
+                // TODO: this serializes twice :/
+                request_metadata.add_response(ResponseOrBytes::Response(&response));
 
-        // TODO: this serializes twice :/
-        request_metadata.add_response(ResponseOrBytes::Response(&response));
+                StreamOrBuffered::Buffered(response)
+            }
+            StreamOrBuffered::Stream(response) => {
+                // the rest of the body is counted as it is sent. the stat is sent once the stream drops its request_metadata
+                request_metadata.add_response(response.buffered_bytes());
+
+                StreamOrBuffered::Stream(response)
+            }
+        };
+
+        if let Some(slow_request_ms) = self.config.slow_request_ms {
+            let elapsed = request_metadata.start_instant.elapsed();
+
+            if elapsed >= Duration::from_millis(slow_request_ms) {
+                let provenance = request_metadata.provenance.lock().clone();
+
+                warn!(method=%request.method, elapsed_ms=%elapsed.as_millis(), ?provenance, "slow request");
+            }
+        }
+
+        if let Some(access_log) = self.access_log.as_ref() {
+            access_log.log(&request.method, &request.params, &request_metadata);
+        }
 
         let rpcs = request_metadata.backend_rpcs_used();
 
+        otel::record_request(
+            &Span::current(),
+            &request.method,
+            &rpcs,
+            request_metadata.start_instant.elapsed(),
+        );
+
         // there might be clones in the background, so this isn't a sure thing
         let _ = request_metadata.try_send_arc_stat();
 
         (code, response, rpcs)
     }
 
+    /// like `_proxy_request_with_caching`, but reads of a transaction that was just sent through this proxy go straight to the rpcs.
+    /// a null result is retried with a backoff until the rpcs have seen it. `read_after_write_wait_ms` limits the wait for each method
+    async fn _proxy_request_after_write(
+        self: &Arc<Self>,
+        method: &str,
+        params: &mut serde_json::Value,
+        head_block: Option<&Web3ProxyBlock>,
+        max_tries: Option<usize>,
+        request_metadata: &Arc<RequestMetadata>,
+        stream_id: Option<&RawValue>,
+    ) -> Web3ProxyResult<StreamOrBuffered<JsonRpcResponseEnum<Arc<RawValue>>>> {
+        let max_wait = self
+            .config
+            .read_after_write_wait_ms
+            .get(method)
+            .filter(|_| {
+                params
+                    .get(0)
+                    .and_then(|x| serde_json::from_value::<H256>(x.clone()).ok())
+                    .map(|x| self.recent_writes.contains_key(&x))
+                    .unwrap_or_default()
+            })
+            .map(|x| Duration::from_millis(*x));
+
+        let max_wait = match max_wait {
+            Some(x) => x,
+            None => {
+                return self
+                    ._proxy_request_with_caching(
+                        method,
+                        params,
+                        head_block,
+                        max_tries,
+                        request_metadata,
+                        stream_id,
+                    )
+                    .await
+            }
+        };
+
+        let start = Instant::now();
+
+        let mut backoff = Duration::from_millis(50);
+
+        loop {
+            let remaining = max_wait.saturating_sub(start.elapsed());
+
+            // a transaction that was just sent is never old. skip the archive retry in the receipt arm (and its archive billing)
+            let response_data: JsonRpcResponseEnum<Arc<RawValue>> = self
+                .balanced_rpcs
+                .try_proxy_connection::<_, Box<RawValue>>(
+                    method,
+                    params,
+                    Some(request_metadata),
+                    max_tries,
+                    Some(remaining),
+                    None,
+                    None,
+                )
+                .await
+                .try_into()?;
+
+            let visible = match &response_data {
+                JsonRpcResponseEnum::Result { value, .. } => value.get() != "null",
+                _ => true,
+            };
+
+            let remaining = max_wait.saturating_sub(start.elapsed());
+
+            if visible || remaining.is_zero() {
+                return Ok(StreamOrBuffered::Buffered(response_data));
+            }
+
+            trace!(%method, ?remaining, "waiting for a recent write to be visible");
+
+            sleep(remaining.min(backoff)).await;
+
+            backoff = (backoff * 2).min(Duration::from_secs(1));
+        }
+    }
+
     /// main logic for proxy_cached_request but in a dedicated function so the try operator is easy to use
     /// TODO: how can we make this generic?
     async fn _proxy_request_with_caching(
@@ -1209,7 +1846,8 @@ impl Web3ProxyApp {
         head_block: Option<&Web3ProxyBlock>,
         max_tries: Option<usize>,
         request_metadata: &Arc<RequestMetadata>,
-    ) -> Web3ProxyResult<JsonRpcResponseEnum<Arc<RawValue>>> {
+        stream_id: Option<&RawValue>,
+    ) -> Web3ProxyResult<StreamOrBuffered<JsonRpcResponseEnum<Arc<RawValue>>>> {
         // TODO: don't clone into a new string?
         let request_method = method.to_string();
 
@@ -1254,9 +1892,6 @@ impl Web3ProxyApp {
             | "eth_compileSerpent"
             | "eth_compileSolidity"
             | "eth_getCompilers"
-            | "eth_sendTransaction"
-            | "eth_sign"
-            | "eth_signTransaction"
             | "eth_submitHashrate"
             | "eth_submitWork"
             | "les_addBalance"
@@ -1270,11 +1905,8 @@ impl Web3ProxyApp {
             | "miner_stop"
             | "personal_ecRecover"
             | "personal_importRawKey"
-            | "personal_listAccounts"
             | "personal_lockAccount"
             | "personal_newAccount"
-            | "personal_sendTransaction"
-            | "personal_sign"
             | "personal_unlockAccount"
             | "shh_addToGroup"
             | "shh_getFilterChanges"
@@ -1293,6 +1925,17 @@ impl Web3ProxyApp {
                     method
                 )).into()
             }
+            // never let a backend sign with its own accounts
+            method if SIGNING_METHODS.contains(&method) => JsonRpcErrorData {
+                message: format!(
+                    "the method {} is not supported. sign locally and use eth_sendRawTransaction",
+                    method
+                )
+                .into(),
+                code: -32601,
+                data: None,
+            }
+            .into(),
             // TODO: implement these commands
             method @ ("eth_getFilterChanges"
             | "eth_getFilterLogs"
@@ -1336,9 +1979,11 @@ impl Web3ProxyApp {
                     return Err(Web3ProxyError::NoServersSynced);
                 }
             },
-            "eth_accounts" => JsonRpcResponseEnum::from(serde_json::Value::Array(vec![])),
+            "eth_accounts" | "personal_listAccounts" => {
+                JsonRpcResponseEnum::from(serde_json::Value::Array(vec![]))
+            }
             "eth_blockNumber" => {
-                match head_block.cloned().or(self.balanced_rpcs.head_block()) {
+                match self.read_rpcs(head_block).1 {
                     Some(head_block) => JsonRpcResponseEnum::from(json!(head_block.number())),
                     None => {
                         return Err(Web3ProxyError::NoServersSynced);
@@ -1349,10 +1994,6 @@ impl Web3ProxyApp {
             // TODO: eth_callBundle (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_callbundle)
             // TODO: eth_cancelPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_cancelprivatetransaction, but maybe just reject)
             // TODO: eth_sendPrivateTransaction (https://docs.flashbots.net/flashbots-auction/searchers/advanced/rpc-endpoint#eth_sendprivatetransaction)
-            "eth_coinbase" => {
-                // no need for serving coinbase
-                JsonRpcResponseEnum::from(json!(Address::zero()))
-            }
             "eth_estimateGas" => {
                 // TODO: timeout
                 let mut gas_estimate = self
@@ -1441,16 +2082,40 @@ impl Web3ProxyApp {
 
                 // TODO: error if the chain_id is incorrect
 
-                let response = timeout(
-                    Duration::from_secs(30),
-                    self
-                        .try_send_protected(
-                            method,
-                            params,
-                            request_metadata,
-                        )
-                )
-                .await?;
+                // the hash of the raw transaction is the transaction's hash
+                let raw_tx_hash = params
+                    .get(0)
+                    .and_then(|x| x.as_str())
+                    .and_then(|x| Bytes::from_str(x).ok())
+                    .map(|x| H256::from(keccak256(x)));
+
+                // a retry of a transaction that was just broadcast gets the same response without broadcasting again
+                let cached = raw_tx_hash
+                    .as_ref()
+                    .and_then(|x| self.sent_transactions.get(x));
+
+                let response = if let Some(cached) = cached {
+                    trace!(?raw_tx_hash, "transaction was already sent");
+
+                    Ok(cached)
+                } else {
+                    let response = timeout(
+                        Duration::from_secs(30),
+                        self
+                            .try_send_protected(
+                                method,
+                                params,
+                                request_metadata,
+                            )
+                    )
+                    .await?;
+
+                    if let (Some(raw_tx_hash), Ok(x)) = (raw_tx_hash, &response) {
+                        self.sent_transactions.insert(raw_tx_hash, x.clone()).await;
+                    }
+
+                    response
+                };
 
                 let mut response = response.try_into()?;
 
@@ -1498,6 +2163,15 @@ impl Web3ProxyApp {
                     }
                 }
 
+                // reads of this transaction might be sent to an rpc that hasn't seen it yet
+                if !self.config.read_after_write_wait_ms.is_empty() {
+                    if let JsonRpcResponseEnum::Result { value, .. } = &response {
+                        if let Ok(tx_hash) = serde_json::from_str::<H256>(value.get()) {
+                            self.recent_writes.insert(tx_hash, ()).await;
+                        }
+                    }
+                }
+
                 // emit transaction count stats
                 // TODO: use this cache to avoid sending duplicate transactions?
                 if let Some(ref salt) = self.config.public_recent_ips_salt {
@@ -1563,9 +2237,10 @@ impl Web3ProxyApp {
             "net_peerCount" => 
                 JsonRpcResponseEnum::from(json!(U64::from(self.balanced_rpcs.num_synced_rpcs())))
             ,
-            "web3_clientVersion" => 
-                JsonRpcResponseEnum::from(serde_json::Value::String(APP_USER_AGENT.to_string()))
-            ,
+            "web3_clientVersion" if !self.config.client_version_passthrough => {
+                // answering this ourselves means the rpcs' clients are not leaked
+                JsonRpcResponseEnum::from(serde_json::Value::String(self.config.client_version()))
+            }
             "web3_sha3" => {
                 // returns Keccak-256 (not the standardized SHA3-256) of the given data.
                 // TODO: timeout
@@ -1618,30 +2293,84 @@ impl Web3ProxyApp {
                 code: -32601,
                 data: None,
             }.into(),
+            method if !self.config.method_allowed(method) => JsonRpcErrorData {
+                message: format!("the method {} does not exist/is not available", method).into(),
+                code: -32601,
+                data: None,
+            }.into(),
             // anything else gets sent to backend rpcs and cached
             method => {
-                if method.starts_with("admin_") {
+                if method_matches(ADMIN_METHODS, method) {
                     // TODO: emit a stat? will probably just be noise
                     return Err(Web3ProxyError::AccessDenied("admin methods are not allowed".into()));
                 }
 
+                if let Some(response_data) = self
+                    .try_private_pending_nonce(method, params, request_metadata)
+                    .await
+                {
+                    return Ok(StreamOrBuffered::Buffered(response_data));
+                }
+
+                let (rpcs, head_block) = self.read_rpcs(head_block);
+
                 // TODO: if no servers synced, wait for them to be synced? probably better to error and let haproxy retry another server
-                let head_block: Web3ProxyBlock = head_block
-                    .cloned()
-                    .or_else(|| self.balanced_rpcs.head_block())
-                    .ok_or(Web3ProxyError::NoServersSynced)?;
+                let head_block: Web3ProxyBlock = head_block.ok_or(Web3ProxyError::NoServersSynced)?;
+
+                if let (Some(max_range), "eth_getLogs") = (self.config.max_logs_block_range, method) {
+                    if let Some((from_block, to_block)) =
+                        logs_block_range(params, head_block.number(), rpcs.finalized_block_num().as_ref())?
+                    {
+                        check_logs_block_range(from_block, to_block, max_range)?;
+                    }
+                }
 
                 // we do this check before checking caches because it might modify the request params
                 // TODO: add a stat for archive vs full since they should probably cost different
                 // TODO: this cache key can be rather large. is that okay?
-                let cache_key: Option<JsonRpcQueryCacheKey> = match CacheMode::new(
-                    method,
-                    params,
-                    &head_block,
-                    &self.balanced_rpcs,
-                )
-                .await
-                {
+                let cache_mode =
+                    CacheMode::new(method, params, &head_block, rpcs).await;
+
+                let from_block_num = cache_mode.from_block_num();
+
+                request_metadata.provenance.lock().block_num = from_block_num.copied();
+
+                if self.config.is_archive_request(method, from_block_num, head_block.number()) {
+                    trace!(%method, ?from_block_num, archive_depth=%self.config.archive_depth, "archive request");
+
+                    request_metadata
+                        .archive_request
+                        .store(true, atomic::Ordering::Release);
+
+                    // a full node would give a confusing error like "missing trie node"
+                    if self.config.reject_archive_requests_without_archive_rpcs
+                        && !rpcs.has_archive_rpcs()
+                    {
+                        return Err(Web3ProxyError::ArchiveDataUnavailable);
+                    }
+                }
+
+                // results that are cached forever need to come from more than one rpc
+                let cache_min_agreement = matches!(cache_mode, CacheMode::CacheSuccessForever)
+                    .then_some(self.config.cache_min_agreement)
+                    .flatten()
+                    .filter(|x| *x > 1);
+
+                // uncached requests still need an rpc that has the block
+                let uncached_block_num = cache_mode.from_block_num().copied();
+
+                // eth_getLogs ranges that are too large for the rpcs are split up and merged again
+                let logs_split_max_depth = (method == "eth_getLogs")
+                    .then_some(self.config.logs_split_max_depth)
+                    .flatten();
+
+                let cache_mode = if method == "eth_call" && !self.config.cache_eth_call {
+                    CacheMode::CacheNever
+                } else {
+                    cache_mode
+                };
+
+                let cache_key: Option<JsonRpcQueryCacheKey> = match cache_mode {
                     CacheMode::CacheSuccessForever => Some(JsonRpcQueryCacheKey::new(
                         None,
                         None,
@@ -1653,66 +2382,191 @@ impl Web3ProxyApp {
                     CacheMode::Cache {
                         block,
                         cache_errors,
-                    } => {
-                        let block_depth = (head_block.number().saturating_sub(*block.num())).as_u64();
-
-                        if block_depth > self.config.archive_depth {
-                            trace!(%block_depth, archive_depth=%self.config.archive_depth);
-
-                            request_metadata
-                                .archive_request
-                                .store(true, atomic::Ordering::Release);
-                        }
-
-                        Some(JsonRpcQueryCacheKey::new(
-                            Some(block),
-                            None,
-                            method,
-                            params,
-                            cache_errors,
-                        ))
-                    }
+                    } => Some(JsonRpcQueryCacheKey::new(
+                        Some(block),
+                        None,
+                        method,
+                        params,
+                        cache_errors,
+                    )),
                     CacheMode::CacheRange {
                         from_block,
                         to_block,
                         cache_errors,
-                    } => {
-                        let block_depth = (head_block.number().saturating_sub(*from_block.num())).as_u64();
+                    } => Some(JsonRpcQueryCacheKey::new(
+                        Some(from_block),
+                        Some(to_block),
+                        method,
+                        params,
+                        cache_errors,
+                    )),
+                };
 
-                        if block_depth > self.config.archive_depth {
-                            trace!(%block_depth, archive_depth=%self.config.archive_depth);
+                // "latest" eth_call responses from the previous head are never asked for again
+                let cache_latest = method == "eth_call" && cache_key.is_some();
 
-                            request_metadata
-                                .archive_request
-                                .store(true, atomic::Ordering::Release);
-                        }
+                if cache_latest {
+                    self.jsonrpc_response_block_index
+                        .on_new_head(head_block.hash())
+                        .await;
+                }
+
+                // TODO: different timeouts for different user tiers. get the duration out of the request_metadata
+                let backend_request_timetout = Duration::from_secs(240);
+
+                let quorum = request_metadata
+                    .authorization
+                    .as_ref()
+                    .and_then(|x| x.quorum);
 
-                        Some(JsonRpcQueryCacheKey::new(
-                            Some(from_block),
-                            Some(to_block),
+                if let Some(quorum) = quorum {
+                    // the cache only has one rpc's answer. ask the rpcs directly
+                    let from_block_num = cache_key.as_ref().and_then(|x| x.from_block_num().copied());
+                    let to_block_num = cache_key.as_ref().and_then(|x| x.to_block_num().copied());
+
+                    let response_data: JsonRpcResponseEnum<Arc<RawValue>> = timeout(
+                        backend_request_timetout + Duration::from_millis(100),
+                        rpcs.try_send_quorum(
                             method,
                             params,
-                            cache_errors,
-                        ))
-                    }
-                };
+                            Some(request_metadata),
+                            quorum,
+                            from_block_num.as_ref(),
+                            to_block_num.as_ref(),
+                        ),
+                    )
+                    .await?
+                    .try_into()?;
 
-                // TODO: different timeouts for different user tiers. get the duration out of the request_metadata
-                let backend_request_timetout = Duration::from_secs(240);
+                    self.strip_response_fields(method, response_data)
+                } else if let Some(stream_id) =
+                    stream_id.filter(|_| self.config.stream_methods.contains(method))
+                {
+                    let from_block_num = cache_key.as_ref().and_then(|x| x.from_block_num().copied());
+                    let to_block_num = cache_key.as_ref().and_then(|x| x.to_block_num().copied());
+                    let cache_jsonrpc_errors =
+                        cache_key.as_ref().map(|x| x.cache_errors()).unwrap_or_default();
+
+                    let fetch = async {
+                        let response = timeout(
+                            backend_request_timetout + Duration::from_millis(100),
+                            rpcs.stream_with_metadata(
+                                stream_id,
+                                method,
+                                params,
+                                request_metadata,
+                                self.config.stream_response_min_bytes,
+                                Some(backend_request_timetout),
+                                from_block_num.as_ref(),
+                                to_block_num.as_ref(),
+                            ),
+                        )
+                        .await?;
+
+                        match response {
+                            Ok(StreamOrBuffered::Buffered(x)) => Ok(StreamOrBuffered::Buffered(
+                                self.strip_response_fields(method, x.into()),
+                            )),
+                            Ok(StreamOrBuffered::Stream(x)) => Ok(StreamOrBuffered::Stream(x)),
+                            // same as the cache below. jsonrpc errors are only saved if the cache key allows it
+                            Err(err) if cache_jsonrpc_errors => {
+                                Ok(StreamOrBuffered::Buffered(err.try_into()?))
+                            }
+                            Err(err) => Err(err),
+                        }
+                    };
+
+                    let response_data = if let Some(cache_key) = cache_key {
+                        let cache_bypass = request_metadata
+                            .authorization
+                            .as_ref()
+                            .map(|x| x.cache_bypass)
+                            .unwrap_or_default();
+
+                        let (response_data, cache_status) = self
+                            .jsonrpc_response_cache_stats
+                            .get_or_stream_with(
+                                &self.jsonrpc_response_cache,
+                                cache_key.hash(),
+                                cache_bypass,
+                                fetch,
+                            )
+                            .await;
+
+                        request_metadata.provenance.lock().cache = Some(cache_status);
+
+                        if matches!(cache_status, CacheStatus::Miss | CacheStatus::Bypassed) {
+                            self.jsonrpc_response_block_index
+                                .insert(
+                                    &cache_key,
+                                    head_block.number(),
+                                    rpcs.finalized_block_num().as_ref(),
+                                )
+                                .await;
+
+                            if cache_latest {
+                                self.jsonrpc_response_block_index
+                                    .insert_head(&cache_key, head_block.hash())
+                                    .await;
+                            }
+                        }
+
+                        response_data?
+                    } else {
+                        fetch.await?
+                    };
 
-                if let Some(cache_key) = cache_key {
+                    match response_data {
+                        StreamOrBuffered::Buffered(x) => x,
+                        // big responses go straight to the client. they are never cached
+                        stream => return Ok(stream),
+                    }
+                } else if let Some(cache_key) = cache_key {
                     let from_block_num = cache_key.from_block_num().copied();
                     let to_block_num = cache_key.to_block_num().copied();
                     let cache_jsonrpc_errors = cache_key.cache_errors();
 
                     // TODO: try to fetch out of s3
 
-                    self
-                        .jsonrpc_response_cache
-                        .try_get_with::<_, Web3ProxyError>(cache_key.hash(), async {
+                    let fetch = async {
+                        if let Some(cache_min_agreement) = cache_min_agreement {
+                            // one bad rpc should not be able to poison the cache forever.
+                            // only the best `cache_min_agreement` rpcs are asked, not every synced rpc
                             let response_data = timeout(
                                 backend_request_timetout + Duration::from_millis(100),
-                                self.balanced_rpcs
+                                rpcs.try_send_quorum(
+                                    method,
+                                    params,
+                                    Some(request_metadata),
+                                    cache_min_agreement,
+                                    from_block_num.as_ref(),
+                                    to_block_num.as_ref(),
+                                ),
+                            )
+                            .await??;
+
+                            return Ok(self.strip_response_fields(method, response_data.into()));
+                        }
+
+                        let response_data = if let Some(max_depth) = logs_split_max_depth {
+                            timeout(
+                                backend_request_timetout + Duration::from_millis(100),
+                                rpcs.try_proxy_logs(
+                                    params,
+                                    Some(request_metadata),
+                                    max_tries,
+                                    Some(backend_request_timetout),
+                                    max_depth,
+                                    self.config.logs_split_max_queries,
+                                    from_block_num.as_ref(),
+                                    to_block_num.as_ref(),
+                                ),
+                            )
+                            .await?
+                        } else {
+                            timeout(
+                                backend_request_timetout + Duration::from_millis(100),
+                                rpcs
                                     .try_proxy_connection::<_, Arc<RawValue>>(
                                         method,
                                         params,
@@ -1722,42 +2576,201 @@ impl Web3ProxyApp {
                                         from_block_num.as_ref(),
                                         to_block_num.as_ref(),
                                     ))
-                                .await?;
+                                .await?
+                        };
 
-                            if !cache_jsonrpc_errors && let Err(err) = response_data {
-                                // if we are not supposed to cache jsonrpc errors,
-                                // then we must not convert Provider errors into a JsonRpcResponseEnum
-                                // return all the errors now. moka will not cache Err results
-                                Err(err)
-                            } else {
-                                let response_data: JsonRpcResponseEnum<Arc<RawValue>> = response_data.try_into()?;
+                        if !cache_jsonrpc_errors && let Err(err) = response_data {
+                            // if we are not supposed to cache jsonrpc errors,
+                            // then we must not convert Provider errors into a JsonRpcResponseEnum
+                            // return all the errors now. moka will not cache Err results
+                            Err(err)
+                        } else {
+                            let response_data: JsonRpcResponseEnum<Arc<RawValue>> = response_data.try_into()?;
 
-                                // TODO: response data should maybe be Arc<JsonRpcResponseEnum<Box<RawValue>>>, but that's more work
-                                Ok(response_data)
-                            }
-                        }).await?
+                            let response_data = self.strip_response_fields(method, response_data);
+
+                            // TODO: response data should maybe be Arc<JsonRpcResponseEnum<Box<RawValue>>>, but that's more work
+                            Ok::<_, Web3ProxyError>(response_data)
+                        }
+                    };
+
+                    let cache_bypass = request_metadata
+                        .authorization
+                        .as_ref()
+                        .map(|x| x.cache_bypass)
+                        .unwrap_or_default();
+
+                    let (response_data, cache_status) = if cache_bypass {
+                        self.jsonrpc_response_cache_stats
+                            .refresh_with(&self.jsonrpc_response_cache, cache_key.hash(), fetch)
+                            .await
+                    } else {
+                        self.jsonrpc_response_cache_stats
+                            .try_get_with_status(&self.jsonrpc_response_cache, cache_key.hash(), fetch)
+                            .await
+                    };
+
+                    request_metadata.provenance.lock().cache = Some(cache_status);
+
+                    if matches!(cache_status, CacheStatus::Miss | CacheStatus::Bypassed) {
+                        self.jsonrpc_response_block_index
+                            .insert(
+                                &cache_key,
+                                head_block.number(),
+                                rpcs.finalized_block_num().as_ref(),
+                            )
+                            .await;
+
+                        if cache_latest {
+                            self.jsonrpc_response_block_index
+                                .insert_head(&cache_key, head_block.hash())
+                                .await;
+                        }
+                    }
+
+                    match response_data {
+                        Ok(x) => x,
+                        Err(err)
+                            if cache_min_agreement.is_some()
+                                && matches!(
+                                    *err,
+                                    Web3ProxyError::InconsistentUpstreams(_)
+                                        | Web3ProxyError::NotEnoughRpcs { .. }
+                                ) =>
+                        {
+                            // not enough rpcs agree yet. answer like an uncachable request
+                            trace!(%method, ?err, "not caching without agreement");
+
+                            let x = timeout(
+                                backend_request_timetout + Duration::from_millis(100),
+                                rpcs.try_proxy_connection::<_, Arc<RawValue>>(
+                                    method,
+                                    params,
+                                    Some(request_metadata),
+                                    max_tries,
+                                    Some(backend_request_timetout),
+                                    from_block_num.as_ref(),
+                                    to_block_num.as_ref(),
+                                ),
+                            )
+                            .await??;
+
+                            self.strip_response_fields(method, x.into())
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                } else if let Some(max_depth) = logs_split_max_depth {
+                    let x = timeout(
+                        backend_request_timetout + Duration::from_millis(100),
+                        rpcs.try_proxy_logs(
+                            params,
+                            Some(request_metadata),
+                            max_tries,
+                            Some(backend_request_timetout),
+                            max_depth,
+                            self.config.logs_split_max_queries,
+                            uncached_block_num.as_ref(),
+                            None,
+                        ),
+                    )
+                    .await??;
+
+                    self.strip_response_fields(method, x.into())
                 } else {
                     let x = timeout(
                         backend_request_timetout + Duration::from_millis(100),
-                        self.balanced_rpcs
+                        rpcs
                         .try_proxy_connection::<_, Arc<RawValue>>(
                             method,
                             params,
                             Some(request_metadata),
                             max_tries,
                             Some(backend_request_timetout),
-                            None,
+                            uncached_block_num.as_ref(),
                             None,
                         )
                     )
                     .await??;
 
-                    x.into()
+                    self.strip_response_fields(method, x.into())
                 }
             }
         };
 
-        Ok(response_data)
+        Ok(StreamOrBuffered::Buffered(response_data))
+    }
+
+    /// the rpcs that serve requests and their head block. the configured rpc_group is used while it is synced.
+    /// this covers reads and transactions that go to the public rpcs. subscriptions always use balanced_rpcs.
+    /// `head_block` is from balanced_rpcs, so it is only used if they are
+    fn read_rpcs(
+        &self,
+        head_block: Option<&Web3ProxyBlock>,
+    ) -> (&Arc<Web3Rpcs>, Option<Web3ProxyBlock>) {
+        if let Some(group_rpcs) = self
+            .config
+            .rpc_group
+            .as_ref()
+            .and_then(|x| self.rpc_groups.get(x))
+            .filter(|x| x.synced())
+        {
+            return (group_rpcs, group_rpcs.head_block());
+        }
+
+        (
+            &self.balanced_rpcs,
+            head_block
+                .cloned()
+                .or_else(|| self.balanced_rpcs.head_block()),
+        )
+    }
+
+    /// the rpc that accepted the sender's latest transaction serves their pending nonce.
+    /// balanced_rpcs and the rpc groups check this themselves, but a private rpc might be the only one that has seen the transaction.
+    /// None to use the other rpcs like normal
+    async fn try_private_pending_nonce(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Option<JsonRpcResponseEnum<Arc<RawValue>>> {
+        let rpc = self
+            .private_rpcs
+            .as_ref()?
+            .sticky_pending_nonce_rpc(method, params)?;
+
+        let authorization = request_metadata.authorization.clone().unwrap_or_default();
+
+        let handle = match rpc
+            .try_request_handle(&authorization, Some(method), None)
+            .await
+        {
+            Ok(OpenRequestResult::Handle(x)) => x,
+            _ => return None,
+        };
+
+        request_metadata.provenance.lock().choose(&rpc);
+        request_metadata.backend_requests.lock().push(rpc.clone());
+
+        match handle.request::<_, Arc<RawValue>>(method, params).await {
+            Ok(x) => Some(x.into()),
+            Err(err) => {
+                trace!(?err, "{} did not serve the pending nonce", rpc);
+                None
+            }
+        }
+    }
+
+    /// remove the configured node-specific fields from a method's response
+    fn strip_response_fields(
+        &self,
+        method: &str,
+        response_data: JsonRpcResponseEnum<Arc<RawValue>>,
+    ) -> JsonRpcResponseEnum<Arc<RawValue>> {
+        match self.config.strip_response_fields.get(method) {
+            Some(pointers) => response_data.strip_fields(pointers),
+            None => response_data,
+        }
     }
 }
 
@@ -6,20 +6,25 @@ use crate::frontend::authorization::{Authorization, RequestMetadata, RequestOrMe
 use crate::jsonrpc::JsonRpcForwardedResponse;
 use crate::jsonrpc::JsonRpcRequest;
 use crate::response_cache::JsonRpcResponseEnum;
+use crate::rpcs::subscriptions::{PendingFullTxSender, TxStatus, UpstreamSubscription};
 use axum::extract::ws::{CloseFrame, Message};
 use deferred_rate_limiter::DeferredRateLimitResult;
-use ethers::types::U64;
+use ethers::types::{Filter, Transaction, U64};
 use futures::future::AbortHandle;
-use futures::future::Abortable;
+use futures::future::{Abortable, FutureExt};
 use futures::stream::StreamExt;
 use http::StatusCode;
 use serde_json::json;
 use std::sync::atomic::{self, AtomicU64};
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc};
 use tokio::time::Instant;
-use tokio_stream::wrappers::WatchStream;
-use tracing::{error, trace};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, WatchStream};
+use tracing::{debug, error, trace, warn};
+
+/// how many pending transactions the newPendingFullTransactions upstream fetches at once
+const MAX_PENDING_TX_FETCHES: usize = 16;
 
 impl Web3ProxyApp {
     pub async fn eth_subscribe<'a>(
@@ -30,6 +35,8 @@ impl Web3ProxyApp {
         // TODO: taking a sender for Message instead of the exact json we are planning to send feels wrong, but its easier for now
         response_sender: mpsc::Sender<Message>,
     ) -> Web3ProxyResult<(AbortHandle, JsonRpcForwardedResponse)> {
+        authorization.check_method(&jsonrpc_request.method)?;
+
         let request_metadata = RequestMetadata::new(
             self,
             authorization.clone(),
@@ -59,6 +66,7 @@ impl Web3ProxyApp {
         // TODO: calling json! on every request is probably not fast. but we can only match against
         // TODO: i think we need a stricter EthSubscribeRequest type that JsonRpcRequest can turn into
         if subscribe_to == "newHeads" {
+            // a watch only keeps the latest head. if the client is slow, heads in between are skipped instead of queued
             let head_block_receiver = self.watch_consensus_head_receiver.clone();
             let app = self.clone();
 
@@ -123,6 +131,87 @@ impl Web3ProxyApp {
 
                 trace!("closed newHeads subscription {:?}", subscription_id);
             });
+        } else if subscribe_to == "logs" {
+            let filter: Filter = match jsonrpc_request.params.get(1) {
+                None => Default::default(),
+                Some(x) => serde_json::from_value(x.clone()).map_err(|_| {
+                    Web3ProxyError::BadRequest("unable to parse the logs filter".into())
+                })?,
+            };
+
+            // clients with the same filter share one upstream subscription. filters with the same json are the same subscription
+            let key = serde_json::to_string(&filter)?;
+
+            let app = self.clone();
+
+            let upstream_logs = self
+                .logs_subscriptions
+                .subscribe(key, |sender| async move {
+                    app.balanced_rpcs.subscribe_logs(filter, sender).await
+                })?;
+
+            tokio::spawn(Abortable::new(
+                self.clone().forward_subscription(
+                    authorization,
+                    subscription_id,
+                    "eth_subscribe(logs)",
+                    upstream_logs,
+                    response_sender,
+                    |log| Some(json!(log)),
+                ),
+                subscription_registration,
+            ));
+        } else if subscribe_to == "newPendingTransactions"
+            || subscribe_to == "newPendingFullTransactions"
+        {
+            // with `true`, the full transactions are sent instead of just their hashes
+            let full_txs = subscribe_to == "newPendingFullTransactions"
+                || jsonrpc_request
+                    .params
+                    .get(1)
+                    .and_then(|x| x.as_bool())
+                    .unwrap_or(false);
+
+            let f = if full_txs {
+                // each transaction is fetched once upstream, not once per client
+                let app = self.clone();
+
+                let upstream_pending_full_txs = self
+                    .pending_full_txs_subscriptions
+                    .subscribe((), |sender| async move {
+                        app.forward_pending_full_txs(sender).await
+                    })?;
+
+                self.clone()
+                    .forward_subscription(
+                        authorization,
+                        subscription_id,
+                        "eth_subscribe(newPendingTransactions)",
+                        upstream_pending_full_txs,
+                        response_sender,
+                        |tx| Some(json!(tx)),
+                    )
+                    .boxed()
+            } else {
+                let upstream_pending_txs = self.subscribe_pending_txs()?;
+
+                self.clone()
+                    .forward_subscription(
+                        authorization,
+                        subscription_id,
+                        "eth_subscribe(newPendingTransactions)",
+                        upstream_pending_txs,
+                        response_sender,
+                        |tx_status| match tx_status {
+                            TxStatus::Pending(tx_hash) => Some(json!(tx_hash)),
+                            // only pending transactions are part of this subscription
+                            TxStatus::Confirmed(_) => None,
+                        },
+                    )
+                    .boxed()
+            };
+
+            tokio::spawn(Abortable::new(f, subscription_registration));
         } else {
             // TODO: make sure this gets a CU cost of unimplemented instead of the normal eth_subscribe cost?
             return Err(Web3ProxyError::NotImplemented(
@@ -143,6 +232,172 @@ impl Web3ProxyApp {
         Ok((subscription_abort_handle, response))
     }
 
+    /// join the upstream pending transactions subscription. every client shares one
+    fn subscribe_pending_txs(
+        self: &Arc<Self>,
+    ) -> Web3ProxyResult<Arc<UpstreamSubscription<TxStatus>>> {
+        let app = self.clone();
+
+        self.pending_txs_subscriptions
+            .subscribe((), |sender| async move {
+                app.balanced_rpcs.subscribe_pending_txs(sender).await
+            })
+    }
+
+    /// the upstream for newPendingFullTransactions. each pending transaction is fetched once and sent to every client
+    async fn forward_pending_full_txs(self: Arc<Self>, sender: PendingFullTxSender) {
+        // this keeps the upstream for the hashes running while any clients want full transactions
+        let upstream_pending_txs = match self.subscribe_pending_txs() {
+            Ok(x) => x,
+            Err(err) => {
+                warn!(?err, "unable to subscribe to pending transactions");
+                return;
+            }
+        };
+
+        BroadcastStream::new(upstream_pending_txs.subscribe())
+            .filter_map(|x| async move {
+                match x {
+                    Ok(TxStatus::Pending(tx_hash)) => Some(tx_hash),
+                    Ok(TxStatus::Confirmed(_)) => None,
+                    Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+                        warn!(skipped, "pending full transactions upstream lagged");
+                        None
+                    }
+                }
+            })
+            .map(|tx_hash| {
+                let app = self.clone();
+
+                async move {
+                    let tx = app
+                        .internal_request::<_, Option<Transaction>>(
+                            "eth_getTransactionByHash",
+                            (tx_hash,),
+                        )
+                        .await;
+
+                    (tx_hash, tx)
+                }
+            })
+            .buffer_unordered(MAX_PENDING_TX_FETCHES)
+            .for_each(|(tx_hash, tx)| {
+                match tx {
+                    // no clients is fine. they might come back before the upstream is dropped
+                    Ok(Some(tx)) => {
+                        let _ = sender.send(Arc::new(tx));
+                    }
+                    // it was confirmed or dropped before we could fetch it
+                    Ok(None) => {}
+                    Err(err) => {
+                        debug!(?err, %tx_hash, "unable to fetch pending transaction");
+                    }
+                }
+
+                async {}
+            })
+            .await;
+    }
+
+    /// send the messages from a shared upstream subscription to one client until it leaves or is rate limited.
+    /// `to_result` turns a message into the subscription's result. messages that the client didn't ask for are None
+    async fn forward_subscription<T, F>(
+        self: Arc<Self>,
+        authorization: Arc<Authorization>,
+        subscription_id: U64,
+        method: &'static str,
+        upstream: Arc<UpstreamSubscription<T>>,
+        response_sender: mpsc::Sender<Message>,
+        to_result: F,
+    ) where
+        T: Clone,
+        F: Fn(T) -> Option<serde_json::Value>,
+    {
+        // the upstream subscription stops once the last client drops `upstream`
+        let mut receiver = upstream.subscribe();
+
+        loop {
+            let result = match receiver.recv().await {
+                Ok(x) => match to_result(x) {
+                    Some(x) => x,
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    // a slow client misses messages instead of holding up everyone else. let it know
+                    warn!(?subscription_id, skipped, "{} lagged", method);
+
+                    let response_json = json!({
+                        "jsonrpc": "2.0",
+                        "method":"eth_subscription",
+                        "params": {
+                            "subscription": subscription_id,
+                            "error": {
+                                "code": -32000,
+                                "message": format!("subscription lagged. {} messages were skipped", skipped),
+                            },
+                        },
+                    });
+
+                    let response_str = serde_json::to_string(&response_json)
+                        .expect("this should always be valid json");
+
+                    if response_sender
+                        .send(Message::Text(response_str))
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    };
+
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let subscription_request_metadata = RequestMetadata::new(
+                &self,
+                authorization.clone(),
+                RequestOrMethod::Method(method, 0),
+                None,
+            )
+            .await;
+
+            if let Some(close_message) = self
+                .rate_limit_close_websocket(&subscription_request_metadata)
+                .await
+            {
+                let _ = response_sender.send(close_message).await;
+                break;
+            }
+
+            let response_json = json!({
+                "jsonrpc": "2.0",
+                "method":"eth_subscription",
+                "params": {
+                    "subscription": subscription_id,
+                    "result": result,
+                },
+            });
+
+            let response_str =
+                serde_json::to_string(&response_json).expect("this should always be valid json");
+
+            let response_bytes = response_str.len();
+
+            if response_sender
+                .send(Message::Text(response_str))
+                .await
+                .is_err()
+            {
+                break;
+            };
+
+            subscription_request_metadata.add_response(response_bytes);
+        }
+
+        trace!("closed {} subscription {:?}", method, subscription_id);
+    }
+
     async fn rate_limit_close_websocket(
         &self,
         request_metadata: &RequestMetadata,
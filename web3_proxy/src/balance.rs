@@ -28,6 +28,10 @@ pub struct Balance {
     /// this always increments and so will always be >= total_spent_paid_credits
     pub total_spent: Decimal,
     pub total_spent_paid_credits: Decimal,
+    /// paid credits held by requests that are still in flight.
+    /// the stat buffer releases them when it gets the request's stat
+    #[serde(default)]
+    pub reserved_paid_credits: Decimal,
     pub user_id: u64,
     pub user_tier_id: u64,
     pub downgrade_tier_id: Option<u64>,
@@ -46,6 +50,7 @@ impl Debug for Balance {
             .field("total_frontend_requests", &self.total_frontend_requests)
             .field("total_spent", &self.total_spent)
             .field("total_spent_paid_credits", &self.total_spent_paid_credits)
+            .field("reserved_paid_credits", &self.reserved_paid_credits)
             .field("user_id", &self.user_id)
             .field("user_tier_id", &self.user_tier_id)
             .field("downgrade_tier_id", &self.downgrade_tier_id)
@@ -106,6 +111,23 @@ impl Balance {
         self.total_deposits() - self.total_spent_paid_credits
     }
 
+    /// hold `cost` paid credits for a request that is about to be proxied.
+    /// returns false if the remaining balance can't cover it on top of the other requests in flight
+    pub fn try_reserve(&mut self, cost: Decimal) -> bool {
+        if self.remaining() - self.reserved_paid_credits < cost {
+            return false;
+        }
+
+        self.reserved_paid_credits += cost;
+
+        true
+    }
+
+    /// give back credits held by `try_reserve`
+    pub fn release(&mut self, reserved: Decimal) {
+        self.reserved_paid_credits = (self.reserved_paid_credits - reserved).max(0.into());
+    }
+
     pub fn total_deposits(&self) -> Decimal {
         self.admin_deposits
             + self.chain_deposits
@@ -268,6 +290,7 @@ impl Balance {
             total_frontend_requests,
             total_spent,
             total_spent_paid_credits,
+            reserved_paid_credits: 0.into(),
             user_id,
             user_tier_id: user_tier_entry.id,
             downgrade_tier_id: user_tier_entry.downgrade_tier_id,
@@ -279,3 +302,58 @@ impl Balance {
         Ok(Some(balance))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Balance;
+    use migration::sea_orm::prelude::Decimal;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use tokio::sync::RwLock as AsyncRwLock;
+
+    #[test_log::test(tokio::test)]
+    async fn test_concurrent_reservations_near_zero() {
+        let cost = Decimal::from_str("0.4").unwrap();
+
+        // enough for 2 requests, but not for 3
+        let balance = Balance {
+            admin_deposits: 10.into(),
+            total_spent_paid_credits: Decimal::from_str("9.1").unwrap(),
+            user_id: 1,
+            ..Default::default()
+        };
+
+        let balance = Arc::new(AsyncRwLock::new(balance));
+
+        let handles: Vec<_> = (0..20)
+            .map(|_| {
+                let balance = balance.clone();
+
+                tokio::spawn(async move { balance.write().await.try_reserve(cost) })
+            })
+            .collect();
+
+        let mut reserved = 0;
+        for handle in handles {
+            if handle.await.unwrap() {
+                reserved += 1;
+            }
+        }
+
+        assert_eq!(reserved, 2);
+
+        // charging the actual cost and releasing the holds never goes below zero
+        {
+            let mut balance = balance.write().await;
+
+            for _ in 0..reserved {
+                balance.total_spent_paid_credits += cost;
+                balance.release(cost);
+            }
+
+            assert_eq!(balance.reserved_paid_credits, Decimal::from(0));
+            assert_eq!(balance.remaining(), Decimal::from_str("0.1").unwrap());
+            assert!(!balance.try_reserve(cost));
+        }
+    }
+}
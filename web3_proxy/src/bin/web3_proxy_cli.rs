@@ -13,6 +13,7 @@ use std::{
 use tokio::runtime;
 use tracing::{info, warn};
 use tracing_subscriber::{prelude::*, EnvFilter};
+use web3_proxy::otel;
 use web3_proxy::pagerduty::panic_handler;
 use web3_proxy::sub_commands;
 use web3_proxy::{
@@ -229,6 +230,43 @@ fn main() -> anyhow::Result<()> {
         }
     });
 
+    // set up tokio's async runtime
+    let mut rt_builder = runtime::Builder::new_multi_thread();
+
+    rt_builder.enable_all();
+
+    if cli_config.workers > 0 {
+        rt_builder.worker_threads(cli_config.workers);
+    }
+
+    if let Some(ref top_config) = top_config {
+        let chain_id = top_config.app.chain_id;
+
+        rt_builder.thread_name_fn(move || {
+            static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
+            // TODO: what ordering? i think we want seqcst so that these all happen in order, but that might be stricter than we really need
+            let worker_id = ATOMIC_ID.fetch_add(1, atomic::Ordering::SeqCst);
+            // TODO: i think these max at 15 characters
+            format!("web3-{}-{}", chain_id, worker_id)
+        });
+    }
+
+    // start tokio's async runtime
+    let rt = rt_builder.build()?;
+
+    // optionally export spans to an OpenTelemetry collector
+    let mut otel_layer = None;
+    if let Some(top_config) = top_config.as_ref() {
+        if let Some(otlp_endpoint) = top_config.app.otlp_endpoint.as_ref() {
+            // the otlp exporter sends batches from a task on the runtime
+            let _guard = rt.enter();
+
+            let tracer = otel::otlp_tracer(otlp_endpoint, top_config.app.otlp_sample_rate)?;
+
+            otel_layer = Some(otel::layer(tracer));
+        }
+    }
+
     tracing_subscriber::fmt()
         // create a subscriber that uses the RUST_LOG env var for filtering levels
         .with_env_filter(EnvFilter::builder().parse(rust_log)?)
@@ -240,6 +278,8 @@ fn main() -> anyhow::Result<()> {
         .finish()
         // attach tracing layer.
         .with(sentry_tracing::layer())
+        // attach the OpenTelemetry layer (if any)
+        .with(otel_layer)
         // register as the default global subscriber
         .init();
 
@@ -273,34 +313,10 @@ fn main() -> anyhow::Result<()> {
         }));
     }
 
-    // set up tokio's async runtime
-    let mut rt_builder = runtime::Builder::new_multi_thread();
-
-    rt_builder.enable_all();
-
-    if cli_config.workers > 0 {
-        rt_builder.worker_threads(cli_config.workers);
-    }
-
-    if let Some(ref top_config) = top_config {
-        let chain_id = top_config.app.chain_id;
-
-        rt_builder.thread_name_fn(move || {
-            static ATOMIC_ID: AtomicUsize = AtomicUsize::new(0);
-            // TODO: what ordering? i think we want seqcst so that these all happen in order, but that might be stricter than we really need
-            let worker_id = ATOMIC_ID.fetch_add(1, atomic::Ordering::SeqCst);
-            // TODO: i think these max at 15 characters
-            format!("web3-{}-{}", chain_id, worker_id)
-        });
-    }
-
-    // start tokio's async runtime
-    let rt = rt_builder.build()?;
-
     let num_workers = rt.metrics().num_workers();
     info!("num_workers: {}", num_workers);
 
-    rt.block_on(async {
+    let result = rt.block_on(async {
         match cli_config.sub_command {
             SubCommand::ChangeAdminStatus(x) => {
                 let db_url = cli_config.db_url.expect(
@@ -457,5 +473,10 @@ fn main() -> anyhow::Result<()> {
                 x.main(&db_conn).await
             }
         }
-    })
+    });
+
+    // send any spans that are still buffered
+    opentelemetry::global::shutdown_tracer_provider();
+
+    result
 }
@@ -1,4 +1,5 @@
 //! Helper functions for turning ether's BlockNumber into numbers and updating incoming queries to match.
+use crate::jsonrpc::JsonRpcErrorData;
 use crate::rpcs::many::Web3Rpcs;
 use crate::{
     errors::{Web3ProxyError, Web3ProxyResult},
@@ -13,14 +14,28 @@ use ethers::{
 use serde_json::json;
 use tracing::{error, trace, warn};
 
+/// how far behind the head a block needs to be before we treat it as finalized.
+/// only used until the rpcs agree on a "finalized" block, and on chains without the tag
+pub const FINALIZED_DEPTH: u64 = 10;
+
+/// the most sub-ranges suggested when an eth_getLogs range is too large. clients can continue after the last one
+pub const MAX_SUGGESTED_LOGS_CHUNKS: usize = 100;
+
+/// `finalized_block` is the block that the rpcs agree is finalized (`Web3Rpcs::finalized_block_num`).
+/// If it is None, "finalized" and "safe" are guessed from the head
 #[allow(non_snake_case)]
-pub fn BlockNumber_to_U64(block_num: BlockNumber, latest_block: &U64) -> (U64, bool) {
+pub fn BlockNumber_to_U64(
+    block_num: BlockNumber,
+    latest_block: &U64,
+    finalized_block: Option<&U64>,
+) -> (U64, bool) {
     match block_num {
         BlockNumber::Earliest => (U64::zero(), false),
-        BlockNumber::Finalized => {
-            warn!("finalized block requested! not yet implemented!");
-            (*latest_block - 10, false)
-        }
+        BlockNumber::Finalized => match finalized_block {
+            // change the tag to the number so that the backend doesn't answer with a different block than we cache with
+            Some(x) => (*x, true),
+            None => (latest_block.saturating_sub(FINALIZED_DEPTH.into()), false),
+        },
         BlockNumber::Latest => {
             // change "latest" to a number
             (*latest_block, true)
@@ -34,10 +49,11 @@ pub fn BlockNumber_to_U64(block_num: BlockNumber, latest_block: &U64) -> (U64, b
             // TODO: think more about how to handle Pending
             (*latest_block, false)
         }
-        BlockNumber::Safe => {
-            warn!("safe block requested! not yet implemented!");
-            (*latest_block - 3, false)
-        }
+        BlockNumber::Safe => match finalized_block {
+            // "safe" is always at or after "finalized", so the finalized block is safe too
+            Some(x) => (*x, true),
+            None => (latest_block.saturating_sub(3.into()), false),
+        },
     }
 }
 
@@ -51,6 +67,15 @@ impl BlockNumAndHash {
     pub fn hash(&self) -> &H256 {
         &self.1
     }
+
+    /// blocks at or before the finalized block can't reorg.
+    /// without a `finalized_block_num`, blocks deep enough behind the head are not expected to reorg
+    pub fn is_finalized(&self, head_block_num: &U64, finalized_block_num: Option<&U64>) -> bool {
+        match finalized_block_num {
+            Some(finalized_block_num) => self.0 <= *finalized_block_num,
+            None => head_block_num.saturating_sub(self.0) >= FINALIZED_DEPTH.into(),
+        }
+    }
 }
 
 impl From<&Web3ProxyBlock> for BlockNumAndHash {
@@ -126,21 +151,21 @@ pub async fn clean_block_number(
                     } else if let Ok(block_number) =
                         serde_json::from_value::<BlockNumber>(x.clone())
                     {
-                        let (block_num, change) =
-                            BlockNumber_to_U64(block_number, latest_block.number());
+                        let (block_num, change) = BlockNumber_to_U64(
+                            block_number,
+                            latest_block.number(),
+                            rpcs.finalized_block_num().as_ref(),
+                        );
 
                         if block_num == *latest_block.number() {
                             (latest_block.into(), change)
                         } else {
-                            let (block_hash, _) = rpcs
-                                .block_hash(&block_num)
-                                .await
-                                .context("fetching block hash from number")?;
-
                             let block = rpcs
-                                .block(&block_hash, None, Some(3), None)
+                                .block_for_tag(block_number)
                                 .await
-                                .context("fetching block from hash")?;
+                                .context("fetching block for tag")?;
+
+                            let block = Web3ProxyBlock::try_from(block)?;
 
                             (BlockNumAndHash::from(&block), change)
                         }
@@ -171,6 +196,78 @@ pub async fn clean_block_number(
     }
 }
 
+/// the block range of an eth_getLogs request. missing blocks default to the head block like geth does.
+/// None if the request is for a single block hash
+pub fn logs_block_range(
+    params: &serde_json::Value,
+    head_block_num: &U64,
+    finalized_block_num: Option<&U64>,
+) -> Web3ProxyResult<Option<(U64, U64)>> {
+    let obj = params
+        .get(0)
+        .and_then(|x| x.as_object())
+        .ok_or_else(|| Web3ProxyError::BadRequest("invalid format. params not object".into()))?;
+
+    if obj.contains_key("blockHash") {
+        return Ok(None);
+    }
+
+    let mut range = [*head_block_num; 2];
+
+    for (x, key) in range.iter_mut().zip(["fromBlock", "toBlock"]) {
+        if let Some(block_num) = obj.get(key) {
+            let block_num: BlockNumber = serde_json::from_value(block_num.clone())?;
+
+            *x = BlockNumber_to_U64(block_num, head_block_num, finalized_block_num).0;
+        }
+    }
+
+    Ok(Some((range[0], range[1])))
+}
+
+/// an error with a machine readable hint of the allowed range if the eth_getLogs range is more than max_range blocks
+pub fn check_logs_block_range(
+    from_block: U64,
+    to_block: U64,
+    max_range: u64,
+) -> Result<(), JsonRpcErrorData> {
+    let max_range = max_range.max(1);
+
+    if to_block < from_block || (to_block - from_block).as_u64() < max_range {
+        return Ok(());
+    }
+
+    let chunks: Vec<_> = (from_block.as_u64()..=to_block.as_u64())
+        .step_by(max_range as usize)
+        .take(MAX_SUGGESTED_LOGS_CHUNKS)
+        .map(|chunk_from| {
+            let chunk_to = chunk_from
+                .saturating_add(max_range - 1)
+                .min(to_block.as_u64());
+
+            json!({
+                "fromBlock": U64::from(chunk_from),
+                "toBlock": U64::from(chunk_to),
+            })
+        })
+        .collect();
+
+    Err(JsonRpcErrorData {
+        message: format!(
+            "eth_getLogs is limited to a {} block range. split the request into the suggested chunks",
+            max_range
+        )
+        .into(),
+        code: -32005,
+        data: Some(json!({
+            "from": from_block,
+            "to": to_block,
+            "maxRange": max_range,
+            "chunks": chunks,
+        })),
+    })
+}
+
 /// TODO: change this to also return the hash needed?
 pub enum CacheMode {
     CacheSuccessForever,
@@ -204,12 +301,40 @@ impl CacheMode {
         }
     }
 
+    /// the oldest block the request needs. None if the request isn't tied to a block we know about
+    pub fn from_block_num(&self) -> Option<&U64> {
+        match self {
+            Self::Cache { block, .. } => Some(block.num()),
+            Self::CacheRange { from_block, .. } => Some(from_block.num()),
+            Self::CacheNever | Self::CacheSuccessForever => None,
+        }
+    }
+
+    /// state reads are only cached once their block is finalized.
+    /// the key includes the block's hash, so the entry can never be served for a different chain
+    pub fn for_state_read(
+        block: BlockNumAndHash,
+        head_block: &Web3ProxyBlock,
+        finalized_block_num: Option<&U64>,
+    ) -> Self {
+        if block.is_finalized(head_block.number(), finalized_block_num) {
+            Self::Cache {
+                block,
+                cache_errors: true,
+            }
+        } else {
+            Self::CacheNever
+        }
+    }
+
     pub async fn try_new(
         method: &str,
         params: &mut serde_json::Value,
         head_block: &Web3ProxyBlock,
         rpcs: &Web3Rpcs,
     ) -> Web3ProxyResult<Self> {
+        let finalized_block_num = rpcs.finalized_block_num();
+
         // some requests have potentially very large responses
         // TODO: only skip caching if the response actually is large
         if method.starts_with("trace_") || method == "debug_traceTransaction" {
@@ -270,8 +395,11 @@ impl CacheMode {
                         // what if its a hash?
                         let block_num: BlockNumber = serde_json::from_value(x.clone())?;
 
-                        let (block_num, change) =
-                            BlockNumber_to_U64(block_num, head_block.number());
+                        let (block_num, change) = BlockNumber_to_U64(
+                            block_num,
+                            head_block.number(),
+                            finalized_block_num.as_ref(),
+                        );
 
                         if change {
                             // TODO: include the hash instead of the number?
@@ -291,8 +419,11 @@ impl CacheMode {
                         // what if its a hash?
                         let block_num: BlockNumber = serde_json::from_value(x.clone())?;
 
-                        let (block_num, change) =
-                            BlockNumber_to_U64(block_num, head_block.number());
+                        let (block_num, change) = BlockNumber_to_U64(
+                            block_num,
+                            head_block.number(),
+                            finalized_block_num.as_ref(),
+                        );
 
                         if change {
                             trace!("changing toBlock in eth_getLogs. {} -> {}", x, block_num);
@@ -361,25 +492,186 @@ impl CacheMode {
             }
         };
 
-        match clean_block_number(params, block_param_id, head_block, rpcs).await {
-            Ok(block) => Ok(CacheMode::Cache {
-                block,
-                cache_errors: true,
-            }),
+        // state reads and block receipts can change with a reorg. only cache them once their block is finalized
+        let finalized_only = matches!(
+            method,
+            "eth_getBalance"
+                | "eth_getBlockReceipts"
+                | "eth_getCode"
+                | "eth_getStorageAt"
+                | "eth_getTransactionCount"
+        );
+
+        let block = match clean_block_number(params, block_param_id, head_block, rpcs).await {
+            Ok(block) => block,
             Err(Web3ProxyError::NoBlocksKnown) => {
                 warn!(%method, ?params, "no servers available to get block from params");
-                Ok(CacheMode::Cache {
-                    block: head_block.into(),
-                    cache_errors: true,
-                })
+                head_block.into()
             }
             Err(err) => {
                 error!(%method, ?params, ?err, "could not get block from params");
-                Ok(CacheMode::Cache {
-                    block: head_block.into(),
-                    cache_errors: true,
-                })
+                head_block.into()
+            }
+        };
+
+        if finalized_only {
+            Ok(Self::for_state_read(
+                block,
+                head_block,
+                finalized_block_num.as_ref(),
+            ))
+        } else {
+            Ok(CacheMode::Cache {
+                block,
+                cache_errors: true,
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_logs_block_range, logs_block_range, BlockNumAndHash, BlockNumber_to_U64, CacheMode,
+        FINALIZED_DEPTH,
+    };
+    use crate::rpcs::blockchain::Web3ProxyBlock;
+    use ethers::types::{Block, BlockNumber, H256, U64};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn logs_range_suggests_chunks() {
+        let head_block_num = U64::from(1_000);
+
+        let params = json!([{"fromBlock": "0x64", "toBlock": "latest"}]);
+
+        let (from_block, to_block) = logs_block_range(&params, &head_block_num, None)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(from_block, 100.into());
+        assert_eq!(to_block, head_block_num);
+
+        // missing blocks default to the head
+        assert_eq!(
+            logs_block_range(&json!([{}]), &head_block_num, None).unwrap(),
+            Some((head_block_num, head_block_num))
+        );
+
+        // a single block hash has no range
+        assert_eq!(
+            logs_block_range(&json!([{"blockHash": H256::zero()}]), &head_block_num, None).unwrap(),
+            None
+        );
+
+        // exactly the max is allowed
+        check_logs_block_range(100.into(), 599.into(), 500).unwrap();
+
+        let err = check_logs_block_range(from_block, to_block, 500).unwrap_err();
+
+        assert_eq!(err.code, -32005);
+        assert_eq!(
+            err.data,
+            Some(json!({
+                "from": "0x64",
+                "to": "0x3e8",
+                "maxRange": 500,
+                "chunks": [
+                    {"fromBlock": "0x64", "toBlock": "0x257"},
+                    {"fromBlock": "0x258", "toBlock": "0x3e8"},
+                ],
+            }))
+        );
+    }
+
+    #[test]
+    fn state_reads_only_cache_finalized_blocks() {
+        let head_num = U64::from(100);
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(head_num),
+            ..Default::default()
+        };
+
+        let head_block = Web3ProxyBlock::try_new(Arc::new(head_block)).unwrap();
+
+        // "latest" resolves to the head block. that block is not finalized
+        let latest = BlockNumAndHash::from(&head_block);
+
+        assert!(matches!(
+            CacheMode::for_state_read(latest, &head_block, None),
+            CacheMode::CacheNever
+        ));
+
+        let finalized_num = head_num - FINALIZED_DEPTH;
+        let finalized_hash = H256::random();
+
+        let finalized = BlockNumAndHash(finalized_num, finalized_hash);
+
+        match CacheMode::for_state_read(finalized, &head_block, None) {
+            CacheMode::Cache { block, .. } => {
+                assert_eq!(block.num(), &finalized_num);
+                assert_eq!(block.hash(), &finalized_hash);
             }
+            _ => panic!("finalized state reads should be cached"),
         }
+
+        let recent = BlockNumAndHash(finalized_num + 1, H256::random());
+
+        assert!(matches!(
+            CacheMode::for_state_read(recent.clone(), &head_block, None),
+            CacheMode::CacheNever
+        ));
+
+        // once the rpcs agree on a finalized block, that is used instead of the depth
+        let agreed_finalized_num = finalized_num + 1;
+
+        assert!(matches!(
+            CacheMode::for_state_read(recent, &head_block, Some(&agreed_finalized_num)),
+            CacheMode::Cache { .. }
+        ));
+
+        let after_agreed = BlockNumAndHash(agreed_finalized_num + 1, H256::random());
+
+        assert!(matches!(
+            CacheMode::for_state_read(after_agreed, &head_block, Some(&agreed_finalized_num)),
+            CacheMode::CacheNever
+        ));
+    }
+
+    #[test]
+    fn finalized_and_safe_tags() {
+        let head_num = U64::from(100);
+
+        // without an agreed finalized block, the tags are guessed from the head and sent to the backend unchanged
+        assert_eq!(
+            BlockNumber_to_U64(BlockNumber::Finalized, &head_num, None),
+            (head_num - FINALIZED_DEPTH, false)
+        );
+        assert_eq!(
+            BlockNumber_to_U64(BlockNumber::Safe, &head_num, None),
+            (head_num - 3, false)
+        );
+
+        // the agreed block is used for both tags, and the request is changed to match
+        let finalized_num = U64::from(64);
+
+        assert_eq!(
+            BlockNumber_to_U64(BlockNumber::Finalized, &head_num, Some(&finalized_num)),
+            (finalized_num, true)
+        );
+        assert_eq!(
+            BlockNumber_to_U64(BlockNumber::Safe, &head_num, Some(&finalized_num)),
+            (finalized_num, true)
+        );
+
+        let params = json!([{"fromBlock": "finalized", "toBlock": "safe"}]);
+
+        assert_eq!(
+            logs_block_range(&params, &head_num, Some(&finalized_num)).unwrap(),
+            Some((finalized_num, finalized_num))
+        );
     }
 }
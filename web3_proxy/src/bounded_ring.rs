@@ -0,0 +1,57 @@
+//! Keep the most recent few of something, like reorgs or dead letters, for the admin endpoints.
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+
+/// The most recent items. Once `max` are saved, the oldest is dropped for each new one
+#[derive(Debug)]
+pub struct BoundedRing<T> {
+    max: usize,
+    items: Mutex<VecDeque<T>>,
+}
+
+/// derive would require `T: Default`
+impl<T> Default for BoundedRing<T> {
+    fn default() -> Self {
+        Self {
+            max: 0,
+            items: Default::default(),
+        }
+    }
+}
+
+impl<T: Clone> BoundedRing<T> {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            items: Mutex::new(VecDeque::with_capacity(max)),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// false if `max` is 0 and nothing is ever saved
+    pub fn is_enabled(&self) -> bool {
+        self.max > 0
+    }
+
+    pub fn push(&self, item: T) {
+        if self.max == 0 {
+            return;
+        }
+
+        let mut items = self.items.lock();
+
+        if items.len() >= self.max {
+            items.pop_front();
+        }
+
+        items.push_back(item);
+    }
+
+    /// oldest first
+    pub fn to_vec(&self) -> Vec<T> {
+        self.items.lock().iter().cloned().collect()
+    }
+}
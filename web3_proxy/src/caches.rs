@@ -3,18 +3,72 @@ use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::authorization::{AuthorizationChecks, RpcSecretKey};
 use derive_more::From;
 use entities::rpc_key;
+use futures::StreamExt;
 use migration::sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
 use moka::future::Cache;
+use redis_rate_limiter::redis;
 use std::fmt;
 use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::RwLock as AsyncRwLock;
-use tracing::trace;
+use tokio::sync::{broadcast, RwLock as AsyncRwLock};
+use tokio::time::{sleep, Duration};
+use tracing::{trace, warn};
 
 /// Cache data from the database about rpc keys
 /// TODO: try Ulid/u128 instead of RpcSecretKey in case my hash method is broken
 pub type RpcSecretKeyCache = Cache<RpcSecretKey, AuthorizationChecks>;
 
+/// Redis pub/sub channel for secret keys that every instance should drop from its `RpcSecretKeyCache`
+pub const RPC_SECRET_KEY_INVALIDATION_CHANNEL: &str = "web3_proxy:rpc_secret_key_invalidations";
+
+/// Drop secret keys from the local cache when any instance publishes them on `RPC_SECRET_KEY_INVALIDATION_CHANNEL`.
+/// Reconnects on errors. The cache's TTL covers anything published while disconnected.
+pub async fn subscribe_rpc_secret_key_invalidations(
+    redis_url: String,
+    rpc_secret_key_cache: RpcSecretKeyCache,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    let client = redis::Client::open(redis_url)?;
+
+    loop {
+        let f = async {
+            let mut pubsub = client.get_async_connection().await?.into_pubsub();
+
+            pubsub
+                .subscribe(RPC_SECRET_KEY_INVALIDATION_CHANNEL)
+                .await?;
+
+            let mut messages = pubsub.on_message();
+
+            while let Some(msg) = messages.next().await {
+                let payload: String = msg.get_payload()?;
+
+                match payload.parse::<RpcSecretKey>() {
+                    Ok(rpc_secret_key) => {
+                        trace!(%rpc_secret_key, "invalidating rotated key");
+                        rpc_secret_key_cache.invalidate(&rpc_secret_key).await;
+                    }
+                    Err(err) => warn!(?err, %payload, "invalid secret key invalidation"),
+                }
+            }
+
+            Ok::<_, Web3ProxyError>(())
+        };
+
+        tokio::select! {
+            x = f => {
+                if let Err(err) = x {
+                    warn!(?err, "secret key invalidation subscription failed. reconnecting");
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+            _ = shutdown_receiver.recv() => {
+                return Ok(());
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Hash, Eq, PartialEq)]
 pub struct RegisteredUserRateLimitKey(pub u64, pub IpAddr);
 
@@ -1,14 +1,18 @@
-use crate::app::Web3ProxyJoinHandle;
+use crate::app::{Web3ProxyJoinHandle, APP_USER_AGENT};
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::rpcs::blockchain::{BlocksByHashCache, Web3ProxyBlock};
+use crate::rpcs::consensus::ConsensusStrategy;
 use crate::rpcs::one::Web3Rpc;
 use argh::FromArgs;
 use ethers::prelude::{Address, TxHash};
 use ethers::types::{U256, U64};
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
+use http::StatusCode;
 use migration::sea_orm::prelude::Decimal;
 use sentry::types::Dsn;
 use serde::Deserialize;
 use serde_inline_default::serde_inline_default;
+use std::fmt;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
@@ -48,6 +52,9 @@ pub struct TopConfig {
     pub balanced_rpcs: HashMap<String, Web3RpcConfig>,
     pub private_rpcs: Option<HashMap<String, Web3RpcConfig>>,
     pub bundler_4337_rpcs: Option<HashMap<String, Web3RpcConfig>>,
+    /// group name -> rpcs. every group keeps its own consensus head. forks between the groups are logged and shown on `/status`
+    #[serde(default = "Default::default")]
+    pub rpc_groups: HashMap<String, HashMap<String, Web3RpcConfig>>,
     /// unknown config options get put here
     #[serde(flatten, default = "HashMap::default")]
     pub extra: HashMap<String, serde_json::Value>,
@@ -58,6 +65,18 @@ pub struct TopConfig {
 #[serde_inline_default]
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
 pub struct AppConfig {
+    /// Write a json line for every request to this file. "-" writes to stdout. None = no access log
+    pub access_log_path: Option<String>,
+
+    /// The params of these methods are left out of the access log. A trailing `*` matches any suffix.
+    /// By default, raw transactions and anything that signs are left out
+    #[serde(default = "default_access_log_redacted_methods")]
+    pub access_log_redacted_methods: HashSet<String>,
+
+    /// Only log 1 out of this many requests in the access log
+    #[serde_inline_default(1u32)]
+    pub access_log_sample_rate: u32,
+
     /// Request limit for allowed origins for anonymous users.
     /// These requests get rate limited by IP.
     #[serde(default = "Default::default")]
@@ -67,14 +86,49 @@ pub struct AppConfig {
     #[serde_inline_default(90_000u64)]
     pub archive_depth: u64,
 
+    /// Methods that always need an archive node, no matter which block they ask about.
+    #[serde(default = "Default::default")]
+    pub archive_methods: HashSet<String>,
+
+    /// save the most recent blocks on the heaviest chain to this file and load them on startup. None = don't save blocks
+    pub block_snapshot_path: Option<String>,
+
+    /// how many blocks below the head to save in the block snapshot. older blocks in a loaded snapshot are skipped
+    #[serde_inline_default(128u64)]
+    pub block_snapshot_depth: u64,
+
+    /// Only cache results that never change (like blocks by hash) once this many rpcs give the same answer.
+    /// Each cache miss for these results is sent to this many of the best rpcs, and their parsed results are compared.
+    /// If they disagree, the answer from a single rpc is served without being cached.
+    /// None = cache the first rpc's answer
+    pub cache_min_agreement: Option<usize>,
+
     /// EVM chain id. 1 for ETH
     /// TODO: better type for chain_id? max of `u64::MAX / 2 - 36` <https://github.com/ethereum/EIPs/issues/2294>
     #[serde_inline_default(1u64)]
     pub chain_id: u64,
 
+    /// How to pick between heads on different chains. "highest_block_number" or "highest_total_difficulty".
+    /// Total difficulty stopped increasing at the merge, so only proof of work chains should compare it.
+    #[serde(default = "Default::default")]
+    pub consensus_strategy: ConsensusStrategy,
+
+    /// Name returned for web3_clientVersion. The crate version is appended.
+    /// None = "llamanodes_web3_proxy/v{version}"
+    pub client_version: Option<String>,
+
+    /// Send web3_clientVersion to the backend rpcs instead of answering it ourselves.
+    /// This leaks which clients the rpcs are running!
+    #[serde(default = "Default::default")]
+    pub client_version_passthrough: bool,
+
     /// Cost per computational unit
     // pub cost_per_cu: Decimal,
 
+    /// Save the params of dead letters as they are. By default only their hash is saved because params can be sensitive
+    #[serde(default = "Default::default")]
+    pub dead_letter_raw_params: bool,
+
     /// Database is used for user data.
     /// Currently supports mysql or compatible backend.
     pub db_url: Option<String>,
@@ -106,12 +160,38 @@ pub struct AppConfig {
     /// Default ERC address for out deposit contract
     pub deposit_factory_contract: Option<Address>,
 
+    /// change the http status of error responses. keys are the status as a string because toml keys are strings.
+    /// `{ "502" = 503 }` makes load balancers that only retry 503s also retry when no servers are synced.
+    #[serde(default = "Default::default")]
+    pub error_status_codes: HashMap<String, u16>,
+
+    /// requests for these methods always go to the synced rpc with the lowest latency instead of being load balanced
+    #[serde(default = "Default::default")]
+    pub fastest_rpc_methods: HashSet<String>,
+
+    /// a request for a block up to this many past the consensus head waits for the block to arrive.
+    /// blocks further ahead are an error right away
+    #[serde_inline_default(2u64)]
+    pub future_block_grace: u64,
+
+    /// the longest a request waits for a block within `future_block_grace`
+    #[serde_inline_default(1_000u64)]
+    pub future_block_wait_ms: u64,
+
     /// minimum amount to increase eth_estimateGas results
     pub gas_increase_min: Option<U256>,
 
     /// percentage to increase eth_estimateGas results. 100 == 100%
     pub gas_increase_percent: Option<U256>,
 
+    /// wait this long before publishing a new head block that only a minority of rpcs are on.
+    /// heads that are quickly uncled are then never sent to users.
+    pub head_publish_delay_ms: Option<u64>,
+
+    /// Check 1 out of this many new heads from each rpc by asking another rpc for the same block.
+    /// rpcs whose heads can't be confirmed several times in a row are left out of consensus until one can be. None disables this
+    pub head_verification_sample_rate: Option<u32>,
+
     /// Restrict user registration.
     /// None = no code needed
     pub invite_code: Option<String>,
@@ -123,17 +203,78 @@ pub struct AppConfig {
     #[serde_inline_default("ssl".to_string())]
     pub kafka_protocol: String,
 
+    /// Methods that are forwarded to the backend rpcs when `reject_unknown_methods` is set.
+    /// Methods that the proxy answers itself are always allowed.
+    #[serde(default = "default_known_methods")]
+    pub known_methods: HashSet<String>,
+
     /// domain in sign-in-with-ethereum messages
     pub login_domain: Option<String>,
 
+    /// when an rpc says an eth_getLogs range is too large, split it in half and send both halves at once.
+    /// halves that are still too large are split again, up to this many times. None = never split
+    pub logs_split_max_depth: Option<u32>,
+
+    /// the most queries that one eth_getLogs request can be split into
+    #[serde_inline_default(16usize)]
+    pub logs_split_max_queries: usize,
+
+    /// how many of the most recent requests that failed on every backend rpc to keep for `/admin/dead_letters`.
+    /// with volatile_redis_url set, they are also saved in redis
+    #[serde_inline_default(100usize)]
+    pub max_dead_letters: usize,
+
+    /// the most requests allowed in one json-rpc batch. bigger batches are rejected without sending any of their requests
+    #[serde_inline_default(1_000usize)]
+    pub max_batch_size: usize,
+
+    /// the most backend rpcs a single request is sent to before its error is returned. None = try every rpc that can serve it.
+    /// keys can ask for a different number with the `X-W3P-MAX-ATTEMPTS` header, but never more than this
+    pub max_backend_attempts: Option<usize>,
+
     /// do not serve any requests if the best known block is behind the best known block by more than this many blocks.
     pub max_head_block_lag: Option<U64>,
 
+    /// reject eth_getLogs requests that cover more blocks than this. the error includes suggested sub-ranges.
+    /// None = no limit
+    pub max_logs_block_range: Option<u64>,
+
+    /// ignore head blocks with unknown parents that are more than this many blocks past the consensus head instead of fetching their parents.
+    /// None = always fetch the parents
+    pub max_parent_fetch_depth: Option<u64>,
+
+    /// how many of the most recent reorgs to keep for `/admin/reorgs`
+    #[serde_inline_default(16usize)]
+    pub max_recent_reorgs: usize,
+
+    /// the most rpcs that a single broadcast (like eth_sendRawTransaction) is sent to. the best scored rpcs are used.
+    /// None = no limit
+    pub max_fanout_rpcs: Option<usize>,
+
+    /// the most upstream `logs` subscriptions that can be open at once.
+    /// clients that subscribe with the same filter share a single upstream subscription.
+    #[serde_inline_default(100usize)]
+    pub max_upstream_logs_subscriptions: usize,
+
     /// Rate limit for the login entrypoint.
     /// This is separate from the rpc limits.
     #[serde_inline_default(10u64)]
     pub login_rate_limit_per_period: u64,
 
+    /// The error message for rpc requests while an admin has turned on maintenance mode.
+    #[serde_inline_default("down for maintenance. please try again later".to_string())]
+    pub maintenance_message: String,
+
+    /// route methods to a dedicated pool of rpcs. method (or prefix ending in `*`) -> pool name.
+    /// `{"trace_*": "archive"}` only sends trace methods to rpcs with `pool = "archive"`. rpcs with a pool never serve any other methods.
+    #[serde(default = "Default::default")]
+    pub method_pools: HashMap<String, String>,
+
+    /// how long each backend rpc gets to answer these methods. method -> milliseconds.
+    /// an rpc that takes longer is cancelled and the request is tried on another rpc. requests that send transactions are not tried again.
+    #[serde(default = "Default::default")]
+    pub method_timeouts_ms: HashMap<String, u64>,
+
     /// The soft limit prevents thundering herds as new blocks are seen.
     #[serde_inline_default(1u32)]
     pub min_sum_soft_limit: u32,
@@ -142,6 +283,26 @@ pub struct AppConfig {
     #[serde_inline_default(1usize)]
     pub min_synced_rpcs: usize,
 
+    /// stop sending requests to an rpc that has been on a minority fork for this long. it is used again once it rejoins the consensus chain.
+    /// None = rpcs on a fork are only skipped for blocks that they don't have
+    pub minority_fork_timeout_ms: Option<u64>,
+
+    /// Export request spans to this OpenTelemetry collector. <http://localhost:4317> for a local Jaeger or Tempo
+    pub otlp_endpoint: Option<String>,
+
+    /// Only 1 out of this many requests is exported to the OpenTelemetry collector.
+    #[serde_inline_default(100u32)]
+    pub otlp_sample_rate: u32,
+
+    /// Count a param for these methods. method -> json pointer into the params.
+    /// `{"eth_call": "/0/to"}` counts the contracts that are called. The most common values are exported to prometheus.
+    #[serde(default = "Default::default")]
+    pub param_metrics: HashMap<String, String>,
+
+    /// Only 1 out of this many matching requests has its param counted.
+    #[serde_inline_default(100u32)]
+    pub param_metrics_sample_rate: u32,
+
     /// Concurrent request limit for anonymous users.
     /// Some(0) = block all requests
     /// None = allow all requests
@@ -152,22 +313,93 @@ pub struct AppConfig {
     /// None = allow all requests
     pub public_requests_per_period: Option<u64>,
 
+    /// How many of our own proxies (like a load balancer) are in front of the frontend.
+    /// The client's ip is read that many entries from the right of X-Forwarded-For. 0 = use the connecting ip.
+    /// None = use the left-most entry, which the client can spoof.
+    pub trusted_proxy_hops: Option<usize>,
+
     /// Salt for hashing recent ips. Not a perfect way to introduce privacy, but better than nothing
     pub public_recent_ips_salt: Option<String>,
 
+    /// for this long after starting, a head that doesn't reach min_synced_rpcs or min_sum_soft_limit is served as a provisional head.
+    /// after that, full consensus is required. None = full consensus is always required
+    pub provisional_head_window_ms: Option<u64>,
+
+    /// After a transaction is sent through this proxy, reads of it get a null result until the chosen rpc has seen it.
+    /// For these methods (which take the transaction hash first), nulls are retried for up to this many milliseconds.
+    /// method -> max wait. `{"eth_getTransactionReceipt": 2_000}`
+    #[serde(default = "Default::default")]
+    pub read_after_write_wait_ms: HashMap<String, u64>,
+
     /// RPC responses are cached locally
     #[serde_inline_default(10u64.pow(8))]
     pub response_cache_max_bytes: u64,
 
+    /// eth_call responses are cached by block. "latest" responses are dropped when a new head arrives.
+    /// false = every eth_call goes to the rpcs
+    #[serde_inline_default(true)]
+    pub cache_eth_call: bool,
+
     /// the stats page url for an anonymous user.
     pub redirect_public_url: Option<String>,
 
     /// the stats page url for a logged in user. if set, must contain "{rpc_key_id}"
     pub redirect_rpc_key_url: Option<String>,
 
+    /// Respond with "archive data not available" instead of forwarding archive requests when none of the rpcs are archive nodes.
+    /// false = full nodes are tried anyway
+    #[serde(default = "Default::default")]
+    pub reject_archive_requests_without_archive_rpcs: bool,
+
+    /// Respond with "method not found" instead of forwarding methods that are not in `known_methods`.
+    /// false = new and chain-specific methods are passed through to the backend rpcs
+    #[serde(default = "Default::default")]
+    pub reject_unknown_methods: bool,
+
+    /// serve requests with this group from `rpc_groups` (and its head block) instead of balanced_rpcs.
+    /// balanced_rpcs are still used while the group is not synced.
+    /// transactions use the group when there are no private_rpcs. subscriptions (and their head blocks) always come from balanced_rpcs
+    pub rpc_group: Option<String>,
+
+    /// take turns between the synced rpcs in the best tier instead of preferring the one with the lowest latency.
+    /// under light load, latency based selection sends nearly everything to the same rpc.
+    /// turns are in proportion to each rpc's soft_limit
+    #[serde(default = "Default::default")]
+    pub round_robin_rpcs: bool,
+
     /// Optionally send errors to <https://sentry.io>
     pub sentry_url: Option<Dsn>,
 
+    /// How long each phase of shutting down can take. The frontend stops taking requests, in-flight requests drain, consensus stops, and then stats are flushed.
+    /// A phase that runs out of time is logged and the next phase still runs, so stats are always flushed.
+    #[serde_inline_default(30_000u64)]
+    pub shutdown_max_wait_ms: u64,
+
+    /// Log how requests that take longer than this many milliseconds were routed
+    pub slow_request_ms: Option<u64>,
+
+    /// Save buffered stats early once any single aggregate has counted this many frontend requests.
+    /// The save intervals still apply. If None, stats are only saved on the intervals.
+    pub stat_flush_frontend_requests: Option<u64>,
+
+    /// Keys can send an `X-W3P-SESSION` header (on http requests or the websocket upgrade) to have their requests with the same session served by the same rpc.
+    /// Sessions are forgotten after this many milliseconds without a request. This spreads load less evenly. None = ignore the header
+    pub sticky_session_ttl_ms: Option<u64>,
+
+    /// Methods with responses that can be streamed to http clients instead of being read into memory first.
+    /// Streamed responses are not cached and `strip_response_fields` does not apply to them.
+    #[serde(default = "Default::default")]
+    pub stream_methods: HashSet<String>,
+
+    /// Responses to `stream_methods` that are at least this many bytes are streamed. Smaller responses are handled like any other
+    #[serde_inline_default(1_000_000usize)]
+    pub stream_response_min_bytes: usize,
+
+    /// Remove fields from a method's responses before they are cached or returned. method -> json pointers into the result.
+    /// Useful for node-specific fields that would otherwise make the cached response depend on which rpc served it.
+    #[serde(default = "Default::default")]
+    pub strip_response_fields: HashMap<String, Vec<String>>,
+
     /// Stripe api key for checking validity of webhooks
     pub stripe_whsec_key: Option<String>,
 
@@ -181,6 +413,11 @@ pub struct AppConfig {
     /// If none, workers * 2 is used
     pub volatile_redis_max_connections: Option<usize>,
 
+    /// Requests that are sent through the cache on startup. /health is not ok until they are done.
+    /// Failures are logged and do not stop the proxy from starting.
+    #[serde(default = "Default::default")]
+    pub warmup_requests: Vec<WarmupRequest>,
+
     /// influxdb host for stats
     pub influxdb_host: Option<String>,
 
@@ -204,6 +441,20 @@ pub struct AppConfig {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+fn default_access_log_redacted_methods() -> HashSet<String> {
+    [
+        "eth_sendRawTransaction",
+        "eth_sendTransaction",
+        "eth_sign",
+        "eth_signTransaction",
+        "eth_signTypedData*",
+        "personal_*",
+    ]
+    .into_iter()
+    .map(|x| x.to_string())
+    .collect()
+}
+
 fn default_influxdb_id() -> String {
     match hostname::get() {
         Ok(x) => x.into_string().unwrap_or_else(|hostname| {
@@ -223,12 +474,144 @@ fn default_influxdb_id() -> String {
     }
 }
 
+/// methods that the proxy answers itself instead of forwarding. they are allowed even if they aren't in `known_methods`
+const LOCAL_METHODS: [&str; 17] = [
+    "eth_accounts",
+    "eth_blockNumber",
+    "eth_chainId",
+    "eth_estimateGas",
+    "eth_getTransactionByHash",
+    "eth_getTransactionReceipt",
+    "eth_hashrate",
+    "eth_mining",
+    "eth_sendRawTransaction",
+    "eth_subscribe",
+    "eth_syncing",
+    "eth_unsubscribe",
+    "net_listening",
+    "net_peerCount",
+    "personal_listAccounts",
+    "web3_clientVersion",
+    "web3_sha3",
+];
+
+/// the standard json-rpc methods (and the common debug and trace namespaces)
+fn default_known_methods() -> HashSet<String> {
+    [
+        "debug_traceBlockByHash",
+        "debug_traceBlockByNumber",
+        "debug_traceCall",
+        "debug_traceTransaction",
+        "eth_blobBaseFee",
+        "eth_call",
+        "eth_createAccessList",
+        "eth_feeHistory",
+        "eth_gasPrice",
+        "eth_getBalance",
+        "eth_getBlockByHash",
+        "eth_getBlockByNumber",
+        "eth_getBlockReceipts",
+        "eth_getBlockTransactionCountByHash",
+        "eth_getBlockTransactionCountByNumber",
+        "eth_getCode",
+        "eth_getLogs",
+        "eth_getProof",
+        "eth_getStorageAt",
+        "eth_getTransactionByBlockHashAndIndex",
+        "eth_getTransactionByBlockNumberAndIndex",
+        "eth_getTransactionCount",
+        "eth_getUncleByBlockHashAndIndex",
+        "eth_getUncleByBlockNumberAndIndex",
+        "eth_getUncleCountByBlockHash",
+        "eth_getUncleCountByBlockNumber",
+        "eth_maxPriorityFeePerGas",
+        "eth_protocolVersion",
+        "net_version",
+        "trace_block",
+        "trace_call",
+        "trace_callMany",
+        "trace_filter",
+        "trace_get",
+        "trace_rawTransaction",
+        "trace_replayBlockTransactions",
+        "trace_replayTransaction",
+        "trace_transaction",
+        "web3_clientVersion",
+    ]
+    .into_iter()
+    .map(|x| x.to_string())
+    .collect()
+}
+
 impl Default for AppConfig {
     fn default() -> Self {
         serde_json::from_str("{}").unwrap()
     }
 }
 
+impl AppConfig {
+    /// the response for web3_clientVersion when it is not sent to the backend rpcs
+    pub fn client_version(&self) -> String {
+        match self.client_version.as_ref() {
+            Some(x) => format!("{}/v{}", x, env!("CARGO_PKG_VERSION")),
+            None => APP_USER_AGENT.to_string(),
+        }
+    }
+
+    /// false if the method should be answered with "method not found" instead of being sent to the backend rpcs
+    pub fn method_allowed(&self, method: &str) -> bool {
+        !self.reject_unknown_methods || self.known_methods.contains(method)
+    }
+
+    /// the name to count a request under in the metrics. clients can send any method name, so anything unknown is "other"
+    pub fn counted_method<'a>(&self, method: &'a str) -> &'a str {
+        if self.known_methods.contains(method) || LOCAL_METHODS.contains(&method) {
+            method
+        } else {
+            "other"
+        }
+    }
+
+    /// true if the request needs state that full nodes have pruned. this sets `archive_request` for both stats and routing
+    pub fn is_archive_request(
+        &self,
+        method: &str,
+        block_num: Option<&U64>,
+        head_block_num: &U64,
+    ) -> bool {
+        if self.archive_methods.contains(method) {
+            return true;
+        }
+
+        match block_num {
+            Some(block_num) => {
+                head_block_num.saturating_sub(*block_num) > self.archive_depth.into()
+            }
+            None => false,
+        }
+    }
+
+    /// the http status to send for an error response. jsonrpc errors already have a 200 and are not mapped
+    pub fn error_status(&self, code: StatusCode) -> StatusCode {
+        if code == StatusCode::OK {
+            return code;
+        }
+
+        self.error_status_codes
+            .get(code.as_str())
+            .and_then(|x| StatusCode::from_u16(*x).ok())
+            .unwrap_or(code)
+    }
+
+    /// cap the number of rpcs a broadcast wants with the configured max_fanout_rpcs
+    pub fn max_fanout(&self, wanted: Option<usize>) -> Option<usize> {
+        match (wanted, self.max_fanout_rpcs) {
+            (Some(wanted), Some(max)) => Some(wanted.min(max)),
+            (wanted, max) => wanted.or(max),
+        }
+    }
+}
+
 /// TODO: we can't query a provider because we need this to create a provider
 pub fn average_block_interval(chain_id: u64) -> Duration {
     match chain_id {
@@ -271,14 +654,37 @@ pub struct Web3RpcConfig {
     pub http_url: Option<String>,
     /// block data limit. If None, will be queried
     pub block_data_limit: Option<u64>,
+    /// how often a queried block data limit is checked again. 0 only checks when connecting
+    #[serde_inline_default(3_600_000u64)]
+    pub block_data_limit_check_interval_ms: u64,
     /// the requests per second at which the server starts slowing down
     #[serde_inline_default(1u32)]
     pub soft_limit: u32,
     /// the requests per second at which the server throws errors (rate limit or otherwise)
     pub hard_limit: Option<u64>,
+    /// the most requests that can be in flight at once. a slow server won't pile up unlimited pending calls. None is unlimited.
+    /// internal requests like health checks and head block queries are not limited, but they do count toward it
+    pub max_active_requests: Option<usize>,
+    /// tighter hard limits for expensive methods like eth_getLogs. methods not listed here use `hard_limit`
+    #[serde(default = "Default::default")]
+    pub method_limits: HashMap<String, u64>,
+    /// sign every http request with a shared secret. some enterprise providers require this
+    pub hmac: Option<HmacConfig>,
+    /// actively check that the server answers requests. head blocks alone don't catch a server that is failing calls
+    pub health_probe: Option<HealthProbeConfig>,
+    /// skip the rpc for a while after too many requests in a row fail to get an answer
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// how many requests can wait for the hard_limit to replenish instead of failing fast. 0 disables the queue
+    #[serde(default = "Default::default")]
+    pub rate_limit_queue_depth: usize,
+    /// the longest that a queued request will wait for the hard_limit to replenish
+    #[serde_inline_default(1_000u64)]
+    pub rate_limit_queue_max_wait_ms: u64,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     #[serde(default = "Default::default")]
     pub backup: bool,
+    /// a named pool that this rpc is dedicated to. it only serves the methods that `method_pools` sends to that pool
+    pub pool: Option<String>,
     /// Subscribe to the firehose of pending transactions
     /// Don't do this with free rpcs
     #[serde(default = "Default::default")]
@@ -288,13 +694,141 @@ pub struct Web3RpcConfig {
     pub extra: HashMap<String, serde_json::Value>,
 }
 
+/// A string that is never included in logs or debug output
+#[derive(Clone, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// How to sign requests to an rpc. The signature is a hex HMAC-SHA256 of `{timestamp}.{body}`
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct HmacConfig {
+    pub secret: Secret,
+    /// header for the hex signature
+    #[serde_inline_default("x-signature".to_string())]
+    pub signature_header: String,
+    /// header for the unix timestamp (in seconds) that was signed
+    #[serde_inline_default("x-timestamp".to_string())]
+    pub timestamp_header: String,
+}
+
+/// A request sent to an rpc on an interval. If it fails too many times in a row, the rpc is taken out of rotation
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct HealthProbeConfig {
+    #[serde_inline_default("eth_blockNumber".to_string())]
+    pub method: String,
+    /// most methods reject null params, so this defaults to `[]`
+    #[serde_inline_default(serde_json::Value::Array(vec![]))]
+    pub params: serde_json::Value,
+    #[serde_inline_default(10_000u64)]
+    pub interval_ms: u64,
+    /// consecutive failures before the rpc is tripped. a single success puts it back into rotation
+    #[serde_inline_default(3u32)]
+    pub failure_threshold: u32,
+}
+
+/// Requests that fail to get any answer from an rpc (connection errors and timeouts) open the circuit.
+/// While it is open, the rpc is skipped. After the cooldown, one request is let through. If it succeeds, the circuit closes
+#[serde_inline_default]
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// consecutive failures that open the circuit
+    #[serde_inline_default(5u32)]
+    pub failure_threshold: u32,
+    /// the failures all have to happen within this many milliseconds of the first one
+    #[serde_inline_default(10_000u64)]
+    pub window_ms: u64,
+    /// how long the circuit stays open before a request is let through to test the rpc
+    #[serde_inline_default(30_000u64)]
+    pub cooldown_ms: u64,
+}
+
+/// A request to send on startup so that its response is already cached when traffic arrives
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+pub struct WarmupRequest {
+    pub method: String,
+    #[serde(default = "Default::default")]
+    pub params: serde_json::Value,
+}
+
 impl Default for Web3RpcConfig {
     fn default() -> Self {
         serde_json::from_str("{}").unwrap()
     }
 }
 
+/// Make sure that no two enabled rpcs point at the same url.
+/// The configs are keyed by name, so a copy-pasted server would otherwise silently double its weight.
+pub fn check_duplicate_rpc_urls(
+    rpc_configs: &HashMap<String, Web3RpcConfig>,
+) -> Web3ProxyResult<()> {
+    // sort by name so that the error is deterministic
+    let mut names: Vec<_> = rpc_configs.keys().collect();
+    names.sort();
+
+    let mut seen = HashMap::<&str, &str>::new();
+
+    for name in names {
+        let rpc_config = &rpc_configs[name];
+
+        if rpc_config.disabled {
+            continue;
+        }
+
+        for url in [rpc_config.http_url.as_ref(), rpc_config.ws_url.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(first) = seen.insert(url.as_str(), name.as_str()) {
+                return Err(Web3ProxyError::DuplicateRpcUrl {
+                    first: first.to_string(),
+                    second: name.to_string(),
+                    url: url.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
 impl Web3RpcConfig {
+    /// true if the only differences are ones that a running rpc can apply without reconnecting
+    pub fn same_connection(&self, other: &Self) -> bool {
+        let a = Self {
+            hard_limit: None,
+            method_limits: Default::default(),
+            ..self.clone()
+        };
+
+        let b = Self {
+            hard_limit: None,
+            method_limits: Default::default(),
+            ..other.clone()
+        };
+
+        a == b
+    }
+
     /// Create a Web3Rpc from config
     /// TODO: move this into Web3Rpc? (just need to make things pub(crate))
     #[allow(clippy::too_many_arguments)]
@@ -330,7 +864,12 @@ impl Web3RpcConfig {
 
 #[cfg(test)]
 mod tests {
-    use super::{AppConfig, Web3RpcConfig};
+    use super::{check_duplicate_rpc_urls, AppConfig, HealthProbeConfig, Web3RpcConfig};
+    use crate::app::APP_USER_AGENT;
+    use crate::errors::Web3ProxyError;
+    use ethers::types::U64;
+    use hashbrown::HashMap;
+    use http::StatusCode;
     use serde_json::json;
 
     #[test]
@@ -355,6 +894,161 @@ mod tests {
         assert_eq!(a, b);
     }
 
+    #[test]
+    fn health_probe_defaults() {
+        let a: HealthProbeConfig = serde_json::from_value(json!({})).unwrap();
+
+        assert_eq!(a.method, "eth_blockNumber");
+        assert_eq!(a.params, json!([]));
+        assert_eq!(a.interval_ms, 10_000);
+        assert_eq!(a.failure_threshold, 3);
+
+        let b: HealthProbeConfig = serde_json::from_value(json!({
+            "method": "eth_getBlockByNumber",
+            "params": ["latest", false],
+        }))
+        .unwrap();
+
+        assert_eq!(b.params, json!(["latest", false]));
+    }
+
+    #[test]
+    fn max_fanout() {
+        let a = AppConfig::default();
+
+        assert_eq!(a.max_fanout_rpcs, None);
+        assert_eq!(a.max_fanout(None), None);
+        assert_eq!(a.max_fanout(Some(10)), Some(10));
+
+        let b: AppConfig = serde_json::from_value(json!({
+            "max_fanout_rpcs": 3,
+        }))
+        .unwrap();
+
+        // the cap applies to broadcasts that want every rpc and to ones that want more than the cap
+        assert_eq!(b.max_fanout(None), Some(3));
+        assert_eq!(b.max_fanout(Some(10)), Some(3));
+        assert_eq!(b.max_fanout(Some(2)), Some(2));
+    }
+
+    #[test]
+    fn client_version() {
+        let a = AppConfig::default();
+
+        assert!(!a.client_version_passthrough);
+        assert_eq!(a.client_version(), APP_USER_AGENT);
+
+        let b: AppConfig = serde_json::from_value(json!({
+            "client_version": "example_proxy",
+        }))
+        .unwrap();
+
+        assert_eq!(
+            b.client_version(),
+            format!("example_proxy/v{}", env!("CARGO_PKG_VERSION"))
+        );
+    }
+
+    #[test]
+    fn unknown_methods() {
+        let permissive = AppConfig::default();
+
+        assert!(!permissive.reject_unknown_methods);
+        assert!(permissive.method_allowed("eth_call"));
+        assert!(permissive.method_allowed("some_newMethod"));
+
+        let strict: AppConfig = serde_json::from_value(json!({
+            "reject_unknown_methods": true,
+        }))
+        .unwrap();
+
+        assert!(strict.method_allowed("eth_call"));
+        assert!(!strict.method_allowed("some_newMethod"));
+
+        // operators can replace the known set
+        let custom: AppConfig = serde_json::from_value(json!({
+            "known_methods": ["some_newMethod"],
+            "reject_unknown_methods": true,
+        }))
+        .unwrap();
+
+        assert!(!custom.method_allowed("eth_call"));
+        assert!(custom.method_allowed("some_newMethod"));
+    }
+
+    #[test]
+    fn archive_requests() {
+        let a = AppConfig::default();
+
+        let head_block_num = U64::from(1_000_000);
+
+        // a deep historical read needs an archive node
+        assert!(a.is_archive_request("eth_getBalance", Some(&U64::one()), &head_block_num));
+
+        // a recent read does not
+        assert!(!a.is_archive_request(
+            "eth_getBalance",
+            Some(&(head_block_num - 10)),
+            &head_block_num
+        ));
+        assert!(!a.is_archive_request(
+            "eth_getBalance",
+            Some(&(head_block_num - a.archive_depth)),
+            &head_block_num
+        ));
+
+        assert!(!a.is_archive_request("eth_getBalance", None, &head_block_num));
+
+        let b: AppConfig = serde_json::from_value(json!({
+            "archive_depth": 128,
+            "archive_methods": ["trace_replayBlockTransactions"],
+        }))
+        .unwrap();
+
+        assert!(b.is_archive_request(
+            "eth_getBalance",
+            Some(&(head_block_num - 129)),
+            &head_block_num
+        ));
+        assert!(b.is_archive_request("trace_replayBlockTransactions", None, &head_block_num));
+    }
+
+    #[test]
+    fn error_status_codes() {
+        let a = AppConfig::default();
+
+        assert_eq!(
+            a.error_status(StatusCode::BAD_GATEWAY),
+            StatusCode::BAD_GATEWAY
+        );
+
+        let b: AppConfig = serde_json::from_value(json!({
+            "error_status_codes": {
+                "502": 503,
+                "408": 999_999,
+                "200": 500,
+            },
+        }))
+        .unwrap();
+
+        assert_eq!(
+            b.error_status(StatusCode::BAD_GATEWAY),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        assert_eq!(
+            b.error_status(StatusCode::INTERNAL_SERVER_ERROR),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+
+        assert_eq!(b.error_status(StatusCode::OK), StatusCode::OK);
+
+        // invalid statuses are ignored
+        assert_eq!(
+            b.error_status(StatusCode::REQUEST_TIMEOUT),
+            StatusCode::REQUEST_TIMEOUT
+        );
+    }
+
     #[test]
     fn expected_rpc_defaults() {
         let a: Web3RpcConfig = serde_json::from_str("{}").unwrap();
@@ -367,4 +1061,78 @@ mod tests {
 
         assert_eq!(a, b);
     }
+
+    #[test]
+    fn rate_limit_only_changes() {
+        let a = Web3RpcConfig {
+            http_url: Some("http://127.0.0.1:8545".to_string()),
+            hard_limit: Some(100),
+            ..Default::default()
+        };
+
+        let b = Web3RpcConfig {
+            hard_limit: Some(10),
+            ..a.clone()
+        };
+
+        assert!(a.same_connection(&b));
+
+        let b = Web3RpcConfig {
+            method_limits: HashMap::from([("eth_getLogs".to_string(), 10)]),
+            ..a.clone()
+        };
+
+        assert!(a.same_connection(&b));
+
+        let c = Web3RpcConfig {
+            http_url: Some("http://127.0.0.1:8546".to_string()),
+            ..a.clone()
+        };
+
+        assert!(!a.same_connection(&c));
+    }
+
+    #[test]
+    fn duplicate_rpc_urls() {
+        let a = Web3RpcConfig {
+            http_url: Some("http://127.0.0.1:8545".to_string()),
+            ..Default::default()
+        };
+
+        let b = Web3RpcConfig {
+            http_url: Some("http://127.0.0.1:8546".to_string()),
+            ..Default::default()
+        };
+
+        let mut rpc_configs = HashMap::new();
+        rpc_configs.insert("a".to_string(), a.clone());
+        rpc_configs.insert("b".to_string(), b);
+
+        check_duplicate_rpc_urls(&rpc_configs).unwrap();
+
+        rpc_configs.insert("c".to_string(), a);
+
+        match check_duplicate_rpc_urls(&rpc_configs) {
+            Err(Web3ProxyError::DuplicateRpcUrl { first, second, url }) => {
+                assert_eq!(first, "a");
+                assert_eq!(second, "c");
+                assert_eq!(url, "http://127.0.0.1:8545");
+            }
+            x => panic!("expected DuplicateRpcUrl, got {:?}", x),
+        }
+
+        // disabled rpcs are allowed to overlap
+        rpc_configs.get_mut("c").unwrap().disabled = true;
+
+        check_duplicate_rpc_urls(&rpc_configs).unwrap();
+    }
+    #[test]
+    fn test_counted_method() {
+        let config = AppConfig::default();
+
+        assert_eq!(config.counted_method("eth_call"), "eth_call");
+        assert_eq!(config.counted_method("eth_chainId"), "eth_chainId");
+        assert_eq!(config.counted_method("eth_doesNotExist"), "other");
+        assert_eq!(config.counted_method("other"), "other");
+    }
 }
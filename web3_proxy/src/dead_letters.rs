@@ -0,0 +1,166 @@
+//! Requests that failed on every backend rpc they were sent to. These are kept apart from the stats so that operators can look for patterns.
+//! With redis configured, they are also saved there so that they survive restarts and are shared between instances.
+use crate::bounded_ring::BoundedRing;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::authorization::RequestMetadata;
+use anyhow::Context;
+use chrono::Utc;
+use ethers::types::H256;
+use ethers::utils::keccak256;
+use redis_rate_limiter::redis::AsyncCommands;
+use redis_rate_limiter::{redis, RedisPool};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// A request that gave up after all of its retries
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DeadLetter {
+    /// unix timestamp of when the request gave up
+    pub timestamp: i64,
+    pub method: String,
+    /// keccak256 of the params as json. the params themselves might be sensitive
+    pub params_hash: H256,
+    /// only saved if `dead_letter_raw_params` is set
+    pub params: Option<serde_json::Value>,
+    pub rpc_key_id: Option<u64>,
+    /// (rpc, error) for every backend attempt. in the order they were tried
+    pub attempts: Vec<(String, String)>,
+    /// the error that was returned to the client
+    pub error: String,
+}
+
+/// The most recent dead letters
+#[derive(Default)]
+pub struct DeadLetters {
+    raw_params: bool,
+    letters: BoundedRing<DeadLetter>,
+    /// a redis list with the newest letter first. trimmed to the same length as `letters`
+    redis_key: String,
+    redis_pool: Option<RedisPool>,
+}
+
+impl DeadLetters {
+    pub fn new(max: usize, raw_params: bool, chain_id: u64, redis_pool: Option<RedisPool>) -> Self {
+        Self {
+            raw_params,
+            letters: BoundedRing::new(max),
+            redis_key: format!("dead_letters:{}", chain_id),
+            redis_pool,
+        }
+    }
+
+    /// false if no dead letters are kept. callers can skip saving a copy of the params
+    pub fn is_enabled(&self) -> bool {
+        self.letters.is_enabled()
+    }
+
+    /// save a request that failed. requests that never reached a backend rpc (like bad requests or rate limits) are not dead letters.
+    /// `params` must be the params the client sent, not the ones that were modified while proxying
+    pub fn push(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+        request_metadata: &RequestMetadata,
+        error: &Web3ProxyError,
+    ) {
+        if !self.is_enabled() || request_metadata.backend_rpcs_used().is_empty() {
+            return;
+        }
+
+        let params_hash = serde_json::to_vec(params)
+            .map(|x| keccak256(x).into())
+            .unwrap_or_default();
+
+        let letter = DeadLetter {
+            timestamp: Utc::now().timestamp(),
+            method: method.to_string(),
+            params_hash,
+            params: self.raw_params.then(|| params.clone()),
+            rpc_key_id: request_metadata
+                .authorization
+                .as_ref()
+                .and_then(|x| x.checks.rpc_secret_key_id)
+                .map(|x| x.get()),
+            attempts: request_metadata.provenance.lock().errors.clone(),
+            error: error.to_string(),
+        };
+
+        if let Some(redis_pool) = self.redis_pool.clone() {
+            let redis_key = self.redis_key.clone();
+            let max = self.letters.max();
+            let letter = letter.clone();
+
+            // the request has already failed. it should not also wait on redis
+            tokio::spawn(async move {
+                if let Err(err) = save_to_redis(redis_pool, redis_key, max, letter).await {
+                    warn!(?err, "failed saving dead letter to redis");
+                }
+            });
+        }
+
+        self.letters.push(letter);
+    }
+
+    /// oldest first. only this instance's letters since it started
+    pub fn to_vec(&self) -> Vec<DeadLetter> {
+        self.letters.to_vec()
+    }
+
+    /// oldest first. read from redis if it is configured, so letters from every instance and from before a restart are included
+    pub async fn recent(&self) -> Vec<DeadLetter> {
+        if let Some(redis_pool) = self.redis_pool.as_ref() {
+            match load_from_redis(redis_pool, &self.redis_key).await {
+                Ok(x) => return x,
+                Err(err) => warn!(?err, "failed loading dead letters from redis"),
+            }
+        }
+
+        self.to_vec()
+    }
+}
+
+async fn save_to_redis(
+    redis_pool: RedisPool,
+    redis_key: String,
+    max: usize,
+    letter: DeadLetter,
+) -> Web3ProxyResult<()> {
+    let letter = serde_json::to_string(&letter)?;
+
+    let mut redis_conn = redis_pool.get().await.context("redis pool error")?;
+
+    redis::pipe()
+        .atomic()
+        .lpush(&redis_key, letter)
+        .ignore()
+        .ltrim(&redis_key, 0, max as isize - 1)
+        .ignore()
+        .query_async::<_, ()>(&mut redis_conn)
+        .await?;
+
+    Ok(())
+}
+
+async fn load_from_redis(
+    redis_pool: &RedisPool,
+    redis_key: &str,
+) -> Web3ProxyResult<Vec<DeadLetter>> {
+    let mut redis_conn = redis_pool.get().await.context("redis pool error")?;
+
+    let letters: Vec<String> = redis_conn.lrange(redis_key, 0, -1).await?;
+
+    // the list is newest first
+    let letters = letters
+        .iter()
+        .rev()
+        .filter_map(|x| match serde_json::from_str(x) {
+            Ok(x) => Some(x),
+            Err(err) => {
+                warn!(?err, "invalid dead letter in redis");
+                None
+            }
+        })
+        .collect();
+
+    Ok(letters)
+}
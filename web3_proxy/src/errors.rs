@@ -1,6 +1,6 @@
 //! Utlities for logging errors for admins and displaying errors to users.
 
-use crate::frontend::authorization::Authorization;
+use crate::frontend::authorization::{Authorization, RequestMetadata};
 use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse};
 use crate::response_cache::JsonRpcResponseEnum;
 use crate::rpcs::provider::EthersHttpProvider;
@@ -23,6 +23,7 @@ use redis_rate_limiter::RedisPoolError;
 use reqwest::header::ToStrError;
 use rust_decimal::Error as DecimalError;
 use serde::Serialize;
+use serde_json::json;
 use serde_json::value::RawValue;
 use siwe::VerificationError;
 use std::sync::Arc;
@@ -50,6 +51,7 @@ pub enum Web3ProxyError {
     #[error(ignore)]
     Anyhow(anyhow::Error),
     Arc(Arc<Self>),
+    ArchiveDataUnavailable,
     #[error(ignore)]
     #[from(ignore)]
     BadRequest(Cow<'static, str>),
@@ -61,6 +63,13 @@ pub enum Web3ProxyError {
     Database(DbErr),
     DatabaseArc(Arc<DbErr>),
     Decimal(DecimalError),
+    #[display(fmt = "{} and {} both use {}", first, second, url)]
+    #[from(ignore)]
+    DuplicateRpcUrl {
+        first: String,
+        second: String,
+        url: String,
+    },
     EthersHttpClient(ethers::providers::HttpClientError),
     EthersProvider(ethers::prelude::ProviderError),
     EthersWsClient(ethers::prelude::WsClientError),
@@ -70,6 +79,11 @@ pub enum Web3ProxyError {
     HeaderToString(ToStrError),
     HttpUri(InvalidUri),
     Hyper(hyper::Error),
+    /// the rpcs did not agree on a result. (rpc name, result) for every rpc that was asked
+    #[display(fmt = "{:?}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    InconsistentUpstreams(Vec<(String, serde_json::Value)>),
     InfluxDb2Request(influxdb2::RequestError),
     #[display(fmt = "{} > {}", min, max)]
     #[from(ignore)]
@@ -95,6 +109,10 @@ pub enum Web3ProxyError {
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     JsonRpcErrorData(JsonRpcErrorData),
+    #[display(fmt = "{}", _0)]
+    #[error(ignore)]
+    #[from(ignore)]
+    Maintenance(Arc<String>),
     #[display(fmt = "{:?}", _0)]
     #[error(ignore)]
     MsgPackEncode(rmp_serde::encode::Error),
@@ -174,6 +192,8 @@ pub enum Web3ProxyError {
 }
 
 impl Web3ProxyError {
+    /// jsonrpc errors from the backend (reverts, bad params) are part of a successful response and get a 200.
+    /// failures of the proxy or its connections (no servers, transport errors, timeouts) get an http error status.
     pub fn as_response_parts<R: Serialize>(&self) -> (StatusCode, JsonRpcResponseEnum<R>) {
         // TODO: include a unique request id in the data
         let (code, err): (StatusCode, JsonRpcErrorData) = match self {
@@ -216,6 +236,17 @@ impl Web3ProxyError {
                 // recurse
                 return err.as_response_parts();
             }
+            Self::ArchiveDataUnavailable => {
+                trace!("ArchiveDataUnavailable");
+                (
+                    StatusCode::OK,
+                    JsonRpcErrorData {
+                        message: "archive data not available".into(),
+                        code: -32043,
+                        data: None,
+                    },
+                )
+            }
             Self::BadRequest(err) => {
                 trace!(?err, "BAD_REQUEST");
                 (
@@ -294,6 +325,21 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::DuplicateRpcUrl { first, second, url } => {
+                error!(%first, %second, %url, "DuplicateRpcUrl");
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    JsonRpcErrorData {
+                        message: format!(
+                            "rpcs {} and {} are configured with the same url",
+                            first, second
+                        )
+                        .into(),
+                        code: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::EthersHttpClient(err) => {
                 if let Ok(err) = JsonRpcErrorData::try_from(err) {
                     trace!(?err, "EthersHttpClient jsonrpc error");
@@ -301,10 +347,10 @@ impl Web3ProxyError {
                 } else {
                     warn!(?err, "EthersHttpClient");
                     (
-                        StatusCode::INTERNAL_SERVER_ERROR,
+                        StatusCode::BAD_GATEWAY,
                         JsonRpcErrorData {
                             message: "ethers http client error".into(),
-                            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+                            code: StatusCode::BAD_GATEWAY.as_u16().into(),
                             data: None,
                         },
                     )
@@ -317,10 +363,10 @@ impl Web3ProxyError {
                 } else {
                     warn!(?err, "EthersProvider");
                     (
-                        StatusCode::INTERNAL_SERVER_ERROR,
+                        StatusCode::BAD_GATEWAY,
                         JsonRpcErrorData {
                             message: "ethers provider error".into(),
-                            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+                            code: StatusCode::BAD_GATEWAY.as_u16().into(),
                             data: None,
                         },
                     )
@@ -333,10 +379,10 @@ impl Web3ProxyError {
                 } else {
                     warn!(?err, "EthersWsClient");
                     (
-                        StatusCode::INTERNAL_SERVER_ERROR,
+                        StatusCode::BAD_GATEWAY,
                         JsonRpcErrorData {
                             message: "ethers ws client error".into(),
-                            code: StatusCode::INTERNAL_SERVER_ERROR.as_u16().into(),
+                            code: StatusCode::BAD_GATEWAY.as_u16().into(),
                             data: None,
                         },
                     )
@@ -410,6 +456,20 @@ impl Web3ProxyError {
                     },
                 )
             }
+            Self::InconsistentUpstreams(responses) => {
+                warn!(?responses, "InconsistentUpstreams");
+                (
+                    StatusCode::BAD_GATEWAY,
+                    JsonRpcErrorData {
+                        message: "inconsistent upstreams".into(),
+                        code: StatusCode::BAD_GATEWAY.as_u16().into(),
+                        data: Some(json!(responses
+                            .iter()
+                            .map(|(rpc, result)| json!({"rpc": rpc, "result": result}))
+                            .collect::<Vec<_>>())),
+                    },
+                )
+            }
             Self::InfluxDb2Request(err) => {
                 // TODO: attach a request id to the message and to this error so that if people report problems, we can dig in sentry to find out more
                 error!(?err, "influxdb2");
@@ -582,6 +642,17 @@ impl Web3ProxyError {
                 // TODO: do this without clone? the Arc needed it though
                 (StatusCode::OK, jsonrpc_error_data.clone())
             }
+            Self::Maintenance(msg) => {
+                trace!(%msg, "maintenance");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    JsonRpcErrorData {
+                        message: msg.to_string().into(),
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16().into(),
+                        data: None,
+                    },
+                )
+            }
             Self::MsgPackEncode(err) => {
                 warn!(?err, "MsgPackEncode");
                 (
@@ -1090,6 +1161,47 @@ impl Web3ProxyError {
         (code, JsonRpcResponseEnum::from(err))
     }
 
+    /// the original error from a backend rpc. as_response_parts hides these since they can include internal details like urls
+    pub fn upstream_error(&self) -> Option<String> {
+        match self {
+            Self::Arc(err) => err.upstream_error(),
+            Self::EthersHttpClient(err) => Some(err.to_string()),
+            Self::EthersProvider(err) => Some(err.to_string()),
+            Self::EthersWsClient(err) => Some(err.to_string()),
+            Self::WithContext(Some(err), _) => err.upstream_error(),
+            _ => None,
+        }
+    }
+
+    /// like as_response_parts, but the original upstream error is attached to the error's data.
+    /// only use this for trusted requests!
+    pub fn as_debug_response_parts<R: Serialize>(&self) -> (StatusCode, JsonRpcResponseEnum<R>) {
+        let (code, response_data) = self.as_response_parts();
+
+        match (response_data, self.upstream_error()) {
+            (JsonRpcResponseEnum::RpcError { mut error_data, .. }, Some(upstream_error))
+                if error_data.data.is_none() =>
+            {
+                error_data.data = Some(json!({ "upstream_error": upstream_error }));
+
+                (code, error_data.into())
+            }
+            (response_data, _) => (code, response_data),
+        }
+    }
+
+    /// as_response_parts, or as_debug_response_parts if an admin key opted in to the original errors from the backend rpcs
+    pub fn as_response_parts_for<R: Serialize>(
+        &self,
+        request_metadata: &RequestMetadata,
+    ) -> (StatusCode, JsonRpcResponseEnum<R>) {
+        if request_metadata.upstream_errors() {
+            self.as_debug_response_parts()
+        } else {
+            self.as_response_parts()
+        }
+    }
+
     #[inline]
     pub fn into_response_with_id(self, id: Option<Box<RawValue>>) -> Response {
         let (status_code, response_data) = self.as_response_parts();
@@ -1174,3 +1286,149 @@ impl Web3ProxyError {
         Message::Text(msg)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Web3ProxyError;
+    use crate::frontend::authorization::{
+        Authorization, AuthorizationChecks, AuthorizationType, RequestMetadata,
+    };
+    use crate::jsonrpc::JsonRpcErrorData;
+    use crate::response_cache::JsonRpcResponseEnum;
+    use ethers::providers::ProviderError;
+    use http::StatusCode;
+    use serde_json::json;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+
+    #[test]
+    fn jsonrpc_errors_are_200_and_infra_errors_are_not() {
+        // a revert is a successful response that contains an error
+        let revert = Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            code: 3,
+            message: "execution reverted".into(),
+            data: Some(json!("0x")),
+        });
+
+        let (status_code, _) = revert.as_response_parts::<()>();
+        assert_eq!(status_code, StatusCode::OK);
+
+        // the backend could not be reached
+        let transport = Web3ProxyError::EthersProvider(ProviderError::CustomError(
+            "connection refused".to_string(),
+        ));
+
+        let (status_code, _) = transport.as_response_parts::<()>();
+        assert_eq!(status_code, StatusCode::BAD_GATEWAY);
+
+        let (status_code, _) = Web3ProxyError::NoServersSynced.as_response_parts::<()>();
+        assert_eq!(status_code, StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn upstream_error_only_in_debug_responses() {
+        let err = Web3ProxyError::EthersProvider(ProviderError::CustomError(
+            "upstream exploded".to_string(),
+        ));
+
+        let (_, response_data) = err.as_response_parts::<()>();
+
+        match response_data {
+            JsonRpcResponseEnum::RpcError { error_data, .. } => {
+                assert_eq!(error_data.message, "ethers provider error");
+                assert_eq!(error_data.data, None);
+            }
+            _ => panic!("expected an error"),
+        }
+
+        let (_, response_data) = err.as_debug_response_parts::<()>();
+
+        match response_data {
+            JsonRpcResponseEnum::RpcError { error_data, .. } => {
+                assert_eq!(error_data.message, "ethers provider error");
+                assert_eq!(
+                    error_data.data,
+                    Some(json!({ "upstream_error": "custom error: upstream exploded" }))
+                );
+            }
+            _ => panic!("expected an error"),
+        }
+
+        // errors that didn't come from an upstream are unchanged
+        let (_, response_data) = Web3ProxyError::NoServersSynced.as_debug_response_parts::<()>();
+
+        match response_data {
+            JsonRpcResponseEnum::RpcError { error_data, .. } => {
+                assert_eq!(error_data.data, None);
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+
+    fn keyed_request_metadata(admin: bool, opt_in: bool) -> RequestMetadata {
+        let checks = AuthorizationChecks {
+            admin,
+            rpc_secret_key_id: NonZeroU64::new(1),
+            ..Default::default()
+        };
+
+        let mut authorization = Authorization::try_new(
+            checks,
+            &"127.0.0.1".parse().unwrap(),
+            None,
+            None,
+            None,
+            AuthorizationType::Frontend,
+        )
+        .unwrap();
+
+        if opt_in {
+            authorization.request_upstream_errors();
+        }
+
+        RequestMetadata {
+            authorization: Some(Arc::new(authorization)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn upstream_errors_need_an_admin_key_and_the_opt_in() {
+        // upstream urls often include the provider's api key
+        let err = Web3ProxyError::EthersProvider(ProviderError::CustomError(
+            "error sending request for url (https://rpc.example.com/SECRET)".to_string(),
+        ));
+
+        for (admin, opt_in) in [(false, false), (false, true), (true, false), (true, true)] {
+            let request_metadata = keyed_request_metadata(admin, opt_in);
+
+            let (_, response_data) = err.as_response_parts_for::<()>(&request_metadata);
+
+            let error_data = match response_data {
+                JsonRpcResponseEnum::RpcError { error_data, .. } => error_data,
+                _ => panic!("expected an error"),
+            };
+
+            if admin && opt_in {
+                assert_eq!(
+                    error_data.data,
+                    Some(
+                        json!({ "upstream_error": "custom error: error sending request for url (https://rpc.example.com/SECRET)" })
+                    )
+                );
+            } else {
+                assert_eq!(error_data.data, None, "admin={} opt_in={}", admin, opt_in);
+            }
+        }
+
+        // requests without a key never get them
+        let (_, response_data) = err.as_response_parts_for::<()>(&RequestMetadata::default());
+
+        match response_data {
+            JsonRpcResponseEnum::RpcError { error_data, .. } => {
+                assert_eq!(error_data.data, None);
+            }
+            _ => panic!("expected an error"),
+        }
+    }
+}
@@ -107,6 +107,131 @@ pub async fn admin_increase_balance(
     Ok(Json(out).into_response())
 }
 
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AdminMaintenancePost {
+    pub enabled: bool,
+    pub message: Option<String>,
+}
+
+/// `POST /admin/maintenance` -- As an admin, turn maintenance mode on or off
+///
+/// - enabled: while true, rpc requests get a 503 error. /health, /status, and the admin endpoints keep working
+/// - message: the error message. defaults to the maintenance_message from the config
+#[debug_handler]
+pub async fn admin_maintenance_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Json(payload): Json<AdminMaintenancePost>,
+) -> Web3ProxyResponse {
+    let caller = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = global_db_replica_conn().await?;
+
+    // Check if the caller is an admin (if not, return early)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::AccessDenied("not an admin".into()))?;
+
+    let msg = app.set_maintenance(payload.enabled, payload.message);
+
+    info!(admin=%caller.id, ?msg, "maintenance mode changed");
+
+    let out = json!({
+        "maintenance": msg,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/reorgs` -- As an admin, see the most recent reorgs of the balanced rpcs' heaviest chain
+///
+/// - oldest first. how many are kept is set by max_recent_reorgs in the config
+#[debug_handler]
+pub async fn admin_reorgs_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let caller = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = global_db_replica_conn().await?;
+
+    // Check if the caller is an admin (if not, return early)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::AccessDenied("not an admin".into()))?;
+
+    let out = json!({
+        "reorgs": app.balanced_rpcs.recent_reorgs(),
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/rpc_lifetime_stats` -- As an admin, compare the balanced rpcs by everything they have done.
+///
+/// - the counts include previous runs if a database is configured
+#[debug_handler]
+pub async fn admin_rpc_lifetime_stats_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let caller = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = global_db_replica_conn().await?;
+
+    // Check if the caller is an admin (if not, return early)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::AccessDenied("not an admin".into()))?;
+
+    let rpcs: HashMap<_, _> = app
+        .balanced_rpcs
+        .by_name
+        .read()
+        .values()
+        .map(|x| (x.name.clone(), x.lifetime_stats().counts()))
+        .collect();
+
+    let out = json!({
+        "rpcs": rpcs,
+    });
+
+    Ok(Json(out).into_response())
+}
+
+/// `GET /admin/dead_letters` -- As an admin, see the most recent requests that failed on every backend rpc.
+///
+/// - oldest first. how many are kept is set by max_dead_letters in the config
+/// - with volatile_redis_url set, this includes every instance's letters and survives restarts
+#[debug_handler]
+pub async fn admin_dead_letters_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+) -> Web3ProxyResponse {
+    let caller = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = global_db_replica_conn().await?;
+
+    // Check if the caller is an admin (if not, return early)
+    admin::Entity::find()
+        .filter(admin::Column::UserId.eq(caller.id))
+        .one(db_replica.as_ref())
+        .await?
+        .ok_or_else(|| Web3ProxyError::AccessDenied("not an admin".into()))?;
+
+    let out = json!({
+        "dead_letters": app.dead_letters.recent().await,
+    });
+
+    Ok(Json(out).into_response())
+}
+
 /// `POST /admin/modify_role` -- As an admin, modify a user's user-tier
 ///
 /// - user_address that is to be modified
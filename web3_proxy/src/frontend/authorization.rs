@@ -4,12 +4,14 @@ use super::rpc_proxy_ws::ProxyMode;
 use crate::app::{Web3ProxyApp, APP_USER_AGENT};
 use crate::balance::Balance;
 use crate::caches::RegisteredUserRateLimitKey;
+use crate::compute_units::ComputeUnit;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::globals::global_db_replica_conn;
-use crate::jsonrpc::{JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcForwardedResponse, JsonRpcRequest};
+use crate::response_cache::CacheStatus;
 use crate::rpcs::blockchain::Web3ProxyBlock;
 use crate::rpcs::one::Web3Rpc;
-use crate::stats::{AppStat, BackendRequests};
+use crate::stats::{AppStat, BackendRequests, StatSender};
 use crate::user_token::UserBearerToken;
 use anyhow::Context;
 use axum::headers::authorization::Bearer;
@@ -19,7 +21,7 @@ use core::fmt;
 use deferred_rate_limiter::DeferredRateLimitResult;
 use derivative::Derivative;
 use derive_more::From;
-use entities::{login, rpc_key, user, user_tier};
+use entities::{admin, login, rpc_key, user, user_tier};
 use ethers::types::{Bytes, U64};
 use ethers::utils::keccak256;
 use futures::TryFutureExt;
@@ -28,6 +30,7 @@ use http::HeaderValue;
 use ipnet::IpNet;
 use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
+use parking_lot::Mutex;
 use rdkafka::message::{Header as KafkaHeader, OwnedHeaders as KafkaOwnedHeaders, OwnedMessage};
 use rdkafka::producer::{FutureProducer, FutureRecord};
 use rdkafka::util::Timeout as KafkaTimeout;
@@ -44,7 +47,7 @@ use std::sync::atomic::{self, AtomicBool, AtomicI64, AtomicU64, AtomicUsize};
 use std::time::Duration;
 use std::{net::IpAddr, str::FromStr, sync::Arc};
 use tokio::sync::RwLock as AsyncRwLock;
-use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio::task::JoinHandle;
 use tokio::time::Instant;
 use tracing::{error, trace, warn};
@@ -155,6 +158,10 @@ pub struct AuthorizationChecks {
     pub allowed_user_agents: Option<Vec<UserAgent>>,
     /// if None, allow any IP Address
     pub allowed_ips: Option<Vec<IpNet>>,
+    /// if None, allow any method. a trailing `*` matches any method with that prefix
+    pub allowed_methods: Option<Vec<String>>,
+    /// if None, deny no methods. checked after allowed_methods
+    pub denied_methods: Option<Vec<String>>,
     /// Chance to save reverting eth_call, eth_estimateGas, and eth_sendRawTransaction to the database.
     /// depending on the caller, errors might be expected. this keeps us from bloating our database
     /// u16::MAX == 100%
@@ -167,6 +174,47 @@ pub struct AuthorizationChecks {
     /// they might spend slightly more than they've paid, but we are okay with that
     /// TODO: we could price the request now and if its too high, downgrade. but thats more complex than we need
     pub paid_credits_used: bool,
+    /// if the key's user is an admin. admins can ask for the original errors from the backend rpcs
+    pub admin: bool,
+}
+
+impl AuthorizationChecks {
+    /// false if this key's allowed_methods/denied_methods keep it from calling the method
+    pub fn method_allowed(&self, method: &str) -> bool {
+        if let Some(allowed_methods) = &self.allowed_methods {
+            if !allowed_methods.iter().any(|x| method_matches(x, method)) {
+                return false;
+            }
+        }
+
+        if let Some(denied_methods) = &self.denied_methods {
+            if denied_methods.iter().any(|x| method_matches(x, method)) {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// `pattern` is a method name or a prefix ending in `*` (like `debug_*`)
+pub fn method_matches(pattern: &str, method: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => method.starts_with(prefix),
+        None => pattern == method,
+    }
+}
+
+/// split a comma separated list of methods from the database. an empty list is the same as no list
+fn parse_methods(x: Option<String>) -> Option<Vec<String>> {
+    let x: Vec<_> = x?
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string())
+        .collect();
+
+    (!x.is_empty()).then_some(x)
 }
 
 /// TODO: include the authorization checks in this?
@@ -178,6 +226,16 @@ pub struct Authorization {
     pub referer: Option<Referer>,
     pub user_agent: Option<UserAgent>,
     pub authorization_type: AuthorizationType,
+    /// skip the response cache and send the request to a backend rpc. the fresh response is still cached
+    pub cache_bypass: bool,
+    /// send the request to at most this many backend rpcs. None = the app's max_backend_attempts
+    pub max_backend_attempts: Option<usize>,
+    /// only return a result that this many synced rpcs agree on
+    pub quorum: Option<usize>,
+    /// rpc key and the client's session id. requests in the same session prefer the same rpc
+    pub sticky_session: Option<String>,
+    /// attach the original error from the backend rpcs to error responses. these can include internal details like urls
+    pub upstream_errors: bool,
 }
 
 pub struct KafkaDebugLogger {
@@ -333,6 +391,65 @@ impl KafkaDebugLogger {
     }
 }
 
+/// Why a backend rpc was not used for a request
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    /// the rpc has not synced the needed block yet
+    Behind,
+    /// the rpc has already pruned the needed block
+    Pruned,
+    RateLimited,
+    /// the rpc already has `max_active_requests` in flight
+    Busy,
+    /// the rpc could not open a request for some other reason
+    NotReady,
+    /// opening a request on the rpc errored
+    Error,
+}
+
+/// How a request was routed. This makes it possible to audit why a request did (or did not) go to an rpc
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Provenance {
+    /// rpcs that were not used and why. in the order that they were checked
+    pub skipped: Vec<(String, SkipReason)>,
+    /// the rpc that was used for the final attempt
+    pub chosen: Option<String>,
+    /// rpcs that returned an error and what it was. in the order that they were tried
+    pub errors: Vec<(String, String)>,
+    /// how many times the request had to be sent again
+    pub retries: u32,
+    /// None if the request could not be cached
+    pub cache: Option<CacheStatus>,
+    /// the block that the request needed, after tags like "latest" were turned into a number
+    pub block_num: Option<U64>,
+}
+
+impl Provenance {
+    pub fn skip(&mut self, rpc: &Web3Rpc, reason: SkipReason) {
+        // rpcs are checked again while waiting for a block. only record each reason once
+        if !self
+            .skipped
+            .iter()
+            .any(|(name, x)| *name == rpc.name && *x == reason)
+        {
+            self.skipped.push((rpc.name.clone(), reason));
+        }
+    }
+
+    pub fn error(&mut self, rpc: &Web3Rpc, error: &impl Display) {
+        self.errors.push((rpc.name.clone(), error.to_string()));
+    }
+
+    pub fn choose(&mut self, rpc: &Web3Rpc) {
+        if self.chosen.is_some() {
+            self.retries += 1;
+        }
+
+        self.chosen = Some(rpc.name.clone());
+    }
+}
+
 #[derive(Debug, Derivative)]
 #[derivative(Default)]
 pub struct RequestMetadata {
@@ -363,6 +480,12 @@ pub struct RequestMetadata {
     pub backend_requests: BackendRequests,
     /// The number of times the request got stuck waiting because no servers were synced
     pub no_servers: AtomicU64,
+    /// Which rpcs were skipped and which one was finally used
+    pub provenance: Mutex<Provenance>,
+    /// The most backend rpcs this request may be sent to. None = no limit
+    pub max_backend_attempts: Option<usize>,
+    /// How long each backend rpc gets to answer. None = only the overall request timeout applies
+    pub backend_timeout: Option<Duration>,
     /// If handling the request hit an application error
     /// This does not count things like a transcation reverting or a malformed request
     pub error_response: AtomicBool,
@@ -384,7 +507,10 @@ pub struct RequestMetadata {
     pub kafka_debug_logger: Option<Arc<KafkaDebugLogger>>,
 
     /// Cancel-safe channel for sending stats to the buffer
-    pub stat_sender: Option<mpsc::UnboundedSender<AppStat>>,
+    pub stat_sender: Option<StatSender>,
+
+    /// Paid credits held on the user's balance until the stat buffer charges this request
+    pub reserved_paid_credits: Mutex<Decimal>,
 }
 
 impl Default for Authorization {
@@ -400,6 +526,13 @@ impl RequestMetadata {
             .map(|x| x.checks.proxy_mode)
             .unwrap_or_default()
     }
+
+    pub fn upstream_errors(&self) -> bool {
+        self.authorization
+            .as_ref()
+            .map(|x| x.upstream_errors)
+            .unwrap_or_default()
+    }
 }
 
 #[derive(From)]
@@ -517,15 +650,28 @@ impl RequestMetadata {
 
         let chain_id = app.config.chain_id;
 
+        let max_backend_attempts = authorization
+            .max_backend_attempts
+            .or(app.config.max_backend_attempts);
+
+        let backend_timeout = app
+            .config
+            .method_timeouts_ms
+            .get(method.as_ref())
+            .map(|x| Duration::from_millis(*x));
+
         let x = Self {
             archive_request: false.into(),
             authorization: Some(authorization),
             backend_requests: Default::default(),
+            backend_timeout,
             chain_id,
             error_response: false.into(),
             kafka_debug_logger,
+            max_backend_attempts,
             method,
             no_servers: 0.into(),
+            provenance: Default::default(),
             request_bytes,
             request_ulid,
             response_bytes: 0.into(),
@@ -533,6 +679,7 @@ impl RequestMetadata {
             response_millis: 0.into(),
             response_timestamp: 0.into(),
             start_instant: Instant::now(),
+            reserved_paid_credits: Default::default(),
             stat_sender: app.stat_sender.clone(),
             usd_per_cu: app.config.usd_per_cu.unwrap_or_default(),
             user_error_response: false.into(),
@@ -545,19 +692,82 @@ impl RequestMetadata {
         self.backend_requests.lock().clone()
     }
 
+    /// true once the request has been sent to as many backend rpcs as it is allowed
+    pub fn out_of_backend_attempts(&self) -> bool {
+        match self.max_backend_attempts {
+            Some(x) => self.backend_requests.lock().len() >= x,
+            None => false,
+        }
+    }
+
+    /// hold the most this request could cost on the user's balance before it is proxied,
+    /// so concurrent requests can't spend more than the balance has left.
+    /// the stat buffer releases the hold before it charges the actual cost
+    pub async fn reserve_paid_credits(&self) -> Web3ProxyResult<()> {
+        let authorization = match self.authorization.as_ref() {
+            Some(x) if x.checks.paid_credits_used => x,
+            _ => return Ok(()),
+        };
+
+        if self.stat_sender.is_none() {
+            // nothing would charge or release this request
+            return Ok(());
+        }
+
+        // archive requests and cache misses are the most expensive
+        let cost = ComputeUnit::new(&self.method, self.chain_id, 0).cost(
+            true,
+            false,
+            false,
+            &self.usd_per_cu,
+        );
+
+        if !authorization
+            .checks
+            .latest_balance
+            .write()
+            .await
+            .try_reserve(cost)
+        {
+            trace!(user_id=%authorization.checks.user_id, %cost, "not enough paid credits");
+            return Err(Web3ProxyError::PaymentRequired);
+        }
+
+        *self.reserved_paid_credits.lock() = cost;
+
+        Ok(())
+    }
+
+    /// give back this request's hold on the user's balance. the hold is taken, so calling this again does nothing
+    pub async fn release_paid_credits(&self) {
+        let reserved = mem::take(&mut *self.reserved_paid_credits.lock());
+
+        if reserved.is_zero() {
+            return;
+        }
+
+        if let Some(authorization) = self.authorization.as_ref() {
+            authorization
+                .checks
+                .latest_balance
+                .write()
+                .await
+                .release(reserved);
+        }
+    }
+
     pub fn try_send_stat(mut self) -> Web3ProxyResult<()> {
         if let Some(stat_sender) = self.stat_sender.take() {
             trace!(?self, "sending stat");
 
             let stat: AppStat = self.into();
 
-            if let Err(err) = stat_sender.send(stat) {
-                error!(?err, "failed sending stat");
-                // TODO: return it? that seems like it might cause an infinite loop
-                // TODO: but dropping stats is bad... hmm... i guess better to undercharge customers than overcharge
-            };
-
-            trace!("stat sent successfully");
+            // a stat that misses the buffer's final flush is saved directly. nothing needs to wait on that
+            if stat_sender.send(stat).is_some() {
+                trace!("stat saved directly");
+            } else {
+                trace!("stat sent successfully");
+            }
         }
 
         Ok(())
@@ -572,6 +782,11 @@ impl RequestMetadata {
         self.response_bytes
             .fetch_add(num_bytes, atomic::Ordering::AcqRel);
 
+        // the rpc that answered gets the same count. cached responses don't have one
+        if let Some(rpc) = self.backend_requests.lock().last() {
+            rpc.lifetime_stats().record_response_bytes(num_bytes);
+        }
+
         self.response_millis.fetch_add(
             self.start_instant.elapsed().as_millis() as u64,
             atomic::Ordering::AcqRel,
@@ -675,6 +890,10 @@ impl From<RpcSecretKey> for Uuid {
 }
 
 impl Authorization {
+    pub fn is_internal(&self) -> bool {
+        matches!(self.authorization_type, AuthorizationType::Internal)
+    }
+
     pub fn internal() -> Web3ProxyResult<Self> {
         let authorization_checks = AuthorizationChecks {
             // any error logs on a local (internal) query are likely problems. log them all
@@ -791,8 +1010,48 @@ impl Authorization {
             referer: referer.cloned(),
             user_agent: user_agent.cloned(),
             authorization_type,
+            cache_bypass: false,
+            max_backend_attempts: None,
+            quorum: None,
+            sticky_session: None,
+            upstream_errors: false,
         })
     }
+
+    /// honor a client's request for a fresh response.
+    /// only keys using paid credits are trusted with this. anyone else could use it to bust the cache for free
+    pub fn request_cache_bypass(&mut self) {
+        self.cache_bypass =
+            self.checks.rpc_secret_key_id.is_some() && self.checks.paid_credits_used;
+    }
+
+    /// honor a client's choice between failing fast and trying more rpcs. the app's ceiling still applies
+    pub fn request_max_backend_attempts(&mut self, max_attempts: usize, ceiling: Option<usize>) {
+        if self.checks.rpc_secret_key_id.is_some() {
+            self.max_backend_attempts = Some(ceiling.map_or(max_attempts, |x| max_attempts.min(x)));
+        }
+    }
+
+    /// honor a client's request for a result that multiple rpcs agree on.
+    /// this multiplies the backend requests, so only keys using paid credits are trusted with it
+    pub fn request_quorum(&mut self, quorum: usize) {
+        if self.checks.rpc_secret_key_id.is_some() && self.checks.paid_credits_used && quorum > 1 {
+            self.quorum = Some(quorum);
+        }
+    }
+
+    /// honor a client's request for the original errors from the backend rpcs.
+    /// these can include upstream urls with their api keys, so only admin keys are trusted with them
+    pub fn request_upstream_errors(&mut self) {
+        self.upstream_errors = self.checks.rpc_secret_key_id.is_some() && self.checks.admin;
+    }
+
+    /// honor a client's request to keep a session on one rpc. sessions are per key so that clients can't share them
+    pub fn request_sticky_session(&mut self, session: &str) {
+        if let Some(rpc_secret_key_id) = self.checks.rpc_secret_key_id {
+            self.sticky_session = Some(format!("{}:{}", rpc_secret_key_id, session));
+        }
+    }
 }
 
 /// rate limit logins only by ip.
@@ -1090,44 +1349,40 @@ impl Web3ProxyApp {
             None,
         )?;
 
-        if let Some(rate_limiter) = &self.frontend_ip_rate_limiter {
-            match rate_limiter
-                .throttle(*ip, authorization.checks.max_requests_per_period, 1)
-                .await
-            {
-                Ok(DeferredRateLimitResult::Allowed) => {
-                    // rate limit allowed us. check concurrent request limits
-                    let semaphore = self.ip_semaphore(ip).await?;
+        match self.throttle(&authorization).await {
+            Some(Ok(DeferredRateLimitResult::Allowed)) => {
+                // rate limit allowed us. check concurrent request limits
+                let semaphore = self.ip_semaphore(ip).await?;
 
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
-                }
-                Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-                    // TODO: set headers so they know when they can retry
-                    // // trace!(?ip, "rate limit exceeded until {:?}", retry_at);
-                    Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
-                }
-                Ok(DeferredRateLimitResult::RetryNever) => {
-                    // TODO: i don't think we'll get here. maybe if we ban an IP forever? seems unlikely
-                    // // trace!(?ip, "rate limit is 0");
-                    Ok(RateLimitResult::RateLimited(authorization, None))
-                }
-                Err(err) => {
-                    // this an internal error of some kind, not the rate limit being hit
-                    // TODO: i really want axum to do this for us in a single place.
-                    error!("rate limiter is unhappy. allowing ip. err={:?}", err);
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
+            }
+            Some(Ok(DeferredRateLimitResult::RetryAt(retry_at))) => {
+                // TODO: set headers so they know when they can retry
+                // // trace!(?ip, "rate limit exceeded until {:?}", retry_at);
+                Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
+            }
+            Some(Ok(DeferredRateLimitResult::RetryNever)) => {
+                // TODO: i don't think we'll get here. maybe if we ban an IP forever? seems unlikely
+                // // trace!(?ip, "rate limit is 0");
+                Ok(RateLimitResult::RateLimited(authorization, None))
+            }
+            Some(Err(err)) => {
+                // this an internal error of some kind, not the rate limit being hit
+                // TODO: i really want axum to do this for us in a single place.
+                error!("rate limiter is unhappy. allowing ip. err={:?}", err);
 
-                    // at least we can still check the semaphore
-                    let semaphore = self.ip_semaphore(ip).await?;
+                // at least we can still check the semaphore
+                let semaphore = self.ip_semaphore(ip).await?;
 
-                    Ok(RateLimitResult::Allowed(authorization, semaphore))
-                }
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
             }
-        } else {
-            // no redis, but we can still check the ip semaphore
-            let semaphore = self.ip_semaphore(ip).await?;
+            None => {
+                // no redis, but we can still check the ip semaphore
+                let semaphore = self.ip_semaphore(ip).await?;
 
-            // TODO: if no redis, rate limit with a local cache? "warn!" probably isn't right
-            Ok(RateLimitResult::Allowed(authorization, semaphore))
+                // TODO: if no redis, rate limit with a local cache? "warn!" probably isn't right
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
+            }
         }
     }
 
@@ -1136,6 +1391,28 @@ impl Web3ProxyApp {
         &self,
         proxy_mode: ProxyMode,
         rpc_secret_key: &RpcSecretKey,
+    ) -> Web3ProxyResult<AuthorizationChecks> {
+        let x = self
+            ._authorization_checks(proxy_mode, rpc_secret_key)
+            .await?;
+
+        // the balance is shared with the stat buffer, but these checks are cached.
+        // if paid credits ran out since then, check again now instead of spending the balance further below zero
+        if x.paid_credits_used && !x.latest_balance.read().await.active_premium() {
+            trace!(user_id=%x.user_id, "paid credits ran out. checking the key again");
+
+            self.rpc_secret_key_cache.invalidate(rpc_secret_key).await;
+
+            return self._authorization_checks(proxy_mode, rpc_secret_key).await;
+        }
+
+        Ok(x)
+    }
+
+    async fn _authorization_checks(
+        &self,
+        proxy_mode: ProxyMode,
+        rpc_secret_key: &RpcSecretKey,
     ) -> Web3ProxyResult<AuthorizationChecks> {
         // TODO: move onto a helper function
 
@@ -1215,6 +1492,9 @@ impl Web3ProxyApp {
                                 None
                             };
 
+                        let allowed_methods = parse_methods(rpc_key_model.allowed_methods);
+                        let denied_methods = parse_methods(rpc_key_model.denied_methods);
+
                         // Get the user_tier
                         let user_model = user::Entity::find_by_id(rpc_key_model.user_id)
                             .one(db_replica.as_ref())
@@ -1264,14 +1544,23 @@ impl Web3ProxyApp {
                             paid_credits_used = false;
                         }
 
+                        let admin = admin::Entity::find()
+                            .filter(admin::Column::UserId.eq(rpc_key_model.user_id))
+                            .one(db_replica.as_ref())
+                            .await?
+                            .is_some();
+
                         let rpc_key_id =
                             Some(rpc_key_model.id.try_into().context("db ids are never 0")?);
 
                         Ok::<_, Web3ProxyError>(AuthorizationChecks {
+                            admin,
                             allowed_ips,
                             allowed_origins,
                             allowed_referers,
                             allowed_user_agents,
+                            allowed_methods,
+                            denied_methods,
                             latest_balance,
                             // TODO: is floating point math going to scale this correctly?
                             log_revert_chance: (rpc_key_model.log_revert_chance * u16::MAX as f64)
@@ -1340,52 +1629,117 @@ impl Web3ProxyApp {
         )?;
 
         // user key is valid. now check rate limits
-        if let Some(user_max_requests_per_period) = authorization.checks.max_requests_per_period {
-            if let Some(rate_limiter) = &self.frontend_registered_user_rate_limiter {
-                match rate_limiter
-                    .throttle(
-                        RegisteredUserRateLimitKey(authorization.checks.user_id, *ip),
-                        Some(user_max_requests_per_period),
-                        1,
-                    )
-                    .await
-                {
-                    Ok(DeferredRateLimitResult::Allowed) => {
-                        return Ok(RateLimitResult::Allowed(authorization, semaphore))
-                    }
-                    Ok(DeferredRateLimitResult::RetryAt(retry_at)) => {
-                        // TODO: set headers so they know when they can retry
-                        // TODO: debug or trace?
-                        // this is too verbose, but a stat might be good
-                        // TODO: keys are secrets! use the id instead
-                        // TODO: emit a stat
-                        // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
-                        return Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)));
-                    }
-                    Ok(DeferredRateLimitResult::RetryNever) => {
-                        // TODO: keys are secret. don't log them!
-                        // trace!(?rpc_key, "rate limit is 0");
-                        // TODO: emit a stat
-                        return Ok(RateLimitResult::RateLimited(authorization, None));
-                    }
-                    Err(err) => {
-                        // internal error, not rate limit being hit
-                        // TODO: i really want axum to do this for us in a single place.
-                        error!(?err, "rate limiter is unhappy. allowing rpc_key");
+        match self.throttle(&authorization).await {
+            Some(Ok(DeferredRateLimitResult::Allowed)) => {
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
+            }
+            Some(Ok(DeferredRateLimitResult::RetryAt(retry_at))) => {
+                // TODO: set headers so they know when they can retry
+                // TODO: debug or trace?
+                // this is too verbose, but a stat might be good
+                // TODO: keys are secrets! use the id instead
+                // TODO: emit a stat
+                // trace!(?rpc_key, "rate limit exceeded until {:?}", retry_at);
+                Ok(RateLimitResult::RateLimited(authorization, Some(retry_at)))
+            }
+            Some(Ok(DeferredRateLimitResult::RetryNever)) => {
+                // TODO: keys are secret. don't log them!
+                // trace!(?rpc_key, "rate limit is 0");
+                // TODO: emit a stat
+                Ok(RateLimitResult::RateLimited(authorization, None))
+            }
+            Some(Err(err)) => {
+                // internal error, not rate limit being hit
+                // TODO: i really want axum to do this for us in a single place.
+                error!(?err, "rate limiter is unhappy. allowing rpc_key");
 
-                        return Ok(RateLimitResult::Allowed(authorization, semaphore));
-                    }
-                }
-            } else {
-                // TODO: if no redis, rate limit with just a local cache?
+                Ok(RateLimitResult::Allowed(authorization, semaphore))
             }
+            // TODO: if no redis, rate limit with just a local cache?
+            None => Ok(RateLimitResult::Allowed(authorization, semaphore)),
+        }
+    }
+
+    /// count one more request against an authorization that was already rate limited once.
+    /// every request in a batch counts, but the batch only goes through `rate_limit_by_ip` or `rate_limit_by_rpc_key` once
+    pub async fn rate_limit_again(&self, authorization: &Authorization) -> Web3ProxyResult<()> {
+        if matches!(
+            authorization.authorization_type,
+            AuthorizationType::Internal
+        ) {
+            return Ok(());
         }
 
-        Ok(RateLimitResult::Allowed(authorization, semaphore))
+        let Some(throttled) = self.throttle(authorization).await else {
+            return Ok(());
+        };
+
+        match throttled {
+            Ok(DeferredRateLimitResult::Allowed) => Ok(()),
+            Ok(DeferredRateLimitResult::RetryAt(retry_at)) => Err(Web3ProxyError::RateLimited(
+                authorization.clone(),
+                Some(retry_at),
+            )),
+            Ok(DeferredRateLimitResult::RetryNever) => {
+                Err(Web3ProxyError::RateLimited(authorization.clone(), None))
+            }
+            Err(err) => {
+                // internal error, not rate limit being hit
+                error!(?err, "rate limiter is unhappy. allowing request");
+
+                Ok(())
+            }
+        }
+    }
+
+    /// count one request against the limit that applies to this authorization. keys are limited by user and ip, everything else by ip.
+    /// None if there is no limit to count against (no redis, or a key without max_requests_per_period)
+    async fn throttle(
+        &self,
+        authorization: &Authorization,
+    ) -> Option<anyhow::Result<DeferredRateLimitResult>> {
+        let checks = &authorization.checks;
+
+        if checks.rpc_secret_key_id.is_some() {
+            let max_requests_per_period = checks.max_requests_per_period?;
+            let rate_limiter = self.frontend_registered_user_rate_limiter.as_ref()?;
+
+            let x = rate_limiter
+                .throttle(
+                    RegisteredUserRateLimitKey(checks.user_id, authorization.ip),
+                    Some(max_requests_per_period),
+                    1,
+                )
+                .await;
+
+            Some(x)
+        } else {
+            let rate_limiter = self.frontend_ip_rate_limiter.as_ref()?;
+
+            let x = rate_limiter
+                .throttle(authorization.ip, checks.max_requests_per_period, 1)
+                .await;
+
+            Some(x)
+        }
     }
 }
 
 impl Authorization {
+    /// the only check of a key's allowed_methods/denied_methods. every request and subscription goes through this
+    pub fn check_method(&self, method: &str) -> Web3ProxyResult<()> {
+        if self.checks.method_allowed(method) {
+            Ok(())
+        } else {
+            Err(JsonRpcErrorData {
+                message: format!("the method {} is disabled for this key", method).into(),
+                code: -32601,
+                data: None,
+            }
+            .into())
+        }
+    }
+
     pub async fn check_again(
         &self,
         app: &Arc<Web3ProxyApp>,
@@ -1411,3 +1765,50 @@ impl Authorization {
         Ok((a, s))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_allowed() {
+        let checks = AuthorizationChecks::default();
+
+        assert!(checks.method_allowed("eth_sendRawTransaction"));
+        assert!(checks.method_allowed("debug_traceTransaction"));
+
+        let checks = AuthorizationChecks {
+            allowed_methods: parse_methods(Some("eth_*, net_version".to_string())),
+            denied_methods: parse_methods(Some("eth_sendRawTransaction,".to_string())),
+            ..Default::default()
+        };
+
+        assert!(checks.method_allowed("eth_call"));
+        assert!(checks.method_allowed("net_version"));
+        assert!(!checks.method_allowed("net_listening"));
+        assert!(!checks.method_allowed("eth_sendRawTransaction"));
+        assert!(!checks.method_allowed("debug_traceTransaction"));
+
+        let checks = AuthorizationChecks {
+            allowed_methods: parse_methods(Some(" , ".to_string())),
+            denied_methods: parse_methods(Some("debug_*".to_string())),
+            ..Default::default()
+        };
+
+        assert!(checks.allowed_methods.is_none());
+        assert!(checks.method_allowed("eth_sendRawTransaction"));
+        assert!(!checks.method_allowed("debug_traceTransaction"));
+
+        let mut authorization = Authorization::internal().unwrap();
+        authorization.checks = checks;
+
+        assert!(authorization.check_method("eth_subscribe").is_ok());
+        assert!(matches!(
+            authorization.check_method("debug_traceTransaction"),
+            Err(Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+                code: -32601,
+                ..
+            }))
+        ));
+    }
+}
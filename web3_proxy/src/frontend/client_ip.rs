@@ -0,0 +1,138 @@
+//! Find the ip address of the client. Keys that are restricted to some ips depend on this.
+use crate::app::Web3ProxyApp;
+use crate::errors::Web3ProxyError;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum_client_ip::InsecureClientIp;
+use http::request::Parts;
+use http::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+/// Like `InsecureClientIp`, but if `trusted_proxy_hops` is set, X-Forwarded-For entries that the client sent are ignored
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = Web3ProxyError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let trusted_proxy_hops = parts
+            .extensions
+            .get::<Arc<Web3ProxyApp>>()
+            .and_then(|x| x.config.trusted_proxy_hops);
+
+        let trusted_proxy_hops = match trusted_proxy_hops {
+            Some(x) => x,
+            None => {
+                // the left-most forwarded ip. the client can set this to anything
+                let InsecureClientIp(ip) = InsecureClientIp::from_request_parts(parts, state)
+                    .await
+                    .map_err(|err| Web3ProxyError::BadRequest(format!("{:?}", err).into()))?;
+
+                return Ok(Self(ip));
+            }
+        };
+
+        let connect_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|x| x.0.ip())
+            .ok_or_else(|| anyhow!("ConnectInfo is required for trusted_proxy_hops"))?;
+
+        Ok(Self(trusted_client_ip(
+            &parts.headers,
+            connect_ip,
+            trusted_proxy_hops,
+        )))
+    }
+}
+
+/// Each proxy appends the ip that it received the request from to X-Forwarded-For.
+/// Anything the client put in the header is to the left of what our proxies added, so count from the right.
+/// If there are fewer entries than proxies, the request did not come through all of them and the connecting ip is used.
+pub fn trusted_client_ip(
+    headers: &HeaderMap,
+    connect_ip: IpAddr,
+    trusted_proxy_hops: usize,
+) -> IpAddr {
+    if trusted_proxy_hops == 0 {
+        return connect_ip;
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(|x| x.trim())
+        .collect();
+
+    forwarded
+        .iter()
+        .rev()
+        .nth(trusted_proxy_hops - 1)
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(connect_ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+
+        for x in values {
+            headers.append("x-forwarded-for", HeaderValue::from_str(x).unwrap());
+        }
+
+        headers
+    }
+
+    #[test]
+    fn test_trusted_client_ip() {
+        let load_balancer: IpAddr = "10.0.0.2".parse().unwrap();
+
+        // ipv4 behind one load balancer
+        let headers = forwarded_for(&["203.0.113.7"]);
+        assert_eq!(
+            trusted_client_ip(&headers, load_balancer, 1),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // ipv6 behind two proxies. the second proxy added the first proxy's address
+        let headers = forwarded_for(&["2001:db8::1, 10.0.0.1"]);
+        assert_eq!(
+            trusted_client_ip(&headers, load_balancer, 2),
+            "2001:db8::1".parse::<IpAddr>().unwrap()
+        );
+
+        // a spoofed entry from the client is to the left of the one our load balancer added
+        let headers = forwarded_for(&["192.0.2.1", "203.0.113.7"]);
+        assert_eq!(
+            trusted_client_ip(&headers, load_balancer, 1),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        let headers = forwarded_for(&["192.0.2.1, 203.0.113.7"]);
+        assert_eq!(
+            trusted_client_ip(&headers, load_balancer, 1),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        // no proxies means the header is ignored
+        assert_eq!(trusted_client_ip(&headers, load_balancer, 0), load_balancer);
+
+        // a request that skipped the load balancer can't pick its own ip
+        let headers = forwarded_for(&["192.0.2.1"]);
+        assert_eq!(trusted_client_ip(&headers, load_balancer, 2), load_balancer);
+        assert_eq!(
+            trusted_client_ip(&HeaderMap::new(), load_balancer, 1),
+            load_balancer
+        );
+    }
+}
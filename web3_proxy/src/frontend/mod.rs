@@ -4,6 +4,7 @@
 // TODO: these are only public so docs are generated. What's a better way to do this?
 pub mod admin;
 pub mod authorization;
+pub mod client_ip;
 pub mod errors;
 pub mod rpc_proxy_http;
 pub mod rpc_proxy_ws;
@@ -30,13 +31,14 @@ use tower::timeout::TimeoutLayer;
 use tower::ServiceBuilder;
 use tower_http::sensitive_headers::SetSensitiveRequestHeadersLayer;
 use tower_http::{cors::CorsLayer, normalize_path::NormalizePathLayer, trace::TraceLayer};
-use tracing::{error_span, info};
+use tracing::{error_span, field::Empty, info};
 use ulid::Ulid;
 
 /// simple keys for caching responses
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, EnumCount, EnumIter)]
 pub enum ResponseCacheKey {
     BackupsNeeded,
+    HeadLag,
     Health,
     Status,
 }
@@ -116,6 +118,8 @@ pub async fn serve(
         .route("/health", get(status::health))
         .route("/status", get(status::status))
         .route("/status/backups_needed", get(status::backups_needed))
+        .route("/status/head_lag", get(status::head_lag))
+        .route("/status/head_lag/history", get(status::head_lag_history))
         .route("/status/debug_request", get(status::debug_request))
         //
         // User stuff
@@ -171,6 +175,14 @@ pub async fn serve(
                 .post(users::rpc_keys::rpc_keys_management)
                 .put(users::rpc_keys::rpc_keys_management),
         )
+        .route(
+            "/user/key/:rpc_key_id/permissions",
+            get(users::rpc_keys::rpc_key_permissions_get),
+        )
+        .route(
+            "/user/key/:rpc_key_id/rotate",
+            post(users::rpc_keys::rpc_key_rotate_post),
+        )
         // .route("/user/referral/:referral_link", get(users::user_referral_link_get))
         .route(
             "/user/referral",
@@ -185,6 +197,7 @@ pub async fn serve(
             get(users::referral::user_shared_referral_stats),
         )
         .route("/user/revert_logs", get(users::stats::user_revert_logs_get))
+        .route("/user/stats", get(users::stats::user_stats_get))
         .route(
             "/user/stats/aggregate",
             get(users::stats::user_influx_stats_aggregated_get),
@@ -209,6 +222,13 @@ pub async fn serve(
             "/admin/increase_balance",
             post(admin::admin_increase_balance),
         )
+        .route("/admin/maintenance", post(admin::admin_maintenance_post))
+        .route("/admin/reorgs", get(admin::admin_reorgs_get))
+        .route("/admin/dead_letters", get(admin::admin_dead_letters_get))
+        .route(
+            "/admin/rpc_lifetime_stats",
+            get(admin::admin_rpc_lifetime_stats_get),
+        )
         .route("/admin/modify_role", post(admin::admin_change_user_roles))
         .route(
             "/admin/imitate_login/:admin_address/:user_address",
@@ -262,9 +282,13 @@ pub async fn serve(
 
                 // And then we put it along with other information into the `request` span
                 // TODO: what other info should we attach? how can we attach an error and a tracing span here?
+                // rpc_method, upstream, and latency_ms are recorded once the request is done
                 error_span!(
                     "request",
                     id = %request_id,
+                    rpc_method = Empty,
+                    upstream = Empty,
+                    latency_ms = Empty,
                     // method = %request.method(),
                     // // don't log the path. it often includes the RPC key!
                     // path = %request.uri().path(),
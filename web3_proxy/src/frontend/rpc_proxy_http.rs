@@ -1,15 +1,15 @@
 //! Take a user's HTTP JSON-RPC requests and either respond from local data or proxy the request to a backend rpc server.
 
 use super::authorization::{ip_is_authorized, key_is_authorized};
+use super::client_ip::ClientIp;
 use super::rpc_proxy_ws::ProxyMode;
 use crate::errors::Web3ProxyError;
 use crate::{app::Web3ProxyApp, jsonrpc::JsonRpcRequestEnum};
 use axum::extract::Path;
-use axum::headers::{Origin, Referer, UserAgent};
+use axum::headers::{CacheControl, Origin, Referer, UserAgent};
 use axum::response::Response;
 use axum::TypedHeader;
 use axum::{response::IntoResponse, Extension, Json};
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use http::HeaderMap;
 use itertools::Itertools;
@@ -23,7 +23,7 @@ use std::time::Duration;
 #[debug_handler]
 pub async fn proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -33,7 +33,7 @@ pub async fn proxy_web3_rpc(
 #[debug_handler]
 pub async fn fastest_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -45,7 +45,7 @@ pub async fn fastest_proxy_web3_rpc(
 #[debug_handler]
 pub async fn versus_proxy_web3_rpc(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -75,11 +75,11 @@ async fn _proxy_web3_rpc(
 
     // TODO: is first_id the right thing to attach to this error?
     let (status_code, response, rpcs) = app
-        .proxy_web3_rpc(authorization, payload)
+        .proxy_web3_rpc(authorization, payload, true)
         .await
         .map_err(|e| e.into_response_with_id(first_id))?;
 
-    let mut response = (status_code, Json(response)).into_response();
+    let mut response = (status_code, response).into_response();
 
     // TODO: DRY this up. it is the same code for public and private queries
     let response_headers = response.headers_mut();
@@ -119,12 +119,15 @@ async fn _proxy_web3_rpc(
 /// Can optionally authorized based on origin, referer, or user agent.
 /// If possible, please use a WebSocket instead.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    cache_control: Option<TypedHeader<CacheControl>>,
+    request_headers: HeaderMap,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -134,6 +137,8 @@ pub async fn proxy_web3_rpc_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        cache_control.as_deref(),
+        &request_headers,
         rpc_key,
         payload,
         ProxyMode::Best,
@@ -146,10 +151,11 @@ pub async fn proxy_web3_rpc_with_key(
 #[allow(clippy::too_many_arguments)]
 pub async fn debug_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    cache_control: Option<TypedHeader<CacheControl>>,
     request_headers: HeaderMap,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
@@ -160,6 +166,8 @@ pub async fn debug_proxy_web3_rpc_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        cache_control.as_deref(),
+        &request_headers,
         rpc_key,
         payload,
         ProxyMode::Debug,
@@ -188,12 +196,15 @@ pub async fn debug_proxy_web3_rpc_with_key(
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    cache_control: Option<TypedHeader<CacheControl>>,
+    request_headers: HeaderMap,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -203,6 +214,8 @@ pub async fn fastest_proxy_web3_rpc_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        cache_control.as_deref(),
+        &request_headers,
         rpc_key,
         payload,
         ProxyMode::Fastest(0),
@@ -211,12 +224,15 @@ pub async fn fastest_proxy_web3_rpc_with_key(
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_proxy_web3_rpc_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    cache_control: Option<TypedHeader<CacheControl>>,
+    request_headers: HeaderMap,
     Path(rpc_key): Path<String>,
     Json(payload): Json<JsonRpcRequestEnum>,
 ) -> Result<Response, Response> {
@@ -226,6 +242,8 @@ pub async fn versus_proxy_web3_rpc_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        cache_control.as_deref(),
+        &request_headers,
         rpc_key,
         payload,
         ProxyMode::Versus,
@@ -240,6 +258,8 @@ async fn _proxy_web3_rpc_with_key(
     origin: Option<&Origin>,
     referer: Option<&Referer>,
     user_agent: Option<&UserAgent>,
+    cache_control: Option<&CacheControl>,
+    request_headers: &HeaderMap,
     rpc_key: String,
     payload: JsonRpcRequestEnum,
     proxy_mode: ProxyMode,
@@ -252,11 +272,71 @@ async fn _proxy_web3_rpc_with_key(
         .parse()
         .map_err(|e: Web3ProxyError| e.into_response_with_id(first_id.clone()))?;
 
-    let (authorization, _semaphore) =
+    let (mut authorization, _semaphore) =
         key_is_authorized(&app, &rpc_key, ip, origin, proxy_mode, referer, user_agent)
             .await
             .map_err(|e| e.into_response_with_id(first_id.clone()))?;
 
+    // `Cache-Control: no-cache` asks for a fresh response from a backend rpc
+    if cache_control.map(|x| x.no_cache()).unwrap_or_default() {
+        authorization.request_cache_bypass();
+    }
+
+    // `X-W3P-QUORUM: 2` asks for a result that at least 2 synced rpcs agree on
+    if let Some(quorum) = request_headers.get("x-w3p-quorum") {
+        let quorum = quorum
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .ok_or_else(|| {
+                Web3ProxyError::BadRequest("X-W3P-QUORUM must be a number".into())
+                    .into_response_with_id(first_id.clone())
+            })?;
+
+        authorization.request_quorum(quorum);
+    }
+
+    // `X-W3P-MAX-ATTEMPTS: 1` fails fast instead of trying another rpc after a backend error
+    if let Some(max_attempts) = request_headers.get("x-w3p-max-attempts") {
+        let max_attempts = max_attempts
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .filter(|x| *x > 0)
+            .ok_or_else(|| {
+                Web3ProxyError::BadRequest("X-W3P-MAX-ATTEMPTS must be a positive number".into())
+                    .into_response_with_id(first_id.clone())
+            })?;
+
+        authorization.request_max_backend_attempts(max_attempts, app.config.max_backend_attempts);
+    }
+
+    // `X-W3P-SESSION: <id>` sends requests with the same id to the same rpc while it is available
+    if let Some(session) = request_headers.get("x-w3p-session") {
+        let session = session.to_str().map_err(|_| {
+            Web3ProxyError::BadRequest("X-W3P-SESSION must be visible ascii".into())
+                .into_response_with_id(first_id.clone())
+        })?;
+
+        authorization.request_sticky_session(session);
+    }
+
+    // `X-W3P-UPSTREAM-ERRORS: true` attaches the original errors from the backend rpcs to error responses. admin keys only
+    if let Some(upstream_errors) = request_headers.get("x-w3p-upstream-errors") {
+        let upstream_errors = upstream_errors
+            .to_str()
+            .ok()
+            .and_then(|x| x.parse::<bool>().ok())
+            .ok_or_else(|| {
+                Web3ProxyError::BadRequest("X-W3P-UPSTREAM-ERRORS must be true or false".into())
+                    .into_response_with_id(first_id.clone())
+            })?;
+
+        if upstream_errors {
+            authorization.request_upstream_errors();
+        }
+    }
+
     let authorization = Arc::new(authorization);
 
     payload
@@ -266,11 +346,11 @@ async fn _proxy_web3_rpc_with_key(
     let rpc_secret_key_id = authorization.checks.rpc_secret_key_id;
 
     let (status_code, response, rpcs) = app
-        .proxy_web3_rpc(authorization, payload)
+        .proxy_web3_rpc(authorization, payload, true)
         .await
         .map_err(|e| e.into_response_with_id(first_id))?;
 
-    let mut response = (status_code, Json(response)).into_response();
+    let mut response = (status_code, response).into_response();
 
     let headers = response.headers_mut();
 
@@ -3,6 +3,7 @@
 //! WebSockets are the preferred method of receiving requests, but not all clients have good support.
 
 use super::authorization::{ip_is_authorized, key_is_authorized, Authorization, RequestMetadata};
+use super::client_ip::ClientIp;
 use crate::errors::{Web3ProxyError, Web3ProxyResponse};
 use crate::jsonrpc::JsonRpcId;
 use crate::{
@@ -17,7 +18,6 @@ use axum::{
     response::{IntoResponse, Redirect},
     Extension, TypedHeader,
 };
-use axum_client_ip::InsecureClientIp;
 use axum_macros::debug_handler;
 use ethers::types::U64;
 use futures::SinkExt;
@@ -57,7 +57,7 @@ pub enum ProxyMode {
 #[debug_handler]
 pub async fn websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
@@ -69,7 +69,7 @@ pub async fn websocket_handler(
 #[debug_handler]
 pub async fn fastest_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
@@ -90,7 +90,7 @@ pub async fn fastest_websocket_handler(
 #[debug_handler]
 pub async fn versus_websocket_handler(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     origin: Option<TypedHeader<Origin>>,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
@@ -128,13 +128,15 @@ async fn _websocket_handler(
 /// Rate limit and billing based on the api key in the url.
 /// Can optionally authorized based on origin, referer, or user agent.
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Path(rpc_key): Path<String>,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     _websocket_handler_with_key(
@@ -145,6 +147,7 @@ pub async fn websocket_handler_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        &headers,
         ws_upgrade,
     )
     .await
@@ -154,7 +157,7 @@ pub async fn websocket_handler_with_key(
 #[allow(clippy::too_many_arguments)]
 pub async fn debug_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Path(rpc_key): Path<String>,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
@@ -170,6 +173,7 @@ pub async fn debug_websocket_handler_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        &headers,
         ws_upgrade,
     )
     .await?;
@@ -191,13 +195,15 @@ pub async fn debug_websocket_handler_with_key(
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn fastest_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Path(rpc_key): Path<String>,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     // TODO: get the fastest number from the url params (default to 0/all)
@@ -209,19 +215,22 @@ pub async fn fastest_websocket_handler_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        &headers,
         ws_upgrade,
     )
     .await
 }
 
 #[debug_handler]
+#[allow(clippy::too_many_arguments)]
 pub async fn versus_websocket_handler_with_key(
     Extension(app): Extension<Arc<Web3ProxyApp>>,
-    InsecureClientIp(ip): InsecureClientIp,
+    ClientIp(ip): ClientIp,
     Path(rpc_key): Path<String>,
     origin: Option<TypedHeader<Origin>>,
     referer: Option<TypedHeader<Referer>>,
     user_agent: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     _websocket_handler_with_key(
@@ -232,6 +241,7 @@ pub async fn versus_websocket_handler_with_key(
         origin.as_deref(),
         referer.as_deref(),
         user_agent.as_deref(),
+        &headers,
         ws_upgrade,
     )
     .await
@@ -246,13 +256,23 @@ async fn _websocket_handler_with_key(
     origin: Option<&Origin>,
     referer: Option<&Referer>,
     user_agent: Option<&UserAgent>,
+    headers: &HeaderMap,
     ws_upgrade: Option<WebSocketUpgrade>,
 ) -> Web3ProxyResponse {
     let rpc_key = rpc_key.parse()?;
 
-    let (authorization, _semaphore) =
+    let (mut authorization, _semaphore) =
         key_is_authorized(&app, &rpc_key, ip, origin, proxy_mode, referer, user_agent).await?;
 
+    // `X-W3P-SESSION: <id>` works the same as it does over http. every request on this socket is in the session
+    if let Some(session) = headers.get("x-w3p-session") {
+        let session = session.to_str().map_err(|_| {
+            Web3ProxyError::BadRequest("X-W3P-SESSION must be visible ascii".into())
+        })?;
+
+        authorization.request_sticky_session(session);
+    }
+
     trace!("websocket_handler_with_key {:?}", authorization);
 
     let authorization = Arc::new(authorization);
@@ -395,9 +415,9 @@ async fn websocket_proxy_web3_rpc(
             Ok(response.into())
         }
         _ => app
-            .proxy_web3_rpc(authorization, json_request.into())
+            .proxy_web3_rpc(authorization, json_request.into(), false)
             .await
-            .map(|(_, response, _)| response),
+            .and_then(|(_, response, _)| response.buffered()),
     };
 
     (response_id, response)
@@ -507,7 +527,7 @@ async fn read_web3_socket(
                             }
                             Message::Close(_) => {
                                 trace!("closing websocket connection");
-                                // TODO: do something to close subscriptions?
+                                // subscriptions are closed once the read loop exits
                                 let _ = close_sender.send(true);
                                 return;
                             }
@@ -557,6 +577,15 @@ async fn read_web3_socket(
             }
         }
     }
+
+    // the client is gone. stop the tasks that forward subscriptions to it
+    for (subscription_id, handle) in subscriptions.write().await.drain() {
+        trace!(
+            ?subscription_id,
+            "closing subscription for closed websocket"
+        );
+        handle.abort();
+    }
 }
 
 async fn write_web3_socket(
@@ -6,10 +6,13 @@
 use super::{ResponseCache, ResponseCacheKey};
 use crate::{
     app::{Web3ProxyApp, APP_USER_AGENT},
-    errors::Web3ProxyError,
+    errors::{Web3ProxyError, Web3ProxyResponse},
+    http_params::{get_query_start_from_params, get_query_stop_from_params},
+    stats::influxdb_queries::query_rpc_head_lag,
 };
 use axum::{
     body::{Bytes, Full},
+    extract::Query,
     http::StatusCode,
     response::{IntoResponse, Response},
     Extension, Json,
@@ -99,7 +102,7 @@ pub async fn health(
 async fn _health(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
     trace!("health is not cached");
 
-    if app.balanced_rpcs.synced() {
+    if app.balanced_rpcs.synced() && app.warmed_up() {
         (StatusCode::OK, CONTENT_TYPE_PLAIN, HEALTH_OK.clone())
     } else {
         (
@@ -159,6 +162,117 @@ async fn _backups_needed(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, B
     }
 }
 
+/// How often each balanced rpc was on the consensus head vs behind it since it connected.
+/// These counts are in memory and start over when an rpc reconnects or the app restarts.
+/// Use `/status/head_lag/history` for counts over a time range.
+#[debug_handler]
+pub async fn head_lag(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Extension(cache): Extension<Arc<ResponseCache>>,
+) -> Result<impl IntoResponse, Web3ProxyError> {
+    let (code, content_type, body) = timeout(
+        Duration::from_secs(3),
+        cache.get_with(
+            ResponseCacheKey::HeadLag,
+            async move { _head_lag(app).await },
+        ),
+    )
+    .await?;
+
+    let x = Response::builder()
+        .status(code)
+        .header("content-type", content_type)
+        .body(Full::from(body))
+        .unwrap();
+
+    Ok(x)
+}
+
+#[inline]
+async fn _head_lag(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
+    trace!("head_lag is not cached");
+
+    let rpcs: HashMap<_, _> = app
+        .balanced_rpcs
+        .by_name
+        .read()
+        .values()
+        .map(|rpc| {
+            let (on_head, behind_head) = rpc.head_lag_counts();
+
+            (
+                rpc.name.clone(),
+                json!({
+                    "on_head": on_head,
+                    "behind_head": behind_head,
+                }),
+            )
+        })
+        .collect();
+
+    let body = json!({
+        "chain_id": app.config.chain_id,
+        "rpcs": rpcs,
+    });
+
+    let body = Bytes::from(body.to_string().into_bytes());
+
+    (StatusCode::OK, CONTENT_TYPE_JSON, body)
+}
+
+/// How often each rpc was on the consensus head vs behind it between `query_start` and `query_stop`.
+/// Useful for deciding which providers are worth keeping. Requires influx.
+#[debug_handler]
+pub async fn head_lag_history(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Web3ProxyResponse {
+    let influxdb_client = app.influxdb_client()?;
+    let bucket = app
+        .config
+        .influxdb_bucket
+        .as_ref()
+        .ok_or(Web3ProxyError::NoDatabaseConfigured)?;
+
+    let query_start = get_query_start_from_params(&params)?.timestamp();
+    let query_stop = get_query_stop_from_params(&params)?.timestamp();
+
+    if query_start >= query_stop {
+        return Err(Web3ProxyError::BadRequest(
+            "query_start must be before query_stop".into(),
+        ));
+    }
+
+    let rpcs: HashMap<_, _> = query_rpc_head_lag(
+        influxdb_client,
+        bucket,
+        app.config.chain_id,
+        query_start,
+        query_stop,
+    )
+    .await?
+    .into_iter()
+    .map(|(rpc, (on_head, behind_head))| {
+        (
+            rpc,
+            json!({
+                "on_head": on_head,
+                "behind_head": behind_head,
+            }),
+        )
+    })
+    .collect();
+
+    let body = json!({
+        "chain_id": app.config.chain_id,
+        "query_start": query_start,
+        "query_stop": query_stop,
+        "rpcs": rpcs,
+    });
+
+    Ok(Json(body).into_response())
+}
+
 /// Very basic status page.
 ///
 /// TODO: replace this with proper stats and monitoring. frontend uses it for their public dashboards though
@@ -190,6 +304,9 @@ async fn _status(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
     // TODO: get out of app.balanced_rpcs instead?
     let head_block = app.watch_consensus_head_receiver.borrow().clone();
 
+    // the body and the status code need to agree about whether this server is synced
+    let synced = app.balanced_rpcs.meets_minimums();
+
     // TODO: what else should we include? uptime, cache hit rates, cpu load, memory used
     // TODO: the hostname is probably not going to change. only get once at the start?
     let body = json!({
@@ -206,16 +323,21 @@ async fn _status(app: Arc<Web3ProxyApp>) -> (StatusCode, &'static str, Bytes) {
         "head_block_num": head_block.as_ref().map(|x| x.number()),
         "head_block_hash": head_block.as_ref().map(|x| x.hash()),
         "hostname": app.hostname,
+        "maintenance": app.maintenance.load_full(),
         "payment_factory_address": app.config.deposit_factory_contract,
         "private_rpcs": app.private_rpcs,
+        "response_cache": app.jsonrpc_response_cache_stats.counts(),
+        "rpc_groups": app.rpc_groups,
+        "synced": synced,
         "version": APP_USER_AGENT,
+        "warmed_up": app.warmed_up(),
     });
 
     let body = body.to_string().into_bytes();
 
     let body = Bytes::from(body);
 
-    let code = if app.balanced_rpcs.synced() {
+    let code = if synced {
         StatusCode::OK
     } else {
         StatusCode::INTERNAL_SERVER_ERROR
@@ -1,8 +1,11 @@
 //! Handle registration, logins, and managing account data.
 use super::super::authorization::RpcSecretKey;
-use crate::app::Web3ProxyApp;
+use super::super::rpc_proxy_ws::ProxyMode;
+use crate::app::{Web3ProxyApp, ADMIN_METHODS, SIGNING_METHODS};
+use crate::caches::RPC_SECRET_KEY_INVALIDATION_CHANNEL;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse};
 use crate::globals::{global_db_conn, global_db_replica_conn};
+use axum::extract::Path;
 use axum::headers::{Header, Origin, Referer, UserAgent};
 use axum::{
     headers::{authorization::Bearer, Authorization},
@@ -20,9 +23,12 @@ use itertools::Itertools;
 use migration::sea_orm::{
     self, ActiveModelTrait, ColumnTrait, EntityTrait, IntoActiveModel, QueryFilter, TryIntoModel,
 };
+use redis_rate_limiter::redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use std::iter;
 use std::sync::Arc;
+use tracing::warn;
 
 /// `GET /user/keys` -- Use a bearer token to get the user's api keys and their settings.
 #[debug_handler]
@@ -48,6 +54,8 @@ pub async fn rpc_keys_get(
         allowed_origins: Option<String>,
         allowed_referers: Option<String>,
         allowed_user_agents: Option<String>,
+        allowed_methods: Option<String>,
+        denied_methods: Option<String>,
         log_revert_chance: f64,
         // Addition
         // role is optional only to handle an inconsistent database. it should always be set
@@ -71,6 +79,8 @@ pub async fn rpc_keys_get(
             allowed_origins: x.allowed_origins,
             allowed_referers: x.allowed_referers,
             allowed_user_agents: x.allowed_user_agents,
+            allowed_methods: x.allowed_methods,
+            denied_methods: x.denied_methods,
             log_revert_chance: x.log_revert_chance,
             role: Some(&Role::Owner),
         })
@@ -103,6 +113,8 @@ pub async fn rpc_keys_get(
             allowed_origins: x.allowed_origins,
             allowed_referers: x.allowed_referers,
             allowed_user_agents: x.allowed_user_agents,
+            allowed_methods: x.allowed_methods,
+            denied_methods: x.denied_methods,
             log_revert_chance: x.log_revert_chance,
             role: secondary_user_entities.get(&x.id).map(|x| &x.role),
         })
@@ -120,6 +132,140 @@ pub async fn rpc_keys_get(
     Ok(Json(response_json).into_response())
 }
 
+/// `GET /user/key/:rpc_key_id/permissions` -- Use a bearer token to see what one of the user's keys is allowed to do.
+///
+/// The methods come from the app's config, the methods the app always rejects, and the key's own lists.
+/// The limits come from the user's effective tier (after any downgrade).
+/// Only the owner of the key can see this.
+#[debug_handler]
+pub async fn rpc_key_permissions_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let user = app.bearer_is_authorized(bearer).await?;
+
+    let db_replica = global_db_replica_conn().await?;
+
+    // other users' keys look the same as keys that don't exist
+    let uk = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::Id.eq(rpc_key_id))
+        .one(db_replica.as_ref())
+        .await
+        .web3_context("failed loading user's key")?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let secret_key: RpcSecretKey = uk.secret_key.into();
+
+    // these are the same checks that requests with this key go through. inactive keys get the defaults
+    let checks = app
+        .authorization_checks(ProxyMode::default(), &secret_key)
+        .await?;
+
+    // None means any method is forwarded to the backend rpcs
+    let allowed_methods = if app.config.reject_unknown_methods {
+        Some(app.config.known_methods.iter().sorted().collect::<Vec<_>>())
+    } else {
+        None
+    };
+
+    // the app always rejects these. then the key's own denied_methods
+    let denied_methods: Vec<&str> = iter::once(ADMIN_METHODS)
+        .chain(SIGNING_METHODS)
+        .chain(checks.denied_methods.iter().flatten().map(|x| x.as_str()))
+        .collect();
+
+    let response_json = json!({
+        "id": uk.id,
+        "active": uk.active,
+        "methods": {
+            "allowed": allowed_methods,
+            "denied": denied_methods,
+            // None means the key doesn't limit the methods any further
+            "key_allowed": checks.allowed_methods,
+        },
+        "restrictions": {
+            "allowed_ips": uk.allowed_ips,
+            "allowed_origins": uk.allowed_origins,
+            "allowed_referers": uk.allowed_referers,
+            "allowed_user_agents": uk.allowed_user_agents,
+            "private_txs": uk.private_txs,
+        },
+        "tier": {
+            "max_concurrent_requests": checks.max_concurrent_requests,
+            "max_requests_per_period": checks.max_requests_per_period,
+            "paid_credits_used": checks.paid_credits_used,
+        },
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
+/// `POST /user/key/:rpc_key_id/rotate` -- Use a bearer token to replace a leaked key's secret.
+///
+/// The key keeps its id, so its settings and accounting history stay with it. The old secret stops working immediately.
+/// Other instances are told over redis. Without redis, they keep accepting it until their cache expires.
+/// Only the owner of the key can rotate it.
+#[debug_handler]
+pub async fn rpc_key_rotate_post(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Path(rpc_key_id): Path<u64>,
+) -> Web3ProxyResponse {
+    let user = app.bearer_is_authorized(bearer).await?;
+
+    let db_conn = global_db_conn().await?;
+
+    // other users' keys look the same as keys that don't exist
+    let uk = rpc_key::Entity::find()
+        .filter(rpc_key::Column::UserId.eq(user.id))
+        .filter(rpc_key::Column::Id.eq(rpc_key_id))
+        .one(&db_conn)
+        .await
+        .web3_context("failed loading user's key")?
+        .ok_or(Web3ProxyError::NotFound)?;
+
+    let old_secret_key: RpcSecretKey = uk.secret_key.into();
+    let new_secret_key = RpcSecretKey::new();
+
+    let mut uk = uk.into_active_model();
+
+    uk.secret_key = sea_orm::Set(new_secret_key.into());
+
+    let uk = uk
+        .update(&db_conn)
+        .await
+        .web3_context("Failed rotating user key")?;
+
+    // requests are authorized from a cache keyed by the secret. the old secret must not stay in it
+    app.rpc_secret_key_cache.invalidate(&old_secret_key).await;
+
+    // other instances have their own caches. tell them to drop the old secret too
+    match app.redis_conn().await {
+        Ok(mut redis_conn) => {
+            if let Err(err) = redis_conn
+                .publish::<_, _, ()>(
+                    RPC_SECRET_KEY_INVALIDATION_CHANNEL,
+                    old_secret_key.to_string(),
+                )
+                .await
+            {
+                warn!(?err, "unable to publish rotated key");
+            }
+        }
+        Err(Web3ProxyError::NoDatabaseConfigured) => {}
+        Err(err) => warn!(?err, "unable to publish rotated key"),
+    }
+
+    let response_json = json!({
+        "id": uk.id,
+        "secret_key": new_secret_key,
+    });
+
+    Ok(Json(response_json).into_response())
+}
+
 /// `DELETE /user/keys` -- Use a bearer token to delete an existing key.
 #[debug_handler]
 pub async fn rpc_keys_delete(
@@ -145,11 +291,27 @@ pub struct UserKeyManagement {
     allowed_origins: Option<String>,
     allowed_referers: Option<String>,
     allowed_user_agents: Option<String>,
+    /// method names or prefixes ending in `*`, separated by commas
+    allowed_methods: Option<String>,
+    denied_methods: Option<String>,
     description: Option<String>,
     // TODO: enable log_revert_trace: Option<f64>,
     private_txs: Option<bool>,
 }
 
+/// clean up a comma separated list of methods. None if there are no methods
+fn join_methods(methods: &str) -> Option<String> {
+    let methods = methods
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| x.to_string());
+
+    let methods: String = Itertools::intersperse(methods, ", ".to_string()).collect();
+
+    (!methods.is_empty()).then_some(methods)
+}
+
 /// `POST /user/keys` or `PUT /user/keys` -- Use a bearer token to create or update an existing key.
 #[debug_handler]
 pub async fn rpc_keys_management(
@@ -340,6 +502,14 @@ pub async fn rpc_keys_management(
         }
     }
 
+    if let Some(allowed_methods) = payload.allowed_methods {
+        uk.allowed_methods = sea_orm::Set(join_methods(&allowed_methods));
+    }
+
+    if let Some(denied_methods) = payload.denied_methods {
+        uk.denied_methods = sea_orm::Set(join_methods(&denied_methods));
+    }
+
     let uk = if uk.is_changed() {
         let db_conn = global_db_conn().await?;
 
@@ -352,5 +522,9 @@ pub async fn rpc_keys_management(
 
     let uk = uk.try_into_model()?;
 
+    // authorization checks (including the allowed origins) are cached by secret key. drop them so the changes apply now
+    let secret_key: RpcSecretKey = uk.secret_key.into();
+    app.rpc_secret_key_cache.invalidate(&secret_key).await;
+
     Ok(Json(uk).into_response())
 }
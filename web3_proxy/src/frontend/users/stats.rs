@@ -1,9 +1,10 @@
 //! Handle registration, logins, and managing account data.
 use crate::app::Web3ProxyApp;
-use crate::errors::{Web3ProxyErrorContext, Web3ProxyResponse};
+use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResponse, Web3ProxyResult};
 use crate::globals::global_db_replica_conn;
 use crate::http_params::{
     get_chain_id_from_params, get_page_from_params, get_query_start_from_params,
+    get_query_stop_from_params, get_stats_granularity_from_params,
 };
 use crate::stats::influxdb_queries::query_user_influx_stats;
 use crate::stats::StatType;
@@ -25,6 +26,9 @@ use std::collections::HashSet;
 use std::sync::Arc;
 use tracing::info;
 
+/// `/user/stats` scans every point in the range, so the range is limited
+const MAX_USER_STATS_DAYS: i64 = 90;
+
 /// `GET /user/revert_logs` -- Use a bearer token to get the user's revert logs.
 #[debug_handler]
 pub async fn user_revert_logs_get(
@@ -160,6 +164,75 @@ pub async fn user_mysql_stats_get(
     Ok(Json(response).into_response())
 }
 
+/// `GET /user/stats` -- Use a bearer token to get the usage of the user's keys, grouped into windows.
+///
+/// The range is `?query_start=$x&query_stop=$y` (unix timestamps) and can be at most 90 days.
+/// The windows are `?granularity=hour` (the default) or `?granularity=day`.
+/// View a single chain with `?chain_id=$x`, a single key with `?rpc_key_id=$x` and a single method with `?method=$x`.
+///
+/// This reads the opt-in timeseries, so it includes keys shared with this user as an admin or owner.
+/// Each window has the p50/p90/p99 response millis of its slowest aggregate.
+#[debug_handler]
+pub async fn user_stats_get(
+    Extension(app): Extension<Arc<Web3ProxyApp>>,
+    TypedHeader(Authorization(bearer)): TypedHeader<Authorization<Bearer>>,
+    Query(mut params): Query<HashMap<String, String>>,
+) -> Web3ProxyResponse {
+    let query_start = get_query_start_from_params(&params)?;
+    let query_stop = get_query_stop_from_params(&params)?;
+
+    check_user_stats_range(query_start, query_stop)?;
+
+    let query_window_seconds = get_stats_granularity_from_params(&params)?;
+
+    params.remove("granularity");
+    params.insert(
+        "query_window_seconds".to_string(),
+        query_window_seconds.to_string(),
+    );
+
+    // always the bearer's own stats. subusers use /user/stats/aggregate with a user_id
+    params.remove("user_id");
+
+    let stat_response_type = if params.contains_key("method") {
+        StatType::Detailed
+    } else {
+        StatType::Aggregated
+    };
+
+    query_user_influx_stats(
+        &app,
+        Some(TypedHeader(Authorization(bearer))),
+        &params,
+        stat_response_type,
+    )
+    .await
+}
+
+/// `/user/stats` ranges must be in order and no longer than `MAX_USER_STATS_DAYS`
+fn check_user_stats_range(
+    query_start: chrono::NaiveDateTime,
+    query_stop: chrono::NaiveDateTime,
+) -> Web3ProxyResult<()> {
+    if query_stop <= query_start {
+        return Err(Web3ProxyError::BadRequest(
+            "query_stop must be after query_start".into(),
+        ));
+    }
+
+    if query_stop - query_start > chrono::Duration::days(MAX_USER_STATS_DAYS) {
+        return Err(Web3ProxyError::BadRequest(
+            format!(
+                "the query range can be at most {} days",
+                MAX_USER_STATS_DAYS
+            )
+            .into(),
+        ));
+    }
+
+    Ok(())
+}
+
 /// `GET /user/stats/detailed` -- Use a bearer token to get the user's key stats such as bandwidth used and methods requested.
 ///
 /// If no bearer is provided, detailed stats for all users will be shown.
@@ -179,3 +252,31 @@ pub async fn user_influx_stats_detailed_get(
 
     Ok(response)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_stats_range() {
+        let query_start = chrono::NaiveDateTime::from_timestamp_opt(1_700_000_000, 0).unwrap();
+
+        let ninety_days = query_start + chrono::Duration::days(MAX_USER_STATS_DAYS);
+        assert!(check_user_stats_range(query_start, ninety_days).is_ok());
+
+        let too_long = ninety_days + chrono::Duration::seconds(1);
+        assert!(matches!(
+            check_user_stats_range(query_start, too_long),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+
+        assert!(matches!(
+            check_user_stats_range(query_start, query_start),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+        assert!(matches!(
+            check_user_stats_range(ninety_days, query_start),
+            Err(Web3ProxyError::BadRequest(_))
+        ));
+    }
+}
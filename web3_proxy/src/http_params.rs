@@ -233,6 +233,17 @@ pub fn get_query_window_seconds_from_params(
     )
 }
 
+/// the width of the windows that `/user/stats` groups by. "hour" (the default) or "day"
+pub fn get_stats_granularity_from_params(params: &HashMap<String, String>) -> Web3ProxyResult<u64> {
+    match params.get("granularity").map(|x| x.as_str()) {
+        None | Some("hour") => Ok(60 * 60),
+        Some("day") => Ok(24 * 60 * 60),
+        Some(_) => Err(Web3ProxyError::BadRequest(
+            "Unable to parse granularity. It must be hour or day".into(),
+        )),
+    }
+}
+
 pub fn get_stats_column_from_params(params: &HashMap<String, String>) -> Web3ProxyResult<&str> {
     params.get("query_stats_column").map_or_else(
         || Ok(""),
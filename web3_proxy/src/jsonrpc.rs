@@ -1,9 +1,14 @@
 use crate::app::Web3ProxyApp;
-use crate::errors::Web3ProxyError;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::authorization::{Authorization, RequestMetadata, RequestOrMethod};
 use crate::response_cache::JsonRpcResponseEnum;
-use axum::response::Response;
+use crate::rpcs::request::OpenRequestHandle;
+use axum::body::{Bytes, StreamBody};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
 use derive_more::From;
+use futures::{stream, StreamExt, TryStreamExt};
+use http::header;
 use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -13,6 +18,7 @@ use std::fmt;
 use std::sync::{atomic, Arc};
 use std::time::Duration;
 use tokio::time::sleep;
+use tracing::warn;
 
 pub trait JsonRpcParams = fmt::Debug + serde::Serialize + Send + Sync + 'static;
 pub trait JsonRpcResultData = serde::Serialize + serde::de::DeserializeOwned + fmt::Debug + Send;
@@ -23,6 +29,7 @@ pub trait JsonRpcResultData = serde::Serialize + serde::de::DeserializeOwned + f
 pub struct JsonRpcRequest {
     pub jsonrpc: String,
     /// id could be a stricter type, but many rpcs do things against the spec
+    /// kept as raw json so that the exact id (string, number, or null) is echoed back to the client unchanged
     pub id: Box<RawValue>,
     pub method: String,
     /// TODO: skip serializing if serde_json::Value::Null
@@ -44,7 +51,7 @@ impl JsonRpcId {
             Self::Number(x) => {
                 serde_json::from_value(json!(x)).expect("number id should always work")
             }
-            Self::String(x) => serde_json::from_str(&x).expect("string id should always work"),
+            Self::String(x) => to_raw_value(&x).expect("string id should always work"),
         }
     }
 }
@@ -81,27 +88,61 @@ impl fmt::Debug for JsonRpcRequest {
     }
 }
 
+/// One request in a batch. Entries that are not valid requests are kept so that they can get their own error response
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum JsonRpcBatchEntry {
+    Request(JsonRpcRequest),
+    Invalid(serde_json::Value),
+}
+
+impl JsonRpcBatchEntry {
+    /// the id to send back with this entry's response. invalid entries without an id get null
+    pub fn id(&self) -> Box<RawValue> {
+        match self {
+            Self::Request(x) => x.id.clone(),
+            Self::Invalid(x) => x
+                .get("id")
+                .and_then(|x| to_raw_value(x).ok())
+                .unwrap_or_else(|| JsonRpcId::None.to_raw_value()),
+        }
+    }
+
+    /// the request, if it parsed and has a valid method
+    pub fn valid_request(&self) -> Option<&JsonRpcRequest> {
+        match self {
+            Self::Request(x) if x.validate_method() => Some(x),
+            _ => None,
+        }
+    }
+}
+
 /// Requests can come in multiple formats
 #[derive(Debug, From, Serialize)]
 pub enum JsonRpcRequestEnum {
-    Batch(Vec<JsonRpcRequest>),
+    Batch(Vec<JsonRpcBatchEntry>),
     Single(JsonRpcRequest),
 }
 
 impl JsonRpcRequestEnum {
     pub fn first_id(&self) -> Option<Box<RawValue>> {
         match self {
-            Self::Batch(x) => x.first().map(|x| x.id.clone()),
+            Self::Batch(x) => x.first().map(|x| x.id()),
             Self::Single(x) => Some(x.id.clone()),
         }
     }
 
     /// returns the id of the first invalid result (if any). None is good
+    /// a batch is only invalid if none of its entries are valid. the other invalid entries get their own errors
     pub fn validate(&self) -> Option<Box<RawValue>> {
         match self {
-            Self::Batch(x) => x
-                .iter()
-                .find_map(|x| (!x.validate_method()).then_some(x.id.clone())),
+            Self::Batch(x) => {
+                if x.iter().any(|x| x.valid_request().is_some()) {
+                    None
+                } else {
+                    x.first().map(|x| x.id())
+                }
+            }
             Self::Single(x) => {
                 if x.validate_method() {
                     None
@@ -181,11 +222,18 @@ impl<'de> Deserialize<'de> for JsonRpcRequestEnum {
                 V: SeqAccess<'de>,
             {
                 // TODO: what size should we use as the default?
-                let mut batch: Vec<JsonRpcRequest> =
+                let mut batch: Vec<JsonRpcBatchEntry> =
                     Vec::with_capacity(seq.size_hint().unwrap_or(10));
 
-                while let Ok(Some(s)) = seq.next_element::<JsonRpcRequest>() {
-                    batch.push(s);
+                // one bad entry shouldn't fail the whole batch. parse each entry on its own
+                while let Some(x) = seq.next_element::<serde_json::Value>()? {
+                    // deserialize from a reference so the entry isn't copied just to keep it for an error
+                    let x = match JsonRpcRequest::deserialize(&x) {
+                        Ok(x) => JsonRpcBatchEntry::Request(x),
+                        Err(_) => JsonRpcBatchEntry::Invalid(x),
+                    };
+
+                    batch.push(x);
                 }
 
                 Ok(JsonRpcRequestEnum::Batch(batch))
@@ -387,6 +435,109 @@ pub enum JsonRpcForwardedResponseEnum {
     Batch(Vec<JsonRpcForwardedResponse>),
 }
 
+/// A response that is still being read from a backend rpc.
+/// The body is passed through to the client as it arrives instead of being read into memory first.
+pub struct StreamResponse {
+    /// the start of the body. it was read to check if the response was small enough to parse
+    pub(crate) buffer: Bytes,
+    pub(crate) response: reqwest::Response,
+    pub(crate) request_metadata: Arc<RequestMetadata>,
+    /// the rpc counts this request as active until the body is done
+    pub(crate) handle: OpenRequestHandle,
+}
+
+impl StreamResponse {
+    pub fn buffered_bytes(&self) -> u64 {
+        self.buffer.len() as u64
+    }
+}
+
+impl fmt::Debug for StreamResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamResponse")
+            .field("buffered_bytes", &self.buffer.len())
+            .field("rpc", &self.handle.connection_name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl IntoResponse for StreamResponse {
+    fn into_response(self) -> Response {
+        let Self {
+            buffer,
+            response,
+            request_metadata,
+            handle,
+        } = self;
+
+        let rpc = handle.clone_connection();
+
+        // the buffered bytes were already counted. the rest are counted as they are sent
+        let rest = stream::unfold(Some((response, handle)), |state| async move {
+            let (mut response, handle) = state?;
+
+            match response.chunk().await {
+                Ok(Some(chunk)) => Some((Ok(chunk), Some((response, handle)))),
+                Ok(None) => None,
+                Err(err) => {
+                    // the status and the start of the body are already sent. all we can do is cut the body short
+                    warn!(?err, rpc=%handle.connection_name(), "stream from backend rpc failed");
+                    Some((Err(err), None))
+                }
+            }
+        })
+        .inspect_ok(move |chunk| {
+            request_metadata
+                .response_bytes
+                .fetch_add(chunk.len() as u64, atomic::Ordering::AcqRel);
+
+            rpc.lifetime_stats()
+                .record_response_bytes(chunk.len() as u64);
+        });
+
+        let body = stream::once(async move { Ok::<_, reqwest::Error>(buffer) }).chain(rest);
+
+        (
+            [(header::CONTENT_TYPE, "application/json")],
+            StreamBody::new(body),
+        )
+            .into_response()
+    }
+}
+
+/// A response that was small enough to read into memory or one that is still streaming from a backend rpc
+#[derive(Debug)]
+pub enum StreamOrBuffered<T> {
+    Buffered(T),
+    Stream(StreamResponse),
+}
+
+impl<T> StreamOrBuffered<T> {
+    pub fn map<U>(self, f: impl FnOnce(T) -> U) -> StreamOrBuffered<U> {
+        match self {
+            Self::Buffered(x) => StreamOrBuffered::Buffered(f(x)),
+            Self::Stream(x) => StreamOrBuffered::Stream(x),
+        }
+    }
+
+    /// for callers that did not allow streaming
+    pub fn buffered(self) -> Web3ProxyResult<T> {
+        match self {
+            Self::Buffered(x) => Ok(x),
+            Self::Stream(_) => Err(anyhow::anyhow!("unexpected stream. this is a bug").into()),
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for StreamOrBuffered<T> {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Buffered(x) => Json(x).into_response(),
+            Self::Stream(x) => x.into_response(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -432,4 +583,105 @@ mod tests {
 
         assert!(matches!(output, JsonRpcRequestEnum::Batch(_)));
     }
+
+    #[test]
+    fn this_deserialize_batch_with_invalid_entries() {
+        let input = r#"[{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":1},{"jsonrpc":"2.0","params":[],"id":2},{"jsonrpc":"2.0","method":"eth_chainId","id":3},"oops",{"jsonrpc":"2.0","method":"eth blockNumber","id":5}]"#;
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        let JsonRpcRequestEnum::Batch(batch) = &output else {
+            panic!("expected a batch");
+        };
+
+        assert_eq!(batch.len(), 5);
+
+        let ids: Vec<_> = batch.iter().map(|x| x.id().to_string()).collect();
+        assert_eq!(ids, ["1", "2", "3", "null", "5"]);
+
+        let valid: Vec<_> = batch.iter().map(|x| x.valid_request().is_some()).collect();
+        assert_eq!(valid, [true, false, true, false, false]);
+
+        // the valid entries keep the batch out of the tarpit
+        assert!(output.validate().is_none());
+
+        let output: JsonRpcRequestEnum = serde_json::from_str(r#"[{"id":1},"oops"]"#).unwrap();
+
+        assert_eq!(output.validate().unwrap().to_string(), "1");
+    }
+
+    /// the response must have exactly the same id as the request
+    fn assert_id_echoed(input: &str, expected: &str) {
+        let request: JsonRpcRequest = serde_json::from_str(input).unwrap();
+
+        let response = JsonRpcForwardedResponse::from_value(json!("0x1"), request.id);
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            format!(r#"{{"jsonrpc":"2.0","id":{},"result":"0x1"}}"#, expected)
+        );
+
+        // the enum has its own deserializer
+        let request: JsonRpcRequestEnum = serde_json::from_str(input).unwrap();
+
+        let response =
+            JsonRpcForwardedResponse::from_value(json!("0x1"), request.first_id().unwrap());
+
+        assert_eq!(
+            serde_json::to_string(&response).unwrap(),
+            format!(r#"{{"jsonrpc":"2.0","id":{},"result":"0x1"}}"#, expected)
+        );
+    }
+
+    #[test]
+    fn echo_string_id() {
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":"abc"}"#,
+            r#""abc""#,
+        );
+
+        // a string that looks like a number is still a string
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":"1"}"#,
+            r#""1""#,
+        );
+    }
+
+    #[test]
+    fn echo_integer_id() {
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":1}"#,
+            "1",
+        );
+
+        // too big for a u64. this would be mangled if it went through a serde_json::Value
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":18446744073709551616}"#,
+            "18446744073709551616",
+        );
+
+        // too small for an i64
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":-9223372036854775809}"#,
+            "-9223372036854775809",
+        );
+    }
+
+    #[test]
+    fn echo_null_id() {
+        assert_id_echoed(
+            r#"{"jsonrpc":"2.0","method":"eth_blockNumber","params":[],"id":null}"#,
+            "null",
+        );
+    }
+
+    #[test]
+    fn internal_ids() {
+        assert_eq!(JsonRpcId::None.to_raw_value().get(), "null");
+        assert_eq!(JsonRpcId::Number(1).to_raw_value().get(), "1");
+        assert_eq!(
+            JsonRpcId::String("abc".to_string()).to_raw_value().get(),
+            r#""abc""#
+        );
+    }
 }
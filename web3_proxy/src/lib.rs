@@ -3,25 +3,31 @@
 #![feature(trait_alias)]
 #![forbid(unsafe_code)]
 
+pub mod access_log;
 pub mod admin_queries;
 pub mod app;
 pub mod balance;
 pub mod block_number;
+pub mod bounded_ring;
 pub mod caches;
 pub mod compute_units;
 pub mod config;
+pub mod dead_letters;
 pub mod errors;
 pub mod frontend;
 pub mod globals;
 pub mod http_params;
 pub mod jsonrpc;
+pub mod otel;
 pub mod pagerduty;
+pub mod param_metrics;
 pub mod premium;
 pub mod prometheus;
 pub mod referral_code;
 pub mod relational_db;
 pub mod response_cache;
 pub mod rpcs;
+pub mod shutdown;
 pub mod stats;
 pub mod sub_commands;
 pub mod user_token;
@@ -0,0 +1,172 @@
+//! Optionally export the existing request spans to an OpenTelemetry collector (Jaeger, Tempo, etc.).
+use crate::rpcs::one::Web3Rpc;
+use itertools::Itertools;
+use opentelemetry::sdk::trace::{self as sdktrace, Sampler, Tracer};
+use opentelemetry::sdk::Resource;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::Span;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// only 1 out of this many traces is exported. child spans follow their parent's decision
+pub fn sampler(sample_rate: u32) -> Sampler {
+    let ratio = 1.0 / sample_rate.max(1) as f64;
+
+    Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(ratio)))
+}
+
+pub fn trace_config(sample_rate: u32) -> sdktrace::Config {
+    sdktrace::config()
+        .with_sampler(sampler(sample_rate))
+        .with_resource(Resource::new([KeyValue::new("service.name", "web3_proxy")]))
+}
+
+/// spans are exported in batches by a background task, so this must be called inside a tokio runtime
+pub fn otlp_tracer(endpoint: &str, sample_rate: u32) -> anyhow::Result<Tracer> {
+    let exporter = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_endpoint(endpoint);
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .with_trace_config(trace_config(sample_rate))
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    Ok(tracer)
+}
+
+pub fn layer<S>(tracer: Tracer) -> impl Layer<S>
+where
+    S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+{
+    tracing_opentelemetry::layer().with_tracer(tracer)
+}
+
+/// fill in the fields that the frontend's "request" span leaves empty. they are only known once the request is done
+pub fn record_request(span: &Span, method: &str, rpcs: &[Arc<Web3Rpc>], elapsed: Duration) {
+    span.record("rpc_method", method);
+    span.record(
+        "upstream",
+        rpcs.iter().map(|x| x.name.as_str()).join(",").as_str(),
+    );
+    span.record("latency_ms", elapsed.as_millis() as u64);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record_request, trace_config};
+    use crate::rpcs::one::Web3Rpc;
+    use futures::future::BoxFuture;
+    use opentelemetry::sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry::sdk::trace::TracerProvider;
+    use opentelemetry::trace::TracerProvider as _;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tracing::field::Empty;
+    use tracing_subscriber::prelude::*;
+
+    /// keeps exported spans in memory instead of sending them to a collector
+    #[derive(Clone, Debug, Default)]
+    struct RecordingExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for RecordingExporter {
+        fn export(&mut self, batch: Vec<SpanData>) -> BoxFuture<'static, ExportResult> {
+            self.0.lock().extend(batch);
+
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    /// run a request span through a subscriber that exports with the given sample rate
+    fn export_requests(sample_rate: u32, num_requests: usize) -> Vec<SpanData> {
+        let exporter = RecordingExporter::default();
+
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .with_config(trace_config(sample_rate))
+            .build();
+
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        // most of the rpc's fields are private to the rpcs module
+        let mut rpc = Web3Rpc::default();
+        rpc.name = "llama".to_string();
+        let rpc = Arc::new(rpc);
+
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..num_requests {
+                // the same fields as the frontend's span
+                let span = tracing::error_span!(
+                    "request",
+                    id = "01H8Z5W9Q3",
+                    rpc_method = Empty,
+                    upstream = Empty,
+                    latency_ms = Empty,
+                );
+
+                record_request(
+                    &span,
+                    "eth_blockNumber",
+                    &[rpc.clone()],
+                    Duration::from_millis(42),
+                );
+            }
+        });
+
+        // the simple exporter sends from a background thread
+        let start = Instant::now();
+        while exporter.0.lock().len() < num_requests && start.elapsed() < Duration::from_secs(1) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        let spans = exporter.0.lock().clone();
+
+        drop(provider);
+
+        spans
+    }
+
+    #[test]
+    fn sampled_request_has_attributes() {
+        let spans = export_requests(1, 1);
+
+        assert_eq!(spans.len(), 1);
+
+        let span = &spans[0];
+
+        assert_eq!(span.name, "request");
+
+        let attributes: Vec<_> = span
+            .attributes
+            .iter()
+            .map(|(k, v)| (k.as_str().to_string(), v.to_string()))
+            .collect();
+
+        for expected in [
+            ("id", "01H8Z5W9Q3"),
+            ("rpc_method", "eth_blockNumber"),
+            ("upstream", "llama"),
+            ("latency_ms", "42"),
+        ] {
+            assert!(
+                attributes.contains(&(expected.0.to_string(), expected.1.to_string())),
+                "{:?} missing from {:?}",
+                expected,
+                attributes
+            );
+        }
+    }
+
+    #[test]
+    fn unsampled_requests_are_not_exported() {
+        let spans = export_requests(u32::MAX, 10);
+
+        assert!(spans.is_empty());
+    }
+}
@@ -0,0 +1,224 @@
+//! Sampled counts of a single param (like the `to` of an `eth_call`) so that operators can find hot contracts.
+use counter::Counter;
+use hashbrown::HashMap;
+use nanorand::Rng;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// don't let high cardinality params use unbounded memory
+const MAX_VALUES_PER_METHOD: usize = 10_000;
+
+/// every count is halved this often so that values that stopped being hot make room for new ones
+const SAMPLES_PER_HALVING: usize = 100_000;
+
+pub struct ParamMetrics {
+    /// method -> json pointer to the param that should be counted. "/0/to" is the contract for eth_call
+    fields: HashMap<String, String>,
+    /// only 1 out of this many matching requests is counted
+    sample_rate: u32,
+    /// the most values that are counted for each method
+    max_values: usize,
+    /// how many samples of a method are taken between halvings of its counts
+    samples_per_halving: usize,
+    /// method -> counted param values
+    counts: Mutex<HashMap<String, MethodCounts>>,
+}
+
+#[derive(Default)]
+struct MethodCounts {
+    /// param value -> count
+    counter: Counter<String>,
+    /// samples since the counts were last halved
+    samples: usize,
+}
+
+/// the most common values for each method. this is what gets exported
+#[derive(Debug, Default, Serialize)]
+pub struct TopParams(pub HashMap<String, HashMap<String, usize>>);
+
+impl ParamMetrics {
+    /// returns None if no fields are configured
+    pub fn new(fields: HashMap<String, String>, sample_rate: u32) -> Option<Self> {
+        if fields.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            fields,
+            sample_rate: sample_rate.max(1),
+            max_values: MAX_VALUES_PER_METHOD,
+            samples_per_halving: SAMPLES_PER_HALVING,
+            counts: Default::default(),
+        })
+    }
+
+    /// count the configured param for this method. most requests return before touching the params
+    pub fn sample(&self, method: &str, params: &serde_json::Value) {
+        let pointer = match self.fields.get(method) {
+            None => return,
+            Some(x) => x,
+        };
+
+        if self.sample_rate > 1 && nanorand::tls_rng().generate_range(0..self.sample_rate) != 0 {
+            return;
+        }
+
+        let value = match params.pointer(pointer) {
+            Some(serde_json::Value::String(x)) => x.to_lowercase(),
+            Some(serde_json::Value::Null) | None => return,
+            Some(x) => x.to_string(),
+        };
+
+        let mut counts = self.counts.lock();
+
+        let method_counts = counts.entry_ref(method).or_default();
+
+        method_counts.samples += 1;
+
+        // this walks every value, so it only happens once per `samples_per_halving` samples instead of whenever the counter is full.
+        // values that were only seen once are forgotten. the hot ones will survive
+        if method_counts.samples >= self.samples_per_halving {
+            method_counts.samples = 0;
+
+            method_counts.counter.retain(|_, count| {
+                *count /= 2;
+                *count > 0
+            });
+        }
+
+        if method_counts.counter.len() >= self.max_values
+            && !method_counts.counter.contains_key(&value)
+        {
+            // full. a new value has to wait for the next halving to make room
+            return;
+        }
+
+        method_counts.counter[&value] += 1;
+    }
+
+    /// the `k` most common values for each method
+    pub fn top_k(&self, k: usize) -> TopParams {
+        let counts = self.counts.lock();
+
+        let top = counts
+            .iter()
+            .map(|(method, method_counts)| {
+                let values = method_counts
+                    .counter
+                    .k_most_common_ordered(k)
+                    .into_iter()
+                    .collect();
+
+                (method.clone(), values)
+            })
+            .collect();
+
+        TopParams(top)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ParamMetrics;
+    use hashbrown::HashMap;
+    use serde_json::json;
+
+    #[test]
+    fn top_contract_by_call_volume() {
+        let fields = HashMap::from([("eth_call".to_string(), "/0/to".to_string())]);
+
+        let param_metrics = ParamMetrics::new(fields, 1).unwrap();
+
+        let hot = "0x00000000000000000000000000000000000000AA";
+        let cold = "0x00000000000000000000000000000000000000bb";
+
+        for _ in 0..5 {
+            param_metrics.sample("eth_call", &json!([{"to": hot, "data": "0x"}, "latest"]));
+        }
+        for _ in 0..2 {
+            param_metrics.sample("eth_call", &json!([{"to": cold, "data": "0x"}, "latest"]));
+        }
+
+        // other methods are never counted
+        param_metrics.sample("eth_estimateGas", &json!([{"to": cold}]));
+
+        let top = param_metrics.top_k(1).0;
+
+        assert_eq!(top.len(), 1);
+
+        let eth_call = top.get("eth_call").unwrap();
+
+        assert_eq!(eth_call.len(), 1);
+        assert_eq!(eth_call.get(&hot.to_lowercase()), Some(&5));
+    }
+
+    #[test]
+    fn values_are_capped() {
+        let fields = HashMap::from([("eth_call".to_string(), "/0/to".to_string())]);
+
+        let param_metrics = ParamMetrics {
+            max_values: 3,
+            samples_per_halving: 1000,
+            ..ParamMetrics::new(fields, 1).unwrap()
+        };
+
+        let call = |to: &str| json!([{ "to": to }, "latest"]);
+
+        // the counts aren't halved during this test, so nothing makes room
+        for to in ["0xa", "0xb", "0xc"] {
+            param_metrics.sample("eth_call", &call(to));
+            param_metrics.sample("eth_call", &call(to));
+        }
+
+        for to in ["0xd", "0xe", "0xf"] {
+            param_metrics.sample("eth_call", &call(to));
+        }
+
+        let top = param_metrics.top_k(10).0;
+        let eth_call = top.get("eth_call").unwrap();
+
+        assert_eq!(eth_call.len(), 3);
+        assert!(!eth_call.contains_key("0xd"));
+
+        // values that are already counted keep counting
+        param_metrics.sample("eth_call", &call("0xa"));
+
+        let top = param_metrics.top_k(10).0;
+
+        assert_eq!(top.get("eth_call").unwrap().get("0xa"), Some(&3));
+    }
+
+    #[test]
+    fn halving_makes_room() {
+        let fields = HashMap::from([("eth_call".to_string(), "/0/to".to_string())]);
+
+        let param_metrics = ParamMetrics {
+            max_values: 2,
+            samples_per_halving: 6,
+            ..ParamMetrics::new(fields, 1).unwrap()
+        };
+
+        let call = |to: &str| json!([{ "to": to }, "latest"]);
+
+        for _ in 0..4 {
+            param_metrics.sample("eth_call", &call("0xa"));
+        }
+        param_metrics.sample("eth_call", &call("0xb"));
+
+        // the 6th sample halves the counts first. 0xb drops to 0 and is forgotten, so 0xc fits
+        param_metrics.sample("eth_call", &call("0xc"));
+
+        let top = param_metrics.top_k(10).0;
+        let eth_call = top.get("eth_call").unwrap();
+
+        assert_eq!(eth_call.len(), 2);
+        assert_eq!(eth_call.get("0xa"), Some(&2));
+        assert_eq!(eth_call.get("0xc"), Some(&1));
+        assert!(!eth_call.contains_key("0xb"));
+    }
+
+    #[test]
+    fn nothing_configured() {
+        assert!(ParamMetrics::new(HashMap::new(), 1).is_none());
+    }
+}
@@ -19,6 +19,7 @@ pub async fn serve(
     // routes should be ordered most to least common
     let router = Router::new()
         .route("/", get(root))
+        .route("/metrics", get(root))
         .layer(Extension(app.clone()));
 
     // note: the port here might be 0
@@ -1,14 +1,25 @@
-use crate::{block_number::BlockNumAndHash, errors::Web3ProxyError, jsonrpc::JsonRpcErrorData};
+use crate::{
+    block_number::BlockNumAndHash,
+    errors::Web3ProxyError,
+    jsonrpc::{JsonRpcErrorData, StreamOrBuffered},
+    rpcs::blockchain::{Reorg, ReorgObserver},
+};
+use async_trait::async_trait;
 use derive_more::From;
 use ethers::{
     providers::{HttpClientError, JsonRpcError, ProviderError, WsClientError},
-    types::U64,
+    types::{H256, U64},
 };
 use hashbrown::hash_map::DefaultHashBuilder;
-use moka::future::Cache;
+use hashbrown::HashSet;
+use moka::future::{Cache, CacheBuilder};
+use parking_lot::Mutex;
+use serde::Serialize;
 use serde_json::value::RawValue;
 use std::{
+    future::Future,
     hash::{BuildHasher, Hash, Hasher},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
     sync::Arc,
 };
 
@@ -34,6 +45,10 @@ impl JsonRpcQueryCacheKey {
     pub fn cache_errors(&self) -> bool {
         self.cache_errors
     }
+    /// the blocks that the response depends on
+    pub fn blocks(&self) -> impl Iterator<Item = &BlockNumAndHash> {
+        self.from_block.iter().chain(self.to_block.iter())
+    }
 }
 
 impl PartialEq for JsonRpcQueryCacheKey {
@@ -86,6 +101,290 @@ impl JsonRpcQueryCacheKey {
 
 pub type JsonRpcResponseCache = Cache<u64, JsonRpcResponseEnum<Arc<RawValue>>>;
 
+/// The response cache keys that depend on blocks that are not finalized yet.
+/// When a reorg replaces one of those blocks, its responses are dropped from the cache.
+/// The keys include the block hash so they would never be served for the new chain, but they would waste space until they expire
+pub struct JsonRpcResponseBlockIndex {
+    cache: JsonRpcResponseCache,
+    /// a set since the same response is often fetched again for the same block
+    keys_by_block: Cache<H256, Arc<Mutex<HashSet<u64>>>>,
+    /// eth_call responses for "latest". nothing asks for them once a new head arrives
+    head_keys: Mutex<(H256, Vec<u64>)>,
+}
+
+impl JsonRpcResponseBlockIndex {
+    pub fn new(cache: JsonRpcResponseCache) -> Self {
+        // blocks are finalized well before this expires
+        let keys_by_block = CacheBuilder::new(10_000)
+            .name("jsonrpc_response_block_index")
+            .time_to_live(std::time::Duration::from_secs(3600))
+            .build();
+
+        Self {
+            cache,
+            keys_by_block,
+            head_keys: Default::default(),
+        }
+    }
+
+    /// drop the responses that were saved for the previous head block
+    pub async fn on_new_head(&self, head_block_hash: &H256) {
+        let old_keys = {
+            let mut head_keys = self.head_keys.lock();
+
+            if head_keys.0 == *head_block_hash {
+                return;
+            }
+
+            head_keys.0 = *head_block_hash;
+
+            std::mem::take(&mut head_keys.1)
+        };
+
+        for key in old_keys {
+            self.cache.invalidate(&key).await;
+        }
+    }
+
+    /// remember a response that was fetched for the head block.
+    /// if a new head arrived while it was being fetched, the response is dropped right away
+    pub async fn insert_head(&self, key: &JsonRpcQueryCacheKey, head_block_hash: &H256) {
+        if key.blocks().any(|x| x.hash() != head_block_hash) {
+            return;
+        }
+
+        {
+            let mut head_keys = self.head_keys.lock();
+
+            if head_keys.0 == *head_block_hash {
+                head_keys.1.push(key.hash());
+                return;
+            }
+        }
+
+        self.cache.invalidate(&key.hash()).await;
+    }
+
+    /// remember which blocks a fetched response depends on. finalized blocks are skipped since they can't be reorged
+    pub async fn insert(
+        &self,
+        key: &JsonRpcQueryCacheKey,
+        head_block_num: &U64,
+        finalized_block_num: Option<&U64>,
+    ) {
+        for block in key.blocks() {
+            if block.is_finalized(head_block_num, finalized_block_num) {
+                continue;
+            }
+
+            let keys = self
+                .keys_by_block
+                .get_with_by_ref(block.hash(), async { Default::default() })
+                .await;
+
+            keys.lock().insert(key.hash());
+        }
+    }
+}
+
+#[async_trait]
+impl ReorgObserver for JsonRpcResponseBlockIndex {
+    async fn on_reorg(&self, reorg: &Reorg) {
+        for hash in reorg.replaced_hashes.iter() {
+            let keys = match self.keys_by_block.get(hash) {
+                Some(x) => x,
+                None => continue,
+            };
+
+            self.keys_by_block.invalidate(hash).await;
+
+            let keys = std::mem::take(&mut *keys.lock());
+
+            for key in keys {
+                self.cache.invalidate(&key).await;
+            }
+        }
+    }
+}
+
+/// a request that was collapsed into failed requests this many times returns the last error instead of trying again
+const MAX_COLLAPSED_ERRORS: usize = 3;
+
+/// Track how much work the response cache saves.
+/// A "collapsed" request found an identical request already in flight and waited for its response instead of sending its own.
+#[derive(Debug, Default)]
+pub struct JsonRpcResponseCacheStats {
+    hits: AtomicU64,
+    collapsed: AtomicU64,
+    misses: AtomicU64,
+    bypassed: AtomicU64,
+}
+
+/// How a single request used the response cache
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheStatus {
+    Hit,
+    /// waited for an identical request that was already in flight
+    Collapsed,
+    Miss,
+    /// the client asked for a fresh response. the cache was refreshed with it
+    Bypassed,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Serialize)]
+pub struct JsonRpcResponseCacheCounts {
+    pub hits: u64,
+    pub collapsed: u64,
+    pub misses: u64,
+    pub bypassed: u64,
+}
+
+impl JsonRpcResponseCacheStats {
+    /// `cache.try_get_with`, but counting if the response was already cached, collapsed into another request, or fetched
+    pub async fn try_get_with<F, E>(
+        &self,
+        cache: &JsonRpcResponseCache,
+        key: u64,
+        init: F,
+    ) -> Result<JsonRpcResponseEnum<Arc<RawValue>>, Arc<E>>
+    where
+        F: Future<Output = Result<JsonRpcResponseEnum<Arc<RawValue>>, E>>,
+        E: Send + Sync + 'static,
+    {
+        self.try_get_with_status(cache, key, init).await.0
+    }
+
+    /// like `try_get_with`, but also returns how this request used the cache
+    pub async fn try_get_with_status<F, E>(
+        &self,
+        cache: &JsonRpcResponseCache,
+        key: u64,
+        init: F,
+    ) -> (
+        Result<JsonRpcResponseEnum<Arc<RawValue>>, Arc<E>>,
+        CacheStatus,
+    )
+    where
+        F: Future<Output = Result<JsonRpcResponseEnum<Arc<RawValue>>, E>>,
+        E: Send + Sync + 'static,
+    {
+        if let Some(x) = cache.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return (Ok(x), CacheStatus::Hit);
+        }
+
+        // pinned here so that it can still be used if the request we waited on failed
+        let mut init = std::pin::pin!(init);
+
+        let mut leader_errors = 0;
+
+        loop {
+            let fetched = AtomicBool::new(false);
+
+            let x = cache
+                .try_get_with(key, async {
+                    fetched.store(true, Ordering::Relaxed);
+                    init.as_mut().await
+                })
+                .await;
+
+            if fetched.load(Ordering::Relaxed) {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+
+                return (x, CacheStatus::Miss);
+            }
+
+            if x.is_ok() {
+                self.collapsed.fetch_add(1, Ordering::Relaxed);
+
+                return (x, CacheStatus::Collapsed);
+            }
+
+            // errors are not cached. one failed request should not fail everyone that was waiting on it.
+            // going through the cache again makes one of the waiters the new leader instead of all of them fetching at once
+            leader_errors += 1;
+
+            if leader_errors >= MAX_COLLAPSED_ERRORS {
+                self.collapsed.fetch_add(1, Ordering::Relaxed);
+
+                return (x, CacheStatus::Collapsed);
+            }
+        }
+    }
+
+    /// skip the cache and any in-flight identical requests, but still save a successful response for everyone else
+    pub async fn refresh_with<F, E>(
+        &self,
+        cache: &JsonRpcResponseCache,
+        key: u64,
+        init: F,
+    ) -> (
+        Result<JsonRpcResponseEnum<Arc<RawValue>>, Arc<E>>,
+        CacheStatus,
+    )
+    where
+        F: Future<Output = Result<JsonRpcResponseEnum<Arc<RawValue>>, E>>,
+        E: Send + Sync + 'static,
+    {
+        self.bypassed.fetch_add(1, Ordering::Relaxed);
+
+        let x = match init.await {
+            Ok(x) => {
+                cache.insert(key, x.clone()).await;
+                Ok(x)
+            }
+            Err(err) => Err(Arc::new(err)),
+        };
+
+        (x, CacheStatus::Bypassed)
+    }
+
+    /// like `try_get_with_status`, but only buffered responses are saved.
+    /// identical requests are not collapsed because a stream can only be sent to one client
+    pub async fn get_or_stream_with<F, E>(
+        &self,
+        cache: &JsonRpcResponseCache,
+        key: u64,
+        bypass: bool,
+        init: F,
+    ) -> (
+        Result<StreamOrBuffered<JsonRpcResponseEnum<Arc<RawValue>>>, E>,
+        CacheStatus,
+    )
+    where
+        F: Future<Output = Result<StreamOrBuffered<JsonRpcResponseEnum<Arc<RawValue>>>, E>>,
+    {
+        let cache_status = if bypass {
+            self.bypassed.fetch_add(1, Ordering::Relaxed);
+            CacheStatus::Bypassed
+        } else if let Some(x) = cache.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return (Ok(StreamOrBuffered::Buffered(x)), CacheStatus::Hit);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            CacheStatus::Miss
+        };
+
+        let x = init.await;
+
+        if let Ok(StreamOrBuffered::Buffered(x)) = &x {
+            cache.insert(key, x.clone()).await;
+        }
+
+        (x, cache_status)
+    }
+
+    pub fn counts(&self) -> JsonRpcResponseCacheCounts {
+        JsonRpcResponseCacheCounts {
+            hits: self.hits.load(Ordering::Relaxed),
+            collapsed: self.collapsed.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypassed: self.bypassed.load(Ordering::Relaxed),
+        }
+    }
+}
+
 /// TODO: we might need one that holds RawValue and one that holds serde_json::Value
 #[derive(Clone, Debug)]
 pub enum JsonRpcResponseEnum<R> {
@@ -109,6 +408,44 @@ impl<R> JsonRpcResponseEnum<R> {
     }
 }
 
+impl JsonRpcResponseEnum<Arc<RawValue>> {
+    /// remove the fields at these json pointers from a successful result. errors are returned unchanged
+    pub fn strip_fields(self, pointers: &[String]) -> Self {
+        let value = match self {
+            Self::Result { ref value, .. } => value,
+            Self::RpcError { .. } => return self,
+        };
+
+        let mut value: serde_json::Value = match serde_json::from_str(value.get()) {
+            Ok(x) => x,
+            Err(_) => return self,
+        };
+
+        for pointer in pointers {
+            remove_pointer(&mut value, pointer);
+        }
+
+        value.into()
+    }
+}
+
+/// like `serde_json::Value::pointer_mut`, but removes the value
+fn remove_pointer(value: &mut serde_json::Value, pointer: &str) -> Option<serde_json::Value> {
+    let (parent, key) = pointer.rsplit_once('/')?;
+
+    let key = key.replace("~1", "/").replace("~0", "~");
+
+    match value.pointer_mut(parent)? {
+        serde_json::Value::Object(x) => x.remove(&key),
+        serde_json::Value::Array(x) => {
+            let index: usize = key.parse().ok()?;
+
+            (index < x.len()).then(|| x.remove(index))
+        }
+        _ => None,
+    }
+}
+
 impl From<serde_json::Value> for JsonRpcResponseEnum<Arc<RawValue>> {
     fn from(value: serde_json::Value) -> Self {
         let value = RawValue::from_string(value.to_string()).unwrap();
@@ -267,11 +604,199 @@ impl JsonRpcResponseWeigher {
 #[cfg(test)]
 mod tests {
     use super::JsonRpcResponseEnum;
-    use crate::response_cache::JsonRpcResponseWeigher;
+    use crate::block_number::BlockNumAndHash;
+    use crate::response_cache::{
+        CacheStatus, JsonRpcQueryCacheKey, JsonRpcResponseBlockIndex, JsonRpcResponseCache,
+        JsonRpcResponseCacheCounts, JsonRpcResponseCacheStats, JsonRpcResponseWeigher,
+    };
+    use crate::rpcs::blockchain::{Reorg, ReorgObserver};
+    use ethers::types::{H256, U64};
+    use futures::future::join_all;
     use moka::future::{Cache, CacheBuilder, ConcurrentCacheExt};
+    use serde_json::json;
     use serde_json::value::RawValue;
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use std::{sync::Arc, time::Duration};
 
+    #[tokio::test]
+    async fn test_strip_fields() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let pointers = vec!["/node".to_string(), "/logs/0/time".to_string()];
+
+        let from_a = JsonRpcResponseEnum::from(
+            json!({"gas": 1, "node": "a", "logs": [{"op": "STOP", "time": 5}]}),
+        )
+        .strip_fields(&pointers);
+
+        let from_b = JsonRpcResponseEnum::from(
+            json!({"gas": 1, "node": "b", "logs": [{"op": "STOP", "time": 9}]}),
+        )
+        .strip_fields(&pointers);
+
+        let expected = r#"{"gas":1,"logs":[{"op":"STOP"}]}"#;
+
+        let served = |x: &JsonRpcResponseEnum<Arc<RawValue>>| match x {
+            JsonRpcResponseEnum::Result { value, num_bytes } => {
+                assert_eq!(*num_bytes as usize, value.get().len());
+                value.get().to_string()
+            }
+            JsonRpcResponseEnum::RpcError { .. } => panic!("expected a result"),
+        };
+
+        assert_eq!(served(&from_a), expected);
+        assert_eq!(served(&from_b), expected);
+
+        cache.insert(1, from_a).await;
+
+        assert_eq!(served(&cache.get(&1).unwrap()), expected);
+
+        // pointers that do not match anything are ignored
+        let untouched = JsonRpcResponseEnum::from(json!("0x1")).strip_fields(&pointers);
+
+        assert_eq!(served(&untouched), r#""0x1""#);
+    }
+
+    #[tokio::test]
+    async fn test_collapsed_requests() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let stats = JsonRpcResponseCacheStats::default();
+
+        let fetches = AtomicUsize::new(0);
+
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::Relaxed);
+
+            // slow enough that the other requests arrive while this is in flight
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            Ok::<_, ()>(JsonRpcResponseEnum::from(json!(1)))
+        };
+
+        let responses = join_all((0..10).map(|_| stats.try_get_with(&cache, 1, fetch()))).await;
+
+        assert!(responses.iter().all(|x| x.is_ok()));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            stats.counts(),
+            JsonRpcResponseCacheCounts {
+                hits: 0,
+                collapsed: 9,
+                misses: 1,
+                bypassed: 0,
+            }
+        );
+
+        // now that it is cached, nothing waits
+        stats.try_get_with(&cache, 1, fetch()).await.unwrap();
+
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            stats.counts(),
+            JsonRpcResponseCacheCounts {
+                hits: 1,
+                collapsed: 9,
+                misses: 1,
+                bypassed: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_collapsed_request_errors_are_retried() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let stats = JsonRpcResponseCacheStats::default();
+
+        let fetches = AtomicUsize::new(0);
+
+        // only the first fetch fails
+        let fetch = || async {
+            let i = fetches.fetch_add(1, Ordering::Relaxed);
+
+            tokio::time::sleep(Duration::from_millis(100)).await;
+
+            if i == 0 {
+                Err(())
+            } else {
+                Ok(JsonRpcResponseEnum::from(json!(1)))
+            }
+        };
+
+        let responses =
+            join_all((0..10).map(|_| stats.try_get_with_status(&cache, 1, fetch()))).await;
+
+        let failed = responses.iter().filter(|(x, _)| x.is_err()).count();
+
+        // the followers did not get the leader's error
+        assert_eq!(failed, 1);
+
+        // one of them became the new leader. the rest waited on it instead of all fetching at once
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(
+            responses
+                .iter()
+                .filter(|(_, status)| *status == CacheStatus::Miss)
+                .count(),
+            2
+        );
+        assert_eq!(
+            responses
+                .iter()
+                .filter(|(_, status)| *status == CacheStatus::Collapsed)
+                .count(),
+            8
+        );
+
+        assert!(cache.get(&1).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_bypass() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let stats = JsonRpcResponseCacheStats::default();
+
+        let fetches = AtomicUsize::new(0);
+
+        let fetch = |x: u64| {
+            let fetches = &fetches;
+            async move {
+                fetches.fetch_add(1, Ordering::Relaxed);
+
+                Ok::<_, ()>(JsonRpcResponseEnum::from(json!(x)))
+            }
+        };
+
+        let (stale, status) = stats.try_get_with_status(&cache, 1, fetch(1)).await;
+        assert_eq!(status, CacheStatus::Miss);
+
+        // a bypass always goes to the backend, even though the response is cached
+        let (fresh, status) = stats.refresh_with(&cache, 1, fetch(2)).await;
+        assert_eq!(status, CacheStatus::Bypassed);
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+
+        let (stale, fresh) = (stale.unwrap(), fresh.unwrap());
+        assert_ne!(format!("{:?}", stale), format!("{:?}", fresh));
+
+        // everyone else now gets the fresh response from the cache
+        let (cached, status) = stats.try_get_with_status(&cache, 1, fetch(3)).await;
+        assert_eq!(status, CacheStatus::Hit);
+        assert_eq!(format!("{:?}", cached.unwrap()), format!("{:?}", fresh));
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+
+        assert_eq!(
+            stats.counts(),
+            JsonRpcResponseCacheCounts {
+                hits: 1,
+                collapsed: 0,
+                misses: 1,
+                bypassed: 1,
+            }
+        );
+    }
+
     #[tokio::test(start_paused = true)]
     async fn test_json_rpc_query_weigher() {
         let max_item_weight = 200;
@@ -329,4 +854,88 @@ mod tests {
         // now it should be empty
         assert!(test_cache.get(&2).is_none());
     }
+
+    #[tokio::test]
+    async fn test_reorg_drops_responses() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let index = JsonRpcResponseBlockIndex::new(cache.clone());
+
+        let head_num = U64::from(100);
+
+        let recent = BlockNumAndHash::from((U64::from(99), H256::repeat_byte(1)));
+        let finalized = BlockNumAndHash::from((U64::from(50), H256::repeat_byte(2)));
+
+        let recent_key =
+            JsonRpcQueryCacheKey::new(Some(recent.clone()), None, "eth_call", &json!([]), true);
+        let finalized_key =
+            JsonRpcQueryCacheKey::new(Some(finalized.clone()), None, "eth_call", &json!([]), true);
+
+        for key in [&recent_key, &finalized_key] {
+            cache.insert(key.hash(), json!(1).into()).await;
+
+            index.insert(key, &head_num, None).await;
+        }
+
+        // fetching the same response again doesn't grow the index
+        index.insert(&recent_key, &head_num, None).await;
+
+        assert_eq!(
+            index.keys_by_block.get(recent.hash()).unwrap().lock().len(),
+            1
+        );
+
+        let reorg = |hash: H256| Reorg {
+            depth: 1,
+            old_head: hash,
+            new_head: H256::repeat_byte(3),
+            new_head_num: head_num,
+            timestamp: 0,
+            affected_numbers: vec![],
+            replaced_hashes: vec![hash],
+            common_ancestor: None,
+        };
+
+        // finalized blocks are not tracked
+        index.on_reorg(&reorg(*finalized.hash())).await;
+        assert!(cache.get(&finalized_key.hash()).is_some());
+
+        index.on_reorg(&reorg(*recent.hash())).await;
+        assert!(cache.get(&recent_key.hash()).is_none());
+        assert!(cache.get(&finalized_key.hash()).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_new_head_drops_latest_responses() {
+        let cache: JsonRpcResponseCache = CacheBuilder::new(100).build();
+
+        let index = JsonRpcResponseBlockIndex::new(cache.clone());
+
+        let old_head = BlockNumAndHash::from((U64::from(99), H256::repeat_byte(1)));
+        let new_head = BlockNumAndHash::from((U64::from(100), H256::repeat_byte(2)));
+
+        let old_key =
+            JsonRpcQueryCacheKey::new(Some(old_head.clone()), None, "eth_call", &json!([]), true);
+        let new_key =
+            JsonRpcQueryCacheKey::new(Some(new_head.clone()), None, "eth_call", &json!([]), true);
+
+        index.on_new_head(old_head.hash()).await;
+
+        cache.insert(old_key.hash(), json!(1).into()).await;
+        index.insert_head(&old_key, old_head.hash()).await;
+        assert!(cache.get(&old_key.hash()).is_some());
+
+        index.on_new_head(new_head.hash()).await;
+        assert!(cache.get(&old_key.hash()).is_none());
+
+        cache.insert(new_key.hash(), json!(2).into()).await;
+        index.insert_head(&new_key, new_head.hash()).await;
+        assert!(cache.get(&new_key.hash()).is_some());
+
+        // a slow request for the old head does not leave its response behind
+        cache.insert(old_key.hash(), json!(1).into()).await;
+        index.insert_head(&old_key, old_head.hash()).await;
+        assert!(cache.get(&old_key.hash()).is_none());
+        assert!(cache.get(&new_key.hash()).is_some());
+    }
 }
@@ -0,0 +1,255 @@
+//! Save the heaviest chain's most recent blocks so that a restart doesn't begin with empty block caches.
+//! The live consensus still has to find a head, but requests for recent block numbers can use the saved hashes.
+use super::blockchain::Web3ProxyBlock;
+use super::many::Web3Rpcs;
+use crate::errors::Web3ProxyResult;
+use ethers::prelude::{Block, TxHash, U64};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::time::interval;
+use tracing::{debug, error, info, warn};
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BlockSnapshot {
+    pub chain_id: u64,
+    /// highest first
+    pub blocks: Vec<Block<TxHash>>,
+}
+
+impl Web3Rpcs {
+    /// the consensus head and the blocks below it, up to `depth` blocks. None if there is no head yet
+    pub fn block_snapshot(&self, depth: u64) -> Option<BlockSnapshot> {
+        let head_block_num = *self.head_block()?.number();
+
+        let mut blocks = vec![];
+
+        let mut num = head_block_num;
+
+        while blocks.len() < depth as usize {
+            // stop at the first gap. anything below it can't be checked against the head's chain
+            let Some(block) = self
+                .blocks_by_number
+                .get(&num)
+                .and_then(|hash| self.blocks_by_hash.get(&hash))
+            else {
+                break;
+            };
+
+            blocks.push(block.block.as_ref().clone());
+
+            if num.is_zero() {
+                break;
+            }
+
+            num -= U64::one();
+        }
+
+        Some(BlockSnapshot {
+            chain_id: self.chain_id,
+            blocks,
+        })
+    }
+
+    /// fill the block caches from a snapshot. numbers that the live consensus already saved are not replaced.
+    /// blocks more than `depth` below the snapshot's highest block are stale and skipped. returns how many blocks were loaded
+    pub async fn load_block_snapshot(
+        &self,
+        snapshot: BlockSnapshot,
+        depth: u64,
+    ) -> Web3ProxyResult<usize> {
+        if snapshot.chain_id != self.chain_id {
+            return Err(anyhow::anyhow!(
+                "block snapshot is for chain {}, not {}",
+                snapshot.chain_id,
+                self.chain_id
+            )
+            .into());
+        }
+
+        let blocks: Vec<Web3ProxyBlock> = snapshot
+            .blocks
+            .into_iter()
+            .filter_map(|x| Web3ProxyBlock::try_from(Arc::new(x)).ok())
+            .collect();
+
+        let Some(highest_num) = blocks.iter().map(|x| *x.number()).max() else {
+            return Ok(0);
+        };
+
+        let oldest_num = highest_num.saturating_sub((depth.saturating_sub(1)).into());
+
+        let mut loaded = 0;
+
+        for block in blocks {
+            if *block.number() < oldest_num {
+                continue;
+            }
+
+            let num = *block.number();
+            let hash = *block.hash();
+
+            self.blocks_by_hash
+                .get_with_by_ref(&hash, async move { block })
+                .await;
+
+            if self
+                .blocks_by_number
+                .entry_by_ref(&num)
+                .or_insert(hash)
+                .await
+                .is_fresh()
+            {
+                loaded += 1;
+            }
+        }
+
+        Ok(loaded)
+    }
+}
+
+async fn read_block_snapshot(path: &Path) -> Web3ProxyResult<BlockSnapshot> {
+    let x = tokio::fs::read(path).await?;
+
+    let x = serde_json::from_slice(&x)?;
+
+    Ok(x)
+}
+
+/// write to a temporary file first so that a crash while saving doesn't leave a partial snapshot
+async fn write_block_snapshot(path: &Path, snapshot: &BlockSnapshot) -> Web3ProxyResult<()> {
+    let x = serde_json::to_vec(snapshot)?;
+
+    let tmp_path = path.with_extension("tmp");
+
+    tokio::fs::write(&tmp_path, x).await?;
+
+    tokio::fs::rename(&tmp_path, path).await?;
+
+    Ok(())
+}
+
+/// Load the snapshot at `path` (if there is one), and then periodically replace it with the latest blocks.
+/// A missing or broken snapshot is logged and otherwise ignored.
+pub async fn block_snapshot_loop(
+    rpcs: Arc<Web3Rpcs>,
+    path: PathBuf,
+    depth: u64,
+    period: Duration,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    match read_block_snapshot(&path).await {
+        Ok(snapshot) => match rpcs.load_block_snapshot(snapshot, depth).await {
+            Ok(loaded) => info!(loaded, ?path, "loaded block snapshot"),
+            Err(err) => warn!(?err, ?path, "unable to load block snapshot"),
+        },
+        Err(err) => warn!(?err, ?path, "unable to read block snapshot"),
+    }
+
+    let mut interval = interval(period);
+
+    // the first tick is immediate. there is nothing new to save yet
+    interval.tick().await;
+
+    loop {
+        let shutting_down = select! {
+            _ = interval.tick() => false,
+            _ = shutdown_receiver.recv() => true,
+        };
+
+        // save one last time before stopping
+        if let Some(snapshot) = rpcs.block_snapshot(depth) {
+            if let Err(err) = write_block_snapshot(&path, &snapshot).await {
+                error!(?err, ?path, "unable to save block snapshot!");
+            } else {
+                debug!(num_blocks = snapshot.blocks.len(), "saved block snapshot");
+            }
+        }
+
+        if shutting_down {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+    use hashbrown::HashMap;
+
+    fn test_rpcs(chain_id: u64) -> Web3Rpcs {
+        Web3Rpcs::test_new(chain_id, HashMap::new()).0
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_block_snapshot() {
+        let mut blocks: Vec<Web3ProxyBlock> = vec![];
+
+        for num in 0..10u64 {
+            let parent_hash = blocks.last().map(|x| *x.hash()).unwrap_or_default();
+
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let rpcs = test_rpcs(1);
+
+        // no head yet
+        assert!(rpcs.block_snapshot(5).is_none());
+
+        for block in blocks.iter() {
+            rpcs.try_cache_block(block.clone(), true).await.unwrap();
+        }
+
+        rpcs.watch_head_block
+            .as_ref()
+            .unwrap()
+            .send_replace(blocks.last().cloned());
+
+        let snapshot = rpcs.block_snapshot(5).unwrap();
+
+        let nums: Vec<_> = snapshot
+            .blocks
+            .iter()
+            .map(|x| x.number.unwrap().as_u64())
+            .collect();
+        assert_eq!(nums, [9, 8, 7, 6, 5]);
+
+        // the snapshot is saved as json
+        let snapshot: BlockSnapshot =
+            serde_json::from_slice(&serde_json::to_vec(&snapshot).unwrap()).unwrap();
+
+        // a snapshot from another chain is rejected
+        let other_chain = test_rpcs(5);
+
+        assert!(other_chain
+            .load_block_snapshot(rpcs.block_snapshot(5).unwrap(), 5)
+            .await
+            .is_err());
+
+        // blocks older than the depth are skipped
+        let restarted = test_rpcs(1);
+
+        assert_eq!(restarted.load_block_snapshot(snapshot, 3).await.unwrap(), 3);
+
+        for block in &blocks[7..] {
+            assert_eq!(
+                restarted.blocks_by_number.get(block.number()),
+                Some(*block.hash())
+            );
+            assert!(restarted.blocks_by_hash.get(block.hash()).is_some());
+        }
+
+        assert!(restarted.blocks_by_number.get(blocks[6].number()).is_none());
+    }
+}
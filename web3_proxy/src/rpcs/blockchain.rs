@@ -2,20 +2,30 @@
 use super::consensus::ConsensusFinder;
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
+use crate::block_number::BlockNumber_to_U64;
+use crate::bounded_ring::BoundedRing;
 use crate::config::{average_block_interval, BlockAndRpc};
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use async_trait::async_trait;
 use derive_more::From;
-use ethers::prelude::{Block, TxHash, H256, U64};
+use ethers::prelude::{Block, BlockNumber, TxHash, H256, U256, U64};
+use futures::future::join_all;
+use hashbrown::HashMap;
+use itertools::Itertools;
 use moka::future::Cache;
 use serde::ser::SerializeStruct;
 use serde::Serialize;
 use serde_json::json;
+use std::cmp::Reverse;
 use std::hash::Hash;
+use std::sync::atomic;
 use std::time::Duration;
 use std::{fmt::Display, sync::Arc};
-use tokio::sync::mpsc;
-use tokio::time::timeout;
-use tracing::{debug, error, warn};
+use tokio::select;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::yield_now;
+use tokio::time::{sleep, timeout, timeout_at, Instant};
+use tracing::{debug, error, info, warn};
 
 // TODO: type for Hydrated Blocks with their full transactions?
 pub type ArcBlock = Arc<Block<TxHash>>;
@@ -23,6 +33,21 @@ pub type ArcBlock = Arc<Block<TxHash>>;
 pub type BlocksByHashCache = Cache<H256, Web3ProxyBlock>;
 pub type BlocksByNumberCache = Cache<U64, H256>;
 
+/// blocks more than this far behind the consensus head are pruned from the caches
+pub const PRUNE_DEPTH: u64 = 1_000;
+
+/// the most `blocks_by_number` entries removed while holding `blocks_by_number_lock`
+pub const PRUNE_BATCH_SIZE: usize = 100;
+
+/// what one call to `Web3Rpcs::prune_blocks` removed
+#[derive(Debug, Default)]
+pub struct PruneStats {
+    pub numbers_pruned: usize,
+    pub hashes_pruned: usize,
+    /// the longest that `blocks_by_number_lock` was held for a single batch
+    pub max_lock_held: Duration,
+}
+
 /// A block and its age.
 #[derive(Clone, Debug, Default, From)]
 pub struct Web3ProxyBlock {
@@ -30,6 +55,9 @@ pub struct Web3ProxyBlock {
     /// number of seconds this block was behind the current time when received
     /// this is only set if the block is from a subscription
     pub received_age: Option<u64>,
+    /// when the block was received. set along with received_age.
+    /// the age keeps growing from here on a monotonic clock, so system clock changes after it arrived don't move it
+    pub received_at: Option<Instant>,
 }
 
 impl Serialize for Web3ProxyBlock {
@@ -84,17 +112,25 @@ impl Web3ProxyBlock {
         let mut x = Self {
             block,
             received_age: None,
+            received_at: None,
         };
 
         // no need to recalulate lag every time
         // if the head block gets too old, a health check restarts this connection
         // TODO: emit a stat for received_age
         x.received_age = Some(x.age().as_secs());
+        x.received_at = Some(Instant::now());
 
         Some(x)
     }
 
+    /// how long ago the block was made.
+    /// blocks from a subscription only compare the wall clock to the block's timestamp once. after that the age is measured with Instant
     pub fn age(&self) -> Duration {
+        if let (Some(received_age), Some(received_at)) = (self.received_age, self.received_at) {
+            return Duration::from_secs(received_age) + received_at.elapsed();
+        }
+
         let now = chrono::Utc::now().timestamp();
 
         let block_timestamp = self.block.timestamp.as_u32() as i64;
@@ -110,6 +146,13 @@ impl Web3ProxyBlock {
         Duration::from_secs(x)
     }
 
+    /// how many seconds the block's timestamp is ahead of our clock. negative if it is behind.
+    /// blocks are always made before we receive them, so a large positive number means our clock is behind.
+    /// negative numbers are expected from rpcs that are lagging or syncing
+    pub fn clock_skew(&self) -> i64 {
+        self.block.timestamp.as_u64() as i64 - chrono::Utc::now().timestamp()
+    }
+
     #[inline(always)]
     pub fn parent_hash(&self) -> &H256 {
         &self.block.parent_hash
@@ -131,6 +174,12 @@ impl Web3ProxyBlock {
             .expect("saved blocks must have a number")
     }
 
+    /// not all nodes report this. some stopped during the merge
+    #[inline(always)]
+    pub fn total_difficulty(&self) -> Option<&U256> {
+        self.block.total_difficulty.as_ref()
+    }
+
     pub fn uncles(&self) -> &[H256] {
         &self.block.uncles
     }
@@ -147,6 +196,7 @@ impl TryFrom<ArcBlock> for Web3ProxyBlock {
         let b = Web3ProxyBlock {
             block: x,
             received_age: None,
+            received_at: None,
         };
 
         Ok(b)
@@ -165,7 +215,131 @@ impl Display for Web3ProxyBlock {
     }
 }
 
+/// A recent block and the rpcs that have it as their head
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ChainGraphBlock {
+    pub hash: H256,
+    pub number: U64,
+    pub parent_hash: H256,
+    /// true if this block is on the chain that the consensus finder picked
+    pub canonical: bool,
+    pub rpcs: Vec<String>,
+}
+
+/// A snapshot of the recent blocks (including forks) for debugging reorgs.
+/// The edges are each block's `parent_hash`. Blocks are sorted from highest to lowest.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ChainGraph {
+    pub blocks: Vec<ChainGraphBlock>,
+}
+
+impl ChainGraph {
+    pub fn get(&self, hash: &H256) -> Option<&ChainGraphBlock> {
+        self.blocks.iter().find(|x| x.hash == *hash)
+    }
+}
+
+/// A change of the heaviest chain that replaced blocks we had already saved
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Reorg {
+    /// how many block numbers got a different block
+    pub depth: u64,
+    /// the highest block that was replaced
+    pub old_head: H256,
+    pub new_head: H256,
+    pub new_head_num: U64,
+    /// unix timestamp of when the reorg was seen
+    pub timestamp: i64,
+    /// the block numbers that got a different block. lowest first
+    pub affected_numbers: Vec<U64>,
+    /// the blocks that were replaced. in the same order as `affected_numbers`
+    pub replaced_hashes: Vec<H256>,
+    /// the highest block that is on both chains.
+    /// None if it was already pruned from the block cache. `depth` is then only how far back we could check
+    pub common_ancestor: Option<H256>,
+}
+
+/// The most recent reorgs
+pub type RecentReorgs = BoundedRing<Reorg>;
+
+/// Something that has to forget blocks that are no longer on the heaviest chain. For example, a cache keyed by block number.
+/// Observers are called before the new head is published, so they never serve the old chain for the new head.
+#[async_trait]
+pub trait ReorgObserver: Send + Sync {
+    async fn on_reorg(&self, reorg: &Reorg);
+}
+
 impl Web3Rpcs {
+    /// tell everything that is watching for reorgs
+    async fn publish_reorg(&self, reorg: Reorg) {
+        info!(
+            depth=%reorg.depth,
+            old=%reorg.old_head,
+            new=%reorg.new_head,
+            ancestor=?reorg.common_ancestor,
+            "reorg"
+        );
+
+        // the lock is not held while the observers run
+        let observers = self.reorg_observers.read().clone();
+
+        for observer in observers {
+            observer.on_reorg(&reorg).await;
+        }
+
+        // no subscribers is fine
+        let _ = self.reorg_sender.send(reorg.clone());
+
+        self.recent_reorgs.push(reorg);
+    }
+
+    /// observers are called for every reorg from now on. unlike `subscribe_reorgs`, they are called before the new head is published
+    pub fn add_reorg_observer(&self, observer: Arc<dyn ReorgObserver>) {
+        self.reorg_observers.write().push(observer);
+    }
+
+    /// the heaviest chain went back to a lower block. forget the blocks above it.
+    /// a rollback to a block on another fork also replaces blocks when the new head is saved with `try_cache_block`
+    pub(super) async fn rollback(&self, old_head: &Web3ProxyBlock, new_head: &Web3ProxyBlock) {
+        let mut affected_numbers = vec![];
+        let mut replaced_hashes = vec![];
+
+        let mut num = *new_head.number() + 1;
+
+        while num <= *old_head.number() {
+            if let Some(hash) = self.blocks_by_number.get(&num) {
+                self.blocks_by_number.invalidate(&num).await;
+
+                affected_numbers.push(num);
+                replaced_hashes.push(hash);
+            }
+
+            num += 1.into();
+        }
+
+        if affected_numbers.is_empty() {
+            return;
+        }
+
+        let common_ancestor = if self.on_same_chain(old_head, new_head) == Some(true) {
+            Some(*new_head.hash())
+        } else {
+            None
+        };
+
+        self.publish_reorg(Reorg {
+            depth: affected_numbers.len() as u64,
+            old_head: *old_head.hash(),
+            new_head: *new_head.hash(),
+            new_head_num: *new_head.number(),
+            timestamp: chrono::Utc::now().timestamp(),
+            affected_numbers,
+            replaced_hashes,
+            common_ancestor,
+        })
+        .await;
+    }
+
     /// add a block to our mappings and track the heaviest chain
     pub async fn try_cache_block(
         &self,
@@ -185,18 +359,55 @@ impl Web3Rpcs {
         if consensus_head {
             let block_num = block.number();
 
-            // TODO: if there is an existing entry with a different block_hash,
-            // TODO: use entry api to handle changing existing entries
-            self.blocks_by_number.insert(*block_num, block_hash).await;
+            // the entry checks below are atomic on their own, but replacing a number's hash is a check and then an insert.
+            // the consensus loop and cannonical_block can both get here. take turns so that their invalidates and inserts don't interleave
+            let blocks_by_number_lock = self.blocks_by_number_lock.lock().await;
+
+            // (number, old hash) for every saved block that this block's chain replaces
+            let mut replaced = vec![];
+
+            let block_num_entry = self
+                .blocks_by_number
+                .entry_by_ref(block_num)
+                .or_insert(block_hash)
+                .await;
+
+            let check_ancestors = if block_num_entry.is_fresh() {
+                true
+            } else if *block_num_entry.value() == block_hash {
+                // this block is already on the heaviest chain. its ancestors were checked when it was first saved
+                false
+            } else {
+                // a competing block is saved at this height.
+                // the consensus finder only passes us blocks that it picked as the head, so this block is on the heaviest chain.
+                // non-consensus blocks never get here and so never replace an existing mapping
+                debug!(num=%block_num, old=%block_num_entry.value(), new=%block_hash, "replacing block on the heaviest chain");
+
+                replaced.push((*block_num, *block_num_entry.value()));
+
+                self.blocks_by_hash
+                    .invalidate(block_num_entry.value())
+                    .await;
+
+                self.blocks_by_number.insert(*block_num, block_hash).await;
+
+                true
+            };
 
             for uncle in block.uncles() {
                 self.blocks_by_hash.invalidate(uncle).await;
                 // TODO: save uncles somewhere?
             }
 
+            let mut common_ancestor = None;
+
             // loop to make sure parent hashes match our caches
             // set the first ancestor to the blocks' parent hash. but keep going up the chain
-            if let Some(parent_num) = block.number().checked_sub(1.into()) {
+            if let Some(parent_num) = block
+                .number()
+                .checked_sub(1.into())
+                .filter(|_| check_ancestors)
+            {
                 struct Ancestor {
                     num: U64,
                     hash: H256,
@@ -214,10 +425,12 @@ impl Web3Rpcs {
 
                     if *ancestor_number_to_hash_entry.value() == ancestor.hash {
                         // the existing number entry matches. all good
+                        common_ancestor = Some(ancestor.hash);
                         break;
                     }
 
                     // oh no! ancestor_number_to_hash_entry is different
+                    replaced.push((ancestor.num, *ancestor_number_to_hash_entry.value()));
 
                     // remove the uncled entry in blocks_by_hash
                     // we will look it up later if necessary
@@ -225,9 +438,6 @@ impl Web3Rpcs {
                         .invalidate(ancestor_number_to_hash_entry.value())
                         .await;
 
-                    // TODO: delete any cached entries for eth_getBlockByHash or eth_getBlockByNumber
-
-                    // TODO: race on this drop and insert?
                     drop(ancestor_number_to_hash_entry);
 
                     // update the entry in blocks_by_number
@@ -251,6 +461,25 @@ impl Web3Rpcs {
                     }
                 }
             }
+
+            // the replaced blocks were found from the top down
+            if let Some((_, old_head)) = replaced.first() {
+                let reorg = Reorg {
+                    depth: replaced.len() as u64,
+                    old_head: *old_head,
+                    new_head: block_hash,
+                    new_head_num: *block_num,
+                    timestamp: chrono::Utc::now().timestamp(),
+                    affected_numbers: replaced.iter().rev().map(|(num, _)| *num).collect(),
+                    replaced_hashes: replaced.iter().rev().map(|(_, hash)| *hash).collect(),
+                    common_ancestor,
+                };
+
+                // the caches are consistent again. observers and subscribers don't need to hold up the other writers
+                drop(blocks_by_number_lock);
+
+                self.publish_reorg(reorg).await;
+            }
         }
 
         let block = self
@@ -261,6 +490,332 @@ impl Web3Rpcs {
         Ok(block)
     }
 
+    /// remove any `blocks_by_number` entries that point at a block in `blocks_by_hash` with a different number.
+    /// a hash that is missing from `blocks_by_hash` was only evicted. that is normal and is left alone.
+    /// the removed numbers are fetched again the next time they are needed. returns how many were removed
+    pub async fn check_block_caches(&self) -> u64 {
+        let diverged: Vec<_> = self
+            .blocks_by_number
+            .iter()
+            .filter(|(num, hash)| {
+                self.blocks_by_hash
+                    .get(hash)
+                    .map(|block| *block.number() != **num)
+                    .unwrap_or(false)
+            })
+            .collect();
+
+        let mut num_repaired = 0;
+
+        for (num, hash) in diverged {
+            // the entry might have been replaced since we checked it
+            if self.blocks_by_number.get(&*num) == Some(hash) {
+                self.blocks_by_number.invalidate(&*num).await;
+
+                num_repaired += 1;
+            }
+        }
+
+        if num_repaired > 0 {
+            self.block_cache_repairs
+                .fetch_add(num_repaired, atomic::Ordering::Relaxed);
+
+            warn!(
+                num_repaired,
+                "blocks_by_number disagreed with blocks_by_hash"
+            );
+        }
+
+        num_repaired
+    }
+
+    pub fn block_cache_repairs(&self) -> u64 {
+        self.block_cache_repairs.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn orphans_dropped(&self) -> u64 {
+        self.orphans_dropped.load(atomic::Ordering::Relaxed)
+    }
+
+    /// the most recent reorgs of the heaviest chain. oldest first
+    pub fn recent_reorgs(&self) -> Vec<Reorg> {
+        self.recent_reorgs.to_vec()
+    }
+
+    /// every reorg of the heaviest chain from now on. slow receivers lag and skip the oldest reorgs
+    pub fn subscribe_reorgs(&self) -> broadcast::Receiver<Reorg> {
+        self.reorg_sender.subscribe()
+    }
+
+    /// true if the block's parent is unknown and it is more than `max_parent_fetch_depth` blocks past the consensus head.
+    /// fetching that many parents could be a lot of load, and a node feeding us a disconnected chain would make us do it over and over
+    pub(super) fn is_unreachable_orphan(&self, block: &Web3ProxyBlock) -> bool {
+        let max_depth = match self.max_parent_fetch_depth {
+            None => return false,
+            Some(x) => x,
+        };
+
+        // during startup, every head is missing its parents
+        let consensus_head = match self.head_block() {
+            None => return false,
+            Some(x) => x,
+        };
+
+        if self.blocks_by_hash.contains_key(block.parent_hash()) {
+            return false;
+        }
+
+        block.number().saturating_sub(*consensus_head.number()) > max_depth.into()
+    }
+
+    pub(super) async fn check_block_caches_loop(
+        &self,
+        interval: Duration,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Web3ProxyResult<()> {
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = shutdown_receiver.recv() => return Ok(()),
+            }
+
+            self.check_block_caches().await;
+        }
+    }
+
+    /// remove every cached block older than `min_block_num`.
+    /// `blocks_by_number_lock` is only held for `batch_size` removals at a time, so try_cache_block never waits on a big backlog
+    pub async fn prune_blocks(&self, min_block_num: U64, batch_size: usize) -> PruneStats {
+        let batch_size = batch_size.max(1);
+
+        let old_numbers: Vec<_> = self
+            .blocks_by_number
+            .iter()
+            .filter(|(num, _)| **num < min_block_num)
+            .collect();
+
+        let mut stats = PruneStats::default();
+
+        for batch in old_numbers.chunks(batch_size) {
+            let blocks_by_number_lock = self.blocks_by_number_lock.lock().await;
+
+            let start = Instant::now();
+
+            for (num, hash) in batch {
+                // the entry might have been replaced since we checked it
+                if self.blocks_by_number.get(&**num).as_ref() == Some(hash) {
+                    self.blocks_by_number.invalidate(&**num).await;
+
+                    stats.numbers_pruned += 1;
+                }
+            }
+
+            stats.max_lock_held = stats.max_lock_held.max(start.elapsed());
+
+            drop(blocks_by_number_lock);
+
+            // give the consensus loop a turn
+            yield_now().await;
+        }
+
+        // orphans are only in blocks_by_hash. removing them doesn't need the lock
+        let old_hashes: Vec<_> = self
+            .blocks_by_hash
+            .iter()
+            .filter(|(_, block)| *block.number() < min_block_num)
+            .map(|(hash, _)| hash)
+            .collect();
+
+        for batch in old_hashes.chunks(batch_size) {
+            for hash in batch {
+                self.blocks_by_hash.invalidate(&**hash).await;
+
+                stats.hashes_pruned += 1;
+            }
+
+            yield_now().await;
+        }
+
+        stats
+    }
+
+    /// every `interval`, prune blocks more than `PRUNE_DEPTH` behind the consensus head
+    pub(super) async fn prune_blocks_loop(
+        &self,
+        interval: Duration,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Web3ProxyResult<()> {
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = shutdown_receiver.recv() => return Ok(()),
+            }
+
+            let head_block_num = match self.head_block_num() {
+                None => continue,
+                Some(x) => x,
+            };
+
+            let min_block_num = head_block_num.saturating_sub(PRUNE_DEPTH.into());
+
+            let stats = self.prune_blocks(min_block_num, PRUNE_BATCH_SIZE).await;
+
+            if stats.numbers_pruned > 0 || stats.hashes_pruned > 0 {
+                debug!(?stats, %min_block_num, "pruned old blocks");
+            }
+        }
+    }
+
+    /// ask every synced rpc for its "finalized" block and publish the highest one that enough of them agree on.
+    /// rpcs that don't support the tag are ignored. if none of them do, the finalized block stays None
+    pub(super) async fn update_finalized_block(&self) -> Option<Web3ProxyBlock> {
+        let ranked_rpcs = self.watch_ranked_rpcs.borrow().clone()?;
+
+        let responses = join_all(ranked_rpcs.all().iter().map(|rpc| {
+            rpc.internal_request::<_, Option<ArcBlock>>(
+                "eth_getBlockByNumber",
+                &("finalized", false),
+                None,
+                Some(1),
+                None,
+            )
+        }))
+        .await;
+
+        let blocks = responses
+            .into_iter()
+            .filter_map(|x| x.ok().flatten())
+            .filter_map(|x| Web3ProxyBlock::try_from(x).ok());
+
+        let new_block = highest_agreed_block(blocks, self.min_synced_rpcs)?;
+
+        // the finalized block never goes backwards. a lagging quorum shouldn't make it look like it did
+        let changed = self
+            .watch_finalized_block
+            .send_if_modified(|old| match old {
+                Some(old) if old.number() >= new_block.number() => false,
+                _ => {
+                    *old = Some(new_block.clone());
+                    true
+                }
+            });
+
+        if changed {
+            debug!(%new_block, "new finalized block");
+        }
+
+        self.finalized_block()
+    }
+
+    pub(super) async fn update_finalized_block_loop(
+        &self,
+        interval: Duration,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Web3ProxyResult<()> {
+        loop {
+            select! {
+                _ = sleep(interval) => {}
+                _ = shutdown_receiver.recv() => return Ok(()),
+            }
+
+            self.update_finalized_block().await;
+        }
+    }
+
+    /// Some(true) if one of the blocks is an ancestor of (or the same as) the other.
+    /// None if a block between them is not in the cache
+    pub(super) fn on_same_chain(&self, a: &Web3ProxyBlock, b: &Web3ProxyBlock) -> Option<bool> {
+        let (mut high, low) = if a.number() >= b.number() {
+            (a.clone(), b)
+        } else {
+            (b.clone(), a)
+        };
+
+        while high.number() > low.number() {
+            high = self.blocks_by_hash.get(high.parent_hash())?;
+        }
+
+        Some(high.hash() == low.hash())
+    }
+
+    /// the known blocks within `depth` of the highest head. every rpc's head is walked back through its parents.
+    /// orphaned blocks are only included while they are still in the cache
+    pub fn export_chain_graph(&self, depth: u64) -> ChainGraph {
+        let rpc_heads: Vec<(String, Web3ProxyBlock)> = self
+            .by_name
+            .read()
+            .values()
+            .filter_map(|rpc| {
+                let head = rpc.head_block.as_ref()?.borrow().clone()?;
+
+                Some((rpc.name.clone(), head))
+            })
+            .collect();
+
+        let consensus_head = self
+            .watch_head_block
+            .as_ref()
+            .and_then(|x| x.borrow().clone());
+
+        let heads: Vec<&Web3ProxyBlock> = rpc_heads
+            .iter()
+            .map(|(_, x)| x)
+            .chain(consensus_head.iter())
+            .collect();
+
+        let highest = match heads.iter().map(|x| *x.number()).max() {
+            None => return ChainGraph::default(),
+            Some(x) => x,
+        };
+
+        let lowest = highest.saturating_sub(depth.into());
+
+        let mut blocks = HashMap::<H256, ChainGraphBlock>::new();
+
+        for head in heads {
+            let mut next = Some(head.clone());
+
+            while let Some(block) = next {
+                if *block.number() < lowest || blocks.contains_key(block.hash()) {
+                    break;
+                }
+
+                let canonical =
+                    self.blocks_by_number.get(block.number()).as_ref() == Some(block.hash());
+
+                blocks.insert(
+                    *block.hash(),
+                    ChainGraphBlock {
+                        hash: *block.hash(),
+                        number: *block.number(),
+                        parent_hash: *block.parent_hash(),
+                        canonical,
+                        rpcs: vec![],
+                    },
+                );
+
+                next = self.blocks_by_hash.get(block.parent_hash());
+            }
+        }
+
+        for (rpc_name, head) in rpc_heads {
+            if let Some(x) = blocks.get_mut(head.hash()) {
+                x.rpcs.push(rpc_name);
+            }
+        }
+
+        let blocks = blocks
+            .into_values()
+            .map(|mut x| {
+                x.rpcs.sort();
+                x
+            })
+            .sorted_by_key(|x| (Reverse(x.number), x.hash))
+            .collect();
+
+        ChainGraph { blocks }
+    }
+
     /// Get a block from caches with fallback.
     /// Will query a specific node or the best available.
     pub async fn block(
@@ -344,7 +899,7 @@ impl Web3Rpcs {
     }
 
     /// Get the heaviest chain's block from cache or backend rpc
-    /// Caution! If a future block is requested, this might wait forever. Be sure to have a timeout outside of this!
+    /// A block just past the head waits up to `future_block_wait` for it to arrive. Blocks further ahead error immediately.
     pub async fn cannonical_block(&self, num: &U64) -> Web3ProxyResult<(Web3ProxyBlock, u64)> {
         // we only have blocks by hash now
         // maybe save them during save_block in a blocks_by_number Cache<U64, Vec<ArcBlock>>
@@ -365,18 +920,29 @@ impl Web3Rpcs {
             .number();
 
         if *num > head_block_num {
+            // clients ask for blocks that were published by their rpc a little before ours. those are likely about to arrive
             // if num is too far in the future, error now
-            if *num - head_block_num > self.max_head_block_lag {
+            if *num - head_block_num > self.future_block_grace {
                 return Err(Web3ProxyError::UnknownBlockNumber {
                     known: head_block_num,
                     unknown: *num,
                 });
             }
 
+            let deadline = Instant::now() + self.future_block_wait;
+
             while *num > head_block_num {
                 debug!(%head_block_num, %num, "waiting for future block");
 
-                consensus_head_receiver.changed().await?;
+                match timeout_at(deadline, consensus_head_receiver.changed()).await {
+                    Ok(x) => x?,
+                    Err(_) => {
+                        return Err(Web3ProxyError::UnknownBlockNumber {
+                            known: head_block_num,
+                            unknown: *num,
+                        })
+                    }
+                }
 
                 if let Some(head) = consensus_head_receiver.borrow_and_update().as_ref() {
                     head_block_num = *head.number();
@@ -389,11 +955,19 @@ impl Web3Rpcs {
         // try to get the hash from our cache
         // deref to not keep the lock open
         if let Some(block_hash) = self.blocks_by_number.get(num) {
-            // TODO: sometimes this needs to fetch the block. why? i thought block_numbers would only be set if the block hash was set
             // TODO: configurable max wait and rpc
-            let block = self.block(&block_hash, None, Some(3), None).await?;
+            match self.block(&block_hash, None, Some(3), None).await {
+                Ok(block) => return Ok((block, block_depth)),
+                Err(err) => {
+                    // blocks_by_number pointed at a hash that we can't find anymore. forget it and fetch by number instead
+                    warn!(%num, %block_hash, ?err, "blocks_by_number diverged from blocks_by_hash");
 
-            return Ok((block, block_depth));
+                    self.blocks_by_number.invalidate(num).await;
+
+                    self.block_cache_repairs
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+                }
+            }
         }
 
         // block number not in cache. we need to ask an rpc for it
@@ -416,20 +990,90 @@ impl Web3Rpcs {
         Ok((block, block_depth))
     }
 
+    /// Get the block for a tag like "latest" or "finalized". Block numbers are also accepted.
+    /// "pending" is whatever an rpc is building, so it is not cached and might not have a hash.
+    pub async fn block_for_tag(&self, block_num: BlockNumber) -> Web3ProxyResult<ArcBlock> {
+        let num = match block_num {
+            BlockNumber::Latest => {
+                let head_block = self.head_block().ok_or(Web3ProxyError::NoServersSynced)?;
+
+                return Ok(head_block.block);
+            }
+            BlockNumber::Pending => {
+                // TODO: use the highest pending block instead of the first one
+                let block = self
+                    .internal_request::<_, Option<ArcBlock>>(
+                        "eth_getBlockByNumber",
+                        &("pending", false),
+                        Some(3),
+                        None,
+                    )
+                    .await?
+                    .ok_or(Web3ProxyError::NoBlocksKnown)?;
+
+                return Ok(block);
+            }
+            BlockNumber::Earliest => U64::zero(),
+            BlockNumber::Finalized | BlockNumber::Safe => {
+                // "safe" is always at or after "finalized", so the finalized block is safe too
+                if let Some(finalized_block) = self.finalized_block() {
+                    return Ok(finalized_block.block);
+                }
+
+                // this chain doesn't have the tag (or the rpcs don't agree yet). guess from the head
+                let head_block_num = self
+                    .head_block_num()
+                    .ok_or(Web3ProxyError::NoServersSynced)?;
+
+                BlockNumber_to_U64(block_num, &head_block_num, None).0
+            }
+            BlockNumber::Number(x) => x,
+        };
+
+        let (block, _) = self.cannonical_block(&num).await?;
+
+        Ok(block.block)
+    }
+
     pub(super) async fn process_incoming_blocks(
         &self,
         mut block_receiver: mpsc::UnboundedReceiver<BlockAndRpc>,
+        mut shutdown_receiver: broadcast::Receiver<()>,
     ) -> Web3ProxyResult<()> {
         let mut consensus_finder =
             ConsensusFinder::new(Some(self.max_head_block_age), Some(self.max_head_block_lag));
 
+        let mut head_verifications = consensus_finder
+            .take_head_verifications()
+            .expect("a new consensus finder has its receiver");
+
         // TODO: what timeout on block receiver? we want to keep consensus_finder fresh so that server tiers are correct
         let double_block_time = average_block_interval(self.chain_id).mul_f32(2.0);
 
         let mut had_first_success = false;
 
         loop {
-            match timeout(double_block_time, block_receiver.recv()).await {
+            let next_block = select! {
+                x = timeout(
+                    consensus_finder.next_refresh(double_block_time),
+                    block_receiver.recv(),
+                ) => x,
+                Some(x) = head_verifications.recv() => {
+                    if consensus_finder.record_head_verification_result(x).await {
+                        if let Err(err) = consensus_finder.refresh(self, None, None).await {
+                            error!(?err, "error while refreshing consensus after a head verification");
+                        }
+                    }
+
+                    continue;
+                }
+                _ = shutdown_receiver.recv() => {
+                    info!("stopping consensus on {}", self);
+                    return Ok(());
+                }
+            };
+
+            match next_block {
                 Ok(Some((new_block, rpc))) => {
                     let rpc_name = rpc.name.clone();
                     let rpc_is_backup = rpc.backup;
@@ -492,3 +1136,694 @@ impl Web3Rpcs {
         }
     }
 }
+
+/// the highest block that at least `min_votes` of `blocks` are exactly the same as
+pub fn highest_agreed_block(
+    blocks: impl IntoIterator<Item = Web3ProxyBlock>,
+    min_votes: usize,
+) -> Option<Web3ProxyBlock> {
+    let mut votes: HashMap<Web3ProxyBlock, usize> = HashMap::new();
+
+    for block in blocks {
+        *votes.entry(block).or_default() += 1;
+    }
+
+    votes
+        .into_iter()
+        .filter(|(_, x)| *x >= min_votes.max(1))
+        .map(|(block, _)| block)
+        .max_by_key(|x| *x.number())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        highest_agreed_block, RecentReorgs, Reorg, ReorgObserver, Web3ProxyBlock, PRUNE_BATCH_SIZE,
+    };
+    use crate::errors::Web3ProxyError;
+    use crate::rpcs::many::Web3Rpcs;
+    use crate::rpcs::one::Web3Rpc;
+    use async_trait::async_trait;
+    use ethers::types::{Block, BlockNumber, H256, U64};
+    use hashbrown::HashMap;
+    use moka::future::CacheBuilder;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+    use tokio::time::{sleep, Instant};
+
+    #[derive(Default)]
+    struct RecordingObserver(Mutex<Vec<Reorg>>);
+
+    #[async_trait]
+    impl ReorgObserver for RecordingObserver {
+        async fn on_reorg(&self, reorg: &Reorg) {
+            self.0.lock().push(reorg.clone());
+        }
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_subscribed_block_age_is_monotonic() {
+        let now = chrono::Utc::now().timestamp();
+
+        let block = Web3ProxyBlock::try_new(Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            timestamp: (now - 2).into(),
+            ..Default::default()
+        }))
+        .unwrap();
+
+        let received_age = block.age();
+
+        assert!(received_age >= Duration::from_secs(2));
+        assert!(received_age < Duration::from_secs(4));
+
+        // paused time moves Instant, but not the system clock
+        tokio::time::advance(Duration::from_secs(60)).await;
+
+        assert_eq!(block.age(), received_age + Duration::from_secs(60));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_competing_block_numbers() {
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            ..Default::default()
+        };
+        let block_1a = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            ..Default::default()
+        };
+        let block_1b = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1a: Web3ProxyBlock = Arc::new(block_1a).try_into().unwrap();
+        let block_1b: Web3ProxyBlock = Arc::new(block_1b).try_into().unwrap();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        rpcs.try_cache_block(block_0.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_1a.clone(), true).await.unwrap();
+
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*block_1a.hash())
+        );
+
+        // a competing block that was not picked by the consensus finder must not replace the mapping
+        rpcs.try_cache_block(block_1b.clone(), false).await.unwrap();
+
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*block_1a.hash())
+        );
+        assert!(rpcs.blocks_by_hash.get(block_1b.hash()).is_some());
+
+        // once the consensus finder picks the competing block, it replaces the old mapping
+        rpcs.try_cache_block(block_1b.clone(), true).await.unwrap();
+
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*block_1b.hash())
+        );
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::zero()),
+            Some(*block_0.hash())
+        );
+        assert!(rpcs.blocks_by_hash.get(block_1a.hash()).is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_export_chain_graph() {
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            ..Default::default()
+        };
+        let block_2a = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            ..Default::default()
+        };
+        let block_2b = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let block_2a: Web3ProxyBlock = Arc::new(block_2a).try_into().unwrap();
+        let block_2b: Web3ProxyBlock = Arc::new(block_2b).try_into().unwrap();
+
+        // two rpcs on one side of the fork, one on the other
+        let rpcs: Vec<_> = [("a1", &block_2a), ("a2", &block_2a), ("b", &block_2b)]
+            .into_iter()
+            .map(|(name, head)| {
+                let (tx, _) = watch::channel(Some(head.clone()));
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    head_block: Some(tx),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        rpcs.watch_head_block
+            .as_ref()
+            .unwrap()
+            .send_replace(Some(block_2a.clone()));
+
+        rpcs.try_cache_block(block_0.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_1.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_2a.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_2b.clone(), false).await.unwrap();
+
+        let graph = rpcs.export_chain_graph(10);
+
+        assert_eq!(graph.blocks.len(), 4);
+
+        let exported_2a = graph.get(block_2a.hash()).unwrap();
+        assert_eq!(exported_2a.parent_hash, *block_1.hash());
+        assert_eq!(exported_2a.rpcs, vec!["a1", "a2"]);
+        assert!(exported_2a.canonical);
+
+        let exported_2b = graph.get(block_2b.hash()).unwrap();
+        assert_eq!(exported_2b.parent_hash, *block_1.hash());
+        assert_eq!(exported_2b.rpcs, vec!["b"]);
+        assert!(!exported_2b.canonical);
+
+        // both branches share the same parent
+        let exported_1 = graph.get(block_1.hash()).unwrap();
+        assert_eq!(exported_1.parent_hash, *block_0.hash());
+        assert!(exported_1.rpcs.is_empty());
+        assert!(exported_1.canonical);
+
+        // highest blocks first
+        assert_eq!(graph.blocks[3].hash, *block_0.hash());
+
+        // the graph can be limited to the most recent blocks
+        let graph = rpcs.export_chain_graph(1);
+
+        assert_eq!(graph.blocks.len(), 3);
+        assert!(graph.get(block_0.hash()).is_none());
+
+        assert!(serde_json::to_string(&graph).is_ok());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_prune_blocks() {
+        let (mut rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        // big enough that nothing is evicted on its own
+        rpcs.blocks_by_hash = CacheBuilder::new(20_000).build();
+        rpcs.blocks_by_number = CacheBuilder::new(20_000).build();
+
+        let mut parent_hash = H256::zero();
+        let mut blocks = vec![];
+
+        for num in 0..10_000u64 {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            parent_hash = block.hash.unwrap();
+
+            let block: Web3ProxyBlock = Arc::new(block).try_into().unwrap();
+
+            rpcs.blocks_by_hash
+                .insert(*block.hash(), block.clone())
+                .await;
+            rpcs.blocks_by_number
+                .insert(*block.number(), *block.hash())
+                .await;
+
+            blocks.push(block);
+        }
+
+        // an orphan is only in blocks_by_hash
+        let orphan: Web3ProxyBlock = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(5.into()),
+            ..Default::default()
+        })
+        .try_into()
+        .unwrap();
+
+        rpcs.blocks_by_hash
+            .insert(*orphan.hash(), orphan.clone())
+            .await;
+
+        let stats = rpcs.prune_blocks(9_000.into(), PRUNE_BATCH_SIZE).await;
+
+        assert_eq!(stats.numbers_pruned, 9_000);
+        assert_eq!(stats.hashes_pruned, 9_001);
+
+        // a batch of removals is quick. one big lock over the whole backlog would not be
+        assert!(
+            stats.max_lock_held < Duration::from_millis(50),
+            "{:?}",
+            stats
+        );
+
+        assert!(rpcs.blocks_by_hash.get(orphan.hash()).is_none());
+        assert!(rpcs.blocks_by_hash.get(blocks[8_999].hash()).is_none());
+        assert!(rpcs.blocks_by_number.get(&U64::from(8_999)).is_none());
+
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(9_000)),
+            Some(*blocks[9_000].hash())
+        );
+        assert!(rpcs.blocks_by_hash.get(blocks[9_999].hash()).is_some());
+
+        // nothing left to prune
+        let stats = rpcs.prune_blocks(9_000.into(), PRUNE_BATCH_SIZE).await;
+
+        assert_eq!(stats.numbers_pruned, 0);
+        assert_eq!(stats.hashes_pruned, 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_block_cache_divergence() {
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        rpcs.try_cache_block(block_0.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_1.clone(), true).await.unwrap();
+
+        rpcs.watch_head_block
+            .as_ref()
+            .unwrap()
+            .send_replace(Some(block_1.clone()));
+
+        // nothing to repair yet
+        assert_eq!(rpcs.check_block_caches().await, 0);
+
+        // block 0's number points at block 1
+        rpcs.blocks_by_number
+            .insert(U64::zero(), *block_1.hash())
+            .await;
+
+        assert_eq!(rpcs.check_block_caches().await, 1);
+        assert_eq!(rpcs.block_cache_repairs(), 1);
+
+        assert!(rpcs.blocks_by_number.get(&U64::zero()).is_none());
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*block_1.hash())
+        );
+
+        // an evicted hash is not a divergence
+        rpcs.blocks_by_hash.invalidate(block_1.hash()).await;
+
+        assert_eq!(rpcs.check_block_caches().await, 0);
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*block_1.hash())
+        );
+
+        // the request path doesn't panic on a divergent entry. with no rpcs to re-fetch from, it errors
+        rpcs.blocks_by_number
+            .insert(U64::from(1), H256::random())
+            .await;
+
+        let x = tokio::time::timeout(Duration::from_secs(5), rpcs.cannonical_block(&U64::from(1)))
+            .await
+            .unwrap();
+
+        assert!(x.is_err());
+        assert!(rpcs.blocks_by_number.get(&U64::from(1)).is_none());
+        assert_eq!(rpcs.block_cache_repairs(), 2);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_recent_reorgs() {
+        let new_block = |num: u64, parent_hash: H256| -> Web3ProxyBlock {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            Arc::new(block).try_into().unwrap()
+        };
+
+        let block_0 = new_block(0, H256::zero());
+        let block_1a = new_block(1, *block_0.hash());
+        let block_1b = new_block(1, *block_0.hash());
+        let block_2b = new_block(2, *block_1b.hash());
+        let block_1c = new_block(1, *block_0.hash());
+        let block_2c = new_block(2, *block_1c.hash());
+        let block_3c = new_block(3, *block_2c.hash());
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        let rpcs = Web3Rpcs {
+            recent_reorgs: RecentReorgs::new(2),
+            watch_head_block: None,
+            ..rpcs
+        };
+
+        let mut reorg_receiver = rpcs.subscribe_reorgs();
+
+        let observer = Arc::new(RecordingObserver::default());
+
+        rpcs.add_reorg_observer(observer.clone());
+
+        rpcs.try_cache_block(block_0.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_1a.clone(), true).await.unwrap();
+
+        assert!(rpcs.recent_reorgs().is_empty());
+
+        // a competing block at the head
+        rpcs.try_cache_block(block_1b.clone(), true).await.unwrap();
+        rpcs.try_cache_block(block_2b.clone(), true).await.unwrap();
+
+        // a longer fork from block 0. only its head is the consensus head
+        rpcs.try_cache_block(block_1c.clone(), false).await.unwrap();
+        rpcs.try_cache_block(block_2c.clone(), false).await.unwrap();
+        rpcs.try_cache_block(block_3c.clone(), true).await.unwrap();
+
+        let reorgs = rpcs.recent_reorgs();
+
+        assert_eq!(reorgs.len(), 2);
+
+        assert_eq!(reorgs[0].depth, 1);
+        assert_eq!(reorgs[0].old_head, *block_1a.hash());
+        assert_eq!(reorgs[0].new_head, *block_1b.hash());
+        assert_eq!(reorgs[0].new_head_num, U64::from(1));
+        assert_eq!(reorgs[0].affected_numbers, vec![U64::from(1)]);
+        assert_eq!(reorgs[0].replaced_hashes, vec![*block_1a.hash()]);
+
+        assert_eq!(reorgs[1].depth, 2);
+        assert_eq!(reorgs[1].old_head, *block_2b.hash());
+        assert_eq!(reorgs[1].new_head, *block_3c.hash());
+        assert_eq!(reorgs[1].new_head_num, U64::from(3));
+        assert_eq!(reorgs[1].affected_numbers, vec![U64::from(1), U64::from(2)]);
+        assert_eq!(
+            reorgs[1].replaced_hashes,
+            vec![*block_1b.hash(), *block_2b.hash()]
+        );
+        assert!(reorgs[1].timestamp >= reorgs[0].timestamp);
+
+        // both forks were walked back to block 0
+        assert_eq!(reorgs[0].common_ancestor, Some(*block_0.hash()));
+        assert_eq!(reorgs[1].common_ancestor, Some(*block_0.hash()));
+
+        // subscribers get the same reorgs
+        assert_eq!(reorg_receiver.try_recv().unwrap(), reorgs[0]);
+        assert_eq!(reorg_receiver.try_recv().unwrap(), reorgs[1]);
+        assert!(reorg_receiver.try_recv().is_err());
+
+        // observers get them too
+        assert_eq!(*observer.0.lock(), reorgs);
+
+        // only the most recent are kept
+        let block_4 = new_block(4, *block_3c.hash());
+        let block_4b = new_block(4, *block_3c.hash());
+
+        rpcs.try_cache_block(block_4, true).await.unwrap();
+        rpcs.try_cache_block(block_4b.clone(), true).await.unwrap();
+
+        let reorgs = rpcs.recent_reorgs();
+
+        assert_eq!(reorgs.len(), 2);
+        assert_eq!(reorgs[0].new_head, *block_3c.hash());
+        assert_eq!(reorgs[1].new_head, *block_4b.hash());
+
+        // a fork whose parent was never cached. the depth can't be known
+        let block_5x = new_block(5, H256::random());
+
+        rpcs.try_cache_block(block_5x.clone(), true).await.unwrap();
+
+        let reorg = rpcs.recent_reorgs().pop().unwrap();
+
+        assert_eq!(reorg.new_head, *block_5x.hash());
+        assert_eq!(reorg.old_head, *block_4b.hash());
+        assert_eq!(reorg.common_ancestor, None);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_rollback() {
+        let mut blocks: Vec<Web3ProxyBlock> = vec![];
+
+        for num in 0..=3u64 {
+            let parent_hash = blocks.last().map(|x| *x.hash()).unwrap_or_default();
+
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        let rpcs = Web3Rpcs {
+            recent_reorgs: RecentReorgs::new(2),
+            watch_head_block: None,
+            ..rpcs
+        };
+
+        let observer = Arc::new(RecordingObserver::default());
+
+        rpcs.add_reorg_observer(observer.clone());
+
+        for block in blocks.iter() {
+            rpcs.try_cache_block(block.clone(), true).await.unwrap();
+        }
+
+        // the chain went back to block 1
+        rpcs.rollback(&blocks[3], &blocks[1]).await;
+
+        assert!(rpcs.blocks_by_number.get(&U64::from(2)).is_none());
+        assert!(rpcs.blocks_by_number.get(&U64::from(3)).is_none());
+        assert_eq!(
+            rpcs.blocks_by_number.get(&U64::from(1)),
+            Some(*blocks[1].hash())
+        );
+
+        let reorgs = observer.0.lock().clone();
+
+        assert_eq!(reorgs.len(), 1);
+        assert_eq!(reorgs[0].depth, 2);
+        assert_eq!(reorgs[0].old_head, *blocks[3].hash());
+        assert_eq!(reorgs[0].new_head, *blocks[1].hash());
+        assert_eq!(reorgs[0].affected_numbers, vec![U64::from(2), U64::from(3)]);
+        assert_eq!(
+            reorgs[0].replaced_hashes,
+            vec![*blocks[2].hash(), *blocks[3].hash()]
+        );
+        assert_eq!(reorgs[0].common_ancestor, Some(*blocks[1].hash()));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_block_for_tag() {
+        let mut blocks: Vec<Web3ProxyBlock> = vec![];
+
+        for num in 0..=12u64 {
+            let parent_hash = blocks.last().map(|x| *x.hash()).unwrap_or_default();
+
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        // no servers are synced yet. this should error instead of panicking
+        assert!(matches!(
+            rpcs.block_for_tag(BlockNumber::Latest).await,
+            Err(Web3ProxyError::NoServersSynced)
+        ));
+        assert!(matches!(
+            rpcs.block_for_tag(BlockNumber::Finalized).await,
+            Err(Web3ProxyError::NoServersSynced)
+        ));
+
+        for block in blocks.iter() {
+            rpcs.try_cache_block(block.clone(), true).await.unwrap();
+        }
+
+        rpcs.watch_head_block
+            .as_ref()
+            .unwrap()
+            .send_replace(blocks.last().cloned());
+
+        for (tag, expected) in [
+            (BlockNumber::Latest, &blocks[12]),
+            (BlockNumber::Earliest, &blocks[0]),
+            (BlockNumber::Finalized, &blocks[2]),
+            (BlockNumber::Safe, &blocks[9]),
+            (BlockNumber::Number(5.into()), &blocks[5]),
+        ] {
+            let block = rpcs.block_for_tag(tag).await.unwrap();
+
+            assert_eq!(block.hash.as_ref(), Some(expected.hash()), "{:?}", tag);
+        }
+
+        // once the rpcs agree on a finalized block, it is used instead of guessing from the head
+        rpcs.watch_finalized_block
+            .send_replace(Some(blocks[8].clone()));
+
+        for tag in [BlockNumber::Finalized, BlockNumber::Safe] {
+            let block = rpcs.block_for_tag(tag).await.unwrap();
+
+            assert_eq!(block.hash.as_ref(), Some(blocks[8].hash()), "{:?}", tag);
+        }
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_future_block_grace() {
+        let mut blocks: Vec<Web3ProxyBlock> = vec![];
+
+        for num in 0..=6u64 {
+            let parent_hash = blocks.last().map(|x| *x.hash()).unwrap_or_default();
+
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                parent_hash,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, HashMap::new());
+
+        for block in blocks.iter() {
+            rpcs.try_cache_block(block.clone(), true).await.unwrap();
+        }
+
+        let head_sender = rpcs.watch_head_block.as_ref().unwrap();
+
+        head_sender.send_replace(Some(blocks[3].clone()));
+
+        // far past the head fails without waiting
+        let start = Instant::now();
+
+        assert!(matches!(
+            rpcs.cannonical_block(&U64::from(10)).await,
+            Err(Web3ProxyError::UnknownBlockNumber { .. })
+        ));
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        // a block that arrives during the wait is returned
+        let (x, _) = tokio::join!(rpcs.cannonical_block(&U64::from(5)), async {
+            sleep(Duration::from_millis(100)).await;
+            head_sender.send_replace(Some(blocks[4].clone()));
+            sleep(Duration::from_millis(100)).await;
+            head_sender.send_replace(Some(blocks[5].clone()));
+        });
+
+        let (block, depth) = x.unwrap();
+
+        assert_eq!(block.hash(), blocks[5].hash());
+        assert_eq!(depth, 0);
+
+        // a block that doesn't arrive in time is an error
+        let start = Instant::now();
+
+        assert!(matches!(
+            rpcs.cannonical_block(&U64::from(6)).await,
+            Err(Web3ProxyError::UnknownBlockNumber { .. })
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_highest_agreed_block() {
+        let new_block = |num: u64| -> Web3ProxyBlock {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(num.into()),
+                ..Default::default()
+            };
+
+            Arc::new(block).try_into().unwrap()
+        };
+
+        let block_10 = new_block(10);
+        let block_11 = new_block(11);
+        let block_11b = new_block(11);
+
+        // one rpc is ahead and one is on a fork. the quorum is on the older block
+        let blocks = [
+            block_10.clone(),
+            block_10.clone(),
+            block_11.clone(),
+            block_11b.clone(),
+        ];
+
+        assert_eq!(
+            highest_agreed_block(blocks.clone(), 2),
+            Some(block_10.clone())
+        );
+        assert_eq!(highest_agreed_block(blocks.clone(), 3), None);
+        assert!(matches!(
+            highest_agreed_block(blocks.clone(), 1),
+            Some(x) if *x.number() == U64::from(11)
+        ));
+
+        // chains without the "finalized" tag give no blocks at all
+        assert_eq!(highest_agreed_block([], 1), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Stop sending requests to an rpc that keeps failing. After a cooldown, one request is let through to test it.
+use crate::config::CircuitBreakerConfig;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// requests are sent like normal
+    Closed,
+    /// the rpc failed too many times. requests skip it until the cooldown is over
+    Open,
+    /// the cooldown is over. one request is allowed through to check if the rpc recovered
+    HalfOpen,
+}
+
+#[derive(Debug)]
+struct CircuitCounts {
+    /// consecutive failures since window_start
+    failures: u32,
+    window_start: Instant,
+    /// Some while the circuit is open or half-open
+    open_until: Option<Instant>,
+    /// when the half-open probe was let through. if it never finishes, another is allowed after a cooldown
+    probe_sent_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    counts: Mutex<CircuitCounts>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: &CircuitBreakerConfig) -> Self {
+        Self {
+            failure_threshold: config.failure_threshold.max(1),
+            window: Duration::from_millis(config.window_ms),
+            cooldown: Duration::from_millis(config.cooldown_ms),
+            counts: Mutex::new(CircuitCounts {
+                failures: 0,
+                window_start: Instant::now(),
+                open_until: None,
+                probe_sent_at: None,
+            }),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match self.counts.lock().open_until {
+            None => CircuitState::Closed,
+            Some(x) if Instant::now() < x => CircuitState::Open,
+            Some(_) => CircuitState::HalfOpen,
+        }
+    }
+
+    /// true if a request can be sent. in the half-open state, this lets only the probe through
+    pub fn try_acquire(&self) -> bool {
+        let now = Instant::now();
+
+        let mut counts = self.counts.lock();
+
+        match counts.open_until {
+            None => true,
+            Some(x) if now < x => false,
+            Some(_) => {
+                if let Some(probe_sent_at) = counts.probe_sent_at {
+                    if now.duration_since(probe_sent_at) < self.cooldown {
+                        return false;
+                    }
+                }
+
+                counts.probe_sent_at = Some(now);
+
+                true
+            }
+        }
+    }
+
+    /// the rpc answered. returns true if this closed the circuit
+    pub fn record_success(&self) -> bool {
+        let mut counts = self.counts.lock();
+
+        counts.failures = 0;
+        counts.probe_sent_at = None;
+
+        counts.open_until.take().is_some()
+    }
+
+    /// the rpc did not answer. returns true if this opened a closed circuit
+    pub fn record_failure(&self) -> bool {
+        let now = Instant::now();
+
+        let mut counts = self.counts.lock();
+
+        if counts.open_until.is_some() {
+            // the probe failed (or a request from before the circuit opened). wait another cooldown
+            counts.open_until = Some(now + self.cooldown);
+            counts.probe_sent_at = None;
+
+            return false;
+        }
+
+        if counts.failures == 0 || now.duration_since(counts.window_start) > self.window {
+            counts.failures = 1;
+            counts.window_start = now;
+        } else {
+            counts.failures += 1;
+        }
+
+        if counts.failures >= self.failure_threshold {
+            counts.failures = 0;
+            counts.open_until = Some(now + self.cooldown);
+
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::advance;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_circuit_breaker() {
+        let circuit_breaker = CircuitBreaker::new(&CircuitBreakerConfig {
+            failure_threshold: 3,
+            window_ms: 1_000,
+            cooldown_ms: 10_000,
+        });
+
+        // failures that are too far apart don't open the circuit
+        for _ in 0..3 {
+            assert!(!circuit_breaker.record_failure());
+            advance(Duration::from_millis(600)).await;
+            assert!(!circuit_breaker.record_failure());
+            advance(Duration::from_millis(1_100)).await;
+        }
+
+        // a success resets the count
+        assert!(!circuit_breaker.record_failure());
+        assert!(!circuit_breaker.record_failure());
+        assert!(!circuit_breaker.record_success());
+        assert!(!circuit_breaker.record_failure());
+        assert!(!circuit_breaker.record_failure());
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+        assert!(circuit_breaker.try_acquire());
+
+        assert!(circuit_breaker.record_failure());
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+        assert!(!circuit_breaker.try_acquire());
+
+        // after the cooldown, only one probe is allowed
+        advance(Duration::from_secs(10)).await;
+        assert_eq!(circuit_breaker.state(), CircuitState::HalfOpen);
+        assert!(circuit_breaker.try_acquire());
+        assert!(!circuit_breaker.try_acquire());
+
+        // a failed probe opens the circuit for another cooldown
+        assert!(!circuit_breaker.record_failure());
+        assert_eq!(circuit_breaker.state(), CircuitState::Open);
+        assert!(!circuit_breaker.try_acquire());
+
+        // a probe that never finishes doesn't keep the circuit half-open forever
+        advance(Duration::from_secs(10)).await;
+        assert!(circuit_breaker.try_acquire());
+        advance(Duration::from_secs(5)).await;
+        assert!(!circuit_breaker.try_acquire());
+        advance(Duration::from_secs(5)).await;
+        assert!(circuit_breaker.try_acquire());
+
+        // a successful probe closes the circuit
+        assert!(circuit_breaker.record_success());
+        assert_eq!(circuit_breaker.state(), CircuitState::Closed);
+        assert!(circuit_breaker.try_acquire());
+        assert!(circuit_breaker.try_acquire());
+    }
+}
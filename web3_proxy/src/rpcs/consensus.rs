@@ -1,22 +1,54 @@
-use super::blockchain::Web3ProxyBlock;
+use super::blockchain::{ArcBlock, Web3ProxyBlock};
 use super::many::Web3Rpcs;
 use super::one::Web3Rpc;
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
+use crate::frontend::authorization::SkipReason;
 use base64::engine::general_purpose;
 use derive_more::Constructor;
-use ethers::prelude::{H256, U64};
+use ethers::prelude::{Block, TxHash, H256, U64};
 use hashbrown::{HashMap, HashSet};
 use hdrhistogram::serialization::{Serializer, V2DeflateSerializer};
 use hdrhistogram::Histogram;
 use itertools::{Itertools, MinMaxResult};
 use moka::future::Cache;
-use serde::Serialize;
+use nanorand::Rng;
+use serde::{Deserialize, Serialize};
 use std::cmp::{Ordering, Reverse};
 use std::sync::{atomic, Arc};
 use std::time::Duration;
-use tokio::time::Instant;
+use tokio::sync::mpsc;
+use tokio::time::{timeout, Instant};
 use tracing::{debug, enabled, info, trace, warn, Level};
 
+/// an rpc that was too far behind must stay near the head for this long before it is trusted with the head block again.
+/// this keeps a node that is catching up from flapping in and out of the synced rpcs
+pub const SYNC_RECOVERY_WINDOW: Duration = Duration::from_secs(30);
+
+/// an rpc whose new heads can't be found on another rpc this many times in a row is left out of consensus.
+/// every new head from a demoted rpc is checked until one is found
+pub const MAX_UNVERIFIED_HEADS: u32 = 3;
+
+/// the rpc asked to verify a head might not have it yet. wait this long for it to catch up
+const VERIFY_HEAD_WAIT: Duration = Duration::from_secs(3);
+
+/// the result of a spawned `verify_head`. the consensus task gets these through `ConsensusFinder::take_head_verifications`
+#[derive(Debug)]
+pub struct HeadVerification {
+    pub rpc: Arc<Web3Rpc>,
+    pub head: Web3ProxyBlock,
+    /// None if it couldn't be checked
+    pub verified: Option<bool>,
+}
+
+/// rpcs that are missing from `ConsensusFinder::sync_status` are synced
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SyncStatus {
+    /// the rpc's head was further behind than `max_head_block_lag`
+    Syncing,
+    /// the rpc has been near the head since this time
+    Recovering(Instant),
+}
+
 #[derive(Clone, Debug, Serialize)]
 struct ConsensusRpcData {
     head_block_num: U64,
@@ -81,6 +113,18 @@ impl PartialOrd for RpcRanking {
     }
 }
 
+/// How to pick between heads that are on different chains
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConsensusStrategy {
+    /// After the merge, total difficulty stops increasing and some chains never report it.
+    /// Ties are broken by how many rpcs are on each block and then by their soft limits
+    #[default]
+    HighestBlockNumber,
+    /// For proof of work chains. If any of the heads are missing total difficulty, the block numbers are compared instead
+    HighestTotalDifficulty,
+}
+
 pub enum ShouldWaitForBlock {
     Ready,
     // BackupReady,
@@ -99,6 +143,8 @@ pub struct RankedRpcs {
     pub head_block: Web3ProxyBlock,
     pub num_synced: usize,
     pub backups_needed: bool,
+    /// a low confidence head from the startup window that did not meet the configured minimums
+    pub provisional: bool,
 
     inner: Vec<Arc<Web3Rpc>>,
 
@@ -122,12 +168,15 @@ impl RankedRpcs {
     //     todo!()
     // }
 
+    /// `recovering` rpcs are only trusted with blocks up to `max_lag_block`
     pub fn from_votes(
+        consensus_strategy: ConsensusStrategy,
         min_synced_rpcs: usize,
         min_sum_soft_limit: u32,
         max_lag_block: U64,
         votes: HashMap<Web3ProxyBlock, (HashSet<&Arc<Web3Rpc>>, u32)>,
         heads: HashMap<Arc<Web3Rpc>, Web3ProxyBlock>,
+        recovering: &HashSet<Arc<Web3Rpc>>,
     ) -> Option<Self> {
         // find the blocks that meets our min_sum_soft_limit and min_synced_rpcs
         let mut votes: Vec<_> = votes
@@ -144,11 +193,41 @@ impl RankedRpcs {
             })
             .collect();
 
+        // total difficulty is only compared if every block has it. during the merge, some nodes stopped reporting it
+        let use_total_difficulty = match consensus_strategy {
+            ConsensusStrategy::HighestBlockNumber => false,
+            ConsensusStrategy::HighestTotalDifficulty => {
+                let num_with_total_difficulty = votes
+                    .iter()
+                    .filter(|(block, ..)| block.total_difficulty().is_some())
+                    .count();
+
+                if num_with_total_difficulty < votes.len() {
+                    debug!(
+                        num_with_total_difficulty,
+                        num_votes = votes.len(),
+                        "inconsistent total difficulty. only comparing block numbers"
+                    );
+
+                    false
+                } else {
+                    true
+                }
+            }
+        };
+
         // sort the votes
-        votes.sort_by_key(|(block, sum_soft_limit, _)| {
+        votes.sort_by_key(|(block, sum_soft_limit, rpcs)| {
+            let total_difficulty = if use_total_difficulty {
+                block.total_difficulty().copied()
+            } else {
+                None
+            };
+
             (
+                Reverse(total_difficulty),
                 Reverse(*block.number()),
-                // TODO: block total difficulty (if we have it)
+                Reverse(rpcs.len()),
                 Reverse(*sum_soft_limit),
                 // TODO: median/peak latency here?
             )
@@ -164,7 +243,11 @@ impl RankedRpcs {
 
             // TODO: add all the unsynced rpcs
             for (x, x_head) in heads.iter() {
-                let data = ConsensusRpcData::new(x, x_head);
+                let mut data = ConsensusRpcData::new(x, x_head);
+
+                if recovering.contains(x) {
+                    data.head_block_num = data.head_block_num.min(max_lag_block);
+                }
 
                 rpc_data.insert(x.clone(), data);
 
@@ -189,6 +272,7 @@ impl RankedRpcs {
             let consensus = RankedRpcs {
                 backups_needed,
                 head_block: best_block,
+                provisional: false,
                 rpc_data,
                 inner: ranked_rpcs,
                 num_synced,
@@ -300,6 +384,17 @@ impl RankedRpcs {
             return false;
         }
 
+        self.rpc_skip_reason(min_block_needed, max_block_needed, rpc)
+            .is_none()
+    }
+
+    /// why the rpc will not work now. None if it will
+    pub fn rpc_skip_reason(
+        &self,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+        rpc: &Arc<Web3Rpc>,
+    ) -> Option<SkipReason> {
         if let Some(min_block_needed) = min_block_needed {
             if !self.has_block_data(rpc, min_block_needed) {
                 trace!(
@@ -307,7 +402,7 @@ impl RankedRpcs {
                     rpc,
                     min_block_needed,
                 );
-                return false;
+                return Some(self.missing_block_reason(rpc, min_block_needed));
             }
         }
 
@@ -318,7 +413,7 @@ impl RankedRpcs {
                     rpc,
                     max_block_needed,
                 );
-                return false;
+                return Some(self.missing_block_reason(rpc, max_block_needed));
             }
         }
 
@@ -326,11 +421,18 @@ impl RankedRpcs {
         if let Some(x) = rpc.hard_limit_until.as_ref() {
             if *x.borrow() > Instant::now() {
                 trace!("{} is rate limited. will not work now", rpc,);
-                return false;
+                return Some(SkipReason::RateLimited);
             }
         }
 
-        true
+        None
+    }
+
+    fn missing_block_reason(&self, rpc: &Web3Rpc, block_num: &U64) -> SkipReason {
+        match self.rpc_data.get(rpc) {
+            Some(x) if *block_num < x.oldest_block_num => SkipReason::Pruned,
+            _ => SkipReason::Behind,
+        }
     }
 
     // TODO: sum_hard_limit?
@@ -344,6 +446,18 @@ impl Web3Rpcs {
             .and_then(|x| x.borrow().clone())
     }
 
+    /// None until enough rpcs agree on a "finalized" block. Always None on chains without the tag
+    pub fn finalized_block(&self) -> Option<Web3ProxyBlock> {
+        self.watch_finalized_block.borrow().clone()
+    }
+
+    pub fn finalized_block_num(&self) -> Option<U64> {
+        self.watch_finalized_block
+            .borrow()
+            .as_ref()
+            .map(|x| *x.number())
+    }
+
     /// note: you probably want to use `head_block` instead
     /// TODO: return a ref?
     pub fn head_block_hash(&self) -> Option<H256> {
@@ -375,6 +489,38 @@ impl Web3Rpcs {
             0
         }
     }
+
+    /// the soft limits of the rpcs that are on the consensus head. compare with `min_sum_soft_limit`
+    pub fn synced_soft_limit(&self) -> u32 {
+        let consensus = self.watch_ranked_rpcs.borrow();
+
+        if let Some(consensus) = consensus.as_ref() {
+            let head_num = consensus.head_block.number();
+
+            consensus
+                .rpc_data
+                .iter()
+                .filter(|(_, x)| x.head_block_num >= *head_num)
+                .map(|(rpc, _)| rpc.soft_limit)
+                .sum()
+        } else {
+            0
+        }
+    }
+
+    pub fn min_sum_soft_limit(&self) -> u32 {
+        self.min_sum_soft_limit
+    }
+
+    /// true if the consensus head has enough rpcs and soft limit behind it. provisional heads do not count
+    pub fn meets_minimums(&self) -> bool {
+        let num_synced = match self.watch_ranked_rpcs.borrow().as_ref() {
+            Some(x) if !x.provisional => x.num_synced,
+            _ => return false,
+        };
+
+        num_synced >= self.min_synced_rpcs && self.synced_soft_limit() >= self.min_sum_soft_limit
+    }
 }
 
 type FirstSeenCache = Cache<H256, Instant>;
@@ -382,12 +528,29 @@ type FirstSeenCache = Cache<H256, Instant>;
 /// A ConsensusConnections builder that tracks all connection heads across multiple groups of servers
 pub struct ConsensusFinder {
     rpc_heads: HashMap<Arc<Web3Rpc>, Web3ProxyBlock>,
+    /// when each rpc last reported its head. rpcs that go quiet for longer than max_head_block_age are removed
+    head_updated_at: HashMap<Arc<Web3Rpc>, Instant>,
     /// no consensus if the best known block is too old
     max_head_block_age: Option<Duration>,
     /// tier 0 will be prefered as long as the distance between it and the other tiers is <= max_tier_lag
     max_head_block_lag: Option<U64>,
     /// Block Hash -> First Seen Instant. used to track rpc.head_delay. The same cache should be shared between all ConnectionsGroups
     first_seen: FirstSeenCache,
+    /// if a new head is being held back, refresh again at this time
+    delayed_until: Option<Instant>,
+    /// rpcs that were recently too far behind. see `SYNC_RECOVERY_WINDOW`
+    sync_status: HashMap<Arc<Web3Rpc>, SyncStatus>,
+    /// rpcs whose recent heads could not be found on another rpc -> how many times in a row. see `MAX_UNVERIFIED_HEADS`
+    unverified_heads: HashMap<Arc<Web3Rpc>, u32>,
+    /// verification runs in a spawned task so that a slow rpc can't stall consensus. results come back here
+    head_verification_sender: mpsc::UnboundedSender<HeadVerification>,
+    head_verification_receiver: Option<mpsc::UnboundedReceiver<HeadVerification>>,
+    /// rpcs that are not on the consensus chain -> when they left it and if they have been removed for it
+    forked_since: HashMap<Arc<Web3Rpc>, (Instant, bool)>,
+    /// the provisional head window is measured from here
+    started_at: Instant,
+    /// the highest head that already had a warning about no consensus. one outage shouldn't warn on every block
+    no_consensus_warned: Option<H256>,
 }
 
 impl ConsensusFinder {
@@ -397,14 +560,40 @@ impl ConsensusFinder {
 
         let rpc_heads = HashMap::new();
 
+        let (head_verification_sender, head_verification_receiver) = mpsc::unbounded_channel();
+
         Self {
             rpc_heads,
+            head_updated_at: HashMap::new(),
             max_head_block_age,
             max_head_block_lag,
             first_seen,
+            delayed_until: None,
+            sync_status: HashMap::new(),
+            unverified_heads: HashMap::new(),
+            head_verification_sender,
+            head_verification_receiver: Some(head_verification_receiver),
+            forked_since: HashMap::new(),
+            started_at: Instant::now(),
+            no_consensus_warned: None,
         }
     }
 
+    /// the results of spawned head verifications. pass them to `record_head_verification_result`. this can only be taken once
+    pub(super) fn take_head_verifications(
+        &mut self,
+    ) -> Option<mpsc::UnboundedReceiver<HeadVerification>> {
+        self.head_verification_receiver.take()
+    }
+
+    /// during the startup window, a head without enough coverage is published as provisional instead of not at all
+    fn in_provisional_window(&self, web3_rpcs: &Web3Rpcs) -> bool {
+        web3_rpcs
+            .provisional_head_window
+            .map(|x| self.started_at.elapsed() < x)
+            .unwrap_or(false)
+    }
+
     pub fn len(&self) -> usize {
         self.rpc_heads.len()
     }
@@ -413,6 +602,47 @@ impl ConsensusFinder {
         self.rpc_heads.is_empty()
     }
 
+    /// how long to wait for new blocks before refreshing on our own.
+    /// shorter than `max_wait` if a new head is being held back
+    pub fn next_refresh(&self, max_wait: Duration) -> Duration {
+        match self.delayed_until {
+            Some(x) => x.saturating_duration_since(Instant::now()).min(max_wait),
+            None => max_wait,
+        }
+    }
+
+    /// returns when to check again if the new head should not be published yet.
+    /// the first head and heads that a majority of rpcs agree on are never delayed.
+    fn head_publish_delay(
+        &self,
+        web3_rpcs: &Web3Rpcs,
+        new_ranked_rpcs: &RankedRpcs,
+    ) -> Option<Instant> {
+        let delay = web3_rpcs.head_publish_delay?;
+
+        let new_head_block = &new_ranked_rpcs.head_block;
+
+        if let Some(old_ranked_rpcs) = web3_rpcs.watch_ranked_rpcs.borrow().as_ref() {
+            if old_ranked_rpcs.head_block.hash() == new_head_block.hash() {
+                return None;
+            }
+        } else {
+            return None;
+        }
+
+        if new_ranked_rpcs.num_synced > 1 && new_ranked_rpcs.num_synced * 2 > self.len() {
+            return None;
+        }
+
+        let publish_at = self.first_seen.get(new_head_block.hash())? + delay;
+
+        if publish_at > Instant::now() {
+            Some(publish_at)
+        } else {
+            None
+        }
+    }
+
     /// `connection_heads` is a mapping of rpc_names to head block hashes.
     /// self.blockchain_map is a mapping of hashes to the complete ArcBlock.
     /// TODO: return something?
@@ -428,18 +658,54 @@ impl ConsensusFinder {
             .await
             .web3_context("error while finding consensus head block!")?
         {
-            None => return Ok(false),
+            None => {
+                // once the startup window is over, a provisional head is not trusted any longer
+                let provisional = web3_rpcs
+                    .watch_ranked_rpcs
+                    .borrow()
+                    .as_ref()
+                    .map(|x| x.provisional)
+                    .unwrap_or(false);
+
+                if provisional && !self.in_provisional_window(web3_rpcs) {
+                    warn!("provisional head expired without consensus");
+
+                    web3_rpcs.watch_ranked_rpcs.send_replace(None);
+
+                    if let Some(x) = web3_rpcs.watch_head_block.as_ref() {
+                        x.send_replace(None);
+                    }
+                }
+
+                return Ok(false);
+            }
             Some(x) => x,
         };
 
         trace!(?new_ranked_rpcs);
 
+        if new_ranked_rpcs.provisional {
+            debug!(head_block=%new_ranked_rpcs.head_block, num_synced=%new_ranked_rpcs.num_synced, "provisional head");
+        }
+
+        if let Some(delayed_until) = self.head_publish_delay(web3_rpcs, &new_ranked_rpcs) {
+            trace!(head_block=%new_ranked_rpcs.head_block, "delaying new head block");
+            self.delayed_until = Some(delayed_until);
+            return Ok(false);
+        }
+
+        self.delayed_until = None;
+
         let watch_consensus_head_sender = web3_rpcs.watch_head_block.as_ref().unwrap();
         // TODO: think more about the default for tiers
         let best_tier = self.best_tier().unwrap_or_default();
         let worst_tier = self.worst_tier().unwrap_or_default();
         let backups_needed = new_ranked_rpcs.backups_needed;
         let consensus_head_block = new_ranked_rpcs.head_block.clone();
+
+        // this runs on every refresh so that rpcs catching up are seen even if the head did not change
+        self.record_head_lag_blocks(&consensus_head_block);
+
         let num_consensus_rpcs = new_ranked_rpcs.num_active_rpcs();
         let num_active_rpcs = self.len();
         let total_rpcs = web3_rpcs.len();
@@ -450,6 +716,14 @@ impl ConsensusFinder {
             .watch_ranked_rpcs
             .send_replace(Some(new_ranked_rpcs.clone()));
 
+        if old_ranked_rpcs
+            .as_ref()
+            .map(|x| x.head_block.hash() != consensus_head_block.hash())
+            .unwrap_or(true)
+        {
+            self.record_head_lag(&consensus_head_block);
+        }
+
         let backups_voted_str = if backups_needed { "B " } else { "" };
 
         let rpc_head_str = if let Some(rpc) = rpc.as_ref() {
@@ -563,7 +837,10 @@ impl ConsensusFinder {
                             warn!("Backup RPCs are in use!");
                         }
 
-                        // TODO: tell save_block to remove any higher block numbers from the cache. not needed because we have other checks on requested blocks being > head, but still seems like a good idea
+                        web3_rpcs
+                            .rollback(old_head_block, &consensus_head_block)
+                            .await;
+
                         let consensus_head_block = web3_rpcs
                             .try_cache_block(consensus_head_block, true)
                             .await
@@ -609,6 +886,130 @@ impl ConsensusFinder {
         Ok(true)
     }
 
+    /// track which rpcs are too far behind and which have been near the head long enough to be trusted again.
+    /// returns the rpcs that are near the head but still recovering
+    fn update_sync_status(&mut self, max_lag_block_number: &U64) -> HashSet<Arc<Web3Rpc>> {
+        let now = Instant::now();
+
+        // rpcs that disconnected or were removed by a config reload
+        let rpc_heads = &self.rpc_heads;
+
+        self.sync_status
+            .retain(|rpc, _| rpc_heads.contains_key(rpc));
+
+        for (rpc, rpc_head) in self.rpc_heads.iter() {
+            if rpc_head.number() < max_lag_block_number {
+                if self.sync_status.insert(rpc.clone(), SyncStatus::Syncing)
+                    != Some(SyncStatus::Syncing)
+                {
+                    debug!(%rpc, %rpc_head, "rpc is syncing");
+                }
+                continue;
+            }
+
+            match self.sync_status.get(rpc) {
+                None => {}
+                Some(SyncStatus::Syncing) => {
+                    debug!(%rpc, %rpc_head, "rpc caught up. waiting before trusting it");
+                    self.sync_status
+                        .insert(rpc.clone(), SyncStatus::Recovering(now));
+                }
+                Some(SyncStatus::Recovering(since)) => {
+                    if now.saturating_duration_since(*since) >= SYNC_RECOVERY_WINDOW {
+                        info!(%rpc, %rpc_head, "rpc is synced");
+                        self.sync_status.remove(rpc);
+                    }
+                }
+            }
+        }
+
+        self.sync_status
+            .iter()
+            .filter(|(_, x)| matches!(x, SyncStatus::Recovering(_)))
+            .map(|(rpc, _)| rpc.clone())
+            .collect()
+    }
+
+    /// track how long each rpc's head has not been on the consensus chain.
+    /// returns the rpcs that have been on a minority fork for longer than `minority_fork_timeout`
+    fn update_fork_status(
+        &mut self,
+        web3_rpcs: &Web3Rpcs,
+        consensus_head_block: &Web3ProxyBlock,
+    ) -> HashSet<Arc<Web3Rpc>> {
+        let timeout = match web3_rpcs.minority_fork_timeout {
+            None => return HashSet::new(),
+            Some(x) => x,
+        };
+
+        let now = Instant::now();
+
+        // rpcs that are too far behind are syncing, not forked
+        let max_lag_block_number = consensus_head_block
+            .number()
+            .saturating_sub(web3_rpcs.max_head_block_lag);
+
+        let rpc_heads = &self.rpc_heads;
+
+        self.forked_since
+            .retain(|rpc, _| rpc_heads.contains_key(rpc));
+
+        let mut forked = HashSet::new();
+
+        for (rpc, rpc_head) in rpc_heads.iter() {
+            // blocks missing from the cache are not counted as a fork
+            if *rpc_head.number() < max_lag_block_number
+                || web3_rpcs.on_same_chain(rpc_head, consensus_head_block) != Some(false)
+            {
+                if let Some((_, true)) = self.forked_since.remove(rpc) {
+                    info!(%rpc, %rpc_head, "rpc rejoined the consensus chain");
+                }
+                continue;
+            }
+
+            let (since, removed) = self.forked_since.entry(rpc.clone()).or_insert_with(|| {
+                debug!(%rpc, %rpc_head, "rpc is not on the consensus chain");
+                (now, false)
+            });
+
+            if now.saturating_duration_since(*since) >= timeout {
+                if !*removed {
+                    warn!(%rpc, %rpc_head, con=%consensus_head_block, "rpc has been on a minority fork too long. removing it until it rejoins");
+                    *removed = true;
+                }
+
+                forked.insert(rpc.clone());
+            }
+        }
+
+        forked
+    }
+
+    /// count which rpcs were on the new consensus head and which were behind it
+    fn record_head_lag(&self, consensus_head_block: &Web3ProxyBlock) {
+        for (rpc, rpc_head) in self.rpc_heads.iter() {
+            if rpc_head.number() >= consensus_head_block.number() {
+                rpc.on_head_count.fetch_add(1, atomic::Ordering::Relaxed);
+                rpc.lifetime_stats.record_consensus_head();
+            } else {
+                rpc.behind_head_count
+                    .fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// how many blocks each rpc is behind the consensus head
+    fn record_head_lag_blocks(&self, consensus_head_block: &Web3ProxyBlock) {
+        for (rpc, rpc_head) in self.rpc_heads.iter() {
+            let lag = consensus_head_block
+                .number()
+                .saturating_sub(*rpc_head.number());
+
+            rpc.head_lag_blocks
+                .store(lag.as_u64(), atomic::Ordering::Relaxed);
+        }
+    }
+
     pub(super) async fn process_block_from_rpc(
         &mut self,
         web3_rpcs: &Web3Rpcs,
@@ -630,9 +1031,35 @@ impl ConsensusFinder {
     }
 
     fn remove(&mut self, rpc: &Arc<Web3Rpc>) -> Option<Web3ProxyBlock> {
+        self.head_updated_at.remove(rpc);
+
         self.rpc_heads.remove(rpc)
     }
 
+    /// remove rpcs that have not reported a head within max_head_block_age. a stalled subscription keeps its last head forever otherwise.
+    /// they are added back on their next head
+    fn remove_stale_heads(&mut self) {
+        let max_age = match self.max_head_block_age {
+            None => return,
+            Some(x) => x,
+        };
+
+        let now = Instant::now();
+
+        let stale: Vec<_> = self
+            .head_updated_at
+            .iter()
+            .filter(|(_, updated_at)| now.saturating_duration_since(**updated_at) > max_age)
+            .map(|(rpc, _)| rpc.clone())
+            .collect();
+
+        for rpc in stale {
+            if let Some(rpc_head) = self.remove(&rpc) {
+                warn!(%rpc, %rpc_head, ?max_age, "rpc has not sent a new head. removing it until it does");
+            }
+        }
+    }
+
     async fn insert(&mut self, rpc: Arc<Web3Rpc>, block: Web3ProxyBlock) -> Option<Web3ProxyBlock> {
         let first_seen = self
             .first_seen
@@ -648,6 +1075,8 @@ impl ConsensusFinder {
             .await
             .record_secs(latency.as_secs_f32());
 
+        self.head_updated_at.insert(rpc.clone(), Instant::now());
+
         // update the local mapping of rpc -> block
         self.rpc_heads.insert(rpc, block)
     }
@@ -663,6 +1092,16 @@ impl ConsensusFinder {
         // add the rpc's block to connection_heads, or remove the rpc from connection_heads
         let changed = match rpc_head_block {
             Some(mut rpc_head_block) => {
+                if web3_connections.is_unreachable_orphan(&rpc_head_block) {
+                    warn!(%rpc, %rpc_head_block, "head block is too far past the consensus head to reconnect. ignoring it");
+
+                    web3_connections
+                        .orphans_dropped
+                        .fetch_add(1, atomic::Ordering::Relaxed);
+
+                    return Ok(self.remove(&rpc).is_some());
+                }
+
                 // we don't know if its on the heaviest chain yet
                 rpc_head_block = web3_connections
                     .try_cache_block(rpc_head_block, false)
@@ -676,6 +1115,24 @@ impl ConsensusFinder {
                     }
                 }
 
+                if let Some(sample_rate) = web3_connections.head_verification_sample_rate {
+                    let is_new_hash = self
+                        .rpc_heads
+                        .get(&rpc)
+                        .map_or(true, |x| x.hash() != rpc_head_block.hash());
+
+                    if is_new_hash && self.should_verify_head(&rpc, sample_rate) {
+                        let verified =
+                            self.spawn_verify_head(&rpc, &rpc_head_block, web3_connections);
+
+                        // a demoted rpc's heads are ignored until `record_head_verification_result` gets one that checks out
+                        if self.record_head_verification(&rpc, verified) {
+                            debug!(%rpc, %rpc_head_block, "ignoring head from an rpc with unverified heads");
+                            return Ok(self.remove(&rpc).is_some());
+                        }
+                    }
+                }
+
                 if let Some(prev_block) = self.insert(rpc, rpc_head_block.clone()).await {
                     // false if this block was already sent by this rpc
                     // true if new block for this rpc
@@ -695,6 +1152,103 @@ impl ConsensusFinder {
         Ok(changed)
     }
 
+    fn is_demoted(&self, rpc: &Arc<Web3Rpc>) -> bool {
+        self.unverified_heads
+            .get(rpc)
+            .map_or(false, |x| *x >= MAX_UNVERIFIED_HEADS)
+    }
+
+    /// demoted rpcs are checked on every new head. everyone else is sampled
+    fn should_verify_head(&self, rpc: &Arc<Web3Rpc>, sample_rate: u32) -> bool {
+        self.is_demoted(rpc)
+            || sample_rate <= 1
+            || nanorand::tls_rng().generate_range(0..sample_rate) == 0
+    }
+
+    /// start checking the head against the highest other rpc. the result is sent to `head_verification_sender`.
+    /// a head that is far past every other rpc is a lie and returns Some(false) without asking
+    fn spawn_verify_head(
+        &self,
+        rpc: &Arc<Web3Rpc>,
+        head: &Web3ProxyBlock,
+        web3_rpcs: &Web3Rpcs,
+    ) -> Option<bool> {
+        // the highest other rpc is the most likely to have the block
+        let (other, other_head) = self
+            .rpc_heads
+            .iter()
+            .filter(|(x, _)| *x != rpc && !self.is_demoted(x))
+            .max_by_key(|(_, x)| x.number())?;
+
+        if *head.number() > *other_head.number() + web3_rpcs.max_head_block_lag {
+            warn!(%rpc, %head, %other, "head block is too far past every other rpc");
+
+            return Some(false);
+        }
+
+        let rpc = rpc.clone();
+        let head = head.clone();
+        let other = other.clone();
+        let head_verification_sender = self.head_verification_sender.clone();
+
+        tokio::spawn(async move {
+            let verified = verify_head(&rpc, &head, &other).await;
+
+            // the receiver only stops when consensus does
+            let _ = head_verification_sender.send(HeadVerification {
+                rpc,
+                head,
+                verified,
+            });
+        });
+
+        None
+    }
+
+    /// handle a result from a spawned head verification. returns true if the rpc's head changed
+    pub(super) async fn record_head_verification_result(&mut self, x: HeadVerification) -> bool {
+        let was_demoted = self.is_demoted(&x.rpc);
+
+        let demoted = self.record_head_verification(&x.rpc, x.verified);
+
+        match (was_demoted, demoted) {
+            (false, true) => self.remove(&x.rpc).is_some(),
+            (true, false) => {
+                // the head that was just verified was ignored when it arrived
+                self.insert(x.rpc, x.head).await;
+
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// returns true if the rpc is demoted and its head should be ignored.
+    /// an unknown result doesn't change the count
+    fn record_head_verification(&mut self, rpc: &Arc<Web3Rpc>, verified: Option<bool>) -> bool {
+        match verified {
+            Some(true) => {
+                if let Some(count) = self.unverified_heads.remove(rpc) {
+                    if count >= MAX_UNVERIFIED_HEADS {
+                        info!(%rpc, "head verified. rpc is back in consensus");
+                    }
+                }
+            }
+            Some(false) => {
+                let count = self.unverified_heads.entry(rpc.clone()).or_default();
+
+                *count += 1;
+
+                if *count == MAX_UNVERIFIED_HEADS {
+                    warn!(%rpc, count, "too many unverified heads. removing rpc from consensus until one is verified");
+                }
+            }
+            None => {}
+        }
+
+        self.is_demoted(rpc)
+    }
+
     pub async fn update_tiers(&mut self) -> Web3ProxyResult<()> {
         match self.rpc_heads.len() {
             0 => {}
@@ -792,6 +1346,8 @@ impl ConsensusFinder {
         &mut self,
         web3_rpcs: &Web3Rpcs,
     ) -> Web3ProxyResult<Option<RankedRpcs>> {
+        self.remove_stale_heads();
+
         self.update_tiers().await?;
 
         let minmax_block = self.rpc_heads.values().minmax_by_key(|&x| x.number());
@@ -802,6 +1358,7 @@ impl ConsensusFinder {
             MinMaxResult::MinMax(min, max) => (min, max),
         };
 
+        let highest_block_hash = *highest_block.hash();
         let highest_block_number = highest_block.number();
 
         trace!("highest_block_number: {}", highest_block_number);
@@ -809,19 +1366,25 @@ impl ConsensusFinder {
         trace!("lowest_block_number: {}", lowest_block.number());
 
         // TODO: move this default. should be in config, not here
-        let max_lag_block_number = highest_block_number
-            .saturating_sub(self.max_head_block_lag.unwrap_or_else(|| U64::from(5)));
+        let max_head_block_lag = self.max_head_block_lag.unwrap_or_else(|| U64::from(5));
+
+        // parents are only walked back this far while looking for blocks that enough rpcs agree on
+        let max_lag_block_number = highest_block_number.saturating_sub(max_head_block_lag);
 
         trace!("max_lag_block_number: {}", max_lag_block_number);
 
         let lowest_block_number = lowest_block.number().max(&max_lag_block_number);
 
+        let recovering = self.update_sync_status(&max_lag_block_number);
+
         // TODO: should lowest block number be set such that the rpc won't ever go backwards?
         trace!("safe lowest_block_number: {}", lowest_block_number);
 
         let num_known = self.rpc_heads.len();
 
-        if num_known < web3_rpcs.min_synced_rpcs {
+        let in_provisional_window = self.in_provisional_window(web3_rpcs);
+
+        if num_known < web3_rpcs.min_synced_rpcs && !in_provisional_window {
             // this keeps us from serving requests when the proxy first starts
             info!(%num_known, min_synced_rpcs=%web3_rpcs.min_synced_rpcs, "not enough servers known");
             return Ok(None);
@@ -837,6 +1400,11 @@ impl ConsensusFinder {
         for (rpc, rpc_head) in self.rpc_heads.iter() {
             let mut block_to_check = rpc_head.clone();
 
+            if recovering.contains(rpc) {
+                // rpcs that just caught up do not get a vote until they have stayed near the head
+                continue;
+            }
+
             while block_to_check.number() >= lowest_block_number {
                 if let Some(max_age) = self.max_head_block_age {
                     if block_to_check.age() > max_age {
@@ -875,25 +1443,97 @@ impl ConsensusFinder {
             }
         }
 
+        // the votes are consumed by from_votes. keep a copy in case a provisional head is needed
+        let provisional_votes = if in_provisional_window {
+            Some(backup_votes.clone())
+        } else {
+            None
+        };
+
         // we finished processing all tiers. check for primary results (if anything but the last tier found consensus, we already returned above)
-        if let Some(consensus) = RankedRpcs::from_votes(
+        let consensus = RankedRpcs::from_votes(
+            web3_rpcs.consensus_strategy,
             web3_rpcs.min_synced_rpcs,
             web3_rpcs.min_sum_soft_limit,
             max_lag_block_number,
             primary_votes,
             self.rpc_heads.clone(),
-        ) {
-            return Ok(Some(consensus));
+            &recovering,
+        )
+        .or_else(|| {
+            // primary votes didn't work. hopefully backup tiers are synced
+            RankedRpcs::from_votes(
+                web3_rpcs.consensus_strategy,
+                web3_rpcs.min_synced_rpcs,
+                web3_rpcs.min_sum_soft_limit,
+                max_lag_block_number,
+                backup_votes,
+                self.rpc_heads.clone(),
+                &recovering,
+            )
+        })
+        .or_else(|| {
+            // not enough coverage yet. during the startup window, take whatever head is highest
+            let mut x = RankedRpcs::from_votes(
+                web3_rpcs.consensus_strategy,
+                1,
+                0,
+                max_lag_block_number,
+                provisional_votes?,
+                self.rpc_heads.clone(),
+                &recovering,
+            )?;
+
+            x.provisional = true;
+
+            Some(x)
+        });
+
+        let mut consensus = match consensus {
+            None => {
+                // walking back max_head_block_lag blocks wasn't enough for min_synced_rpcs/min_sum_soft_limit
+                if self.no_consensus_warned == Some(highest_block_hash) {
+                    debug!(%highest_block_hash, %max_head_block_lag, "still no consensus");
+                } else {
+                    warn!(
+                        %highest_block_hash,
+                        %max_head_block_lag,
+                        %num_known,
+                        "no consensus within max_head_block_lag"
+                    );
+
+                    self.no_consensus_warned = Some(highest_block_hash);
+                }
+
+                return Ok(None);
+            }
+            Some(x) => x,
+        };
+
+        self.no_consensus_warned = None;
+
+        let forked = self.update_fork_status(web3_rpcs, &consensus.head_block);
+
+        if !forked.is_empty() {
+            consensus.inner.retain(|x| !forked.contains(x));
+
+            // num_synced was counted before the forked rpcs were removed
+            let head_block = &consensus.head_block;
+
+            consensus.num_synced = consensus
+                .inner
+                .iter()
+                .filter(|x| {
+                    self.rpc_heads.get(*x).map_or(false, |rpc_head| {
+                        rpc_head.number() >= head_block.number()
+                            && web3_rpcs.on_same_chain(rpc_head, head_block) == Some(true)
+                    })
+                })
+                .count()
+                .min(consensus.num_synced);
         }
 
-        // primary votes didn't work. hopefully backup tiers are synced
-        Ok(RankedRpcs::from_votes(
-            web3_rpcs.min_synced_rpcs,
-            web3_rpcs.min_sum_soft_limit,
-            max_lag_block_number,
-            backup_votes,
-            self.rpc_heads.clone(),
-        ))
+        Ok(Some(consensus))
     }
 
     pub fn best_tier(&self) -> Option<u32> {
@@ -911,10 +1551,1488 @@ impl ConsensusFinder {
     }
 }
 
-#[cfg(test)]
-mod test {
-    // #[test]
-    // fn test_simplest_case_consensus_head_connections() {
-    //     todo!();
-    // }
+/// ask `other` for the head by its hash and check that it has the same number and parent.
+/// None if it couldn't be checked
+async fn verify_head(
+    rpc: &Arc<Web3Rpc>,
+    head: &Web3ProxyBlock,
+    other: &Arc<Web3Rpc>,
+) -> Option<bool> {
+    // the first rpc to announce a block is ahead of everyone else. give the other rpc a chance to catch up
+    if let Some(other_head) = other.head_block.as_ref() {
+        let mut other_head = other_head.subscribe();
+
+        let caught_up = timeout(VERIFY_HEAD_WAIT, async {
+            loop {
+                if other_head
+                    .borrow_and_update()
+                    .as_ref()
+                    .map_or(false, |x| x.number() >= head.number())
+                {
+                    return true;
+                }
+
+                if other_head.changed().await.is_err() {
+                    return false;
+                }
+            }
+        })
+        .await;
+
+        if !matches!(caught_up, Ok(true)) {
+            trace!(%rpc, %head, %other, "other rpc didn't catch up. unable to verify head");
+            return None;
+        }
+    }
+
+    let get_block = |hash: H256| async move {
+        other
+            .internal_request::<_, Option<ArcBlock>>(
+                "eth_getBlockByHash",
+                &(hash, false),
+                None,
+                Some(1),
+                Some(Duration::from_secs(1)),
+            )
+            .await
+            .map_err(|err| debug!(%rpc, %other, ?err, "unable to verify head"))
+            .ok()
+    };
+
+    let block = get_block(*head.hash()).await?;
+
+    let verified = match check_head_by_hash(head, block.as_deref()) {
+        Some(x) => x,
+        None => {
+            // an uncle or a short fork isn't a lie. its parent is still on the other rpc's chain
+            let parent = get_block(*head.parent_hash()).await?;
+
+            if parent.map_or(false, |x| x.number.map(|x| x + 1) == Some(*head.number())) {
+                trace!(%rpc, %head, %other, "head is on a fork");
+                return None;
+            }
+
+            false
+        }
+    };
+
+    if !verified {
+        warn!(%rpc, %head, %other, "head block could not be verified");
+    }
+
+    Some(verified)
+}
+
+/// compare a head with the block that another rpc has for the same hash. None if the other rpc doesn't have it
+fn check_head_by_hash(head: &Web3ProxyBlock, found: Option<&Block<TxHash>>) -> Option<bool> {
+    let found = found?;
+
+    Some(found.number == Some(*head.number()) && found.parent_hash == *head.parent_hash())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_head_by_hash, ConsensusFinder, ConsensusStrategy, HeadVerification, RankedRpcs,
+        MAX_UNVERIFIED_HEADS, SYNC_RECOVERY_WINDOW,
+    };
+    use crate::rpcs::blockchain::Web3ProxyBlock;
+    use crate::rpcs::many::Web3Rpcs;
+    use crate::rpcs::one::Web3Rpc;
+    use ethers::types::{Block, H256, U256};
+    use hashbrown::{HashMap, HashSet};
+    use latency::PeakEwmaLatency;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::watch;
+
+    // #[test]
+    // fn test_simplest_case_consensus_head_connections() {
+    //     todo!();
+    // }
+
+    #[test_log::test(tokio::test)]
+    async fn test_head_publish_delay() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 2,
+            ..Default::default()
+        };
+
+        // two competing children of block_0. block_1a is uncled quickly
+        let block_1a = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_1b = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1a: Web3ProxyBlock = Arc::new(block_1a).try_into().unwrap();
+        let block_1b: Web3ProxyBlock = Arc::new(block_1b).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let web3_rpcs = Web3Rpcs {
+            head_publish_delay: Some(Duration::from_secs(60)),
+            ..web3_rpcs
+        };
+
+        let head_hash = || {
+            watch_consensus_head_receiver
+                .borrow()
+                .as_ref()
+                .map(|x: &Web3ProxyBlock| *x.hash())
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        // the first head is never delayed
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(head_hash(), Some(*block_0.hash()));
+
+        // only 1 of 3 rpcs is on block_1a. it should be held back
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1a.clone()), rpcs[0].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(head_hash(), Some(*block_0.hash()));
+        assert!(consensus_finder.next_refresh(Duration::from_secs(120)) <= Duration::from_secs(60));
+
+        // block_1a is uncled before the delay is over
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1b.clone()), rpcs[0].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(head_hash(), Some(*block_0.hash()));
+
+        // once most rpcs agree, the head is published immediately
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1b.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(head_hash(), Some(*block_1b.hash()));
+        assert_eq!(
+            consensus_finder.next_refresh(Duration::from_secs(120)),
+            Duration::from_secs(120)
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_head_lag_counts() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 2,
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_2 = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let block_2: Web3ProxyBlock = Arc::new(block_2).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["fast", "slow"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let (fast, slow) = (rpcs[0].clone(), rpcs[1].clone());
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        // both rpcs are on block_0. fast was first, so the head is published with slow not yet known
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(fast.head_lag_counts(), (1, 0));
+        assert_eq!(slow.head_lag_counts(), (0, 0));
+
+        // fast defines the next two heads. slow is behind for both
+        for block in [&block_1, &block_2] {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block.clone()), fast.clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(fast.head_lag_counts(), (3, 0));
+        assert_eq!(slow.head_lag_counts(), (0, 2));
+
+        // slow catching up does not publish a new head, so nothing is counted
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2.clone()), slow.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(fast.head_lag_counts(), (3, 0));
+        assert_eq!(slow.head_lag_counts(), (0, 2));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_head_lag_blocks() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 2,
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_2 = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let block_2: Web3ProxyBlock = Arc::new(block_2).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["fast", "medium", "slow"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let (fast, medium, slow) = (rpcs[0].clone(), rpcs[1].clone(), rpcs[2].clone());
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(fast.head_lag_blocks(), 0);
+        assert_eq!(medium.head_lag_blocks(), 0);
+        assert_eq!(slow.head_lag_blocks(), 0);
+
+        // fast moves the head to block_2
+        for block in [&block_1, &block_2] {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block.clone()), fast.clone())
+                .await
+                .unwrap();
+        }
+
+        // medium gets part of the way there. the head does not change, but the lag does
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1.clone()), medium.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(fast.head_lag_blocks(), 0);
+        assert_eq!(medium.head_lag_blocks(), 1);
+        assert_eq!(slow.head_lag_blocks(), 2);
+
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2.clone()), slow.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(medium.head_lag_blocks(), 1);
+        assert_eq!(slow.head_lag_blocks(), 0);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_mixed_total_difficulty() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let parent_hash = H256::random();
+
+        // two forks at the same height
+        let new_block = |total_difficulty: Option<u64>| -> Web3ProxyBlock {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(1.into()),
+                parent_hash,
+                timestamp: now,
+                total_difficulty: total_difficulty.map(Into::into),
+                ..Default::default()
+            };
+
+            Arc::new(block).try_into().unwrap()
+        };
+
+        let new_rpc = |name: &str, soft_limit: u32| {
+            Arc::new(Web3Rpc {
+                name: name.to_string(),
+                soft_limit,
+                automatic_block_limit: false,
+                block_data_limit: u64::MAX.into(),
+                peak_latency: Some(PeakEwmaLatency::spawn(
+                    Duration::from_secs(1),
+                    4,
+                    Duration::from_secs(1),
+                )),
+                ..Default::default()
+            })
+        };
+
+        // heavy has more work, but light has more soft limit
+        let heavy = new_rpc("heavy", 1);
+        let light = new_rpc("light", 2);
+
+        let find_head = |consensus_strategy: ConsensusStrategy,
+                         heavy_block: &Web3ProxyBlock,
+                         light_block: &Web3ProxyBlock| {
+            let votes = HashMap::from([
+                (
+                    heavy_block.clone(),
+                    (HashSet::from([&heavy]), heavy.soft_limit),
+                ),
+                (
+                    light_block.clone(),
+                    (HashSet::from([&light]), light.soft_limit),
+                ),
+            ]);
+
+            let heads = HashMap::from([
+                (heavy.clone(), heavy_block.clone()),
+                (light.clone(), light_block.clone()),
+            ]);
+
+            RankedRpcs::from_votes(
+                consensus_strategy,
+                1,
+                1,
+                0.into(),
+                votes,
+                heads,
+                &HashSet::new(),
+            )
+            .unwrap()
+            .head_block
+        };
+
+        let td = ConsensusStrategy::HighestTotalDifficulty;
+
+        // when both nodes report total difficulty, the most work wins
+        let heavy_block = new_block(Some(200));
+        let light_block = new_block(Some(100));
+
+        assert_eq!(find_head(td, &heavy_block, &light_block), heavy_block);
+
+        // one node does not report total difficulty. fall back to the block number and then soft limit
+        let heavy_block = new_block(Some(200));
+        let light_block = new_block(None);
+
+        assert_eq!(find_head(td, &heavy_block, &light_block), light_block);
+
+        // post-merge, total difficulty is ignored even when every node reports it
+        let heavy_block = new_block(Some(200));
+        let light_block = new_block(Some(100));
+
+        assert_eq!(
+            find_head(
+                ConsensusStrategy::HighestBlockNumber,
+                &heavy_block,
+                &light_block
+            ),
+            light_block
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_block_number_consensus_ties() {
+        let parent_hash = H256::random();
+
+        // two forks at the same height. neither reports total difficulty
+        let new_block = || -> Web3ProxyBlock {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(1.into()),
+                parent_hash,
+                timestamp: chrono::Utc::now().timestamp().into(),
+                ..Default::default()
+            };
+
+            Arc::new(block).try_into().unwrap()
+        };
+
+        let new_rpc = |name: &str, soft_limit: u32| {
+            Arc::new(Web3Rpc {
+                name: name.to_string(),
+                soft_limit,
+                automatic_block_limit: false,
+                block_data_limit: u64::MAX.into(),
+                peak_latency: Some(PeakEwmaLatency::spawn(
+                    Duration::from_secs(1),
+                    4,
+                    Duration::from_secs(1),
+                )),
+                ..Default::default()
+            })
+        };
+
+        // one big rpc has more soft limit than the two small rpcs combined
+        let small_1 = new_rpc("small_1", 1);
+        let small_2 = new_rpc("small_2", 1);
+        let big = new_rpc("big", 5);
+
+        let small_block = new_block();
+        let big_block = new_block();
+
+        let votes = HashMap::from([
+            (
+                small_block.clone(),
+                (HashSet::from([&small_1, &small_2]), 2),
+            ),
+            (big_block.clone(), (HashSet::from([&big]), 5)),
+        ]);
+
+        let heads = HashMap::from([
+            (small_1.clone(), small_block.clone()),
+            (small_2.clone(), small_block.clone()),
+            (big.clone(), big_block.clone()),
+        ]);
+
+        let ranked = RankedRpcs::from_votes(
+            ConsensusStrategy::HighestBlockNumber,
+            1,
+            1,
+            0.into(),
+            votes,
+            heads,
+            &HashSet::new(),
+        )
+        .unwrap();
+
+        // the block that more rpcs are on wins
+        assert_eq!(ranked.head_block, small_block);
+        assert_eq!(ranked.num_synced, 2);
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_sync_recovery_window() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        // a simple chain of blocks 0..=12
+        let mut blocks: Vec<Web3ProxyBlock> = Vec::with_capacity(13);
+        for i in 0..=12u64 {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(i.into()),
+                parent_hash: blocks.last().map(|x| *x.hash()).unwrap_or_default(),
+                timestamp: now - 12 + i,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let rpcs: Vec<_> = ["synced", "syncing"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let (synced, syncing) = (rpcs[0].clone(), rpcs[1].clone());
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let watch_ranked_rpcs_receiver = web3_rpcs.watch_ranked_rpcs.subscribe();
+
+        // is the rpc trusted with this block number?
+        let trusted_with = |rpc: &Arc<Web3Rpc>, block_num: u64| {
+            watch_ranked_rpcs_receiver
+                .borrow()
+                .as_ref()
+                .unwrap()
+                .rpc_will_work_now(&[], None, Some(&block_num.into()), rpc)
+        };
+
+        // the default max_head_block_lag is 5
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        for block in blocks[..=10].iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block.clone()), synced.clone())
+                .await
+                .unwrap();
+        }
+
+        // syncing is far behind
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(blocks[2].clone()), syncing.clone())
+            .await
+            .unwrap();
+
+        assert!(trusted_with(&synced, 10));
+        assert!(!watch_ranked_rpcs_receiver
+            .borrow()
+            .as_ref()
+            .unwrap()
+            .all()
+            .contains(&syncing));
+
+        // syncing suddenly jumps to the head. it is only trusted with older blocks
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(blocks[10].clone()), syncing.clone())
+            .await
+            .unwrap();
+
+        assert!(!trusted_with(&syncing, 10));
+        assert!(trusted_with(&syncing, 5));
+
+        // still inside the window
+        tokio::time::advance(SYNC_RECOVERY_WINDOW / 2).await;
+
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(blocks[11].clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert!(trusted_with(&synced, 11));
+        assert!(!trusted_with(&syncing, 11));
+
+        // it stayed at the head for the whole window
+        tokio::time::advance(SYNC_RECOVERY_WINDOW / 2).await;
+
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(blocks[12].clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert!(trusted_with(&synced, 12));
+        assert!(trusted_with(&syncing, 12));
+
+        // it falls behind again and then disconnects. its status is forgotten
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(blocks[2].clone()), syncing.clone())
+            .await
+            .unwrap();
+
+        assert!(consensus_finder.sync_status.contains_key(&syncing));
+
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, None, syncing.clone())
+            .await
+            .unwrap();
+
+        assert!(consensus_finder.sync_status.is_empty());
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_minority_fork_timeout() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 2,
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_2 = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+        let block_2_fork = Block {
+            hash: Some(H256::random()),
+            number: Some(2.into()),
+            parent_hash: block_1.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let block_2: Web3ProxyBlock = Arc::new(block_2).try_into().unwrap();
+        let block_2_fork: Web3ProxyBlock = Arc::new(block_2_fork).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["a", "b", "forked"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let forked = rpcs[2].clone();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let minority_fork_timeout = Duration::from_secs(10);
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let watch_ranked_rpcs_receiver = web3_rpcs.watch_ranked_rpcs.subscribe();
+
+        let web3_rpcs = Web3Rpcs {
+            min_synced_rpcs: 2,
+            minority_fork_timeout: Some(minority_fork_timeout),
+            ..web3_rpcs
+        };
+
+        let is_ranked = |rpc: &Arc<Web3Rpc>| {
+            watch_ranked_rpcs_receiver
+                .borrow()
+                .as_ref()
+                .unwrap()
+                .all()
+                .contains(rpc)
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        for block in [&block_0, &block_1] {
+            for rpc in rpcs.iter() {
+                consensus_finder
+                    .process_block_from_rpc(&web3_rpcs, Some(block.clone()), rpc.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // two rpcs agree on block_2. the third is on a fork at the same height
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2.clone()), rpcs[0].clone())
+            .await
+            .unwrap();
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2_fork.clone()), forked.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_2.hash()));
+
+        // a momentary fork is not enough to remove it
+        assert!(is_ranked(&forked));
+
+        tokio::time::advance(minority_fork_timeout / 2).await;
+
+        consensus_finder
+            .refresh(&web3_rpcs, None, None)
+            .await
+            .unwrap();
+
+        assert!(is_ranked(&forked));
+
+        // it stayed on the fork for too long
+        tokio::time::advance(minority_fork_timeout / 2).await;
+
+        consensus_finder
+            .refresh(&web3_rpcs, None, None)
+            .await
+            .unwrap();
+
+        assert!(!is_ranked(&forked));
+        assert!(is_ranked(&rpcs[0]));
+        assert!(is_ranked(&rpcs[1]));
+        assert_eq!(web3_rpcs.num_synced_rpcs(), 2);
+
+        // it rejoins the consensus chain
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_2.clone()), forked.clone())
+            .await
+            .unwrap();
+
+        assert!(is_ranked(&forked));
+        assert_eq!(web3_rpcs.num_synced_rpcs(), 3);
+
+        // it disconnects. it isn't counted as synced anymore
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, None, forked.clone())
+            .await
+            .unwrap();
+
+        assert!(!is_ranked(&forked));
+        assert_eq!(web3_rpcs.num_synced_rpcs(), 2);
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_provisional_head_window() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let provisional_head_window = Duration::from_secs(10);
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let watch_ranked_rpcs_receiver = web3_rpcs.watch_ranked_rpcs.subscribe();
+
+        let web3_rpcs = Web3Rpcs {
+            min_synced_rpcs: 2,
+            provisional_head_window: Some(provisional_head_window),
+            ..web3_rpcs
+        };
+
+        let is_provisional = || {
+            watch_ranked_rpcs_receiver
+                .borrow()
+                .as_ref()
+                .map(|x| x.provisional)
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        // only one rpc is known, but it is still inside the window
+        for block in [&block_0, &block_1] {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block.clone()), rpcs[0].clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_1.hash()));
+        assert_eq!(is_provisional(), Some(true));
+
+        // the window is over. one rpc is not enough anymore
+        tokio::time::advance(provisional_head_window).await;
+
+        consensus_finder
+            .refresh(&web3_rpcs, None, None)
+            .await
+            .unwrap();
+
+        assert!(web3_rpcs.head_block_hash().is_none());
+        assert_eq!(is_provisional(), None);
+
+        // a second rpc agrees
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_1.hash()));
+        assert_eq!(is_provisional(), Some(false));
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_meets_minimums() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["a", "b", "c"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let web3_rpcs = Web3Rpcs {
+            min_synced_rpcs: 2,
+            min_sum_soft_limit: 2_000,
+            provisional_head_window: Some(Duration::from_secs(10)),
+            ..web3_rpcs
+        };
+
+        // nothing is known yet
+        assert!(!web3_rpcs.meets_minimums());
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        // one rpc gives a provisional head. it is served, but it does not count as synced
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpcs[0].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_0.hash()));
+        assert!(web3_rpcs.synced());
+        assert!(!web3_rpcs.meets_minimums());
+
+        // a second rpc agrees. that is enough rpcs and enough soft limit
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.num_synced_rpcs(), 2);
+        assert_eq!(web3_rpcs.synced_soft_limit(), 2_000);
+        assert!(web3_rpcs.meets_minimums());
+
+        // a third rpc adds to the soft limit
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_0.clone()), rpcs[2].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.synced_soft_limit(), 3_000);
+        assert!(web3_rpcs.meets_minimums());
+
+        // the same head is not enough once more soft limit is required
+        let web3_rpcs = Web3Rpcs {
+            min_sum_soft_limit: 4_000,
+            ..web3_rpcs
+        };
+
+        assert!(!web3_rpcs.meets_minimums());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_max_parent_fetch_depth() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: block_0.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+        // the parents of these are unknown
+        let orphan_near = Block {
+            hash: Some(H256::random()),
+            number: Some(3.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+        let orphan_far = Block {
+            hash: Some(H256::random()),
+            number: Some(5.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let orphan_near: Web3ProxyBlock = Arc::new(orphan_near).try_into().unwrap();
+        let orphan_far: Web3ProxyBlock = Arc::new(orphan_far).try_into().unwrap();
+
+        let rpcs: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let web3_rpcs = Web3Rpcs {
+            max_parent_fetch_depth: Some(2),
+            ..web3_rpcs
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        for block in [&block_0, &block_1] {
+            for rpc in rpcs.iter() {
+                consensus_finder
+                    .process_block_from_rpc(&web3_rpcs, Some(block.clone()), rpc.clone())
+                    .await
+                    .unwrap();
+            }
+        }
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_1.hash()));
+
+        // 4 blocks past the head is deeper than the limit. it is dropped without fetching its parents
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(orphan_far.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.orphans_dropped(), 1);
+        assert!(web3_rpcs.blocks_by_hash.get(orphan_far.hash()).is_none());
+        assert_eq!(consensus_finder.len(), 1);
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_1.hash()));
+
+        // 2 blocks past the head is within the limit. its parents would be fetched
+        assert!(!web3_rpcs.is_unreachable_orphan(&orphan_near));
+        assert!(web3_rpcs.is_unreachable_orphan(&orphan_far));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_no_consensus_warns_once() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block_0 = Block {
+            hash: Some(H256::random()),
+            number: Some(0.into()),
+            timestamp: now - 1,
+            ..Default::default()
+        };
+
+        let block_0: Web3ProxyBlock = Arc::new(block_0).try_into().unwrap();
+
+        // two forks at the same height
+        let new_block = || -> Web3ProxyBlock {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(1.into()),
+                parent_hash: *block_0.hash(),
+                timestamp: now,
+                ..Default::default()
+            };
+
+            Arc::new(block).try_into().unwrap()
+        };
+
+        let block_1a = new_block();
+        let block_1b = new_block();
+
+        let rpcs: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let web3_rpcs = Web3Rpcs {
+            max_head_block_lag: 0.into(),
+            min_synced_rpcs: 2,
+            ..web3_rpcs
+        };
+
+        web3_rpcs
+            .try_cache_block(block_0.clone(), true)
+            .await
+            .unwrap();
+
+        // no lag is allowed, so the common parent doesn't count
+        let mut consensus_finder = ConsensusFinder::new(None, Some(0.into()));
+
+        for (rpc, block) in rpcs.iter().zip([&block_1a, &block_1b]) {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(block.clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert!(web3_rpcs.head_block().is_none());
+
+        let warned = consensus_finder.no_consensus_warned;
+
+        assert!(warned == Some(*block_1a.hash()) || warned == Some(*block_1b.hash()));
+
+        // the same head again doesn't change anything
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1b.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(consensus_finder.no_consensus_warned, warned);
+
+        // once the rpcs agree again, the next outage warns again
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(block_1a.clone()), rpcs[1].clone())
+            .await
+            .unwrap();
+
+        assert_eq!(web3_rpcs.head_block_hash().as_ref(), Some(block_1a.hash()));
+        assert_eq!(consensus_finder.no_consensus_warned, None);
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_stale_heads_removed() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let mut blocks: Vec<Web3ProxyBlock> = Vec::with_capacity(2);
+        for i in 0..2u64 {
+            let block = Block {
+                hash: Some(H256::random()),
+                number: Some(i.into()),
+                parent_hash: blocks.last().map(|x| *x.hash()).unwrap_or_default(),
+                timestamp: now - 1 + i,
+                ..Default::default()
+            };
+
+            blocks.push(Arc::new(block).try_into().unwrap());
+        }
+
+        let rpcs: Vec<_> = ["stalled", "live"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let (stalled, live) = (rpcs[0].clone(), rpcs[1].clone());
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let watch_ranked_rpcs_receiver = web3_rpcs.watch_ranked_rpcs.subscribe();
+
+        let is_ranked = |rpc: &Arc<Web3Rpc>| {
+            watch_ranked_rpcs_receiver
+                .borrow()
+                .as_ref()
+                .unwrap()
+                .all()
+                .contains(rpc)
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(Some(Duration::from_secs(10)), None);
+
+        for rpc in rpcs.iter() {
+            consensus_finder
+                .process_block_from_rpc(&web3_rpcs, Some(blocks[0].clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(consensus_finder.len(), 2);
+        assert!(is_ranked(&stalled));
+
+        // only one rpc keeps sending heads
+        tokio::time::advance(Duration::from_secs(11)).await;
+
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(blocks[1].clone()), live.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(consensus_finder.len(), 1);
+        assert!(is_ranked(&live));
+        assert!(!is_ranked(&stalled));
+
+        // the next head brings it back
+        consensus_finder
+            .process_block_from_rpc(&web3_rpcs, Some(blocks[1].clone()), stalled.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(consensus_finder.len(), 2);
+        assert!(is_ranked(&stalled));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_unverified_heads_demoted() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let honest_block: Web3ProxyBlock = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        })
+        .try_into()
+        .unwrap();
+
+        let rpcs: Vec<_> = ["honest", "liar"]
+            .into_iter()
+            .map(|name| {
+                let (tx, _) = watch::channel(None);
+
+                Arc::new(Web3Rpc {
+                    name: name.to_string(),
+                    soft_limit: 1_000,
+                    automatic_block_limit: false,
+                    block_data_limit: u64::MAX.into(),
+                    head_block: Some(tx),
+                    peak_latency: Some(PeakEwmaLatency::spawn(
+                        Duration::from_secs(1),
+                        4,
+                        Duration::from_secs(1),
+                    )),
+                    ..Default::default()
+                })
+            })
+            .collect();
+
+        let (honest, liar) = (rpcs[0].clone(), rpcs[1].clone());
+
+        let by_name = rpcs.iter().map(|x| (x.name.clone(), x.clone())).collect();
+
+        let (web3_rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let web3_rpcs = Web3Rpcs {
+            head_verification_sample_rate: Some(1),
+            ..web3_rpcs
+        };
+
+        let mut consensus_finder = ConsensusFinder::new(None, None);
+
+        consensus_finder
+            .update_rpc(Some(honest_block.clone()), honest.clone(), &web3_rpcs)
+            .await
+            .unwrap();
+
+        // heads that are far past every other rpc can't be verified
+        for i in 1..=MAX_UNVERIFIED_HEADS {
+            let lie: Web3ProxyBlock = Arc::new(Block {
+                hash: Some(H256::random()),
+                number: Some((100 + i).into()),
+                parent_hash: H256::random(),
+                timestamp: now,
+                ..Default::default()
+            })
+            .try_into()
+            .unwrap();
+
+            consensus_finder
+                .update_rpc(Some(lie), liar.clone(), &web3_rpcs)
+                .await
+                .unwrap();
+
+            let expected_len = if i < MAX_UNVERIFIED_HEADS { 2 } else { 1 };
+
+            assert_eq!(consensus_finder.len(), expected_len);
+        }
+
+        assert!(consensus_finder.is_demoted(&liar));
+        assert!(!consensus_finder.is_demoted(&honest));
+
+        // a failed check doesn't bring it back
+        assert!(
+            !consensus_finder
+                .record_head_verification_result(HeadVerification {
+                    rpc: liar.clone(),
+                    head: honest_block.clone(),
+                    verified: None,
+                })
+                .await
+        );
+        assert_eq!(consensus_finder.len(), 1);
+
+        // a verified head does. it was ignored when it arrived, so it is added now
+        assert!(
+            consensus_finder
+                .record_head_verification_result(HeadVerification {
+                    rpc: liar.clone(),
+                    head: honest_block.clone(),
+                    verified: Some(true),
+                })
+                .await
+        );
+        assert!(!consensus_finder.is_demoted(&liar));
+        assert_eq!(consensus_finder.len(), 2);
+
+        // results from the spawned checks demote an rpc too
+        for i in 1..=MAX_UNVERIFIED_HEADS {
+            let changed = consensus_finder
+                .record_head_verification_result(HeadVerification {
+                    rpc: liar.clone(),
+                    head: honest_block.clone(),
+                    verified: Some(false),
+                })
+                .await;
+
+            assert_eq!(changed, i == MAX_UNVERIFIED_HEADS);
+        }
+
+        assert!(consensus_finder.is_demoted(&liar));
+        assert_eq!(consensus_finder.len(), 1);
+    }
+
+    #[test]
+    fn test_check_head_by_hash() {
+        let now: U256 = chrono::Utc::now().timestamp().into();
+
+        let block = Block {
+            hash: Some(H256::random()),
+            number: Some(5.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head: Web3ProxyBlock = Arc::new(block.clone()).try_into().unwrap();
+
+        assert_eq!(check_head_by_hash(&head, Some(&block)), Some(true));
+
+        // the other rpc has that hash at a different height
+        let wrong_number = Block {
+            number: Some(6.into()),
+            ..block.clone()
+        };
+        assert_eq!(check_head_by_hash(&head, Some(&wrong_number)), Some(false));
+
+        // or with a different parent
+        let wrong_parent = Block {
+            parent_hash: H256::random(),
+            ..block.clone()
+        };
+        assert_eq!(check_head_by_hash(&head, Some(&wrong_parent)), Some(false));
+
+        // not found might be a fork. the caller checks the parent
+        assert_eq!(check_head_by_hash(&head, None), None);
+    }
 }
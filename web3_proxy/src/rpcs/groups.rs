@@ -0,0 +1,266 @@
+//! Groups of rpcs that each keep their own consensus. For example, one group per region.
+//! Reads can be served by a nearby group while the heads of all the groups are compared to catch forks between them.
+use super::blockchain::Web3ProxyBlock;
+use super::many::Web3Rpcs;
+use crate::errors::Web3ProxyResult;
+use ethers::types::{H256, U64};
+use hashbrown::HashMap;
+use itertools::Itertools;
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, watch};
+use tokio::time::interval;
+use tracing::{info, warn};
+
+/// Two groups that have different blocks at the same height
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct GroupFork {
+    pub num: U64,
+    /// (group name, the group's block at `num`)
+    pub a: (String, H256),
+    pub b: (String, H256),
+}
+
+pub struct RpcGroup {
+    pub rpcs: Arc<Web3Rpcs>,
+    /// Web3Rpcs can only send its head block while something is watching it
+    _head_block_receiver: watch::Receiver<Option<Web3ProxyBlock>>,
+}
+
+impl RpcGroup {
+    pub fn new(
+        rpcs: Arc<Web3Rpcs>,
+        head_block_receiver: watch::Receiver<Option<Web3ProxyBlock>>,
+    ) -> Self {
+        Self {
+            rpcs,
+            _head_block_receiver: head_block_receiver,
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct RpcGroups {
+    groups: HashMap<String, RpcGroup>,
+    /// the forks found by the last `reconcile`
+    forks: Mutex<Vec<GroupFork>>,
+}
+
+impl RpcGroups {
+    pub fn new(groups: HashMap<String, RpcGroup>) -> Self {
+        Self {
+            groups,
+            forks: Default::default(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<Web3Rpcs>> {
+        self.groups.get(name).map(|x| &x.rpcs)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    pub fn forks(&self) -> Vec<GroupFork> {
+        self.forks.lock().clone()
+    }
+
+    /// compare the heads of every pair of groups. a group that is only behind another group is not a fork.
+    /// if the group that is ahead does not know its block at the lower height, the pair is skipped
+    pub fn reconcile(&self) -> Vec<GroupFork> {
+        let heads: Vec<_> = self
+            .groups
+            .iter()
+            .filter_map(|(name, group)| group.rpcs.head_block().map(|x| (name, &group.rpcs, x)))
+            .sorted_by(|a, b| a.0.cmp(b.0))
+            .collect();
+
+        let mut forks = vec![];
+
+        for ((a_name, a_rpcs, a_head), (b_name, b_rpcs, b_head)) in
+            heads.iter().tuple_combinations()
+        {
+            let num = *a_head.number().min(b_head.number());
+
+            let a_hash = if *a_head.number() == num {
+                Some(*a_head.hash())
+            } else {
+                a_rpcs.blocks_by_number.get(&num)
+            };
+
+            let b_hash = if *b_head.number() == num {
+                Some(*b_head.hash())
+            } else {
+                b_rpcs.blocks_by_number.get(&num)
+            };
+
+            if let (Some(a_hash), Some(b_hash)) = (a_hash, b_hash) {
+                if a_hash != b_hash {
+                    forks.push(GroupFork {
+                        num,
+                        a: (a_name.to_string(), a_hash),
+                        b: (b_name.to_string(), b_hash),
+                    });
+                }
+            }
+        }
+
+        let mut old_forks = self.forks.lock();
+
+        for fork in forks.iter() {
+            if !old_forks.contains(fork) {
+                warn!(?fork, "rpc groups disagree");
+            }
+        }
+
+        if !old_forks.is_empty() && forks.is_empty() {
+            info!("rpc groups agree again");
+        }
+
+        *old_forks = forks.clone();
+
+        forks
+    }
+
+    pub async fn reconcile_loop(
+        self: Arc<Self>,
+        period: Duration,
+        mut shutdown_receiver: broadcast::Receiver<()>,
+    ) -> Web3ProxyResult<()> {
+        let mut interval = interval(period);
+
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.reconcile();
+                }
+                _ = shutdown_receiver.recv() => {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for RpcGroups {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("RpcGroups", 2)?;
+
+        let groups: HashMap<_, _> = self.groups.iter().map(|(k, v)| (k, &v.rpcs)).collect();
+
+        state.serialize_field("groups", &groups)?;
+        state.serialize_field("forks", &*self.forks.lock())?;
+
+        state.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GroupFork, RpcGroup, RpcGroups};
+    use crate::rpcs::blockchain::Web3ProxyBlock;
+    use crate::rpcs::many::Web3Rpcs;
+    use ethers::types::{Block, H256, U64};
+    use hashbrown::HashMap;
+    use std::sync::Arc;
+
+    fn new_block(num: u64, parent_hash: H256) -> Web3ProxyBlock {
+        let block = Block {
+            hash: Some(H256::random()),
+            number: Some(num.into()),
+            parent_hash,
+            ..Default::default()
+        };
+
+        Arc::new(block).try_into().unwrap()
+    }
+
+    /// a group that has seen these blocks. the last one is its consensus head
+    async fn new_group(name: &str, blocks: &[&Web3ProxyBlock]) -> RpcGroup {
+        let (rpcs, _block_receiver, head_block_receiver) = Web3Rpcs::test_new(1, HashMap::new());
+
+        let rpcs = Web3Rpcs {
+            name: name.to_string().into(),
+            ..rpcs
+        };
+
+        for block in blocks {
+            rpcs.try_cache_block((*block).clone(), true).await.unwrap();
+        }
+
+        rpcs.watch_head_block
+            .as_ref()
+            .unwrap()
+            .send_replace(blocks.last().cloned().cloned());
+
+        RpcGroup::new(Arc::new(rpcs), head_block_receiver)
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_group_forks() {
+        let block_99 = new_block(99, H256::random());
+        let block_100a = new_block(100, *block_99.hash());
+        let block_100b = new_block(100, *block_99.hash());
+        let block_101b = new_block(101, *block_100b.hash());
+        let block_101c = new_block(101, *block_100a.hash());
+
+        // "a" is one block behind "c" on the same chain. "b" is on a fork
+        let groups = RpcGroups::new(HashMap::from([
+            (
+                "a".to_string(),
+                new_group("a", &[&block_99, &block_100a]).await,
+            ),
+            (
+                "b".to_string(),
+                new_group("b", &[&block_99, &block_100b, &block_101b]).await,
+            ),
+            (
+                "c".to_string(),
+                new_group("c", &[&block_99, &block_100a, &block_101c]).await,
+            ),
+        ]));
+
+        // every group serves its own head
+        assert_eq!(
+            groups.get("a").unwrap().head_block(),
+            Some(block_100a.clone())
+        );
+        assert_eq!(
+            groups.get("b").unwrap().head_block(),
+            Some(block_101b.clone())
+        );
+        assert_eq!(
+            groups.get("c").unwrap().head_block(),
+            Some(block_101c.clone())
+        );
+
+        let forks = groups.reconcile();
+
+        assert_eq!(
+            forks,
+            vec![
+                GroupFork {
+                    num: U64::from(100),
+                    a: ("a".to_string(), *block_100a.hash()),
+                    b: ("b".to_string(), *block_100b.hash()),
+                },
+                GroupFork {
+                    num: U64::from(101),
+                    a: ("b".to_string(), *block_101b.hash()),
+                    b: ("c".to_string(), *block_101c.hash()),
+                },
+            ]
+        );
+
+        assert_eq!(groups.forks(), forks);
+    }
+}
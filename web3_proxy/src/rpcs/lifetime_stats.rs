@@ -0,0 +1,233 @@
+//! Counters for everything an rpc has ever done. Operators use these to compare providers.
+//! They are saved to the database so that they survive restarts.
+use crate::errors::Web3ProxyResult;
+use entities::rpc_lifetime_stats;
+use migration::sea_orm::{self, ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter};
+use migration::{Expr, OnConflict};
+use parking_lot::Mutex;
+use serde::ser::{SerializeStruct, Serializer};
+use serde::Serialize;
+use std::ops::{Add, Sub};
+use std::sync::atomic::{self, AtomicBool, AtomicU64};
+use std::time::Duration;
+
+/// A snapshot of `LifetimeStats`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LifetimeCounts {
+    pub requests: u64,
+    /// reverts are not counted. they are the caller's fault, not the rpc's
+    pub errors: u64,
+    /// bytes of the responses sent to users. streamed responses count the whole body. internal requests are not counted
+    pub response_bytes: u64,
+    pub sum_latency_us: u64,
+    /// how many consensus heads this rpc was synced to when they were published
+    pub consensus_heads: u64,
+}
+
+impl LifetimeCounts {
+    pub fn avg_latency_ms(&self) -> f64 {
+        if self.requests == 0 {
+            return 0.0;
+        }
+
+        self.sum_latency_us as f64 / self.requests as f64 / 1000.0
+    }
+}
+
+impl Add for LifetimeCounts {
+    type Output = Self;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Self {
+            requests: self.requests + rhs.requests,
+            errors: self.errors + rhs.errors,
+            response_bytes: self.response_bytes + rhs.response_bytes,
+            sum_latency_us: self.sum_latency_us + rhs.sum_latency_us,
+            consensus_heads: self.consensus_heads + rhs.consensus_heads,
+        }
+    }
+}
+
+impl Sub for LifetimeCounts {
+    type Output = Self;
+
+    fn sub(self, rhs: Self) -> Self::Output {
+        Self {
+            requests: self.requests.saturating_sub(rhs.requests),
+            errors: self.errors.saturating_sub(rhs.errors),
+            response_bytes: self.response_bytes.saturating_sub(rhs.response_bytes),
+            sum_latency_us: self.sum_latency_us.saturating_sub(rhs.sum_latency_us),
+            consensus_heads: self.consensus_heads.saturating_sub(rhs.consensus_heads),
+        }
+    }
+}
+
+impl From<rpc_lifetime_stats::Model> for LifetimeCounts {
+    fn from(x: rpc_lifetime_stats::Model) -> Self {
+        Self {
+            requests: x.requests,
+            errors: x.errors,
+            response_bytes: x.response_bytes,
+            sum_latency_us: x.sum_latency_us,
+            consensus_heads: x.consensus_heads,
+        }
+    }
+}
+
+impl Serialize for LifetimeCounts {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut state = serializer.serialize_struct("LifetimeCounts", 5)?;
+
+        state.serialize_field("requests", &self.requests)?;
+        state.serialize_field("errors", &self.errors)?;
+        state.serialize_field("response_bytes", &self.response_bytes)?;
+        state.serialize_field("avg_latency_ms", &self.avg_latency_ms())?;
+        state.serialize_field("consensus_heads", &self.consensus_heads)?;
+
+        state.end()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LifetimeStats {
+    requests: AtomicU64,
+    errors: AtomicU64,
+    response_bytes: AtomicU64,
+    sum_latency_us: AtomicU64,
+    consensus_heads: AtomicU64,
+    /// the counts from the database are added the first time this rpc is saved
+    loaded: AtomicBool,
+    /// the counts that are already in the database
+    saved: Mutex<LifetimeCounts>,
+}
+
+impl LifetimeStats {
+    pub fn counts(&self) -> LifetimeCounts {
+        LifetimeCounts {
+            requests: self.requests.load(atomic::Ordering::Relaxed),
+            errors: self.errors.load(atomic::Ordering::Relaxed),
+            response_bytes: self.response_bytes.load(atomic::Ordering::Relaxed),
+            sum_latency_us: self.sum_latency_us.load(atomic::Ordering::Relaxed),
+            consensus_heads: self.consensus_heads.load(atomic::Ordering::Relaxed),
+        }
+    }
+
+    pub fn record_request(&self, latency: Duration) {
+        self.requests.fetch_add(1, atomic::Ordering::Relaxed);
+        self.sum_latency_us
+            .fetch_add(latency.as_micros() as u64, atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_error(&self) {
+        self.errors.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_response_bytes(&self, num_bytes: u64) {
+        self.response_bytes
+            .fetch_add(num_bytes, atomic::Ordering::Relaxed);
+    }
+
+    pub fn record_consensus_head(&self) {
+        self.consensus_heads.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+
+    fn add_counts(&self, x: LifetimeCounts) {
+        self.requests
+            .fetch_add(x.requests, atomic::Ordering::Relaxed);
+        self.errors.fetch_add(x.errors, atomic::Ordering::Relaxed);
+        self.response_bytes
+            .fetch_add(x.response_bytes, atomic::Ordering::Relaxed);
+        self.sum_latency_us
+            .fetch_add(x.sum_latency_us, atomic::Ordering::Relaxed);
+        self.consensus_heads
+            .fetch_add(x.consensus_heads, atomic::Ordering::Relaxed);
+    }
+
+    /// add everything since the last save to the database.
+    /// the first save also loads the counts from before this rpc was connected
+    pub async fn save(
+        &self,
+        chain_id: u64,
+        rpc_name: &str,
+        db_conn: &DatabaseConnection,
+    ) -> Web3ProxyResult<()> {
+        if !self.loaded.load(atomic::Ordering::Acquire) {
+            let old = rpc_lifetime_stats::Entity::find()
+                .filter(rpc_lifetime_stats::Column::ChainId.eq(chain_id))
+                .filter(rpc_lifetime_stats::Column::RpcName.eq(rpc_name))
+                .one(db_conn)
+                .await?;
+
+            if let Some(old) = old {
+                let old = LifetimeCounts::from(old);
+
+                self.add_counts(old);
+
+                let mut saved = self.saved.lock();
+                *saved = *saved + old;
+            }
+
+            self.loaded.store(true, atomic::Ordering::Release);
+        }
+
+        let counts = self.counts();
+
+        let new = counts - *self.saved.lock();
+
+        if new == LifetimeCounts::default() {
+            return Ok(());
+        }
+
+        let x = rpc_lifetime_stats::ActiveModel {
+            id: sea_orm::NotSet,
+            chain_id: sea_orm::Set(chain_id),
+            rpc_name: sea_orm::Set(rpc_name.to_string()),
+            requests: sea_orm::Set(new.requests),
+            errors: sea_orm::Set(new.errors),
+            response_bytes: sea_orm::Set(new.response_bytes),
+            sum_latency_us: sea_orm::Set(new.sum_latency_us),
+            consensus_heads: sea_orm::Set(new.consensus_heads),
+        };
+
+        // other proxies might be saving the same rpc. add to the row instead of replacing it
+        rpc_lifetime_stats::Entity::insert(x)
+            .on_conflict(
+                OnConflict::new()
+                    .values([
+                        (
+                            rpc_lifetime_stats::Column::Requests,
+                            Expr::col(rpc_lifetime_stats::Column::Requests).add(new.requests),
+                        ),
+                        (
+                            rpc_lifetime_stats::Column::Errors,
+                            Expr::col(rpc_lifetime_stats::Column::Errors).add(new.errors),
+                        ),
+                        (
+                            rpc_lifetime_stats::Column::ResponseBytes,
+                            Expr::col(rpc_lifetime_stats::Column::ResponseBytes)
+                                .add(new.response_bytes),
+                        ),
+                        (
+                            rpc_lifetime_stats::Column::SumLatencyUs,
+                            Expr::col(rpc_lifetime_stats::Column::SumLatencyUs)
+                                .add(new.sum_latency_us),
+                        ),
+                        (
+                            rpc_lifetime_stats::Column::ConsensusHeads,
+                            Expr::col(rpc_lifetime_stats::Column::ConsensusHeads)
+                                .add(new.consensus_heads),
+                        ),
+                    ])
+                    .to_owned(),
+            )
+            .exec(db_conn)
+            .await?;
+
+        *self.saved.lock() = counts;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,408 @@
+//! Split `eth_getLogs` ranges that an rpc says are too large and merge the results back together.
+use super::many::Web3Rpcs;
+use crate::block_number::logs_block_range;
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use crate::frontend::authorization::RequestMetadata;
+use crate::jsonrpc::JsonRpcErrorData;
+use ethers::types::{Log, U64};
+use futures::future::{try_join, BoxFuture, FutureExt};
+use hashbrown::HashSet;
+use serde_json::json;
+use serde_json::value::RawValue;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+/// lowercase parts of the errors that rpcs send when an eth_getLogs range covers too many blocks or has too many results
+const RANGE_TOO_LARGE_ERRORS: &[&str] = &[
+    "query returned more than",
+    "exceed maximum block range",
+    "block range is too wide",
+    "block range too large",
+    "range is too large",
+    "response size exceeded",
+    "too many blocks",
+];
+
+/// true if the rpc rejected the request because its range was too large. a smaller range might work
+pub fn is_range_too_large(err: &Web3ProxyError) -> bool {
+    let message = match err {
+        Web3ProxyError::JsonRpcErrorData(x) => x.message.to_lowercase(),
+        Web3ProxyError::EthersProvider(x) => match JsonRpcErrorData::try_from(x) {
+            Ok(x) => x.message.to_lowercase(),
+            Err(_) => return false,
+        },
+        _ => return false,
+    };
+
+    RANGE_TOO_LARGE_ERRORS.iter().any(|x| message.contains(x))
+}
+
+/// the two halves of a block range. None if it is a single block
+pub fn split_block_range(from_block: U64, to_block: U64) -> Option<[(U64, U64); 2]> {
+    if from_block >= to_block {
+        return None;
+    }
+
+    let mid = from_block + (to_block - from_block) / 2;
+
+    Some([(from_block, mid), (mid + 1, to_block)])
+}
+
+/// join the logs from consecutive ranges. an rpc might answer with logs outside of the range it was asked for,
+/// so logs that are in more than one range are only kept once
+pub fn merge_logs(ranges: impl IntoIterator<Item = Vec<Log>>) -> Vec<Log> {
+    let mut seen = HashSet::new();
+
+    let mut logs: Vec<_> = ranges
+        .into_iter()
+        .flatten()
+        .filter(|x| seen.insert((x.block_hash, x.transaction_hash, x.log_index)))
+        .collect();
+
+    // a stable sort keeps the rpc's order for logs without numbers
+    logs.sort_by_key(|x| (x.block_number, x.log_index));
+
+    logs
+}
+
+/// the original request's params with a different block range
+fn with_block_range(
+    params: &serde_json::Value,
+    from_block: U64,
+    to_block: U64,
+) -> serde_json::Value {
+    let mut params = params.clone();
+
+    if let Some(filter) = params.get_mut(0).and_then(|x| x.as_object_mut()) {
+        filter.insert("fromBlock".into(), json!(from_block));
+        filter.insert("toBlock".into(), json!(to_block));
+    }
+
+    params
+}
+
+/// `send` queries a single block range. it is a closure so the splitting can be tested without any rpcs
+struct LogsSplit<F> {
+    send: F,
+    max_queries: usize,
+    /// every query sent for this request, including the first one
+    num_queries: AtomicUsize,
+}
+
+impl<F, Fut> LogsSplit<F>
+where
+    F: Fn(U64, U64) -> Fut + Sync,
+    Fut: Future<Output = Web3ProxyResult<Vec<Log>>> + Send,
+{
+    fn new(send: F, max_queries: usize) -> Self {
+        Self {
+            send,
+            max_queries,
+            num_queries: AtomicUsize::new(1),
+        }
+    }
+
+    /// reserve the queries for both halves. false if that would be more than max_queries
+    fn try_reserve_split(&self) -> bool {
+        self.num_queries
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                (x + 2 <= self.max_queries).then_some(x + 2)
+            })
+            .is_ok()
+    }
+
+    /// query both halves at the same time. each half can be split up to `depth - 1` more times
+    fn split(
+        &self,
+        halves: [(U64, U64); 2],
+        depth: u32,
+    ) -> BoxFuture<'_, Web3ProxyResult<Vec<Log>>> {
+        async move {
+            let [(a_from, a_to), (b_from, b_to)] = halves;
+
+            let (a, b) = try_join(
+                self.query(a_from, a_to, depth - 1),
+                self.query(b_from, b_to, depth - 1),
+            )
+            .await?;
+
+            Ok(merge_logs([a, b]))
+        }
+        .boxed()
+    }
+
+    async fn query(&self, from_block: U64, to_block: U64, depth: u32) -> Web3ProxyResult<Vec<Log>> {
+        let err = match (self.send)(from_block, to_block).await {
+            Err(err) if depth > 0 && is_range_too_large(&err) => err,
+            x => return x,
+        };
+
+        match split_block_range(from_block, to_block) {
+            Some(halves) if self.try_reserve_split() => {
+                trace!(%from_block, %to_block, depth, "splitting eth_getLogs range again");
+
+                self.split(halves, depth).await
+            }
+            _ => Err(err),
+        }
+    }
+}
+
+impl Web3Rpcs {
+    /// send an eth_getLogs request. if the rpc says the range is too large, split it in half and query both halves at once.
+    /// halves that are still too large are split again, up to `max_depth` times and `max_queries` queries in total.
+    /// the merged logs are in block order
+    #[allow(clippy::too_many_arguments)]
+    pub async fn try_proxy_logs(
+        &self,
+        params: &serde_json::Value,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        max_tries: Option<usize>,
+        max_wait: Option<Duration>,
+        max_depth: u32,
+        max_queries: usize,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Web3ProxyResult<Arc<RawValue>> {
+        let err = match self
+            .try_proxy_connection::<_, Arc<RawValue>>(
+                "eth_getLogs",
+                params,
+                request_metadata,
+                max_tries,
+                max_wait,
+                min_block_needed,
+                max_block_needed,
+            )
+            .await
+        {
+            Err(err) if max_depth > 0 && is_range_too_large(&err) => err,
+            x => return x,
+        };
+
+        let head_block_num = self
+            .head_block_num()
+            .ok_or(Web3ProxyError::NoServersSynced)?;
+
+        // requests for a single block hash can't be split
+        let Some(halves) =
+            logs_block_range(params, &head_block_num, self.finalized_block_num().as_ref())?
+                .and_then(|(from_block, to_block)| split_block_range(from_block, to_block))
+        else {
+            return Err(err);
+        };
+
+        // each sub-query is sent with the request's metadata, so they all count toward its stats
+        let send = move |from_block: U64, to_block: U64| {
+            let params = with_block_range(params, from_block, to_block);
+
+            async move {
+                self.try_proxy_connection::<_, Vec<Log>>(
+                    "eth_getLogs",
+                    &params,
+                    request_metadata,
+                    max_tries,
+                    max_wait,
+                    Some(&from_block),
+                    Some(&to_block),
+                )
+                .await
+            }
+        };
+
+        let logs_split = LogsSplit::new(send, max_queries);
+
+        if !logs_split.try_reserve_split() {
+            return Err(err);
+        }
+
+        let logs = logs_split.split(halves, max_depth).await?;
+
+        debug!(
+            num_logs = logs.len(),
+            num_queries = logs_split.num_queries.load(Ordering::Acquire),
+            "merged split eth_getLogs"
+        );
+
+        Ok(serde_json::value::to_raw_value(&logs)?.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+
+    #[test]
+    fn test_split_block_range() {
+        assert_eq!(split_block_range(5.into(), 5.into()), None);
+        assert_eq!(split_block_range(6.into(), 5.into()), None);
+
+        assert_eq!(
+            split_block_range(0.into(), 1.into()),
+            Some([(0.into(), 0.into()), (1.into(), 1.into())])
+        );
+        assert_eq!(
+            split_block_range(10.into(), 20.into()),
+            Some([(10.into(), 15.into()), (16.into(), 20.into())])
+        );
+    }
+
+    #[test]
+    fn test_merge_logs() {
+        let log = |block_num: u64, log_index: u64| Log {
+            block_hash: Some(H256::from_low_u64_be(block_num)),
+            block_number: Some(block_num.into()),
+            transaction_hash: Some(H256::from_low_u64_be(block_num * 100 + log_index)),
+            log_index: Some(log_index.into()),
+            ..Default::default()
+        };
+
+        // the first rpc returned a log from past the end of its range
+        let merged = merge_logs([
+            vec![log(1, 0), log(1, 1), log(2, 0)],
+            vec![log(2, 0), log(2, 1), log(3, 0)],
+        ]);
+
+        let order: Vec<_> = merged
+            .iter()
+            .map(|x| {
+                (
+                    x.block_number.unwrap().as_u64(),
+                    x.log_index.unwrap().as_u64(),
+                )
+            })
+            .collect();
+
+        assert_eq!(order, [(1, 0), (1, 1), (2, 0), (2, 1), (3, 0)]);
+    }
+
+    #[test]
+    fn test_is_range_too_large() {
+        let too_many_results = Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            message: "query returned more than 10000 results".into(),
+            code: -32005,
+            data: None,
+        });
+        assert!(is_range_too_large(&too_many_results));
+
+        let too_many_blocks = Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            message: "exceed maximum block range: 5000".into(),
+            code: -32000,
+            data: None,
+        });
+        assert!(is_range_too_large(&too_many_blocks));
+
+        // "block range" on its own shows up in errors that splitting can't fix
+        let bad_range = Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            message: "invalid block range params".into(),
+            code: -32602,
+            data: None,
+        });
+        assert!(!is_range_too_large(&bad_range));
+
+        let revert = Web3ProxyError::JsonRpcErrorData(JsonRpcErrorData {
+            message: "execution reverted".into(),
+            code: 3,
+            data: None,
+        });
+        assert!(!is_range_too_large(&revert));
+
+        assert!(!is_range_too_large(&Web3ProxyError::NoServersSynced));
+    }
+
+    #[test]
+    fn test_with_block_range() {
+        let params = json!([{"fromBlock": "earliest", "toBlock": "latest", "topics": []}]);
+
+        assert_eq!(
+            with_block_range(&params, 1.into(), 16.into()),
+            json!([{"fromBlock": "0x1", "toBlock": "0x10", "topics": []}])
+        );
+    }
+
+    /// a fake rpc that rejects any range with more than `max_blocks` blocks and has one log in every block
+    fn fake_send(
+        max_blocks: u64,
+        num_sent: &AtomicUsize,
+    ) -> impl Fn(U64, U64) -> futures::future::Ready<Web3ProxyResult<Vec<Log>>> + Sync + '_ {
+        move |from_block, to_block| {
+            num_sent.fetch_add(1, Ordering::SeqCst);
+
+            if (to_block - from_block).as_u64() + 1 > max_blocks {
+                return futures::future::ready(Err(Web3ProxyError::JsonRpcErrorData(
+                    JsonRpcErrorData {
+                        message: "query returned more than 10000 results".into(),
+                        code: -32005,
+                        data: None,
+                    },
+                )));
+            }
+
+            let logs = (from_block.as_u64()..=to_block.as_u64())
+                .map(|x| Log {
+                    block_hash: Some(H256::from_low_u64_be(x)),
+                    block_number: Some(x.into()),
+                    log_index: Some(0.into()),
+                    ..Default::default()
+                })
+                .collect();
+
+            futures::future::ready(Ok(logs))
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_split_depth() {
+        // 8 blocks need 2 splits to get down to 2 blocks per query
+        let num_sent = AtomicUsize::new(0);
+        let logs_split = LogsSplit::new(fake_send(2, &num_sent), 100);
+
+        let logs = logs_split.query(1.into(), 8.into(), 2).await.unwrap();
+
+        let block_nums: Vec<_> = logs
+            .iter()
+            .map(|x| x.block_number.unwrap().as_u64())
+            .collect();
+        assert_eq!(block_nums, (1..=8).collect::<Vec<_>>());
+
+        // 1 + 2 + 4
+        assert_eq!(num_sent.load(Ordering::SeqCst), 7);
+        assert_eq!(logs_split.num_queries.load(Ordering::SeqCst), 7);
+
+        // with only 1 split allowed, the halves are still too large
+        let num_sent = AtomicUsize::new(0);
+        let logs_split = LogsSplit::new(fake_send(2, &num_sent), 100);
+
+        let err = logs_split.query(1.into(), 8.into(), 1).await.unwrap_err();
+        assert!(is_range_too_large(&err));
+
+        // the first half fails and try_join stops waiting on the second. so 2 or 3 queries were sent
+        assert!(num_sent.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_split_max_queries() {
+        // 8 blocks need 7 queries in total. 5 is enough for the first split but not for both halves to split again
+        let num_sent = AtomicUsize::new(0);
+        let logs_split = LogsSplit::new(fake_send(2, &num_sent), 5);
+
+        let err = logs_split.query(1.into(), 8.into(), 10).await.unwrap_err();
+        assert!(is_range_too_large(&err));
+
+        assert!(logs_split.num_queries.load(Ordering::SeqCst) <= 5);
+        assert!(num_sent.load(Ordering::SeqCst) <= 5);
+
+        // a single query isn't split at all
+        let num_sent = AtomicUsize::new(0);
+        let logs_split = LogsSplit::new(fake_send(2, &num_sent), 1);
+
+        let err = logs_split.query(1.into(), 8.into(), 10).await.unwrap_err();
+        assert!(is_range_too_large(&err));
+
+        assert_eq!(num_sent.load(Ordering::SeqCst), 1);
+    }
+}
@@ -1,37 +1,47 @@
 //! Load balanced communication with a group of web3 rpc providers
-use super::blockchain::{BlocksByHashCache, BlocksByNumberCache, Web3ProxyBlock};
-use super::consensus::{RankedRpcs, ShouldWaitForBlock};
+use super::blockchain::{
+    BlocksByHashCache, BlocksByNumberCache, RecentReorgs, Reorg, ReorgObserver, Web3ProxyBlock,
+};
+use super::circuit_breaker::CircuitState;
+use super::consensus::{ConsensusStrategy, RankedRpcs, ShouldWaitForBlock};
 use super::one::Web3Rpc;
 use super::request::{OpenRequestHandle, OpenRequestResult, RequestErrorHandler};
+use super::subscriptions::{LogsSender, PendingTxSender, TxStatus};
 use crate::app::{flatten_handle, Web3ProxyApp, Web3ProxyJoinHandle};
-use crate::config::{average_block_interval, BlockAndRpc, Web3RpcConfig};
+use crate::config::{
+    average_block_interval, check_duplicate_rpc_urls, AppConfig, BlockAndRpc, Web3RpcConfig,
+};
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
-use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::frontend::authorization::{Authorization, RequestMetadata, SkipReason};
 use crate::frontend::rpc_proxy_ws::ProxyMode;
 use crate::frontend::status::MokaCacheSerializer;
-use crate::jsonrpc::{JsonRpcErrorData, JsonRpcParams, JsonRpcResultData};
+use crate::jsonrpc::{JsonRpcErrorData, JsonRpcParams, JsonRpcResultData, StreamOrBuffered};
 use counter::Counter;
 use derive_more::From;
-use ethers::prelude::{ProviderError, U64};
+use ethers::prelude::{Address, Bytes, Filter, ProviderError, Transaction, TxHash, U64};
+use ethers::utils::rlp::{Decodable, Rlp};
 use futures::future::try_join_all;
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use itertools::Itertools;
-use moka::future::CacheBuilder;
-use parking_lot::RwLock;
+use moka::future::{Cache, CacheBuilder};
+use nanorand::WyRand;
+use parking_lot::{Mutex, RwLock};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
 use serde_json::value::RawValue;
 use std::borrow::Cow;
-use std::cmp::min_by_key;
+use std::cmp::{min_by_key, Reverse};
 use std::fmt::{self, Display};
-use std::sync::atomic::Ordering;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::select;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{sleep, sleep_until, Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Mutex as AsyncMutex};
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
 use tracing::{debug, error, info, trace, warn};
 
 /// A collection of web3 connections. Sends requests either the current best server or all servers.
@@ -39,6 +49,14 @@ use tracing::{debug, error, info, trace, warn};
 pub struct Web3Rpcs {
     pub(crate) name: Cow<'static, str>,
     pub(crate) chain_id: u64,
+    /// how to pick between heads on different chains
+    pub(super) consensus_strategy: ConsensusStrategy,
+    /// requests for these methods always go to the synced rpc with the lowest latency instead of being load balanced
+    pub(super) fastest_methods: HashSet<String>,
+    /// requests for blocks up to this many past the consensus head wait for them instead of erroring
+    pub(super) future_block_grace: U64,
+    /// the longest to wait for a block within future_block_grace
+    pub(super) future_block_wait: Duration,
     /// if watch_consensus_head_sender is some, Web3Rpc inside self will send blocks here when they get them
     pub(crate) block_sender: mpsc::UnboundedSender<(Option<Web3ProxyBlock>, Arc<Web3Rpc>)>,
     /// any requests will be forwarded to one (or more) of these connections
@@ -51,11 +69,20 @@ pub struct Web3Rpcs {
     pub(crate) watch_ranked_rpcs: watch::Sender<Option<Arc<RankedRpcs>>>,
     /// this head receiver makes it easy to wait until there is a new block
     pub(super) watch_head_block: Option<watch::Sender<Option<Web3ProxyBlock>>>,
-    /// TODO: this map is going to grow forever unless we do some sort of pruning. maybe store pruned in redis?
+    /// the highest "finalized" block that enough synced rpcs agree on. None if the chain doesn't have the tag
+    pub(super) watch_finalized_block: watch::Sender<Option<Web3ProxyBlock>>,
     /// all blocks, including orphans
+    /// blocks more than PRUNE_DEPTH behind the head are pruned in small batches by `prune_blocks_loop`
+    /// TODO: maybe store pruned blocks in redis?
     pub(super) blocks_by_hash: BlocksByHashCache,
     /// blocks on the heaviest chain
     pub(super) blocks_by_number: BlocksByNumberCache,
+    /// held while a consensus head updates blocks_by_number. two heads changing the same numbers at once could leave a stale hash behind
+    pub(super) blocks_by_number_lock: AsyncMutex<()>,
+    /// how many `blocks_by_number` entries were removed because they didn't match `blocks_by_hash`
+    pub(super) block_cache_repairs: AtomicU64,
+    /// how many head blocks were ignored because they were too far past the consensus head to reconnect
+    pub(super) orphans_dropped: AtomicU64,
     /// the number of rpcs required to agree on consensus for the head block (thundering herd protection)
     pub(super) min_synced_rpcs: usize,
     /// the soft limit required to agree on consensus for the head block. (thundering herd protection)
@@ -65,22 +92,153 @@ pub struct Web3Rpcs {
     /// how old our consensus head block we can be before we stop serving requests
     /// calculated based on max_head_block_lag and averge block times
     pub(super) max_head_block_age: Duration,
+    /// a head block whose parent is unknown is ignored if it is more than this many blocks past the consensus head.
+    /// None = always try to fetch its parents
+    pub(super) max_parent_fetch_depth: Option<u64>,
+    /// the last few changes to the heaviest chain. for incident review
+    pub(super) recent_reorgs: RecentReorgs,
+    /// every change to the heaviest chain is also sent here. for alerting
+    pub(super) reorg_sender: broadcast::Sender<Reorg>,
+    /// called for every change to the heaviest chain before the new head is published
+    pub(super) reorg_observers: RwLock<Vec<Arc<dyn ReorgObserver>>>,
+    /// method (or prefix ending in `*`) -> the pool of rpcs that serve it. rpcs in a pool don't serve anything else
+    pub(super) method_pools: HashMap<String, String>,
+    /// how long to hold back a new head block that only a minority of rpcs are on
+    pub(super) head_publish_delay: Option<Duration>,
+    /// 1 out of this many new heads from each rpc is checked against another rpc. None = never
+    pub(super) head_verification_sample_rate: Option<u32>,
+    /// rpcs that stay on a minority fork for this long are left out of the ranked rpcs until they rejoin
+    pub(super) minority_fork_timeout: Option<Duration>,
+    /// sender -> name of the rpc that accepted their latest transaction
+    pub(super) pending_tx_rpcs: Cache<Address, String>,
+    /// while starting up, a head without enough coverage is published as provisional
+    pub(super) provisional_head_window: Option<Duration>,
+    /// only set when round robin selection is on. the next turn within the best tier
+    pub(super) round_robin: Option<AtomicUsize>,
+    /// only set when a seed is given. otherwise load balancing uses a thread local rng seeded with entropy
+    pub(super) selection_rng: Option<Mutex<WyRand>>,
+    /// rpc key and session -> name of the rpc that served the session's latest request. None when sticky sessions are off
+    pub(super) session_rpcs: Option<Cache<String, String>>,
+}
+
+/// sender -> name of the rpc that accepted their latest transaction
+pub fn new_pending_tx_rpcs() -> Cache<Address, String> {
+    // transactions should be mined (or dropped) well before this expires
+    CacheBuilder::new(10_000)
+        .name("pending_tx_rpcs")
+        .time_to_live(Duration::from_secs(5 * 60))
+        .build()
+}
+
+/// rpc key and session -> name of the rpc that served the session's latest request
+pub fn new_session_rpcs(ttl: Duration) -> Cache<String, String> {
+    // expired sessions are evicted. their next request picks an rpc like normal
+    CacheBuilder::new(10_000)
+        .name("session_rpcs")
+        .time_to_idle(ttl)
+        .build()
+}
+
+/// Settings for a group of rpcs.
+/// Groups that only forward requests (like the private and bundler rpcs) only need `chain_id` and `name`.
+#[derive(Default)]
+pub struct Web3RpcsOptions {
+    pub chain_id: u64,
+    /// how to pick between heads on different chains
+    pub consensus_strategy: ConsensusStrategy,
+    /// these methods always go to the synced rpc with the lowest latency
+    pub fastest_methods: HashSet<String>,
+    /// requests for blocks up to this many past the consensus head wait for them
+    pub future_block_grace: u64,
+    /// the longest to wait for a block within future_block_grace
+    pub future_block_wait: Duration,
+    pub head_publish_delay: Option<Duration>,
+    pub head_verification_sample_rate: Option<u32>,
+    pub max_head_block_lag: Option<U64>,
+    pub max_parent_fetch_depth: Option<u64>,
+    pub max_recent_reorgs: usize,
+    pub method_pools: HashMap<String, String>,
+    pub minority_fork_timeout: Option<Duration>,
+    pub min_head_rpcs: usize,
+    pub min_sum_soft_limit: u32,
+    pub name: Cow<'static, str>,
+    /// sender -> name of the rpc that accepted their latest transaction.
+    /// share one cache between the groups so that a transaction sent through one group sticks nonce queries in the others
+    pub pending_tx_rpcs: Option<Cache<Address, String>>,
+    pub provisional_head_window: Option<Duration>,
+    pub round_robin: bool,
+    /// load balancing is only seeded in tests
+    pub selection_seed: Option<u64>,
+    /// rpc key and session -> name of the rpc that served the session's latest request. None turns sticky sessions off.
+    /// share one cache between the groups so that a session keeps its rpc when requests move between groups
+    pub session_rpcs: Option<Cache<String, String>>,
+    /// if set, the consensus head is published here
+    pub watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
+}
+
+impl Web3RpcsOptions {
+    /// the settings shared by the balanced rpcs and every rpc group
+    pub fn from_app_config(config: &AppConfig, name: Cow<'static, str>) -> Self {
+        Self {
+            chain_id: config.chain_id,
+            consensus_strategy: config.consensus_strategy,
+            fastest_methods: config.fastest_rpc_methods.clone(),
+            future_block_grace: config.future_block_grace,
+            future_block_wait: Duration::from_millis(config.future_block_wait_ms),
+            head_publish_delay: config.head_publish_delay_ms.map(Duration::from_millis),
+            head_verification_sample_rate: config.head_verification_sample_rate,
+            max_head_block_lag: config.max_head_block_lag,
+            max_parent_fetch_depth: config.max_parent_fetch_depth,
+            max_recent_reorgs: config.max_recent_reorgs,
+            method_pools: config.method_pools.clone(),
+            minority_fork_timeout: config.minority_fork_timeout_ms.map(Duration::from_millis),
+            min_head_rpcs: config.min_synced_rpcs,
+            min_sum_soft_limit: config.min_sum_soft_limit,
+            name,
+            pending_tx_rpcs: None,
+            provisional_head_window: config.provisional_head_window_ms.map(Duration::from_millis),
+            round_robin: config.round_robin_rpcs,
+            selection_seed: None,
+            session_rpcs: None,
+            watch_consensus_head_sender: None,
+        }
+    }
 }
 
 impl Web3Rpcs {
     /// Spawn durable connections to multiple Web3 providers.
     pub async fn spawn(
-        chain_id: u64,
-        max_head_block_lag: Option<U64>,
-        min_head_rpcs: usize,
-        min_sum_soft_limit: u32,
-        name: Cow<'static, str>,
-        watch_consensus_head_sender: Option<watch::Sender<Option<Web3ProxyBlock>>>,
+        options: Web3RpcsOptions,
+        shutdown_receiver: broadcast::Receiver<()>,
     ) -> anyhow::Result<(
         Arc<Self>,
         Web3ProxyJoinHandle<()>,
         watch::Receiver<Option<Arc<RankedRpcs>>>,
     )> {
+        let Web3RpcsOptions {
+            chain_id,
+            consensus_strategy,
+            fastest_methods,
+            future_block_grace,
+            future_block_wait,
+            head_publish_delay,
+            head_verification_sample_rate,
+            max_head_block_lag,
+            max_parent_fetch_depth,
+            max_recent_reorgs,
+            method_pools,
+            minority_fork_timeout,
+            min_head_rpcs,
+            min_sum_soft_limit,
+            name,
+            pending_tx_rpcs,
+            provisional_head_window,
+            round_robin,
+            selection_seed,
+            session_rpcs,
+            watch_consensus_head_sender,
+        } = options;
+
         let (block_sender, block_receiver) = mpsc::unbounded_channel::<BlockAndRpc>();
 
         // these blocks don't have full transactions, but they do have rather variable amounts of transaction hashes
@@ -99,6 +257,8 @@ impl Web3Rpcs {
             .time_to_idle(Duration::from_secs(30 * 60))
             .build();
 
+        let pending_tx_rpcs = pending_tx_rpcs.unwrap_or_else(new_pending_tx_rpcs);
+
         let (watch_consensus_rpcs_sender, consensus_connections_watcher) =
             watch::channel(Default::default());
 
@@ -111,16 +271,37 @@ impl Web3Rpcs {
             average_block_interval(chain_id).mul_f32((max_head_block_lag.as_u64() * 10) as f32);
 
         let connections = Arc::new(Self {
+            block_cache_repairs: Default::default(),
             block_sender,
             blocks_by_hash,
             blocks_by_number,
+            blocks_by_number_lock: Default::default(),
             by_name,
             chain_id,
+            consensus_strategy,
+            fastest_methods,
+            future_block_grace: future_block_grace.into(),
+            future_block_wait,
+            head_publish_delay,
+            head_verification_sample_rate,
             max_head_block_age,
             max_head_block_lag,
+            max_parent_fetch_depth,
+            method_pools,
             min_synced_rpcs: min_head_rpcs,
             min_sum_soft_limit,
+            minority_fork_timeout,
             name,
+            orphans_dropped: Default::default(),
+            pending_tx_rpcs,
+            provisional_head_window,
+            recent_reorgs: RecentReorgs::new(max_recent_reorgs),
+            reorg_observers: Default::default(),
+            reorg_sender: broadcast::channel(16).0,
+            round_robin: round_robin.then(Default::default),
+            selection_rng: selection_seed.map(|x| Mutex::new(WyRand::new_seed(x))),
+            session_rpcs,
+            watch_finalized_block: watch::channel(None).0,
             watch_head_block: watch_consensus_head_sender,
             watch_ranked_rpcs: watch_consensus_rpcs_sender,
         });
@@ -128,7 +309,7 @@ impl Web3Rpcs {
         let handle = {
             let connections = connections.clone();
 
-            tokio::spawn(connections.subscribe(block_receiver))
+            tokio::spawn(connections.subscribe(block_receiver, shutdown_receiver))
         };
 
         Ok((connections, handle, consensus_connections_watcher))
@@ -141,6 +322,8 @@ impl Web3Rpcs {
         rpc_configs: HashMap<String, Web3RpcConfig>,
     ) -> Web3ProxyResult<()> {
         // safety checks
+        check_duplicate_rpc_urls(&rpc_configs)?;
+
         if rpc_configs.len() < app.config.min_synced_rpcs {
             // TODO: don't count disabled servers!
             // TODO: include if this is balanced, private, or 4337
@@ -182,6 +365,35 @@ impl Web3Rpcs {
                 let http_client = app.http_client.clone();
                 let vredis_pool = app.vredis_pool.clone();
 
+                // rate limits can change without reconnecting. anything else needs a new connection
+                if let Some(old_rpc) = self.get(&server_name) {
+                    if old_rpc.config.same_connection(&server_config) {
+                        if old_rpc.hard_limit() != server_config.hard_limit {
+                            if let Err(err) = old_rpc.set_hard_limit(
+                                chain_id,
+                                server_config.hard_limit,
+                                vredis_pool.clone(),
+                            ) {
+                                error!(?err, "unable to update the hard limit on {}", old_rpc);
+                            }
+                        }
+
+                        if old_rpc.method_limits() != server_config.method_limits {
+                            if let Err(err) = old_rpc.set_method_limits(
+                                chain_id,
+                                &server_config.method_limits,
+                                vredis_pool,
+                            ) {
+                                error!(?err, "unable to update the method limits on {}", old_rpc);
+                            }
+                        }
+
+                        names_to_keep.push(server_name);
+
+                        return None;
+                    }
+                }
+
                 let block_sender = if self.watch_head_block.is_some() {
                     Some(self.block_sender.clone())
                 } else {
@@ -298,6 +510,11 @@ impl Web3Rpcs {
         self.by_name.read().is_empty()
     }
 
+    /// true if any of the rpcs keep every block's state
+    pub fn has_archive_rpcs(&self) -> bool {
+        self.by_name.read().values().any(|x| x.is_archive())
+    }
+
     /// TODO: rename to be consistent between "head" and "synced"
     pub fn min_head_rpcs(&self) -> usize {
         self.min_synced_rpcs
@@ -309,6 +526,7 @@ impl Web3Rpcs {
     async fn subscribe(
         self: Arc<Self>,
         block_receiver: mpsc::UnboundedReceiver<BlockAndRpc>,
+        shutdown_receiver: broadcast::Receiver<()>,
     ) -> Web3ProxyResult<()> {
         let mut futures = vec![];
 
@@ -340,10 +558,58 @@ impl Web3Rpcs {
         // setup the block funnel
         if self.watch_head_block.is_some() {
             let connections = Arc::clone(&self);
+            let shutdown_receiver = shutdown_receiver.resubscribe();
 
             let handle = tokio::task::Builder::default()
                 .name("process_incoming_blocks")
-                .spawn(async move { connections.process_incoming_blocks(block_receiver).await })?;
+                .spawn(async move {
+                    connections
+                        .process_incoming_blocks(block_receiver, shutdown_receiver)
+                        .await
+                })?;
+
+            futures.push(flatten_handle(handle));
+
+            let connections = Arc::clone(&self);
+            let shutdown_receiver = shutdown_receiver.resubscribe();
+
+            let handle = tokio::task::Builder::default()
+                .name("check_block_caches")
+                .spawn(async move {
+                    connections
+                        .check_block_caches_loop(Duration::from_secs(60), shutdown_receiver)
+                        .await
+                })?;
+
+            futures.push(flatten_handle(handle));
+
+            let connections = Arc::clone(&self);
+            let shutdown_receiver = shutdown_receiver.resubscribe();
+
+            let handle =
+                tokio::task::Builder::default()
+                    .name("prune_blocks")
+                    .spawn(async move {
+                        connections
+                            .prune_blocks_loop(Duration::from_secs(60), shutdown_receiver)
+                            .await
+                    })?;
+
+            futures.push(flatten_handle(handle));
+
+            let connections = Arc::clone(&self);
+            let shutdown_receiver = shutdown_receiver.resubscribe();
+
+            // finalized blocks only move once per epoch on most chains. no need to check every block
+            let period = average_block_interval(self.chain_id).mul_f32(10.0);
+
+            let handle = tokio::task::Builder::default()
+                .name("update_finalized_block")
+                .spawn(async move {
+                    connections
+                        .update_finalized_block_loop(period, shutdown_receiver)
+                        .await
+                })?;
 
             futures.push(flatten_handle(handle));
         }
@@ -351,13 +617,15 @@ impl Web3Rpcs {
         if futures.is_empty() {
             // no transaction or block subscriptions.
 
+            let mut shutdown_receiver = shutdown_receiver.resubscribe();
+
             let handle = tokio::task::Builder::default()
                 .name("noop")
                 .spawn(async move {
-                    loop {
-                        sleep(Duration::from_secs(600)).await;
-                        // TODO: "every interval, do a health check or disconnect the rpc"
-                    }
+                    // TODO: "every interval, do a health check or disconnect the rpc"
+                    let _ = shutdown_receiver.recv().await;
+
+                    Ok(())
                 })?;
 
             futures.push(flatten_handle(handle));
@@ -379,6 +647,7 @@ impl Web3Rpcs {
         active_request_handles: Vec<OpenRequestHandle>,
         method: &str,
         params: &P,
+        request_metadata: Option<&Arc<RequestMetadata>>,
         // TODO: remove this box once i figure out how to do the options
     ) -> Result<Box<RawValue>, ProviderError> {
         // TODO: if only 1 active_request_handles, do self.try_send_request?
@@ -387,20 +656,37 @@ impl Web3Rpcs {
         let responses = active_request_handles
             .into_iter()
             .map(|active_request_handle| async move {
+                let rpc = active_request_handle.clone_connection();
+
                 let result: Result<Box<RawValue>, _> =
                     active_request_handle.request(method, &json!(&params)).await;
-                result
+
+                (rpc, result)
             })
             .collect::<FuturesUnordered<_>>()
-            .collect::<Vec<Result<Box<RawValue>, ProviderError>>>()
+            .collect::<Vec<(Arc<Web3Rpc>, Result<Box<RawValue>, ProviderError>)>>()
             .await;
 
         // TODO: Strings are not great keys, but we can't use RawValue or ProviderError as keys because they don't implement Hash or Eq
         let mut count_map: HashMap<String, _> = HashMap::new();
         let mut counts: Counter<String> = Counter::new();
         let mut any_ok_with_json_result = false;
-        for partial_response in responses {
+        for (rpc, partial_response) in responses {
+            if let Err(err) = &partial_response {
+                debug!(%rpc, method, ?err, "parallel request failed");
+            }
+
             if partial_response.is_ok() {
+                if !any_ok_with_json_result && method == "eth_sendRawTransaction" {
+                    // the responses are in the order they finished. the fastest rpc to accept a transaction serves the sender's pending nonces
+                    self.stick_pending_nonce(&json!(&params), &rpc).await;
+                }
+
+                if !any_ok_with_json_result {
+                    // the session's next request (like eth_getTransactionReceipt) goes to the rpc that answered first
+                    self.stick_session(request_metadata, &rpc).await;
+                }
+
                 any_ok_with_json_result = true;
             }
 
@@ -414,6 +700,15 @@ impl Web3Rpcs {
             counts.update([s]);
         }
 
+        if count_map.values().filter(|x| x.is_ok()).count() > 1 {
+            // this shouldn't happen. a malformed transaction might do it
+            warn!(
+                method,
+                ?counts,
+                "rpcs disagree. using the most common result"
+            );
+        }
+
         // return the most_common success if any. otherwise return the most_common error
         for (most_common, _) in counts.most_common_ordered() {
             let most_common = count_map
@@ -440,62 +735,308 @@ impl Web3Rpcs {
         unimplemented!("this shouldn't be possible")
     }
 
+    /// sort by tier and block, and then randomly within those.
+    /// a seeded rng makes the order reproducible
+    fn shuffle_for_load_balancing(
+        &self,
+        rpcs: &mut [Arc<Web3Rpc>],
+        max_block_needed: Option<&U64>,
+    ) {
+        match self.selection_rng.as_ref() {
+            Some(rng) => {
+                let mut rng = rng.lock();
+
+                // the rpcs come out of hash maps. put them in a known order so the same seed gives the same shuffle
+                rpcs.sort_by(|a, b| a.name.cmp(&b.name));
+
+                rpcs.sort_by_cached_key(|x| {
+                    x.shuffle_for_load_balancing_on(&mut rng, max_block_needed.copied())
+                });
+            }
+            None => {
+                let mut rng = nanorand::tls_rng();
+
+                rpcs.sort_by_cached_key(|x| {
+                    x.shuffle_for_load_balancing_on(&mut rng, max_block_needed.copied())
+                });
+            }
+        }
+    }
+
+    /// sort by tier and block, and then take turns within the best of those
+    fn round_robin_for_load_balancing(
+        &self,
+        rpcs: &mut [Arc<Web3Rpc>],
+        max_block_needed: Option<&U64>,
+        turn: usize,
+    ) {
+        let max_block_needed = max_block_needed.copied();
+
+        // the rpcs come out of hash maps. put them in a known order so that every rpc gets its turn
+        rpcs.sort_by_cached_key(|x| (x.sort_on(max_block_needed), x.name.clone()));
+
+        let best = match rpcs.first() {
+            Some(x) => x.sort_on(max_block_needed),
+            None => return,
+        };
+
+        let num_best = rpcs
+            .iter()
+            .take_while(|x| x.sort_on(max_block_needed) == best)
+            .count();
+
+        // built once per request. each turn is then a binary search
+        let cumulative_soft_limits: Vec<u64> = rpcs[..num_best]
+            .iter()
+            .scan(0, |total, x| {
+                *total += x.soft_limit as u64;
+                Some(*total)
+            })
+            .collect();
+
+        let first = weighted_turn(&cumulative_soft_limits, turn);
+
+        rpcs[..num_best].rotate_left(first);
+    }
+
+    /// remember which rpc accepted a transaction so that the sender's pending nonce can be read from it
+    async fn stick_pending_nonce(&self, params: &serde_json::Value, rpc: &Web3Rpc) {
+        let tx = params
+            .get(0)
+            .and_then(|x| x.as_str())
+            .and_then(|x| Bytes::from_str(x).ok())
+            .and_then(|x| Transaction::decode(&Rlp::new(x.as_ref())).ok());
+
+        if let Some(from) = tx.and_then(|x| x.recover_from().ok()) {
+            trace!(?from, "pending nonces will prefer {}", rpc);
+
+            self.pending_tx_rpcs.insert(from, rpc.name.clone()).await;
+        }
+    }
+
+    /// for `eth_getTransactionCount` with "pending", the synced rpc that accepted the sender's latest transaction.
+    /// other rpcs might not have seen that transaction yet and would return an old nonce
+    pub fn sticky_pending_nonce_rpc(
+        &self,
+        method: &str,
+        params: &serde_json::Value,
+    ) -> Option<Arc<Web3Rpc>> {
+        if method != "eth_getTransactionCount" || params.get(1)?.as_str()? != "pending" {
+            return None;
+        }
+
+        let from: Address = serde_json::from_value(params.get(0)?.clone()).ok()?;
+
+        let rpc_name = self.pending_tx_rpcs.get(&from)?;
+
+        if self.watch_head_block.is_none() {
+            // groups that only forward requests (like the private rpcs) don't rank their rpcs
+            return self.by_name.read().get(&rpc_name).cloned();
+        }
+
+        let ranked_rpcs = self.watch_ranked_rpcs.borrow().clone()?;
+
+        ranked_rpcs
+            .all()
+            .iter()
+            .find(|x| x.name == rpc_name)
+            .cloned()
+    }
+
+    /// remember which rpc served a request in the client's sticky session
+    async fn stick_session(&self, request_metadata: Option<&Arc<RequestMetadata>>, rpc: &Web3Rpc) {
+        let Some(session_rpcs) = self.session_rpcs.as_ref() else {
+            return;
+        };
+
+        let session = request_metadata
+            .and_then(|x| x.authorization.as_ref())
+            .and_then(|x| x.sticky_session.clone());
+
+        if let Some(session) = session {
+            session_rpcs.insert(session, rpc.name.clone()).await;
+        }
+    }
+
+    /// the synced rpc that served the latest request in the client's sticky session.
+    /// None if it can't serve this request. the normal selection picks a new rpc for the session then
+    pub fn sticky_session_rpc(
+        &self,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Option<Arc<Web3Rpc>> {
+        let session_rpcs = self.session_rpcs.as_ref()?;
+
+        let session = request_metadata?
+            .authorization
+            .as_ref()?
+            .sticky_session
+            .as_ref()?;
+
+        let rpc_name = session_rpcs.get(session)?;
+
+        let ranked_rpcs = self.watch_ranked_rpcs.borrow().clone()?;
+
+        ranked_rpcs
+            .all()
+            .iter()
+            .find(|x| x.name == rpc_name)
+            .filter(|x| {
+                ranked_rpcs
+                    .rpc_skip_reason(min_block_needed, max_block_needed, x)
+                    .is_none()
+            })
+            .cloned()
+    }
+
+    /// for methods in fastest_methods, the synced rpc with the lowest peak latency
+    pub fn fastest_rpc(
+        &self,
+        method: &str,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Option<Arc<Web3Rpc>> {
+        if !self.fastest_methods.contains(method) {
+            return None;
+        }
+
+        let ranked_rpcs = self.watch_ranked_rpcs.borrow().clone()?;
+
+        ranked_rpcs
+            .all()
+            .iter()
+            .filter(|x| {
+                ranked_rpcs
+                    .rpc_skip_reason(min_block_needed, max_block_needed, x)
+                    .is_none()
+            })
+            .min_by_key(|x| (x.backup, x.peak_latency()))
+            .cloned()
+    }
+
+    /// the pool that serves this method. None = the general pool
+    fn method_pool(&self, method: &str) -> Option<&str> {
+        if let Some(x) = self.method_pools.get(method) {
+            return Some(x);
+        }
+
+        self.method_pools
+            .iter()
+            .filter_map(|(pattern, pool)| {
+                let prefix = pattern.strip_suffix('*')?;
+
+                method.starts_with(prefix).then_some((prefix.len(), pool))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, pool)| pool.as_str())
+    }
+
+    /// the rpcs that are not in this method's pool. they are skipped when picking an rpc for it
+    pub(super) fn outside_method_pool(&self, method: &str) -> Vec<Arc<Web3Rpc>> {
+        if self.method_pools.is_empty() {
+            return vec![];
+        }
+
+        let pool = self.method_pool(method);
+
+        self.by_name
+            .read()
+            .values()
+            .filter(|x| x.pool.as_deref() != pool)
+            .cloned()
+            .collect()
+    }
+
     async fn _best_available_rpc(
         &self,
         authorization: &Arc<Authorization>,
+        request_metadata: Option<&Arc<RequestMetadata>>,
         error_handler: Option<RequestErrorHandler>,
         potential_rpcs: &[Arc<Web3Rpc>],
         skip: &mut Vec<Arc<Web3Rpc>>,
+        earliest_retry_at: &mut Option<(Instant, Arc<Web3Rpc>)>,
     ) -> OpenRequestResult {
-        let mut earliest_retry_at = None;
+        let record_skip = |rpc: &Web3Rpc, reason| {
+            if let Some(request_metadata) = request_metadata {
+                request_metadata.provenance.lock().skip(rpc, reason);
+            }
+        };
+
+        let method = request_metadata.map(|x| x.method.as_ref());
 
-        for (rpc_a, rpc_b) in potential_rpcs.iter().circular_tuple_windows() {
-            trace!("{} vs {}", rpc_a, rpc_b);
-            // TODO: ties within X% to the server with the smallest block_data_limit
-            // faster rpc. backups always lose.
-            let faster_rpc = min_by_key(rpc_a, rpc_b, |x| (x.backup, x.weighted_peak_latency()));
-            trace!("winner: {}", faster_rpc);
+        // round robin already put the rpcs in the order they should be tried
+        let candidates: Vec<&Arc<Web3Rpc>> = if self.round_robin.is_some() {
+            potential_rpcs.iter().collect()
+        } else {
+            potential_rpcs
+                .iter()
+                .circular_tuple_windows()
+                .map(|(rpc_a, rpc_b)| {
+                    trace!("{} vs {}", rpc_a, rpc_b);
+                    // TODO: ties within X% to the server with the smallest block_data_limit
+                    // faster rpc. backups always lose.
+                    let faster_rpc =
+                        min_by_key(rpc_a, rpc_b, |x| (x.backup, x.weighted_peak_latency()));
+                    trace!("winner: {}", faster_rpc);
+                    faster_rpc
+                })
+                .collect()
+        };
 
+        for best_rpc in candidates {
             // add to the skip list in case this one fails
-            skip.push(Arc::clone(faster_rpc));
+            skip.push(Arc::clone(best_rpc));
 
-            // just because it has lower latency doesn't mean we are sure to get a connection. there might be rate limits
+            // just because it is next doesn't mean we are sure to get a connection. there might be rate limits
             // TODO: what error_handler?
-            match faster_rpc
-                .try_request_handle(authorization, error_handler)
+            match best_rpc
+                .try_request_handle(authorization, method, error_handler)
                 .await
             {
                 Ok(OpenRequestResult::Handle(handle)) => {
-                    trace!("opened handle: {}", faster_rpc);
+                    trace!("opened handle: {}", best_rpc);
                     return OpenRequestResult::Handle(handle);
                 }
                 Ok(OpenRequestResult::RetryAt(retry_at)) => {
                     trace!(
                         "retry on {} @ {}",
-                        faster_rpc,
+                        best_rpc,
                         retry_at.duration_since(Instant::now()).as_secs_f32()
                     );
 
-                    if earliest_retry_at.is_none() {
-                        earliest_retry_at = Some(retry_at);
-                    } else {
-                        earliest_retry_at = earliest_retry_at.min(Some(retry_at));
+                    record_skip(best_rpc, SkipReason::RateLimited);
+
+                    if earliest_retry_at
+                        .as_ref()
+                        .map(|(x, _)| retry_at < *x)
+                        .unwrap_or(true)
+                    {
+                        *earliest_retry_at = Some((retry_at, best_rpc.clone()));
                     }
                 }
                 Ok(OpenRequestResult::NotReady) => {
                     // TODO: log a warning? emit a stat?
-                    trace!("best_rpc not ready: {}", faster_rpc);
+                    trace!("best_rpc not ready: {}", best_rpc);
+
+                    record_skip(best_rpc, SkipReason::NotReady);
+                }
+                Ok(OpenRequestResult::Busy) => {
+                    trace!("best_rpc is busy: {}", best_rpc);
+
+                    record_skip(best_rpc, SkipReason::Busy);
                 }
                 Err(err) => {
-                    trace!("No request handle for {}. err={:?}", faster_rpc, err)
+                    trace!("No request handle for {}. err={:?}", best_rpc, err);
+
+                    record_skip(best_rpc, SkipReason::Error);
                 }
             }
         }
 
-        if let Some(retry_at) = earliest_retry_at {
-            OpenRequestResult::RetryAt(retry_at)
-        } else {
-            OpenRequestResult::NotReady
+        match earliest_retry_at {
+            Some((retry_at, _)) => OpenRequestResult::RetryAt(*retry_at),
+            None => OpenRequestResult::NotReady,
         }
     }
 
@@ -535,41 +1076,117 @@ impl Web3Rpcs {
                         .all()
                         .iter()
                         .filter(|rpc| {
-                            ranked_rpcs.rpc_will_work_now(
-                                skip_rpcs,
+                            if skip_rpcs.contains(rpc) {
+                                return false;
+                            }
+
+                            match ranked_rpcs.rpc_skip_reason(
                                 min_block_needed,
                                 max_block_needed,
                                 rpc,
-                            )
+                            ) {
+                                None => true,
+                                Some(reason) => {
+                                    if let Some(request_metadata) = request_metadata {
+                                        request_metadata.provenance.lock().skip(rpc, reason);
+                                    }
+                                    false
+                                }
+                            }
                         })
                         .cloned(),
                 );
 
                 if potential_rpcs.len() >= self.min_synced_rpcs {
-                    // we have enough potential rpcs. try to load balance
-                    potential_rpcs.sort_by_cached_key(|x| {
-                        x.shuffle_for_load_balancing_on(max_block_needed.copied())
-                    });
-
-                    match self
-                        ._best_available_rpc(
-                            &authorization,
-                            error_handler,
-                            &potential_rpcs,
-                            skip_rpcs,
-                        )
-                        .await
-                    {
-                        OpenRequestResult::Handle(x) => return Ok(OpenRequestResult::Handle(x)),
-                        OpenRequestResult::NotReady => {}
-                        OpenRequestResult::RetryAt(retry_at) => {
-                            if earliest_retry_at.is_none() {
-                                earliest_retry_at = Some(retry_at);
-                            } else {
-                                earliest_retry_at = earliest_retry_at.min(Some(retry_at));
+                    // save the archive rpcs for the requests that need them. anything a full node can serve goes to them first.
+                    // old blocks were already filtered to only the rpcs that have them, so those still go to the archive rpcs
+                    let (mut full_rpcs, mut archive_rpcs): (Vec<_>, Vec<_>) = potential_rpcs
+                        .iter()
+                        .cloned()
+                        .partition(|x| !x.is_archive());
+
+                    // one turn per request. both partitions use it so neither skips rpcs
+                    let turn = self
+                        .round_robin
+                        .as_ref()
+                        .map(|next| next.fetch_add(1, Ordering::Relaxed));
+
+                    // we have enough potential rpcs. load balance by tier within each partition
+                    for rpcs in [&mut full_rpcs, &mut archive_rpcs] {
+                        match turn {
+                            Some(turn) => {
+                                self.round_robin_for_load_balancing(rpcs, max_block_needed, turn)
+                            }
+                            None => self.shuffle_for_load_balancing(rpcs, max_block_needed),
+                        }
+                    }
+
+                    // the rate limited rpc that replenishes first, across both partitions
+                    let mut earliest_retry_rpc = None;
+
+                    for rpcs in [full_rpcs, archive_rpcs] {
+                        if rpcs.is_empty() {
+                            continue;
+                        }
+
+                        if let OpenRequestResult::Handle(x) = self
+                            ._best_available_rpc(
+                                &authorization,
+                                request_metadata,
+                                error_handler,
+                                &rpcs,
+                                skip_rpcs,
+                                &mut earliest_retry_rpc,
+                            )
+                            .await
+                        {
+                            if let Some(request_metadata) = request_metadata {
+                                request_metadata
+                                    .provenance
+                                    .lock()
+                                    .choose(&x.clone_connection());
                             }
+
+                            return Ok(OpenRequestResult::Handle(x));
                         }
                     }
+
+                    // everything is rate limited. this is the only place that waits in an rpc's queue (if it has one).
+                    // the queue never holds the request past our own max_wait
+                    if let Some((retry_at, rpc)) = earliest_retry_rpc {
+                        let method = request_metadata.map(|x| x.method.as_ref());
+
+                        let retry_at = match rpc
+                            .wait_in_rate_limit_queue(
+                                &authorization,
+                                method,
+                                error_handler,
+                                retry_at,
+                                max_wait.map(|x| start + x),
+                            )
+                            .await
+                        {
+                            Ok(OpenRequestResult::Handle(x)) => {
+                                if let Some(request_metadata) = request_metadata {
+                                    request_metadata
+                                        .provenance
+                                        .lock()
+                                        .choose(&x.clone_connection());
+                                }
+
+                                return Ok(OpenRequestResult::Handle(x));
+                            }
+                            Ok(OpenRequestResult::RetryAt(x)) => x,
+                            Ok(OpenRequestResult::NotReady | OpenRequestResult::Busy) => retry_at,
+                            Err(err) => {
+                                trace!("No request handle for {}. err={:?}", rpc, err);
+                                retry_at
+                            }
+                        };
+
+                        earliest_retry_at =
+                            Some(earliest_retry_at.map_or(retry_at, |x: Instant| x.min(retry_at)));
+                    }
                 }
 
                 let waiting_for = min_block_needed.max(max_block_needed);
@@ -626,6 +1243,92 @@ impl Web3Rpcs {
         }
     }
 
+    /// forward logs from a synced rpc's websocket until this future is dropped.
+    /// if the subscription ends, another rpc is used
+    pub async fn subscribe_logs(&self, filter: Filter, sender: LogsSender) {
+        loop {
+            // TODO: prefer the same rpc as last time?
+            let rpc = self.watch_ranked_rpcs.borrow().as_ref().and_then(|x| {
+                x.all()
+                    .iter()
+                    .find(|rpc| rpc.ws_provider.load().is_some())
+                    .cloned()
+            });
+
+            match rpc {
+                Some(rpc) => {
+                    if let Err(err) = rpc.forward_logs(&filter, &sender).await {
+                        warn!(%rpc, ?err, "logs subscription failed");
+                    }
+                }
+                None => {
+                    debug!("no synced rpcs with websockets for a logs subscription");
+                }
+            }
+
+            sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    /// forward pending transactions from a synced rpc's websocket and the transactions in each new head block until this future is dropped.
+    /// if the subscription ends, another rpc is used
+    pub async fn subscribe_pending_txs(&self, sender: PendingTxSender) {
+        // rpcs announce transactions again after a reconnect. confirmed transactions that are announced late are not pending
+        // TODO: what's a good capacity for this?
+        let seen: Cache<TxHash, ()> = CacheBuilder::new(100_000)
+            .time_to_live(Duration::from_secs(600))
+            .build();
+
+        let pending = async {
+            loop {
+                let rpc = self.watch_ranked_rpcs.borrow().as_ref().and_then(|x| {
+                    x.all()
+                        .iter()
+                        .find(|rpc| rpc.ws_provider.load().is_some())
+                        .cloned()
+                });
+
+                match rpc {
+                    Some(rpc) => {
+                        if let Err(err) = rpc.forward_pending_txs(&sender, &seen).await {
+                            warn!(%rpc, ?err, "pending transactions subscription failed");
+                        }
+                    }
+                    None => {
+                        debug!("no synced rpcs with websockets for a pending transactions subscription");
+                    }
+                }
+
+                sleep(Duration::from_secs(1)).await;
+            }
+        };
+
+        let confirmed = async {
+            let Some(mut head_block_receiver) =
+                self.watch_head_block.as_ref().map(|x| x.subscribe())
+            else {
+                return;
+            };
+
+            while head_block_receiver.changed().await.is_ok() {
+                let head_block = head_block_receiver.borrow_and_update().clone();
+
+                let Some(head_block) = head_block else {
+                    continue;
+                };
+
+                for tx_hash in head_block.block.transactions.iter() {
+                    seen.insert(*tx_hash, ()).await;
+
+                    // an error just means that no clients are listening right now
+                    let _ = sender.send(TxStatus::Confirmed(*tx_hash));
+                }
+            }
+        };
+
+        tokio::join!(pending, confirmed);
+    }
+
     /// get all rpc servers that are not rate limited
     /// this prefers synced servers, but it will return servers even if they aren't fully in sync.
     /// This is useful for broadcasting signed transactions.
@@ -668,6 +1371,8 @@ impl Web3Rpcs {
             .and_then(|x| x.authorization.clone())
             .unwrap_or_default();
 
+        let method = request_metadata.map(|x| x.method.as_ref());
+
         for rpc in all_rpcs {
             trace!("trying {}", rpc);
 
@@ -687,7 +1392,10 @@ impl Web3Rpcs {
             }
 
             // check rate limits and increment our connection counter
-            match rpc.try_request_handle(&authorization, error_level).await {
+            match rpc
+                .try_request_handle(&authorization, method, error_level)
+                .await
+            {
                 Ok(OpenRequestResult::RetryAt(retry_at)) => {
                     // this rpc is not available. skip it
                     trace!("{} is rate limited. skipping", rpc);
@@ -703,7 +1411,15 @@ impl Web3Rpcs {
                     }
                 }
                 Ok(OpenRequestResult::NotReady) => {
-                    warn!("no request handle for {}", rpc)
+                    // an open circuit was logged when it opened
+                    if rpc.circuit_state() == CircuitState::Closed {
+                        warn!("no request handle for {}", rpc)
+                    } else {
+                        trace!("circuit on {} is not closed. skipping", rpc)
+                    }
+                }
+                Ok(OpenRequestResult::Busy) => {
+                    trace!("{} is busy. skipping", rpc);
                 }
                 Err(err) => {
                     warn!(?err, "error getting request handle for {}", rpc)
@@ -771,7 +1487,14 @@ impl Web3Rpcs {
                 Err(err) => {
                     // TODO: only log params in dev
                     warn!(rpc=%self, %method, ?params, ?err, %tries, "retry-able error");
-                    last_error = Some(err)
+                    last_error = Some(err);
+
+                    if request_metadata
+                        .map(|x| x.out_of_backend_attempts())
+                        .unwrap_or_default()
+                    {
+                        break;
+                    }
                 }
             }
         }
@@ -793,17 +1516,92 @@ impl Web3Rpcs {
         min_block_needed: Option<&U64>,
         max_block_needed: Option<&U64>,
     ) -> Web3ProxyResult<R> {
-        let mut skip_rpcs = vec![];
-        let mut method_not_available_response = None;
-
-        let mut watch_consensus_rpcs = self.watch_ranked_rpcs.subscribe();
+        self._request_with_metadata(
+            method,
+            params,
+            request_metadata,
+            max_wait,
+            min_block_needed,
+            max_block_needed,
+            |handle| handle.request::<P, R>(method, params),
+        )
+        .await
+    }
 
-        let start = Instant::now();
+    /// Like `request_with_metadata`, but big responses are streamed instead of read into memory.
+    /// Streamed responses are not checked for errors. Only the start of their body has been read.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn stream_with_metadata(
+        &self,
+        id: &RawValue,
+        method: &str,
+        params: &serde_json::Value,
+        request_metadata: &Arc<RequestMetadata>,
+        max_buffer_bytes: usize,
+        max_wait: Option<Duration>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Web3ProxyResult<StreamOrBuffered<Box<RawValue>>> {
+        self._request_with_metadata(
+            method,
+            params,
+            Some(request_metadata),
+            max_wait,
+            min_block_needed,
+            max_block_needed,
+            |handle| handle.stream_request(id, method, params, max_buffer_bytes, request_metadata),
+        )
+        .await
+    }
+
+    /// pick rpcs and retry errors on other rpcs. `send` makes the actual request
+    #[allow(clippy::too_many_arguments)]
+    async fn _request_with_metadata<P, T, F, Fut>(
+        &self,
+        method: &str,
+        params: &P,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        max_wait: Option<Duration>,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+        send: F,
+    ) -> Web3ProxyResult<T>
+    where
+        P: JsonRpcParams,
+        F: Fn(OpenRequestHandle) -> Fut,
+        Fut: Future<Output = Result<T, ProviderError>>,
+    {
+        // rpcs dedicated to another pool are never tried
+        let mut skip_rpcs = self.outside_method_pool(method);
+        let num_outside_pool = skip_rpcs.len();
+        let mut method_not_available_response = None;
+
+        let mut watch_consensus_rpcs = self.watch_ranked_rpcs.subscribe();
+
+        let start = Instant::now();
 
         // set error_handler to Save. this might be overridden depending on the request_metadata.authorization
         let error_handler = Some(RequestErrorHandler::Save);
 
         let mut last_provider_error = None;
+        let mut num_provider_errors = 0;
+
+        let backend_timeout = request_metadata.and_then(|x| x.backend_timeout);
+
+        // only eth_getTransactionCount can be sticky by nonce. check that before paying to turn the params into a Value
+        let pending_nonce_rpc = if method == "eth_getTransactionCount" {
+            self.sticky_pending_nonce_rpc(method, &json!(params))
+        } else {
+            None
+        };
+
+        // try the sticky or fastest rpc first. if it errors, it is in skip_rpcs and the normal selection takes over
+        let mut sticky_rpc = pending_nonce_rpc
+            .or_else(|| {
+                self.sticky_session_rpc(request_metadata, min_block_needed, max_block_needed)
+            })
+            .or_else(|| self.fastest_rpc(method, min_block_needed, max_block_needed))
+            .filter(|x| !skip_rpcs.contains(x));
 
         // TODO: the loop here feels somewhat redundant with the loop in best_available_rpc
         loop {
@@ -814,17 +1612,54 @@ impl Web3Rpcs {
                 }
             }
 
-            match self
-                .wait_for_best_rpc(
-                    request_metadata,
-                    &mut skip_rpcs,
-                    min_block_needed,
-                    max_block_needed,
-                    max_wait,
-                    error_handler,
-                )
-                .await?
-            {
+            if let Some(request_metadata) = request_metadata {
+                if request_metadata.out_of_backend_attempts() {
+                    trace!(max_backend_attempts=?request_metadata.max_backend_attempts, "out of backend attempts");
+                    break;
+                }
+            }
+
+            let sticky_handle = match sticky_rpc.take() {
+                Some(rpc) => {
+                    let authorization = request_metadata
+                        .and_then(|x| x.authorization.clone())
+                        .unwrap_or_default();
+
+                    skip_rpcs.push(rpc.clone());
+
+                    match rpc
+                        .try_request_handle(&authorization, Some(method), error_handler)
+                        .await
+                    {
+                        Ok(OpenRequestResult::Handle(handle)) => {
+                            if let Some(request_metadata) = request_metadata {
+                                request_metadata.provenance.lock().choose(&rpc);
+                            }
+
+                            Some(handle)
+                        }
+                        _ => None,
+                    }
+                }
+                None => None,
+            };
+
+            let open_request_result = match sticky_handle {
+                Some(handle) => OpenRequestResult::Handle(handle),
+                None => {
+                    self.wait_for_best_rpc(
+                        request_metadata,
+                        &mut skip_rpcs,
+                        min_block_needed,
+                        max_block_needed,
+                        max_wait,
+                        error_handler,
+                    )
+                    .await?
+                }
+            };
+
+            match open_request_result {
                 OpenRequestResult::Handle(active_request_handle) => {
                     // save the rpc in case we get an error and want to retry on another server
                     // TODO: look at backend_requests instead
@@ -836,7 +1671,37 @@ impl Web3Rpcs {
 
                     let is_backup_response = rpc.backup;
 
-                    match active_request_handle.request::<P, R>(method, params).await {
+                    let response = match backend_timeout {
+                        Some(backend_timeout) => {
+                            match timeout(backend_timeout, send(active_request_handle)).await {
+                                Ok(x) => x,
+                                Err(_) => {
+                                    // dropping the future cancelled the request. its handle freed up a slot
+                                    warn!(%method, ?backend_timeout, "request to {} timed out", rpc);
+
+                                    if let Some(request_metadata) = request_metadata {
+                                        request_metadata
+                                            .provenance
+                                            .lock()
+                                            .error(&rpc, &"timed out");
+
+                                        request_metadata
+                                            .error_response
+                                            .store(true, Ordering::Release);
+                                    }
+
+                                    if is_idempotent(method) {
+                                        continue;
+                                    }
+
+                                    return Err(Web3ProxyError::Timeout(Some(backend_timeout)));
+                                }
+                            }
+                        }
+                        None => send(active_request_handle).await,
+                    };
+
+                    match response {
                         Ok(response) => {
                             // TODO: if there are multiple responses being aggregated, this will only use the last server's backup type
                             if let Some(request_metadata) = request_metadata {
@@ -853,9 +1718,15 @@ impl Web3Rpcs {
                                     .store(false, Ordering::Release);
                             }
 
+                            self.stick_session(request_metadata, &rpc).await;
+
                             return Ok(response);
                         }
                         Err(error) => {
+                            if let Some(request_metadata) = request_metadata {
+                                request_metadata.provenance.lock().error(&rpc, &error);
+                            }
+
                             // TODO: if this is an error, do NOT return. continue to try on another server
                             let error = match JsonRpcErrorData::try_from(&error) {
                                 Ok(x) => {
@@ -881,6 +1752,22 @@ impl Web3Rpcs {
 
                                     last_provider_error = Some(error);
 
+                                    // the next rpc is tried right away. after that, back off in case the problem is on our side
+                                    let backoff = retry_backoff(num_provider_errors);
+                                    num_provider_errors += 1;
+
+                                    if !backoff.is_zero() {
+                                        let backoff = match max_wait {
+                                            Some(max_wait) => backoff
+                                                .min(max_wait.saturating_sub(start.elapsed())),
+                                            None => backoff,
+                                        };
+
+                                        trace!(?backoff, "backing off before the next rpc");
+
+                                        sleep(backoff).await;
+                                    }
+
                                     continue;
                                 }
                             };
@@ -1013,14 +1900,17 @@ impl Web3Rpcs {
                     tokio::select! {
                         _ = sleep_until(retry_at) => {
                             trace!("slept!");
-                            skip_rpcs.pop();
+                            // the rpcs outside of the method's pool stay skipped
+                            if skip_rpcs.len() > num_outside_pool {
+                                skip_rpcs.pop();
+                            }
                         }
                         _ = watch_consensus_rpcs.changed() => {
                             watch_consensus_rpcs.borrow_and_update();
                         }
                     }
                 }
-                OpenRequestResult::NotReady => {
+                OpenRequestResult::NotReady | OpenRequestResult::Busy => {
                     if let Some(request_metadata) = request_metadata {
                         request_metadata
                             .error_response
@@ -1072,6 +1962,9 @@ impl Web3Rpcs {
                 ?params,
                 "No servers synced",
             );
+
+            // this is our failure, not a jsonrpc error about the request. it gets an http error status
+            return Err(Web3ProxyError::NoServersSynced);
         } else if head_block_num.as_ref() > needed {
             // we have synced past the needed block
             // TODO: log ranked rpcs
@@ -1167,7 +2060,12 @@ impl Web3Rpcs {
                     }
 
                     let x = self
-                        .try_send_parallel_requests(active_request_handles, method, params)
+                        .try_send_parallel_requests(
+                            active_request_handles,
+                            method,
+                            params,
+                            request_metadata,
+                        )
                         .await?;
 
                     return Ok(x);
@@ -1243,6 +2141,85 @@ impl Web3Rpcs {
         Err(Web3ProxyError::NoServersSynced)
     }
 
+    /// Send the request to the best `quorum` synced rpcs that have the needed blocks and only return a result that all of them agree on.
+    pub async fn try_send_quorum<P: JsonRpcParams>(
+        &self,
+        method: &str,
+        params: &P,
+        request_metadata: Option<&Arc<RequestMetadata>>,
+        quorum: usize,
+        min_block_needed: Option<&U64>,
+        max_block_needed: Option<&U64>,
+    ) -> Web3ProxyResult<Box<RawValue>> {
+        let ranked_rpcs = self
+            .watch_ranked_rpcs
+            .borrow()
+            .clone()
+            .ok_or(Web3ProxyError::NoServersSynced)?;
+
+        let authorization = request_metadata
+            .and_then(|x| x.authorization.clone())
+            .unwrap_or_default();
+
+        let mut active_request_handles = Vec::with_capacity(quorum);
+
+        // every extra rpc is another backend request. only ask as many as need to agree
+        for rpc in ranked_rpcs.all() {
+            if active_request_handles.len() >= quorum {
+                break;
+            }
+
+            if let Some(block_needed) = min_block_needed {
+                if !rpc.has_block_data(block_needed) {
+                    continue;
+                }
+            }
+
+            if let Some(block_needed) = max_block_needed {
+                if !rpc.has_block_data(block_needed) {
+                    continue;
+                }
+            }
+
+            if let Ok(OpenRequestResult::Handle(handle)) = rpc
+                .try_request_handle(&authorization, Some(method), None)
+                .await
+            {
+                active_request_handles.push(handle);
+            }
+        }
+
+        if active_request_handles.len() < quorum {
+            return Err(Web3ProxyError::NotEnoughRpcs {
+                num_known: active_request_handles.len(),
+                min_head_rpcs: quorum,
+            });
+        }
+
+        if let Some(request_metadata) = request_metadata {
+            request_metadata
+                .backend_requests
+                .lock()
+                .extend(active_request_handles.iter().map(|x| x.clone_connection()));
+        }
+
+        let responses = active_request_handles
+            .into_iter()
+            .map(|active_request_handle| async move {
+                let rpc = active_request_handle.connection_name();
+
+                let result: Result<Box<RawValue>, _> =
+                    active_request_handle.request(method, &json!(&params)).await;
+
+                (rpc, result)
+            })
+            .collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>()
+            .await;
+
+        quorum_response(responses, quorum)
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn try_proxy_connection<P: JsonRpcParams, R: JsonRpcResultData>(
         &self,
@@ -1275,6 +2252,121 @@ impl Web3Rpcs {
     }
 }
 
+/// the response that at least `quorum` rpcs agree on. agreeing errors (like a revert) count too
+fn quorum_response(
+    responses: Vec<(String, Result<Box<RawValue>, ProviderError>)>,
+    quorum: usize,
+) -> Web3ProxyResult<Box<RawValue>> {
+    // rpcs format the same result differently (whitespace, key order), so results are compared after parsing
+    let parsed: Vec<Result<serde_json::Value, String>> = responses
+        .iter()
+        .map(|(_, x)| match x {
+            Ok(x) => Ok(serde_json::from_str(x.get()).unwrap_or_else(|_| json!(x.get()))),
+            Err(err) => Err(format!("{:?}", err)),
+        })
+        .collect();
+
+    // Value can't be a hash key. there are only a few responses, so compare them all
+    let mut counts: Vec<(usize, usize)> = vec![];
+
+    for (i, x) in parsed.iter().enumerate() {
+        match counts.iter_mut().find(|(first, _)| &parsed[*first] == x) {
+            Some((_, num)) => *num += 1,
+            None => counts.push((i, 1)),
+        }
+    }
+
+    // stable, so ties keep the order of the responses
+    counts.sort_by_key(|(_, num)| Reverse(*num));
+
+    if let Some((agreed, num)) = counts.first().copied() {
+        // a tie means there is no single answer to return
+        let tied = counts.get(1).map(|(_, x)| *x == num).unwrap_or(false);
+
+        if num >= quorum && !tied {
+            let (_, x) = responses
+                .into_iter()
+                .nth(agreed)
+                .expect("counts only has indexes of responses");
+
+            return x.map_err(Into::into);
+        }
+    }
+
+    let responses = responses
+        .into_iter()
+        .map(|(rpc, x)| {
+            let x = match x {
+                Ok(x) => serde_json::from_str(x.get()).unwrap_or_else(|_| json!(x.get())),
+                Err(err) => json!({ "error": err.to_string() }),
+            };
+
+            (rpc, x)
+        })
+        .collect();
+
+    Err(Web3ProxyError::InconsistentUpstreams(responses))
+}
+
+#[cfg(test)]
+impl Web3Rpcs {
+    /// rpcs for tests. nothing is spawned and there are no sticky sessions or seeded selection.
+    /// the receivers are returned so that sending blocks doesn't fail. change any other fields with struct update syntax
+    pub(crate) fn test_new(
+        chain_id: u64,
+        by_name: HashMap<String, Arc<Web3Rpc>>,
+    ) -> (
+        Self,
+        mpsc::UnboundedReceiver<BlockAndRpc>,
+        watch::Receiver<Option<Web3ProxyBlock>>,
+    ) {
+        let (block_sender, block_receiver) = mpsc::unbounded_channel();
+        let (watch_consensus_head_sender, watch_consensus_head_receiver) = watch::channel(None);
+
+        let x = Self {
+            block_cache_repairs: Default::default(),
+            block_sender,
+            blocks_by_hash: CacheBuilder::new(100)
+                .time_to_live(Duration::from_secs(60))
+                .build(),
+            blocks_by_number: CacheBuilder::new(100)
+                .time_to_live(Duration::from_secs(60))
+                .build(),
+            blocks_by_number_lock: Default::default(),
+            by_name: RwLock::new(by_name),
+            chain_id,
+            consensus_strategy: Default::default(),
+            fastest_methods: Default::default(),
+            future_block_grace: 2.into(),
+            future_block_wait: Duration::from_secs(1),
+            head_publish_delay: None,
+            head_verification_sample_rate: None,
+            max_head_block_age: Duration::from_secs(60),
+            max_head_block_lag: 5.into(),
+            max_parent_fetch_depth: None,
+            method_pools: Default::default(),
+            min_synced_rpcs: 1,
+            min_sum_soft_limit: 1,
+            minority_fork_timeout: None,
+            name: "test".into(),
+            orphans_dropped: Default::default(),
+            pending_tx_rpcs: CacheBuilder::new(100).build(),
+            provisional_head_window: None,
+            recent_reorgs: Default::default(),
+            reorg_observers: Default::default(),
+            reorg_sender: broadcast::channel(16).0,
+            round_robin: None,
+            selection_rng: None,
+            session_rpcs: None,
+            watch_finalized_block: watch::channel(None).0,
+            watch_head_block: Some(watch_consensus_head_sender),
+            watch_ranked_rpcs: watch::channel(None).0,
+        };
+
+        (x, block_receiver, watch_consensus_head_receiver)
+    }
+}
+
 impl Display for Web3Rpcs {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_str(&self.name)
@@ -1293,12 +2385,67 @@ impl fmt::Debug for Web3Rpcs {
     }
 }
 
+/// false for methods that send transactions. sending them again after a timeout could send them twice
+fn is_idempotent(method: &str) -> bool {
+    !matches!(
+        method,
+        "eth_sendRawTransaction"
+            | "eth_sendRawTransactionConditional"
+            | "eth_sendBundle"
+            | "eth_sendPrivateTransaction"
+            | "eth_sendUserOperation"
+    )
+}
+
+/// how long to wait before retrying a request that got a provider error (timeout, bad status, dropped connection).
+/// the first retry is immediate. after that it doubles from 50ms up to 1 second
+fn retry_backoff(num_errors: u32) -> Duration {
+    match num_errors {
+        0 => Duration::ZERO,
+        x => Duration::from_millis(50 << (x - 1).min(5)).min(Duration::from_secs(1)),
+    }
+}
+
+/// which rpc's turn it is, with turns given out in proportion to the weights.
+/// `cumulative_weights` is the running total of the weights, so the last one is the sum of all of them.
+/// every cycle of `sum / gcd` turns gives each rpc exactly its share. within a cycle, the turns step through
+/// the weights by about sum / golden ratio, so one rpc doesn't get a long run of turns
+fn weighted_turn(cumulative_weights: &[u64], turn: usize) -> usize {
+    let sum = match cumulative_weights.last() {
+        Some(x) if *x > 0 => *x,
+        // no weights. take plain turns
+        _ => return turn % cumulative_weights.len().max(1),
+    };
+
+    // the running totals have the same gcd as the weights. equal weights become a cycle of one turn each
+    let unit = cumulative_weights.iter().fold(0, |a, b| gcd(a, *b));
+    let cycle = sum / unit;
+
+    // the step has to be coprime with the cycle for every turn in the cycle to be different
+    let mut step = ((cycle as f64 / 1.618_033_988_75).round() as u64).max(1);
+    while gcd(step, cycle) != 1 {
+        step += 1;
+    }
+
+    let point = (turn as u128 % cycle as u128 * step as u128 % cycle as u128) as u64 * unit;
+
+    cumulative_weights.partition_point(|x| *x <= point)
+}
+
+fn gcd(mut a: u64, mut b: u64) -> u64 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+
+    a
+}
+
 impl Serialize for Web3Rpcs {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        let mut state = serializer.serialize_struct("Web3Rpcs", 5)?;
+        let mut state = serializer.serialize_struct("Web3Rpcs", 8)?;
 
         {
             let by_name = self.by_name.read();
@@ -1318,6 +2465,11 @@ impl Serialize for Web3Rpcs {
             }
         }
 
+        state.serialize_field("finalized_block", &*self.watch_finalized_block.borrow())?;
+
+        state.serialize_field("min_sum_soft_limit", &self.min_sum_soft_limit)?;
+        state.serialize_field("synced_soft_limit", &self.synced_soft_limit())?;
+
         state.serialize_field(
             "caches",
             &(
@@ -1345,11 +2497,14 @@ mod tests {
     #![allow(unused_imports)]
 
     use super::*;
+    use crate::dead_letters::DeadLetters;
     use crate::rpcs::blockchain::Web3ProxyBlock;
     use crate::rpcs::consensus::ConsensusFinder;
     use arc_swap::ArcSwap;
+    use ethers::signers::{LocalWallet, Signer};
+    use ethers::types::transaction::eip2718::TypedTransaction;
     use ethers::types::H256;
-    use ethers::types::{Block, U256};
+    use ethers::types::{Block, TransactionRequest, U256};
     use latency::PeakEwmaLatency;
     use moka::future::{Cache, CacheBuilder};
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -1507,37 +2662,14 @@ mod tests {
         let head_rpc = Arc::new(head_rpc);
         let lagged_rpc = Arc::new(lagged_rpc);
 
-        let (block_sender, _block_receiver) = mpsc::unbounded_channel();
-        let (watch_ranked_rpcs, _watch_consensus_rpcs_receiver) = watch::channel(None);
-        let (watch_consensus_head_sender, _watch_consensus_head_receiver) = watch::channel(None);
-
         let chain_id = 1;
 
         let mut by_name = HashMap::new();
         by_name.insert(head_rpc.name.clone(), head_rpc.clone());
         by_name.insert(lagged_rpc.name.clone(), lagged_rpc.clone());
 
-        // TODO: make a Web3Rpcs::new
-        let rpcs = Web3Rpcs {
-            block_sender: block_sender.clone(),
-            by_name: RwLock::new(by_name),
-            chain_id,
-            name: "test".into(),
-            watch_head_block: Some(watch_consensus_head_sender),
-            watch_ranked_rpcs,
-            blocks_by_hash: CacheBuilder::new(100)
-                .time_to_live(Duration::from_secs(60))
-                .build(),
-            blocks_by_number: CacheBuilder::new(100)
-                .time_to_live(Duration::from_secs(60))
-                .build(),
-            // TODO: test max_head_block_age?
-            max_head_block_age: Duration::from_secs(60),
-            // TODO: test max_head_block_lag?
-            max_head_block_lag: 5.into(),
-            min_synced_rpcs: 1,
-            min_sum_soft_limit: 1,
-        };
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(chain_id, by_name);
 
         let mut consensus_finder = ConsensusFinder::new(None, None);
 
@@ -1585,7 +2717,7 @@ mod tests {
         lagged_rpc
             .send_head_block_result(
                 Ok(Some(lagged_block.clone())),
-                &block_sender,
+                &rpcs.block_sender,
                 &rpcs.blocks_by_hash,
             )
             .await
@@ -1604,7 +2736,7 @@ mod tests {
         head_rpc
             .send_head_block_result(
                 Ok(Some(lagged_block.clone())),
-                &block_sender,
+                &rpcs.block_sender,
                 &rpcs.blocks_by_hash,
             )
             .await
@@ -1635,7 +2767,7 @@ mod tests {
         head_rpc
             .send_head_block_result(
                 Ok(Some(head_block.clone())),
-                &block_sender,
+                &rpcs.block_sender,
                 &rpcs.blocks_by_hash,
             )
             .await
@@ -1763,33 +2895,18 @@ mod tests {
         let pruned_rpc = Arc::new(pruned_rpc);
         let archive_rpc = Arc::new(archive_rpc);
 
-        let (block_sender, _) = mpsc::unbounded_channel();
-        let (watch_ranked_rpcs, _) = watch::channel(None);
-        let (watch_consensus_head_sender, _watch_consensus_head_receiver) = watch::channel(None);
-
         let chain_id = 1;
 
         let mut by_name = HashMap::new();
         by_name.insert(pruned_rpc.name.clone(), pruned_rpc.clone());
         by_name.insert(archive_rpc.name.clone(), archive_rpc.clone());
 
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(chain_id, by_name);
+
         let rpcs = Web3Rpcs {
-            block_sender,
-            by_name: RwLock::new(by_name),
-            chain_id,
-            name: "test".into(),
-            watch_head_block: Some(watch_consensus_head_sender),
-            watch_ranked_rpcs,
-            blocks_by_hash: CacheBuilder::new(100)
-                .time_to_live(Duration::from_secs(120))
-                .build(),
-            blocks_by_number: CacheBuilder::new(100)
-                .time_to_live(Duration::from_secs(120))
-                .build(),
-            min_synced_rpcs: 1,
             min_sum_soft_limit: 4_000,
-            max_head_block_age: Duration::from_secs(60),
-            max_head_block_lag: 5.into(),
+            ..rpcs
         };
 
         let mut connection_heads = ConsensusFinder::new(None, None);
@@ -1866,55 +2983,1111 @@ mod tests {
     }
 
     #[test_log::test(tokio::test)]
-    async fn test_all_connections() {
-        // TODO: use chrono, not SystemTime
-        let now: U256 = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            .into();
+    async fn test_server_selection_by_block_age() {
+        let now = chrono::Utc::now().timestamp().into();
 
-        let block_1 = Block {
+        let head_block = Block {
             hash: Some(H256::random()),
             number: Some(1_000_000.into()),
             parent_hash: H256::random(),
             timestamp: now,
             ..Default::default()
         };
-        let block_2 = Block {
-            hash: Some(H256::random()),
-            number: Some(1_000_001.into()),
-            parent_hash: block_1.hash.unwrap(),
-            timestamp: now + 1,
-            ..Default::default()
-        };
 
-        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
-        let block_2: Web3ProxyBlock = Arc::new(block_2).try_into().unwrap();
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
 
-        let (tx_mock_geth, _) = watch::channel(Some(block_1.clone()));
-        let (tx_mock_erigon_archive, _) = watch::channel(Some(block_2.clone()));
+        let (tx_full, _) = watch::channel(Some(head_block.clone()));
 
-        let mock_geth = Web3Rpc {
-            name: "mock_geth".to_string(),
-            soft_limit: 1_000,
+        let full_rpc = Web3Rpc {
+            name: "full".to_string(),
+            soft_limit: 2_000,
             automatic_block_limit: false,
             backup: false,
-            block_data_limit: 64.into(),
-            // tier: 0,
-            head_block: Some(tx_mock_geth),
-            peak_latency: Some(new_peak_latency()),
+            block_data_limit: 128.into(),
+            tier: 1.into(),
+            head_block: Some(tx_full),
             ..Default::default()
         };
 
-        let mock_erigon_archive = Web3Rpc {
-            name: "mock_erigon_archive".to_string(),
-            soft_limit: 1_000,
+        let (tx_archive, _) = watch::channel(Some(head_block.clone()));
+
+        let archive_rpc = Web3Rpc {
+            name: "archive".to_string(),
+            soft_limit: 2_000,
             automatic_block_limit: false,
             backup: false,
             block_data_limit: u64::MAX.into(),
-            // tier: 1,
-            head_block: Some(tx_mock_erigon_archive),
+            tier: 1.into(),
+            head_block: Some(tx_archive),
+            ..Default::default()
+        };
+
+        assert!(!full_rpc.is_archive());
+        assert!(archive_rpc.is_archive());
+
+        let full_rpc = Arc::new(full_rpc);
+        let archive_rpc = Arc::new(archive_rpc);
+
+        let mut by_name = HashMap::new();
+        by_name.insert(full_rpc.name.clone(), full_rpc.clone());
+        by_name.insert(archive_rpc.name.clone(), archive_rpc.clone());
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 4_000,
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        connection_heads
+            .process_block_from_rpc(&rpcs, Some(head_block.clone()), full_rpc.clone())
+            .await
+            .unwrap();
+        connection_heads
+            .process_block_from_rpc(&rpcs, Some(head_block.clone()), archive_rpc.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(rpcs.num_synced_rpcs(), 2);
+
+        // recent blocks go to the full node. try a few times since load balancing is random
+        for _ in 0..10 {
+            let recent_server = rpcs
+                .wait_for_best_rpc(
+                    None,
+                    &mut vec![],
+                    Some(&999_990.into()),
+                    None,
+                    Some(Duration::from_secs(0)),
+                    None,
+                )
+                .await;
+
+            match recent_server {
+                Ok(OpenRequestResult::Handle(x)) => {
+                    assert_eq!(x.clone_connection().name, "full".to_string())
+                }
+                x => panic!("unexpected result: {:?}", x),
+            }
+        }
+
+        // deep historical blocks go to the archive node
+        let historical_server = rpcs
+            .wait_for_best_rpc(
+                None,
+                &mut vec![],
+                Some(&1.into()),
+                None,
+                Some(Duration::from_secs(0)),
+                None,
+            )
+            .await;
+
+        match historical_server {
+            Ok(OpenRequestResult::Handle(x)) => {
+                assert_eq!(x.clone_connection().name, "archive".to_string())
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_sticky_pending_nonce() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let (tx_a, _) = watch::channel(Some(head_block.clone()));
+        let (tx_b, _) = watch::channel(Some(head_block.clone()));
+
+        let rpc_a = Arc::new(Web3Rpc {
+            name: "a".to_string(),
+            soft_limit: 1_000,
+            automatic_block_limit: false,
+            backup: false,
+            block_data_limit: 64.into(),
+            tier: 1.into(),
+            head_block: Some(tx_a),
+            ..Default::default()
+        });
+
+        let rpc_b = Arc::new(Web3Rpc {
+            name: "b".to_string(),
+            soft_limit: 1_000,
+            automatic_block_limit: false,
+            backup: false,
+            block_data_limit: 64.into(),
+            tier: 1.into(),
+            head_block: Some(tx_b),
+            ..Default::default()
+        });
+
+        let mut by_name = HashMap::new();
+        by_name.insert(rpc_a.name.clone(), rpc_a.clone());
+        by_name.insert(rpc_b.name.clone(), rpc_b.clone());
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        connection_heads
+            .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc_a.clone())
+            .await
+            .unwrap();
+        connection_heads
+            .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc_b.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(rpcs.num_synced_rpcs(), 2);
+
+        let wallet: LocalWallet =
+            "0x4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+                .parse()
+                .unwrap();
+
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(Address::zero())
+            .nonce(0)
+            .gas(21_000)
+            .gas_price(1)
+            .chain_id(1)
+            .into();
+
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+        let raw_tx = tx.rlp_signed(&signature);
+
+        let pending_nonce_params = json!([wallet.address(), "pending"]);
+
+        // nothing has been broadcast yet
+        assert!(rpcs
+            .sticky_pending_nonce_rpc("eth_getTransactionCount", &pending_nonce_params)
+            .is_none());
+
+        // "b" accepted the broadcast first
+        rpcs.stick_pending_nonce(&json!([raw_tx]), &rpc_b).await;
+
+        let sticky_rpc = rpcs
+            .sticky_pending_nonce_rpc("eth_getTransactionCount", &pending_nonce_params)
+            .unwrap();
+
+        assert_eq!(sticky_rpc.name, "b");
+
+        // only pending nonces need to be sticky
+        assert!(rpcs
+            .sticky_pending_nonce_rpc(
+                "eth_getTransactionCount",
+                &json!([wallet.address(), "latest"])
+            )
+            .is_none());
+
+        // other senders are load balanced like normal
+        assert!(rpcs
+            .sticky_pending_nonce_rpc(
+                "eth_getTransactionCount",
+                &json!([Address::zero(), "pending"])
+            )
+            .is_none());
+
+        // a private group shares the cache. it doesn't rank its rpcs, so any of them can be sticky
+        let private_rpc = Arc::new(Web3Rpc {
+            name: "private".to_string(),
+            ..Default::default()
+        });
+
+        let (private_rpcs, _, _) = Web3Rpcs::test_new(
+            1,
+            [(private_rpc.name.clone(), private_rpc.clone())]
+                .into_iter()
+                .collect(),
+        );
+
+        let private_rpcs = Web3Rpcs {
+            pending_tx_rpcs: rpcs.pending_tx_rpcs.clone(),
+            watch_head_block: None,
+            ..private_rpcs
+        };
+
+        private_rpcs
+            .stick_pending_nonce(&json!([raw_tx]), &private_rpc)
+            .await;
+
+        assert_eq!(
+            private_rpcs
+                .sticky_pending_nonce_rpc("eth_getTransactionCount", &pending_nonce_params)
+                .unwrap()
+                .name,
+            "private"
+        );
+
+        // the balanced rpcs don't have that rpc. the app asks the private rpcs before falling back to them
+        assert!(rpcs
+            .sticky_pending_nonce_rpc("eth_getTransactionCount", &pending_nonce_params)
+            .is_none());
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_request_provenance() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let parent_block = Block {
+            hash: Some(H256::random()),
+            number: Some(999_999.into()),
+            parent_hash: H256::random(),
+            timestamp: now - 12,
+            ..Default::default()
+        };
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: parent_block.hash.unwrap(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let parent_block: Web3ProxyBlock = Arc::new(parent_block).try_into().unwrap();
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let new_rpc = |name: &str, block: &Web3ProxyBlock| {
+            let (tx, _) = watch::channel(Some(block.clone()));
+
+            Web3Rpc {
+                name: name.to_string(),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: u64::MAX.into(),
+                tier: 1.into(),
+                head_block: Some(tx),
+                ..Default::default()
+            }
+        };
+
+        let (hard_limit_until, _) = watch::channel(Instant::now() + Duration::from_secs(60));
+
+        let limited_rpc = Arc::new(Web3Rpc {
+            hard_limit_until: Some(hard_limit_until),
+            ..new_rpc("limited", &head_block)
+        });
+        let behind_rpc = Arc::new(new_rpc("behind", &parent_block));
+        let synced_rpc = Arc::new(new_rpc("synced", &head_block));
+
+        let by_name = [&limited_rpc, &behind_rpc, &synced_rpc]
+            .into_iter()
+            .map(|x| (x.name.clone(), x.clone()))
+            .collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for (rpc, block) in [
+            (&behind_rpc, &parent_block),
+            (&limited_rpc, &head_block),
+            (&synced_rpc, &head_block),
+        ] {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(block.clone()), rpc.clone())
+                .await
+                .unwrap();
+        }
+
+        let request_metadata = Arc::new(RequestMetadata::default());
+
+        let x = rpcs
+            .wait_for_best_rpc(
+                Some(&request_metadata),
+                &mut vec![],
+                None,
+                Some(head_block.number()),
+                Some(Duration::ZERO),
+                None,
+            )
+            .await
+            .unwrap();
+
+        match x {
+            OpenRequestResult::Handle(handle) => assert_eq!(handle.connection_name(), "synced"),
+            x => panic!("unexpected result: {:?}", x),
+        }
+
+        let provenance = request_metadata.provenance.lock().clone();
+
+        assert_eq!(provenance.skipped.len(), 2);
+        assert!(provenance
+            .skipped
+            .contains(&("limited".to_string(), SkipReason::RateLimited)));
+        assert!(provenance
+            .skipped
+            .contains(&("behind".to_string(), SkipReason::Behind)));
+        assert_eq!(provenance.chosen.as_deref(), Some("synced"));
+        assert_eq!(provenance.retries, 0);
+    }
+
+    /// spawn rpcs that are all on the same head and in the same tier
+    async fn seeded_rpcs(seed: u64) -> Web3Rpcs {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        for i in 0..5 {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: format!("rpc_{}", i),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: 64.into(),
+                tier: 1.into(),
+                head_block: Some(tx),
+                peak_latency: Some(new_peak_latency()),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let all_rpcs: Vec<_> = by_name.values().cloned().collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            selection_rng: Some(Mutex::new(WyRand::new_seed(seed))),
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for rpc in all_rpcs {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(rpcs.num_synced_rpcs(), 5);
+
+        rpcs
+    }
+
+    async fn selection_sequence(rpcs: &Web3Rpcs) -> Vec<String> {
+        let mut sequence = vec![];
+
+        for _ in 0..20 {
+            match rpcs
+                .wait_for_best_rpc(None, &mut vec![], None, None, Some(Duration::ZERO), None)
+                .await
+            {
+                Ok(OpenRequestResult::Handle(x)) => {
+                    sequence.push(x.clone_connection().name.clone())
+                }
+                x => panic!("unexpected result: {:?}", x),
+            }
+        }
+
+        sequence
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_seeded_selection_is_reproducible() {
+        let first = selection_sequence(&seeded_rpcs(42).await).await;
+        let second = selection_sequence(&seeded_rpcs(42).await).await;
+
+        assert_eq!(first, second);
+
+        // the seed doesn't turn off load balancing
+        assert!(first.iter().any(|x| x != &first[0]));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_max_backend_attempts() {
+        // none of these rpcs have a provider, so every request sent to them is a backend error
+        let rpcs = seeded_rpcs(42).await;
+
+        let fail_fast = Arc::new(RequestMetadata {
+            max_backend_attempts: Some(1),
+            ..Default::default()
+        });
+
+        let x = rpcs
+            .request_with_metadata_and_retries::<_, serde_json::Value>(
+                "eth_chainId",
+                &(),
+                Some(&fail_fast),
+                Some(2),
+                Some(Duration::from_millis(100)),
+                None,
+                None,
+            )
+            .await;
+
+        assert!(x.is_err());
+        assert_eq!(fail_fast.backend_rpcs_used().len(), 1);
+
+        // by default, both tries fail over to every rpc
+        let default = Arc::new(RequestMetadata::default());
+
+        let x = rpcs
+            .request_with_metadata_and_retries::<_, serde_json::Value>(
+                "eth_chainId",
+                &(),
+                Some(&default),
+                Some(2),
+                Some(Duration::from_millis(100)),
+                None,
+                None,
+            )
+            .await;
+
+        assert!(x.is_err());
+        assert_eq!(default.backend_rpcs_used().len(), 10);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_sticky_session() {
+        let mut rpcs = seeded_rpcs(42).await;
+
+        rpcs.session_rpcs = Some(new_session_rpcs(Duration::from_secs(60)));
+
+        let session = |id: &str| {
+            let mut authorization = Authorization::internal().unwrap();
+
+            authorization.sticky_session = Some(id.to_string());
+
+            Arc::new(RequestMetadata {
+                authorization: Some(Arc::new(authorization)),
+                ..Default::default()
+            })
+        };
+
+        let (a, b) = (session("1:a"), session("1:b"));
+
+        // a new session uses the normal selection
+        assert!(rpcs.sticky_session_rpc(Some(&a), None, None).is_none());
+
+        let rpc = rpcs.by_name.read().get("rpc_1").cloned().unwrap();
+
+        rpcs.stick_session(Some(&a), &rpc).await;
+
+        assert_eq!(
+            rpcs.sticky_session_rpc(Some(&a), None, None).unwrap().name,
+            "rpc_1"
+        );
+
+        // other sessions and requests without a session are load balanced like normal
+        assert!(rpcs.sticky_session_rpc(Some(&b), None, None).is_none());
+        assert!(rpcs.sticky_session_rpc(None, None, None).is_none());
+
+        // an rpc that isn't synced anymore isn't used. the next response picks a new one
+        let gone = Web3Rpc {
+            name: "gone".to_string(),
+            ..Default::default()
+        };
+
+        rpcs.stick_session(Some(&a), &gone).await;
+
+        assert!(rpcs.sticky_session_rpc(Some(&a), None, None).is_none());
+
+        // groups share the sessions. a session started on one group keeps its rpc on the other
+        let mut other_rpcs = seeded_rpcs(7).await;
+
+        other_rpcs.session_rpcs = rpcs.session_rpcs.clone();
+
+        other_rpcs.stick_session(Some(&b), &rpc).await;
+
+        assert_eq!(
+            rpcs.sticky_session_rpc(Some(&b), None, None).unwrap().name,
+            "rpc_1"
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_backend_timeout() {
+        let rpcs = seeded_rpcs(42).await;
+
+        // these rpcs never answer
+        let send = |handle: OpenRequestHandle| async move {
+            let _handle = handle;
+            futures::future::pending::<Result<serde_json::Value, ProviderError>>().await
+        };
+
+        let request_metadata = Arc::new(RequestMetadata {
+            backend_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+
+        let x = rpcs
+            ._request_with_metadata(
+                "eth_call",
+                &(),
+                Some(&request_metadata),
+                Some(Duration::from_secs(1)),
+                None,
+                None,
+                send,
+            )
+            .await;
+
+        // reads are tried on every rpc
+        assert!(x.is_err());
+        assert_eq!(request_metadata.backend_rpcs_used().len(), 5);
+
+        // cancelled requests don't keep their slots
+        assert!(rpcs
+            .by_name
+            .read()
+            .values()
+            .all(|x| x.active_requests() == 0));
+
+        // transactions are only sent once
+        let request_metadata = Arc::new(RequestMetadata {
+            backend_timeout: Some(Duration::from_millis(10)),
+            ..Default::default()
+        });
+
+        let x = rpcs
+            ._request_with_metadata(
+                "eth_sendRawTransaction",
+                &(),
+                Some(&request_metadata),
+                Some(Duration::from_secs(1)),
+                None,
+                None,
+                send,
+            )
+            .await;
+
+        assert!(matches!(x, Err(Web3ProxyError::Timeout(Some(_)))));
+        assert_eq!(request_metadata.backend_rpcs_used().len(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_dead_letters() {
+        // none of these rpcs have a provider, so the request fails on all of them
+        let rpcs = seeded_rpcs(42).await;
+
+        let request_metadata = Arc::new(RequestMetadata::default());
+
+        let params = json!(["0x0000000000000000000000000000000000000001", "latest"]);
+
+        let err = rpcs
+            .request_with_metadata_and_retries::<_, serde_json::Value>(
+                "eth_getBalance",
+                &params,
+                Some(&request_metadata),
+                Some(1),
+                Some(Duration::from_millis(100)),
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+
+        let dead_letters = DeadLetters::new(1, false, 1, None);
+
+        dead_letters.push("eth_getBalance", &params, &request_metadata, &err);
+
+        let letters = dead_letters.to_vec();
+
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].method, "eth_getBalance");
+        assert!(letters[0].params.is_none());
+        assert!(!letters[0].params_hash.is_zero());
+
+        // one error for every rpc that was tried
+        let mut tried: Vec<_> = letters[0]
+            .attempts
+            .iter()
+            .map(|(x, _)| x.as_str())
+            .collect();
+        tried.sort();
+
+        assert_eq!(tried, ["rpc_0", "rpc_1", "rpc_2", "rpc_3", "rpc_4"]);
+        assert!(letters[0]
+            .attempts
+            .iter()
+            .all(|(_, x)| x.contains("no provider configured")));
+
+        // requests that never reached an rpc are not dead letters
+        dead_letters.push(
+            "eth_getBalance",
+            &params,
+            &RequestMetadata::default(),
+            &Web3ProxyError::NoServersSynced,
+        );
+
+        assert_eq!(dead_letters.to_vec().len(), 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_fastest_rpc_methods() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        // rpc_2 has the lowest latency
+        for (i, latency_ms) in [300, 200, 50, 400, 250].into_iter().enumerate() {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: format!("rpc_{}", i),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: 64.into(),
+                tier: 1.into(),
+                head_block: Some(tx),
+                peak_latency: Some(PeakEwmaLatency::spawn(
+                    Duration::from_secs(1),
+                    4,
+                    Duration::from_millis(latency_ms),
+                )),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let all_rpcs: Vec<_> = by_name.values().cloned().collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            fastest_methods: ["eth_gasPrice".to_string()].into_iter().collect(),
+            min_sum_soft_limit: 1_000,
+            selection_rng: Some(Mutex::new(WyRand::new_seed(42))),
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for rpc in all_rpcs {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(rpcs.num_synced_rpcs(), 5);
+
+        for _ in 0..20 {
+            let fastest = rpcs.fastest_rpc("eth_gasPrice", None, None).unwrap();
+
+            assert_eq!(fastest.name, "rpc_2");
+        }
+
+        // other methods are still load balanced
+        assert!(rpcs.fastest_rpc("eth_call", None, None).is_none());
+
+        let sequence = selection_sequence(&rpcs).await;
+
+        assert!(sequence.iter().any(|x| x != &sequence[0]));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_round_robin_selection() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        // rpc_2 has the lowest latency. it should not get all of the light traffic
+        for (i, latency_ms) in [300, 200, 50, 400, 250].into_iter().enumerate() {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: format!("rpc_{}", i),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: 64.into(),
+                tier: 1.into(),
+                head_block: Some(tx),
+                peak_latency: Some(PeakEwmaLatency::spawn(
+                    Duration::from_secs(1),
+                    4,
+                    Duration::from_millis(latency_ms),
+                )),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let all_rpcs: Vec<_> = by_name.values().cloned().collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            round_robin: Some(Default::default()),
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for rpc in all_rpcs {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(rpcs.num_synced_rpcs(), 5);
+
+        // every handle is dropped before the next request, so there is never more than one request in flight
+        let sequence = selection_sequence(&rpcs).await;
+
+        let counts: Counter<_> = sequence.iter().collect();
+
+        assert_eq!(counts.len(), 5);
+        assert!(counts.values().all(|x| *x == 4), "{:?}", counts);
+
+        // no rpc is picked twice in a row
+        assert!(sequence.iter().tuple_windows().all(|(a, b)| a != b));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_round_robin_within_partition() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        // the archive rpc is in the best tier, but recent blocks should still take turns between the full rpcs
+        for (name, block_data_limit, tier) in [
+            ("archive", u64::MAX, 0),
+            ("full_a", 64, 1),
+            ("full_b", 64, 1),
+        ] {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: name.to_string(),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: block_data_limit.into(),
+                tier: tier.into(),
+                head_block: Some(tx),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let all_rpcs: Vec<_> = by_name.values().cloned().collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            round_robin: Some(Default::default()),
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for rpc in all_rpcs {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(rpcs.num_synced_rpcs(), 3);
+
+        let sequence = selection_sequence(&rpcs).await;
+
+        let counts: Counter<&str> = sequence.iter().map(|x| x.as_str()).collect();
+
+        assert_eq!(counts.len(), 2, "{:?}", counts);
+        assert_eq!(counts["full_a"], 10);
+        assert_eq!(counts["full_b"], 10);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let backoffs: Vec<_> = (0..8).map(|x| retry_backoff(x).as_millis()).collect();
+
+        assert_eq!(backoffs, [0, 50, 100, 200, 400, 800, 1_000, 1_000]);
+    }
+
+    #[test]
+    fn test_weighted_turns() {
+        let cumulative = |weights: &[u64]| -> Vec<u64> {
+            weights
+                .iter()
+                .scan(0, |total, x| {
+                    *total += x;
+                    Some(*total)
+                })
+                .collect()
+        };
+
+        let count_turns = |weights: &[u64]| {
+            let cumulative_weights = cumulative(weights);
+
+            let mut counts = vec![0; weights.len()];
+
+            for turn in 0..10_000 {
+                counts[weighted_turn(&cumulative_weights, turn)] += 1;
+            }
+
+            counts
+        };
+
+        // equal weights split evenly
+        let counts = count_turns(&[1_000, 1_000, 1_000]);
+        assert!(
+            counts.iter().all(|x| (3_333..=3_334).contains(x)),
+            "{:?}",
+            counts
+        );
+
+        // and never give the same rpc two turns in a row
+        let cumulative_weights = cumulative(&[5, 5, 5]);
+        let turns: Vec<_> = (0..100)
+            .map(|x| weighted_turn(&cumulative_weights, x))
+            .collect();
+        assert!(turns.windows(2).all(|x| x[0] != x[1]), "{:?}", turns);
+
+        // double the soft limit gets double the requests
+        let counts = count_turns(&[1_000, 2_000, 1_000]);
+        assert!((4_900..=5_100).contains(&counts[1]), "{:?}", counts);
+        assert!((2_400..=2_600).contains(&counts[0]), "{:?}", counts);
+        assert!((2_400..=2_600).contains(&counts[2]), "{:?}", counts);
+
+        // tiny rpcs get a tiny share. about 1 in a million here
+        let counts = count_turns(&[1, 1_000_000]);
+        assert!(counts[0] <= 1, "{:?}", counts);
+
+        // a weight of 0 never gets a turn
+        let counts = count_turns(&[1_000, 0, 1_000]);
+        assert_eq!(counts[1], 0, "{:?}", counts);
+        assert!((4_900..=5_100).contains(&counts[0]), "{:?}", counts);
+
+        // without any weights, the rpcs take plain turns
+        assert_eq!(
+            (0..6)
+                .map(|x| weighted_turn(&cumulative(&[0, 0, 0]), x))
+                .collect::<Vec<_>>(),
+            [0, 1, 2, 0, 1, 2]
+        );
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_method_pools() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        // rpc_0 is dedicated to trace methods
+        for i in 0..4 {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: format!("rpc_{}", i),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: u64::MAX.into(),
+                tier: 1.into(),
+                head_block: Some(tx),
+                peak_latency: Some(PeakEwmaLatency::spawn(
+                    Duration::from_secs(1),
+                    4,
+                    Duration::from_secs(1),
+                )),
+                pool: (i == 0).then(|| "archive".to_string()),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let all_rpcs: Vec<_> = by_name.values().cloned().collect();
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            method_pools: [("trace_*".to_string(), "archive".to_string())]
+                .into_iter()
+                .collect(),
+            selection_rng: Some(Mutex::new(WyRand::new_seed(42))),
+            ..rpcs
+        };
+
+        let mut connection_heads = ConsensusFinder::new(None, None);
+
+        for rpc in all_rpcs {
+            connection_heads
+                .process_block_from_rpc(&rpcs, Some(head_block.clone()), rpc)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(rpcs.num_synced_rpcs(), 4);
+
+        assert_eq!(rpcs.method_pool("trace_block"), Some("archive"));
+        assert_eq!(rpcs.method_pool("eth_call"), None);
+
+        async fn pick(rpcs: &Web3Rpcs, method: &str) -> String {
+            let mut skip_rpcs = rpcs.outside_method_pool(method);
+
+            match rpcs
+                .wait_for_best_rpc(None, &mut skip_rpcs, None, None, Some(Duration::ZERO), None)
+                .await
+            {
+                Ok(OpenRequestResult::Handle(x)) => x.clone_connection().name.clone(),
+                x => panic!("unexpected result: {:?}", x),
+            }
+        }
+
+        let mut general = HashSet::new();
+
+        for _ in 0..20 {
+            assert_eq!(pick(&rpcs, "trace_block").await, "rpc_0");
+
+            general.insert(pick(&rpcs, "eth_call").await);
+        }
+
+        // eth_call never goes to the dedicated rpc, but it is still load balanced between the others
+        assert!(!general.contains("rpc_0"));
+        assert!(general.len() > 1);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_all_connections() {
+        // TODO: use chrono, not SystemTime
+        let now: U256 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            .into();
+
+        let block_1 = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+        let block_2 = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_001.into()),
+            parent_hash: block_1.hash.unwrap(),
+            timestamp: now + 1,
+            ..Default::default()
+        };
+
+        let block_1: Web3ProxyBlock = Arc::new(block_1).try_into().unwrap();
+        let block_2: Web3ProxyBlock = Arc::new(block_2).try_into().unwrap();
+
+        let (tx_mock_geth, _) = watch::channel(Some(block_1.clone()));
+        let (tx_mock_erigon_archive, _) = watch::channel(Some(block_2.clone()));
+
+        let mock_geth = Web3Rpc {
+            name: "mock_geth".to_string(),
+            soft_limit: 1_000,
+            automatic_block_limit: false,
+            backup: false,
+            block_data_limit: 64.into(),
+            // tier: 0,
+            head_block: Some(tx_mock_geth),
+            peak_latency: Some(new_peak_latency()),
+            ..Default::default()
+        };
+
+        let mock_erigon_archive = Web3Rpc {
+            name: "mock_erigon_archive".to_string(),
+            soft_limit: 1_000,
+            automatic_block_limit: false,
+            backup: false,
+            block_data_limit: u64::MAX.into(),
+            // tier: 1,
+            head_block: Some(tx_mock_erigon_archive),
             peak_latency: Some(new_peak_latency()),
             ..Default::default()
         };
@@ -1927,10 +4100,6 @@ mod tests {
         let mock_geth = Arc::new(mock_geth);
         let mock_erigon_archive = Arc::new(mock_erigon_archive);
 
-        let (block_sender, _) = mpsc::unbounded_channel();
-        let (watch_ranked_rpcs, _) = watch::channel(None);
-        let (watch_consensus_head_sender, _watch_consensus_head_receiver) = watch::channel(None);
-
         let chain_id = 1;
 
         let mut by_name = HashMap::new();
@@ -1940,20 +4109,12 @@ mod tests {
             mock_erigon_archive.clone(),
         );
 
-        // TODO: make a Web3Rpcs::new
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(chain_id, by_name);
+
         let rpcs = Web3Rpcs {
-            block_sender,
-            by_name: RwLock::new(by_name),
-            chain_id,
-            name: "test".into(),
-            watch_head_block: Some(watch_consensus_head_sender),
-            watch_ranked_rpcs,
-            blocks_by_hash: Cache::new(10_000),
-            blocks_by_number: Cache::new(10_000),
-            min_synced_rpcs: 1,
             min_sum_soft_limit: 1_000,
-            max_head_block_age: Duration::from_secs(60),
-            max_head_block_lag: 5.into(),
+            ..rpcs
         };
 
         let mut consensus_finder = ConsensusFinder::new(None, None);
@@ -2006,6 +4167,63 @@ mod tests {
             "wrong number of connections"
         )
     }
+
+    #[test_log::test(tokio::test)]
+    async fn test_all_connections_max_count() {
+        let now = chrono::Utc::now().timestamp().into();
+
+        let head_block = Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            parent_hash: H256::random(),
+            timestamp: now,
+            ..Default::default()
+        };
+
+        let head_block: Web3ProxyBlock = Arc::new(head_block).try_into().unwrap();
+
+        let mut by_name = HashMap::new();
+
+        // the lower tiers score better
+        for tier in (0..10).rev() {
+            let (tx, _) = watch::channel(Some(head_block.clone()));
+
+            let rpc = Web3Rpc {
+                name: format!("tier_{}", tier),
+                soft_limit: 1_000,
+                automatic_block_limit: false,
+                backup: false,
+                block_data_limit: 64.into(),
+                tier: tier.into(),
+                head_block: Some(tx),
+                peak_latency: Some(new_peak_latency()),
+                ..Default::default()
+            };
+
+            by_name.insert(rpc.name.clone(), Arc::new(rpc));
+        }
+
+        let (rpcs, _block_receiver, _watch_consensus_head_receiver) =
+            Web3Rpcs::test_new(1, by_name);
+
+        let rpcs = Web3Rpcs {
+            min_sum_soft_limit: 1_000,
+            ..rpcs
+        };
+
+        let selected = rpcs
+            .all_connections(None, None, None, Some(3), None)
+            .await
+            .unwrap();
+
+        let mut selected: Vec<_> = selected
+            .iter()
+            .map(|x| x.clone_connection().name.clone())
+            .collect();
+        selected.sort();
+
+        assert_eq!(selected, vec!["tier_0", "tier_1", "tier_2"]);
+    }
 }
 
 #[cfg(test)]
@@ -2036,4 +4254,55 @@ mod test {
 
         assert_eq!(test_vec, sorted_vec);
     }
+
+    #[test]
+    fn test_quorum_response() {
+        let responses = || {
+            vec![
+                ("a".to_string(), RawValue::from_string("\"0x1\"".into())),
+                ("b".to_string(), RawValue::from_string("\"0x1\"".into())),
+                ("c".to_string(), RawValue::from_string("\"0x2\"".into())),
+            ]
+            .into_iter()
+            .map(|(rpc, x)| (rpc, Ok(x.unwrap())))
+            .collect::<Vec<_>>()
+        };
+
+        // two of the three agree
+        let x = quorum_response(responses(), 2).unwrap();
+        assert_eq!(x.get(), "\"0x1\"");
+
+        // all three have to agree
+        match quorum_response(responses(), 3) {
+            Err(Web3ProxyError::InconsistentUpstreams(x)) => {
+                assert_eq!(
+                    x,
+                    vec![
+                        ("a".to_string(), json!("0x1")),
+                        ("b".to_string(), json!("0x1")),
+                        ("c".to_string(), json!("0x2")),
+                    ]
+                );
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+
+        // the same block from clients that format it differently
+        let responses = vec![
+            (
+                "a".to_string(),
+                RawValue::from_string(r#"{"hash":"0x1","number":"0x2"}"#.into()),
+            ),
+            (
+                "b".to_string(),
+                RawValue::from_string(r#"{ "number": "0x2", "hash": "0x1" }"#.into()),
+            ),
+        ]
+        .into_iter()
+        .map(|(rpc, x)| (rpc, Ok(x.unwrap())))
+        .collect::<Vec<_>>();
+
+        let x = quorum_response(responses, 2).unwrap();
+        assert_eq!(x.get(), r#"{"hash":"0x1","number":"0x2"}"#);
+    }
 }
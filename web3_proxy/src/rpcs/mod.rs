@@ -1,7 +1,14 @@
 // TODO: all pub, or export useful things here instead?
+pub mod block_snapshot;
 pub mod blockchain;
+pub mod circuit_breaker;
 pub mod consensus;
+pub mod groups;
+pub mod lifetime_stats;
+pub mod logs_split;
 pub mod many;
 pub mod one;
 pub mod provider;
 pub mod request;
+pub mod signed_http;
+pub mod subscriptions;
@@ -1,36 +1,125 @@
 //! Rate-limited communication with a web3 provider.
 use super::blockchain::{ArcBlock, BlocksByHashCache, Web3ProxyBlock};
-use super::provider::{connect_http, connect_ws, EthersHttpProvider, EthersWsProvider};
+use super::circuit_breaker::{CircuitBreaker, CircuitState};
+use super::lifetime_stats::LifetimeStats;
+use super::provider::{
+    connect_http, connect_ws, extract_auth, EthersHttpProvider, EthersWsProvider,
+};
 use super::request::{OpenRequestHandle, OpenRequestResult};
+use super::signed_http::{connect_signed_http, EthersSignedHttpProvider};
+use super::subscriptions::{LogsSender, PendingTxSender, TxStatus};
 use crate::app::{flatten_handle, Web3ProxyJoinHandle};
-use crate::config::{BlockAndRpc, Web3RpcConfig};
+use crate::config::{BlockAndRpc, HealthProbeConfig, Web3RpcConfig};
 use crate::errors::{Web3ProxyError, Web3ProxyErrorContext, Web3ProxyResult};
 use crate::frontend::authorization::Authorization;
 use crate::jsonrpc::{JsonRpcParams, JsonRpcResultData};
 use crate::rpcs::request::RequestErrorHandler;
 use anyhow::{anyhow, Context};
-use arc_swap::ArcSwapOption;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use ethers::prelude::{Bytes, Middleware, U64};
-use ethers::types::{Address, Transaction, U256};
+use ethers::types::{Address, Filter, Transaction, TxHash, U256};
 use futures::stream::FuturesUnordered;
 use futures::StreamExt;
+use hashbrown::HashMap;
 use latency::{EwmaLatency, PeakEwmaLatency, RollingQuantileLatency};
 use migration::sea_orm::DatabaseConnection;
-use nanorand::Rng;
+use moka::future::Cache;
+use nanorand::{Rng, WyRand};
 use redis_rate_limiter::{RedisPool, RedisRateLimitResult, RedisRateLimiter};
 use serde::ser::{SerializeStruct, Serializer};
 use serde::Serialize;
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::cmp::Reverse;
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::sync::atomic::{self, AtomicU32, AtomicU64, AtomicUsize};
+use std::sync::atomic::{self, AtomicI64, AtomicU32, AtomicU64, AtomicUsize};
 use std::{cmp::Ordering, sync::Arc};
-use tokio::sync::{mpsc, watch, RwLock as AsyncRwLock};
-use tokio::time::{interval, sleep, sleep_until, Duration, Instant, MissedTickBehavior};
+use tokio::select;
+use tokio::sync::{mpsc, watch, RwLock as AsyncRwLock, Semaphore};
+use tokio::time::{
+    interval, sleep, sleep_until, timeout_at, Duration, Instant, MissedTickBehavior,
+};
 use tracing::{debug, error, info, trace, warn, Level};
 use url::Url;
 
+/// warn if a block's timestamp is this many seconds ahead of our clock
+pub const MAX_CLOCK_SKEW_SECS: i64 = 5;
+
+/// Requests that wait for a rate limited rpc instead of failing fast
+#[derive(Debug)]
+pub struct RateLimitQueue {
+    /// one permit per request that is allowed to wait
+    waiting: Semaphore,
+    max_wait: Duration,
+}
+
+impl RateLimitQueue {
+    pub fn new(depth: usize, max_wait: Duration) -> Self {
+        Self {
+            waiting: Semaphore::new(depth),
+            max_wait,
+        }
+    }
+}
+
+/// the requests per minute limiter for an rpc. the counts are in redis so that multiple proxies share them
+fn hard_limiter(
+    chain_id: u64,
+    name: &str,
+    hard_limit: Option<u64>,
+    redis_pool: Option<RedisPool>,
+) -> anyhow::Result<Option<RedisRateLimiter>> {
+    match (hard_limit, redis_pool) {
+        (None, _) => Ok(None),
+        (Some(hard_limit), Some(redis_pool)) => {
+            // TODO: in process rate limiter instead? or is deffered good enough?
+            let rrl = RedisRateLimiter::new(
+                "web3_proxy",
+                &format!("{}:{}", chain_id, name),
+                hard_limit,
+                60.0,
+                redis_pool,
+            );
+
+            Ok(Some(rrl))
+        }
+        (Some(_hard_limit), None) => Err(anyhow::anyhow!(
+            "no redis client pool! needed for hard limit"
+        )),
+    }
+}
+
+/// a limiter for each method that has its own limit
+fn method_hard_limiters(
+    chain_id: u64,
+    name: &str,
+    method_limits: &HashMap<String, u64>,
+    redis_pool: Option<RedisPool>,
+) -> anyhow::Result<HashMap<String, Arc<RedisRateLimiter>>> {
+    method_limits
+        .iter()
+        .map(|(method, limit)| {
+            let limiter = hard_limiter(
+                chain_id,
+                &format!("{}:{}", name, method),
+                Some(*limit),
+                redis_pool.clone(),
+            )?
+            .expect("a limit was given");
+
+            Ok((method.clone(), Arc::new(limiter)))
+        })
+        .collect()
+}
+
+/// A plain http connection. Used to pass big responses through without reading them into memory
+pub(super) struct StreamHttp {
+    pub client: reqwest::Client,
+    pub url: Url,
+    pub auth: Option<ethers::providers::Authorization>,
+}
+
 /// An active connection to a Web3 RPC server like geth or erigon.
 #[derive(Default)]
 pub struct Web3Rpc {
@@ -40,6 +129,10 @@ pub struct Web3Rpc {
     pub db_conn: Option<DatabaseConnection>,
     /// most all requests prefer use the http_provider
     pub(super) http_provider: Option<EthersHttpProvider>,
+    /// if the rpc requires signed requests, they are sent with this instead of the http_provider
+    pub(super) signed_http_provider: Option<EthersSignedHttpProvider>,
+    /// streamed responses skip the ethers providers. None if the rpc requires signed requests
+    pub(super) stream_http: Option<StreamHttp>,
     /// the websocket url is only used for subscriptions
     pub(super) ws_url: Option<Url>,
     /// the websocket provider is only used for subscriptions
@@ -49,13 +142,23 @@ pub struct Web3Rpc {
     pub(super) hard_limit_until: Option<watch::Sender<Instant>>,
     /// rate limits are stored in a central redis so that multiple proxies can share their rate limits
     /// We do not use the deferred rate limiter because going over limits would cause errors
-    pub(super) hard_limit: Option<RedisRateLimiter>,
+    /// This is swappable so that config reloads can change the quota without reconnecting
+    pub(super) hard_limit: ArcSwapOption<RedisRateLimiter>,
+    /// limits for specific methods. a method in here does not use the hard_limit
+    /// This is swappable for the same reason as hard_limit
+    pub(super) method_hard_limits: ArcSwap<HashMap<String, Arc<RedisRateLimiter>>>,
+    /// the config this rpc was spawned with. the hard_limit might have been changed since
+    pub(super) config: Web3RpcConfig,
+    /// optional bounded queue for requests that would rather wait for the hard_limit than fail
+    pub(super) rate_limit_queue: Option<RateLimitQueue>,
     /// used for ensuring enough requests are available before advancing the head block
     pub(super) soft_limit: u32,
     /// use web3 queries to find the block data limit for archive/pruned nodes
     pub(super) automatic_block_limit: bool,
     /// only use this rpc if everything else is lagging too far. this allows us to ignore fast but very low limit rpcs
     pub backup: bool,
+    /// dedicated to the methods that are routed to this pool. None = the general pool
+    pub(super) pool: Option<String>,
     /// TODO: have an enum for this so that "no limit" prints pretty?
     pub(super) block_data_limit: AtomicU64,
     /// head_block is only inside an Option so that the "Default" derive works. it will always be set.
@@ -82,6 +185,24 @@ pub struct Web3Rpc {
     pub(super) disconnect_watch: Option<watch::Sender<bool>>,
     /// created_at is only inside an Option so that the "Default" derive works. it will always be set.
     pub(super) created_at: Option<Instant>,
+    /// how many published consensus heads this rpc was synced to
+    pub(super) on_head_count: AtomicU64,
+    /// how many published consensus heads this rpc was behind
+    pub(super) behind_head_count: AtomicU64,
+    /// how many blocks this rpc's head was behind the latest consensus head
+    pub(super) head_lag_blocks: AtomicU64,
+    /// seconds that the latest head block's timestamp was ahead of our clock when it was received
+    pub(super) clock_skew: AtomicI64,
+    /// optional request that is sent on an interval to check that the rpc is actually serving requests
+    pub(super) health_probe: Option<HealthProbeConfig>,
+    /// how many times in a row the health probe has failed
+    pub(super) health_probe_failures: AtomicU32,
+    /// how many requests were turned away by this rpc's rate limits
+    pub(super) rate_limited: AtomicU64,
+    /// skips this rpc after too many requests in a row fail to get an answer. None if not configured
+    pub(super) circuit_breaker: Option<CircuitBreaker>,
+    /// counters that are kept across restarts
+    pub(super) lifetime_stats: LifetimeStats,
 }
 
 impl Web3Rpc {
@@ -102,27 +223,12 @@ impl Web3Rpc {
     ) -> anyhow::Result<(Arc<Web3Rpc>, Web3ProxyJoinHandle<()>)> {
         let created_at = Instant::now();
 
-        let hard_limit = match (config.hard_limit, redis_pool) {
-            (None, None) => None,
-            (Some(hard_limit), Some(redis_pool)) => {
-                // TODO: in process rate limiter instead? or is deffered good enough?
-                let rrl = RedisRateLimiter::new(
-                    "web3_proxy",
-                    &format!("{}:{}", chain_id, name),
-                    hard_limit,
-                    60.0,
-                    redis_pool,
-                );
-
-                Some(rrl)
-            }
-            (None, Some(_)) => None,
-            (Some(_hard_limit), None) => {
-                return Err(anyhow::anyhow!(
-                    "no redis client pool! needed for hard limit"
-                ))
-            }
-        };
+        let spawned_config = config.clone();
+
+        let method_hard_limits =
+            method_hard_limiters(chain_id, &name, &config.method_limits, redis_pool.clone())?;
+
+        let hard_limit = hard_limiter(chain_id, &name, config.hard_limit, redis_pool)?;
 
         let backup = config.backup;
 
@@ -142,6 +248,13 @@ impl Web3Rpc {
 
         let (head_block, _) = watch::channel(None);
 
+        let rate_limit_queue = (config.rate_limit_queue_depth > 0).then(|| {
+            RateLimitQueue::new(
+                config.rate_limit_queue_depth,
+                Duration::from_millis(config.rate_limit_queue_max_wait_ms),
+            )
+        });
+
         // Spawn the task for calculting average peak latency
         // TODO Should these defaults be in config
         let peak_latency = PeakEwmaLatency::spawn(
@@ -156,6 +269,35 @@ impl Web3Rpc {
 
         let median_request_latency = RollingQuantileLatency::spawn_median(1_000).await;
 
+        // signed requests have to go through the signed_http_provider
+        let stream_http = match (&http_client, &config.http_url, &config.hmac) {
+            (Some(http_client), Some(http_url), None) => {
+                let mut url = http_url.parse::<Url>()?;
+
+                let auth = extract_auth(&mut url);
+
+                Some(StreamHttp {
+                    client: http_client.clone(),
+                    url,
+                    auth,
+                })
+            }
+            _ => None,
+        };
+
+        let signed_http_provider = match (config.hmac, config.http_url.as_ref()) {
+            (Some(hmac), Some(http_url)) => Some(connect_signed_http(
+                http_url.parse::<Url>()?,
+                http_client.clone(),
+                hmac,
+                block_interval,
+            )?),
+            (Some(_), None) => {
+                return Err(anyhow!("hmac signing requires an http_url").into());
+            }
+            (None, _) => None,
+        };
+
         let http_provider = if let Some(http_url) = config.http_url {
             let http_url = http_url.parse::<Url>()?;
 
@@ -181,17 +323,25 @@ impl Web3Rpc {
             backup,
             block_data_limit,
             block_interval,
+            circuit_breaker: config.circuit_breaker.as_ref().map(CircuitBreaker::new),
+            config: spawned_config,
             created_at: Some(created_at),
             display_name: config.display_name,
-            hard_limit,
+            hard_limit: ArcSwapOption::from_pointee(hard_limit),
             hard_limit_until: Some(hard_limit_until),
+            method_hard_limits: ArcSwap::from_pointee(method_hard_limits),
             head_block: Some(head_block),
+            health_probe: config.health_probe,
             http_provider,
             max_head_block_age,
             name,
             peak_latency: Some(peak_latency),
             median_latency: Some(median_request_latency),
+            pool: config.pool,
+            rate_limit_queue,
+            signed_http_provider,
             soft_limit: config.soft_limit,
+            stream_http,
             ws_url,
             disconnect_watch: Some(disconnect_watch),
             ..Default::default()
@@ -213,6 +363,61 @@ impl Web3Rpc {
         Ok((new_connection, handle))
     }
 
+    /// the requests per minute currently allowed by the hard limit
+    pub fn hard_limit(&self) -> Option<u64> {
+        self.hard_limit
+            .load()
+            .as_ref()
+            .map(|x| x.max_requests_per_period)
+    }
+
+    /// swap in a new hard limit without reconnecting. requests that already have a handle are not affected
+    pub fn set_hard_limit(
+        &self,
+        chain_id: u64,
+        hard_limit: Option<u64>,
+        redis_pool: Option<RedisPool>,
+    ) -> anyhow::Result<()> {
+        let hard_limiter = hard_limiter(chain_id, &self.name, hard_limit, redis_pool)?;
+
+        self.hard_limit.store(hard_limiter.map(Arc::new));
+
+        // a wait that came from the old quota should not hold back the new one
+        if let Some(hard_limit_until) = self.hard_limit_until.as_ref() {
+            hard_limit_until.send_replace(Instant::now());
+        }
+
+        info!(?hard_limit, "updated hard limit on {}", self);
+
+        Ok(())
+    }
+
+    /// the requests per minute currently allowed for each method with its own limit
+    pub fn method_limits(&self) -> HashMap<String, u64> {
+        self.method_hard_limits
+            .load()
+            .iter()
+            .map(|(method, x)| (method.clone(), x.max_requests_per_period))
+            .collect()
+    }
+
+    /// swap in new method limits without reconnecting. requests that already have a handle are not affected
+    pub fn set_method_limits(
+        &self,
+        chain_id: u64,
+        method_limits: &HashMap<String, u64>,
+        redis_pool: Option<RedisPool>,
+    ) -> anyhow::Result<()> {
+        let method_hard_limits =
+            method_hard_limiters(chain_id, &self.name, method_limits, redis_pool)?;
+
+        self.method_hard_limits.store(Arc::new(method_hard_limits));
+
+        info!(?method_limits, "updated method limits on {}", self);
+
+        Ok(())
+    }
+
     /// sort by...
     /// - backups last
     /// - tier (ascending)
@@ -221,7 +426,7 @@ impl Web3Rpc {
     /// TODO: should tier or block number take priority?
     /// TODO: should this return a struct that implements sorting traits?
     /// TODO: move this to consensus.rs
-    fn sort_on(&self, max_block: Option<U64>) -> (bool, Reverse<U64>, u32) {
+    pub fn sort_on(&self, max_block: Option<U64>) -> (bool, Reverse<U64>, u32) {
         let mut head_block = self
             .head_block
             .as_ref()
@@ -259,23 +464,26 @@ impl Web3Rpc {
     /// TODO: move this to consensus.rs
     pub fn shuffle_for_load_balancing_on(
         &self,
+        rng: &mut WyRand,
         max_block: Option<U64>,
     ) -> ((bool, Reverse<U64>, u32), u8) {
         let sort_on = self.sort_on(max_block);
 
-        let mut rng = nanorand::tls_rng();
-
         let r = rng.generate::<u8>();
 
         (sort_on, r)
     }
 
-    pub fn weighted_peak_latency(&self) -> Duration {
-        let peak_latency = if let Some(peak_latency) = self.peak_latency.as_ref() {
+    pub fn peak_latency(&self) -> Duration {
+        if let Some(peak_latency) = self.peak_latency.as_ref() {
             peak_latency.latency()
         } else {
             Duration::from_secs(1)
-        };
+        }
+    }
+
+    pub fn weighted_peak_latency(&self) -> Duration {
+        let peak_latency = self.peak_latency();
 
         // TODO: what ordering?
         let active_requests = self.active_requests.load(atomic::Ordering::Acquire) as f32 + 1.0;
@@ -366,11 +574,70 @@ impl Web3Rpc {
         Ok(limit)
     }
 
+    /// how many published consensus heads this rpc was on vs behind
+    pub fn head_lag_counts(&self) -> (u64, u64) {
+        (
+            self.on_head_count.load(atomic::Ordering::Relaxed),
+            self.behind_head_count.load(atomic::Ordering::Relaxed),
+        )
+    }
+
+    pub fn lifetime_stats(&self) -> &LifetimeStats {
+        &self.lifetime_stats
+    }
+
+    /// how many blocks this rpc's head was behind the latest consensus head
+    pub fn head_lag_blocks(&self) -> u64 {
+        self.head_lag_blocks.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn active_requests(&self) -> usize {
+        self.active_requests.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn max_active_requests(&self) -> Option<usize> {
+        self.config.max_active_requests
+    }
+
+    /// true if the rpc already has `max_active_requests` in flight
+    pub fn is_busy(&self) -> bool {
+        self.max_active_requests()
+            .map(|max| self.active_requests() >= max)
+            .unwrap_or(false)
+    }
+
+    /// count a new in-flight request. false if that would go over `max_active_requests`.
+    /// internal requests (health checks, head blocks, subscriptions) are always counted. users filling the rpc must not starve them
+    pub(super) fn try_inc_active_requests(&self, internal: bool) -> bool {
+        if internal {
+            self.active_requests.fetch_add(1, atomic::Ordering::AcqRel);
+            return true;
+        }
+
+        let max = self.max_active_requests().unwrap_or(usize::MAX);
+
+        self.active_requests
+            .fetch_update(atomic::Ordering::AcqRel, atomic::Ordering::Acquire, |x| {
+                (x < max).then_some(x + 1)
+            })
+            .is_ok()
+    }
+
+    /// how many requests were turned away by this rpc's rate limits
+    pub fn rate_limited_count(&self) -> u64 {
+        self.rate_limited.load(atomic::Ordering::Relaxed)
+    }
+
     /// TODO: this might be too simple. different nodes can prune differently. its possible we will have a block range
     pub fn block_data_limit(&self) -> U64 {
         self.block_data_limit.load(atomic::Ordering::Acquire).into()
     }
 
+    /// archive rpcs can serve any block. they should be saved for the requests that need old blocks
+    pub fn is_archive(&self) -> bool {
+        self.block_data_limit.load(atomic::Ordering::Acquire) == u64::MAX
+    }
+
     /// TODO: get rid of this now that consensus rpcs does it
     pub fn has_block_data(&self, needed_block_num: &U64) -> bool {
         let head_block_num = match self.head_block.as_ref().unwrap().borrow().as_ref() {
@@ -477,6 +744,8 @@ impl Web3Rpc {
                         None
                     }
                     Some(new_head_block) => {
+                        self.record_clock_skew(&new_head_block);
+
                         let new_hash = *new_head_block.hash();
 
                         // if we already have this block saved, set new_head_block to that arc. otherwise store this copy
@@ -512,6 +781,13 @@ impl Web3Rpc {
             }
         };
 
+        // rpcs that are failing their health probe stay out of consensus until the probe passes again
+        let new_head_block = if self.health_probe_tripped() {
+            None
+        } else {
+            new_head_block
+        };
+
         // tell web3rpcs about this rpc having this block
         block_and_rpc_sender
             .send((new_head_block, self.clone()))
@@ -520,6 +796,117 @@ impl Web3Rpc {
         Ok(())
     }
 
+    /// compare our clock to the timestamp of a block that was just received
+    fn record_clock_skew(&self, new_head_block: &Web3ProxyBlock) {
+        let clock_skew = new_head_block.clock_skew();
+
+        self.clock_skew.store(clock_skew, atomic::Ordering::Relaxed);
+
+        // an old head is normal for an rpc that is lagging or syncing. only a head from the future says anything about our clock
+        if clock_skew > MAX_CLOCK_SKEW_SECS {
+            warn!(
+                clock_skew_s = clock_skew,
+                "block {} from {} is in the future. check the system clock", new_head_block, self
+            );
+        }
+    }
+
+    /// seconds that the latest head block was ahead of our clock. large positive numbers mean our clock is behind.
+    /// negative numbers are usually just a lagging rpc
+    pub fn clock_skew(&self) -> i64 {
+        self.clock_skew.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_clock_skewed(&self) -> bool {
+        self.clock_skew() > MAX_CLOCK_SKEW_SECS
+    }
+
+    /// true if the health probe has failed too many times in a row
+    pub fn health_probe_tripped(&self) -> bool {
+        match self.health_probe.as_ref() {
+            Some(health_probe) => {
+                self.health_probe_failures.load(atomic::Ordering::Relaxed)
+                    >= health_probe.failure_threshold.max(1)
+            }
+            None => false,
+        }
+    }
+
+    /// Closed if there is no circuit breaker
+    pub fn circuit_state(&self) -> CircuitState {
+        self.circuit_breaker
+            .as_ref()
+            .map(|x| x.state())
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    /// count a request for the circuit breaker. `answered` is false for connection errors and timeouts
+    pub(super) fn record_circuit_result(&self, answered: bool) {
+        let circuit_breaker = match self.circuit_breaker.as_ref() {
+            Some(x) => x,
+            None => return,
+        };
+
+        if answered {
+            if circuit_breaker.record_success() {
+                info!("{} answered. closing its circuit", self);
+            }
+        } else if circuit_breaker.record_failure() {
+            warn!("too many requests to {} failed. opening its circuit", self);
+        }
+    }
+
+    /// send the configured health probe once. returns true if that tripped or reset the rpc
+    pub(super) async fn send_health_probe(
+        self: &Arc<Self>,
+        error_handler: Option<RequestErrorHandler>,
+    ) -> bool {
+        let health_probe = match self.health_probe.as_ref() {
+            Some(x) => x,
+            None => return false,
+        };
+
+        let was_tripped = self.health_probe_tripped();
+
+        match self
+            .internal_request::<_, Box<RawValue>>(
+                &health_probe.method,
+                &health_probe.params,
+                error_handler,
+                Some(1),
+                Some(Duration::from_millis(health_probe.interval_ms)),
+            )
+            .await
+        {
+            Ok(_) => {
+                self.health_probe_failures
+                    .store(0, atomic::Ordering::Relaxed);
+            }
+            Err(err) => {
+                let failures = self
+                    .health_probe_failures
+                    .fetch_add(1, atomic::Ordering::Relaxed)
+                    + 1;
+
+                debug!(?err, failures, "health probe on {} failed", self);
+            }
+        }
+
+        let tripped = self.health_probe_tripped();
+
+        if tripped == was_tripped {
+            return false;
+        }
+
+        if tripped {
+            warn!(method=%health_probe.method, "health probe on {} keeps failing. removing it from rotation", self);
+        } else {
+            info!("health probe on {} passed. returning it to rotation", self);
+        }
+
+        true
+    }
+
     fn should_disconnect(&self) -> bool {
         *self.disconnect_watch.as_ref().unwrap().borrow()
     }
@@ -580,19 +967,29 @@ impl Web3Rpc {
         Ok(())
     }
 
-    /// TODO: this needs to be a subscribe_with_reconnect that does a retry with jitter and exponential backoff
+    /// keep the subscriptions running. reconnects with exponential backoff and jitter.
+    /// while a websocket is down, heads are polled over http (if there is an http_url)
     async fn subscribe_with_reconnect(
         self: Arc<Self>,
         block_map: BlocksByHashCache,
         block_and_rpc_sender: Option<mpsc::UnboundedSender<BlockAndRpc>>,
         chain_id: u64,
     ) -> Web3ProxyResult<()> {
+        let mut attempt = 0;
+
         loop {
-            if let Err(err) = self
+            let start = Instant::now();
+
+            let subscribe_result = self
                 .clone()
                 .subscribe(block_map.clone(), block_and_rpc_sender.clone(), chain_id)
-                .await
-            {
+                .await;
+
+            // subscribe can return early with an error after it connected. the websocket must never outlive it
+            // TODO: tell ethers to disconnect?
+            self.ws_provider.store(None);
+
+            if let Err(err) = subscribe_result {
                 if self.should_disconnect() {
                     break;
                 }
@@ -602,14 +999,46 @@ impl Web3Rpc {
                 break;
             }
 
+            // a subscription that worked for a while starts the backoff over
+            if start.elapsed() > Duration::from_secs(60) {
+                attempt = 0;
+            }
+
+            let backoff = reconnect_backoff(attempt);
+            let jitter = Duration::from_millis(
+                nanorand::tls_rng().generate_range(0..=backoff.as_millis() as u64 / 4),
+            );
+            let reconnect_at = Instant::now() + backoff + jitter;
+
+            attempt += 1;
+
             if self.backup {
-                debug!("reconnecting to {} in 30 seconds", self);
+                debug!("reconnecting to {} in {:?}", self, backoff + jitter);
             } else {
-                info!("reconnecting to {} in 30 seconds", self);
+                info!("reconnecting to {} in {:?}", self, backoff + jitter);
             }
 
-            // TODO: exponential backoff with jitter
-            sleep(Duration::from_secs(30)).await;
+            match block_and_rpc_sender.as_ref() {
+                Some(block_and_rpc_sender)
+                    if self.ws_url.is_some() && self.http_provider.is_some() =>
+                {
+                    // ws_provider was cleared, so subscribe_new_heads polls the http_provider until it is time to reconnect
+                    let (_subscribe_stop_tx, subscribe_stop_rx) = watch::channel(false);
+
+                    let poll_heads = self.subscribe_new_heads(
+                        block_and_rpc_sender.clone(),
+                        block_map.clone(),
+                        subscribe_stop_rx,
+                    );
+
+                    if let Ok(Err(err)) = timeout_at(reconnect_at, poll_heads).await {
+                        debug!(?err, "polling heads on {} failed", self);
+
+                        sleep_until(reconnect_at).await;
+                    }
+                }
+                _ => sleep_until(reconnect_at).await,
+            }
         }
 
         Ok(())
@@ -730,6 +1159,51 @@ impl Web3Rpc {
             futures.push(flatten_handle(tokio::spawn(f)));
         }
 
+        // active health probe. unlike the health check above, this runs even when there are lots of requests
+        if let (Some(health_probe), Some(block_and_rpc_sender)) =
+            (self.health_probe.as_ref(), block_and_rpc_sender.clone())
+        {
+            let rpc = self.clone();
+            let subscribe_stop_rx = subscribe_stop_tx.subscribe();
+
+            let mut probe_interval = interval(Duration::from_millis(health_probe.interval_ms));
+            probe_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+            let f = async move {
+                while !(*subscribe_stop_rx.borrow()) {
+                    probe_interval.tick().await;
+
+                    if rpc.send_health_probe(error_handler).await {
+                        // the rpc was tripped or reset. tell consensus about it
+                        let head_block = if rpc.health_probe_tripped() {
+                            None
+                        } else {
+                            rpc.head_block.as_ref().unwrap().borrow().clone()
+                        };
+
+                        block_and_rpc_sender
+                            .send((head_block, rpc.clone()))
+                            .context("block_and_rpc_sender failed sending")?;
+                    }
+                }
+
+                trace!("health probe loop on {} exited", rpc);
+
+                Ok(())
+            };
+
+            futures.push(flatten_handle(tokio::spawn(f)));
+        }
+
+        // operators sometimes resync an archive node with pruning (or the other way around). check the limit again
+        if self.automatic_block_limit && self.config.block_data_limit_check_interval_ms > 0 {
+            let f = self
+                .clone()
+                .block_data_limit_loop(subscribe_stop_tx.subscribe());
+
+            futures.push(flatten_handle(tokio::spawn(f)));
+        }
+
         // subscribe to new heads
         if let Some(block_and_rpc_sender) = block_and_rpc_sender.clone() {
             let clone = self.clone();
@@ -761,8 +1235,58 @@ impl Web3Rpc {
 
         // TODO: wait for all of the futures to exit?
 
-        // TODO: tell ethers to disconnect?
-        self.ws_provider.store(None);
+        Ok(())
+    }
+
+    /// check the block data limit every `block_data_limit_check_interval_ms` until the subscriptions stop
+    async fn block_data_limit_loop(
+        self: Arc<Self>,
+        mut subscribe_stop_rx: watch::Receiver<bool>,
+    ) -> Web3ProxyResult<()> {
+        let mut check_interval = interval(Duration::from_millis(
+            self.config.block_data_limit_check_interval_ms,
+        ));
+        check_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // the first tick is immediate. check_provider already checked the limit
+        check_interval.tick().await;
+
+        while !(*subscribe_stop_rx.borrow_and_update()) {
+            // the interval is long. don't wait for the next tick to notice that the subscriptions stopped
+            select! {
+                _ = check_interval.tick() => {}
+                x = subscribe_stop_rx.changed() => {
+                    if x.is_err() {
+                        break;
+                    }
+
+                    continue;
+                }
+            }
+
+            let old_limit = self.block_data_limit();
+
+            match self.check_block_data_limit().await {
+                Ok(_) => {
+                    let new_limit = self.block_data_limit();
+
+                    if old_limit != new_limit {
+                        warn!(
+                            %old_limit,
+                            %new_limit,
+                            "block data limit on {} changed",
+                            self
+                        );
+                    }
+                }
+                Err(err) => {
+                    // keep the old limit. the rpc might just be having a bad moment
+                    warn!(?err, "failed checking block data limit on {}", self);
+                }
+            }
+        }
+
+        trace!("block data limit loop on {} exited", self);
 
         Ok(())
     }
@@ -788,7 +1312,7 @@ impl Web3Rpc {
             let authorization = Default::default();
 
             let active_request_handle = self
-                .wait_for_request_handle(&authorization, None, error_handler)
+                .wait_for_request_handle(&authorization, None, None, error_handler)
                 .await;
             let mut blocks = ws_provider.subscribe_blocks().await?;
             drop(active_request_handle);
@@ -865,9 +1389,56 @@ impl Web3Rpc {
         }
     }
 
+    /// forward logs from a websocket subscription until the subscription ends
+    pub(super) async fn forward_logs(
+        &self,
+        filter: &Filter,
+        sender: &LogsSender,
+    ) -> Web3ProxyResult<()> {
+        let ws_provider = self
+            .ws_provider
+            .load_full()
+            .ok_or_else(|| anyhow!("{} has no websocket", self))?;
+
+        let mut logs = ws_provider.subscribe_logs(filter).await?;
+
+        while let Some(log) = logs.next().await {
+            // an error just means that no clients are listening right now
+            let _ = sender.send(Arc::new(log));
+        }
+
+        Err(anyhow!("logs subscription exited. reconnect needed").into())
+    }
+
+    /// forward transaction hashes that are not in `seen` yet
+    pub(super) async fn forward_pending_txs(
+        &self,
+        sender: &PendingTxSender,
+        seen: &Cache<TxHash, ()>,
+    ) -> Web3ProxyResult<()> {
+        let ws_provider = self
+            .ws_provider
+            .load_full()
+            .ok_or_else(|| anyhow!("{} has no websocket", self))?;
+
+        let mut pending_txs = ws_provider.subscribe_pending_txs().await?;
+
+        while let Some(tx_hash) = pending_txs.next().await {
+            if !seen.entry(tx_hash).or_insert(()).await.is_fresh() {
+                continue;
+            }
+
+            // an error just means that no clients are listening right now
+            let _ = sender.send(TxStatus::Pending(tx_hash));
+        }
+
+        Err(anyhow!("pending transactions subscription exited. reconnect needed").into())
+    }
+
     pub async fn wait_for_request_handle(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
+        method: Option<&str>,
         max_wait: Option<Duration>,
         error_handler: Option<RequestErrorHandler>,
     ) -> Web3ProxyResult<OpenRequestHandle> {
@@ -876,7 +1447,10 @@ impl Web3Rpc {
         let max_wait_until = max_wait.map(|x| Instant::now() + x);
 
         loop {
-            match self.try_request_handle(authorization, error_handler).await {
+            match self
+                .try_request_handle(authorization, method, error_handler)
+                .await
+            {
                 Ok(OpenRequestResult::Handle(handle)) => return Ok(handle),
                 Ok(OpenRequestResult::RetryAt(retry_at)) => {
                     // TODO: emit a stat?
@@ -897,7 +1471,7 @@ impl Web3Rpc {
 
                     sleep_until(retry_at).await;
                 }
-                Ok(OpenRequestResult::NotReady) => {
+                Ok(OpenRequestResult::NotReady | OpenRequestResult::Busy) => {
                     // TODO: when can this happen? log? emit a stat?
                     trace!("{} has no handle ready", self);
 
@@ -916,13 +1490,71 @@ impl Web3Rpc {
         }
     }
 
+    /// wait in this rpc's queue for its rate limit to replenish.
+    /// returns RetryAt if there is no queue, the queue is full, or the rate limit will not replenish within the queue's max wait.
+    /// `max_wait_until` is the caller's own deadline. the request never waits in the queue past it
+    pub async fn wait_in_rate_limit_queue(
+        self: &Arc<Self>,
+        authorization: &Arc<Authorization>,
+        method: Option<&str>,
+        error_handler: Option<RequestErrorHandler>,
+        mut retry_at: Instant,
+        max_wait_until: Option<Instant>,
+    ) -> Web3ProxyResult<OpenRequestResult> {
+        let queue = match self.rate_limit_queue.as_ref() {
+            Some(x) => x,
+            None => return Ok(OpenRequestResult::RetryAt(retry_at)),
+        };
+
+        let mut wait_until = Instant::now() + queue.max_wait;
+
+        if let Some(max_wait_until) = max_wait_until {
+            wait_until = wait_until.min(max_wait_until);
+        }
+
+        let _permit = match queue.waiting.try_acquire() {
+            Ok(x) => x,
+            Err(_) => {
+                trace!("rate limit queue on {} is full", self);
+                return Ok(OpenRequestResult::RetryAt(retry_at));
+            }
+        };
+
+        while retry_at <= wait_until {
+            sleep_until(retry_at).await;
+
+            match self
+                .try_request_handle(authorization, method, error_handler)
+                .await?
+            {
+                OpenRequestResult::RetryAt(x) => retry_at = x,
+                x => return Ok(x),
+            }
+        }
+
+        Ok(OpenRequestResult::RetryAt(retry_at))
+    }
+
+    /// `method` is used to pick the rate limit. None uses the hard_limit
     pub async fn try_request_handle(
         self: &Arc<Self>,
         authorization: &Arc<Authorization>,
+        method: Option<&str>,
         error_handler: Option<RequestErrorHandler>,
     ) -> Web3ProxyResult<OpenRequestResult> {
         // TODO: if websocket is reconnecting, return an error?
 
+        if self.circuit_state() == CircuitState::Open {
+            trace!("circuit on {} is open", self);
+            return Ok(OpenRequestResult::NotReady);
+        }
+
+        // checked before the rate limits so that a busy rpc doesn't use up its quota. internal requests are never busy
+        if !authorization.is_internal() && self.is_busy() {
+            trace!("{} has max_active_requests in flight", self);
+            return Ok(OpenRequestResult::Busy);
+        }
+
         // check cached rate limits
         if let Some(hard_limit_until) = self.hard_limit_until.as_ref() {
             let hard_limit_ready = *hard_limit_until.borrow();
@@ -932,8 +1564,31 @@ impl Web3Rpc {
             }
         }
 
-        // check shared rate limits
-        if let Some(ratelimiter) = self.hard_limit.as_ref() {
+        // methods with their own limit skip the hard_limit. they don't set hard_limit_until since other methods are still allowed
+        let method_ratelimiter =
+            method.and_then(|x| self.method_hard_limits.load().get(x).cloned());
+
+        if let Some(ratelimiter) = method_ratelimiter {
+            match ratelimiter
+                .throttle()
+                .await
+                .context(format!("attempting to throttle {}", self))?
+            {
+                RedisRateLimitResult::Allowed(_) => {}
+                RedisRateLimitResult::RetryAt(retry_at, _) => {
+                    trace!(?method, "Exhausted method rate limit on {}", self);
+
+                    self.rate_limited.fetch_add(1, atomic::Ordering::Relaxed);
+
+                    return Ok(OpenRequestResult::RetryAt(retry_at));
+                }
+                RedisRateLimitResult::RetryNever => {
+                    warn!(?method, "how did retry never on {} happen?", self);
+                    return Ok(OpenRequestResult::NotReady);
+                }
+            }
+        } else if let Some(ratelimiter) = self.hard_limit.load_full() {
+            // check shared rate limits
             // TODO: how should we know if we should set expire or not?
             match ratelimiter
                 .throttle()
@@ -945,6 +1600,8 @@ impl Web3Rpc {
                 }
                 RedisRateLimitResult::RetryAt(retry_at, _) => {
                     // rate limit gave us a wait time
+                    self.rate_limited.fetch_add(1, atomic::Ordering::Relaxed);
+
                     // if not a backup server, warn. backups hit rate limits often
                     if !self.backup {
                         let when = retry_at.duration_since(Instant::now());
@@ -968,10 +1625,22 @@ impl Web3Rpc {
             }
         };
 
-        let handle =
-            OpenRequestHandle::new(authorization.clone(), self.clone(), error_handler).await;
+        // checked last so that a half-open circuit's probe isn't used up by a rate limit
+        if let Some(circuit_breaker) = self.circuit_breaker.as_ref() {
+            if !circuit_breaker.try_acquire() {
+                trace!("circuit on {} is waiting for its probe", self);
+                return Ok(OpenRequestResult::NotReady);
+            }
+        }
 
-        Ok(handle.into())
+        // other requests might have taken the last slot while the rate limits were checked
+        match OpenRequestHandle::new(authorization.clone(), self.clone(), error_handler).await {
+            Some(handle) => Ok(handle.into()),
+            None => {
+                trace!("{} has max_active_requests in flight", self);
+                Ok(OpenRequestResult::Busy)
+            }
+        }
     }
 
     pub async fn internal_request<P: JsonRpcParams, R: JsonRpcResultData>(
@@ -1013,7 +1682,7 @@ impl Web3Rpc {
             tries -= 1;
 
             let handle = match self
-                .wait_for_request_handle(authorization, max_wait, error_handler)
+                .wait_for_request_handle(authorization, Some(method), max_wait, error_handler)
                 .await
             {
                 Ok(x) => x,
@@ -1084,8 +1753,8 @@ impl Serialize for Web3Rpc {
     where
         S: Serializer,
     {
-        // 14 if we bring head_delay back
-        let mut state = serializer.serialize_struct("Web3Rpc", 13)?;
+        // 23 if we bring head_delay back
+        let mut state = serializer.serialize_struct("Web3Rpc", 22)?;
 
         // the url is excluded because it likely includes private information. just show the name that we use in keys
         state.serialize_field("name", &self.name)?;
@@ -1094,6 +1763,8 @@ impl Serialize for Web3Rpc {
 
         state.serialize_field("backup", &self.backup)?;
 
+        state.serialize_field("pool", &self.pool)?;
+
         match self.block_data_limit.load(atomic::Ordering::Acquire) {
             u64::MAX => {
                 state.serialize_field("block_data_limit", &None::<()>)?;
@@ -1130,6 +1801,24 @@ impl Serialize for Web3Rpc {
             &self.active_requests.load(atomic::Ordering::Relaxed),
         )?;
 
+        state.serialize_field("max_active_requests", &self.max_active_requests())?;
+
+        {
+            let (on_head, behind_head) = self.head_lag_counts();
+            state.serialize_field("on_head_count", &on_head)?;
+            state.serialize_field("behind_head_count", &behind_head)?;
+        }
+
+        state.serialize_field("head_lag_blocks", &self.head_lag_blocks())?;
+
+        state.serialize_field("clock_skew_s", &self.clock_skew())?;
+
+        state.serialize_field("health_probe_tripped", &self.health_probe_tripped())?;
+
+        state.serialize_field("circuit", &self.circuit_state())?;
+
+        state.serialize_field("lifetime", &self.lifetime_stats.counts())?;
+
         // {
         //     let head_delay_ms = self.head_delay.read().await.latency().as_secs_f32() * 1000.0;
         //     state.serialize_field("head_delay_ms", &(head_delay_ms))?;
@@ -1199,10 +1888,20 @@ impl fmt::Display for Web3Rpc {
     }
 }
 
+/// how long to wait before reconnecting. doubles from 1 second up to 30 seconds
+fn reconnect_backoff(attempt: u32) -> Duration {
+    let secs = 1u64.checked_shl(attempt).unwrap_or(u64::MAX).min(30);
+
+    Duration::from_secs(secs)
+}
+
 mod tests {
     #![allow(unused_imports)]
     use super::*;
+    use crate::frontend::authorization::AuthorizationType;
     use ethers::types::{Block, H256, U256};
+    use tokio::task::yield_now;
+    use tokio::time::timeout;
 
     #[test]
     fn test_archive_node_has_block_data() {
@@ -1275,6 +1974,318 @@ mod tests {
         assert!(!x.has_block_data(&(head_block.number() + 1000)));
     }
 
+    #[test_log::test(tokio::test)]
+    async fn test_clock_skew() {
+        let (tx, _) = watch::channel(None);
+
+        let x = Arc::new(Web3Rpc {
+            name: "name".to_string(),
+            soft_limit: 1_000,
+            automatic_block_limit: false,
+            backup: false,
+            head_block: Some(tx),
+            ..Default::default()
+        });
+
+        let (block_and_rpc_sender, _block_and_rpc_receiver) = mpsc::unbounded_channel();
+        let block_map = BlocksByHashCache::new(100);
+
+        let now = chrono::Utc::now().timestamp();
+
+        // a block from a minute in the future means our clock is behind
+        let skewed_block = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_000.into()),
+            timestamp: (now + 60).into(),
+            ..Default::default()
+        });
+
+        x.send_head_block_result(Ok(Some(skewed_block)), &block_and_rpc_sender, &block_map)
+            .await
+            .unwrap();
+
+        assert!(x.clock_skew() >= 59);
+        assert!(x.is_clock_skewed());
+
+        // a block from a few seconds ago is normal
+        let normal_block = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_001.into()),
+            timestamp: (now - 2).into(),
+            ..Default::default()
+        });
+
+        x.send_head_block_result(Ok(Some(normal_block)), &block_and_rpc_sender, &block_map)
+            .await
+            .unwrap();
+
+        assert!(x.clock_skew() <= 0);
+        assert!(!x.is_clock_skewed());
+
+        // a block from a minute ago is just a lagging rpc. that says nothing about our clock
+        let old_block = Arc::new(Block {
+            hash: Some(H256::random()),
+            number: Some(1_000_002.into()),
+            timestamp: (now - 60).into(),
+            ..Default::default()
+        });
+
+        x.send_head_block_result(Ok(Some(old_block)), &block_and_rpc_sender, &block_map)
+            .await
+            .unwrap();
+
+        assert!(x.clock_skew() <= -59);
+        assert!(!x.is_clock_skewed());
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_rate_limit_queue() {
+        let (hard_limit_until, _) = watch::channel(Instant::now() + Duration::from_millis(100));
+
+        let rpc = Arc::new(Web3Rpc {
+            name: "saturated".to_string(),
+            hard_limit_until: Some(hard_limit_until),
+            rate_limit_queue: Some(RateLimitQueue::new(2, Duration::from_secs(1))),
+            ..Default::default()
+        });
+
+        let authorization = Arc::new(Authorization::default());
+
+        let retry_at = match rpc
+            .try_request_handle(&authorization, None, None)
+            .await
+            .unwrap()
+        {
+            OpenRequestResult::RetryAt(x) => x,
+            _ => panic!("rpc should be rate limited"),
+        };
+
+        // 2 requests fit in the queue and get handles once the limit replenishes. the third fails fast
+        let (a, b, c) = tokio::join!(
+            rpc.wait_in_rate_limit_queue(&authorization, None, None, retry_at, None),
+            rpc.wait_in_rate_limit_queue(&authorization, None, None, retry_at, None),
+            rpc.wait_in_rate_limit_queue(&authorization, None, None, retry_at, None),
+        );
+
+        assert!(matches!(a, Ok(OpenRequestResult::Handle(_))));
+        assert!(matches!(b, Ok(OpenRequestResult::Handle(_))));
+        assert!(matches!(c, Ok(OpenRequestResult::RetryAt(_))));
+        assert!(Instant::now() >= retry_at);
+
+        // the queue is empty again, but this limit will not replenish within the max wait
+        let retry_at = Instant::now() + Duration::from_secs(5);
+
+        rpc.hard_limit_until
+            .as_ref()
+            .unwrap()
+            .send_replace(retry_at);
+
+        let x = rpc
+            .wait_in_rate_limit_queue(&authorization, None, None, retry_at, None)
+            .await
+            .unwrap();
+
+        assert!(matches!(x, OpenRequestResult::RetryAt(_)));
+        assert!(Instant::now() < retry_at);
+
+        // this limit replenishes within the queue's max wait, but not before the caller's own deadline
+        let retry_at = Instant::now() + Duration::from_millis(100);
+
+        rpc.hard_limit_until
+            .as_ref()
+            .unwrap()
+            .send_replace(retry_at);
+
+        let x = rpc
+            .wait_in_rate_limit_queue(
+                &authorization,
+                None,
+                None,
+                retry_at,
+                Some(Instant::now() + Duration::from_millis(50)),
+            )
+            .await
+            .unwrap();
+
+        assert!(matches!(x, OpenRequestResult::RetryAt(_)));
+        assert!(Instant::now() < retry_at);
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_block_data_limit_loop_stops() {
+        let rpc = Arc::new(Web3Rpc {
+            name: "archive".to_string(),
+            automatic_block_limit: true,
+            config: Web3RpcConfig {
+                block_data_limit_check_interval_ms: 3_600_000,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let (subscribe_stop_tx, subscribe_stop_rx) = watch::channel(false);
+
+        let handle = tokio::spawn(rpc.block_data_limit_loop(subscribe_stop_rx));
+
+        // let the loop start waiting for its next check
+        yield_now().await;
+
+        subscribe_stop_tx.send_replace(true);
+
+        // the next check is an hour away. the loop should not wait for it
+        timeout(Duration::from_secs(1), handle)
+            .await
+            .expect("the loop should stop right away")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_max_active_requests() {
+        let rpc = Arc::new(Web3Rpc {
+            name: "busy".to_string(),
+            config: Web3RpcConfig {
+                max_active_requests: Some(2),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        let authorization = Arc::new(
+            Authorization::try_new(
+                Default::default(),
+                &"127.0.0.1".parse().unwrap(),
+                None,
+                None,
+                None,
+                AuthorizationType::Frontend,
+            )
+            .unwrap(),
+        );
+
+        let a = rpc.try_request_handle(&authorization, None, None).await;
+        let b = rpc.try_request_handle(&authorization, None, None).await;
+
+        assert!(matches!(a, Ok(OpenRequestResult::Handle(_))));
+        assert!(matches!(b, Ok(OpenRequestResult::Handle(_))));
+        assert!(rpc.is_busy());
+
+        let c = rpc.try_request_handle(&authorization, None, None).await;
+
+        assert!(matches!(c, Ok(OpenRequestResult::Busy)));
+        assert_eq!(rpc.active_requests(), 2);
+
+        // finishing a request frees up its slot
+        drop(a);
+
+        assert!(!rpc.is_busy());
+
+        let d = rpc.try_request_handle(&authorization, None, None).await;
+
+        assert!(matches!(d, Ok(OpenRequestResult::Handle(_))));
+        assert_eq!(rpc.active_requests(), 2);
+
+        // internal requests still get through a busy rpc
+        let internal = Arc::new(Authorization::internal().unwrap());
+
+        let e = rpc.try_request_handle(&internal, None, None).await;
+
+        assert!(matches!(e, Ok(OpenRequestResult::Handle(_))));
+        assert_eq!(rpc.active_requests(), 3);
+
+        // and they don't leave the rpc open to users when they finish
+        drop(e);
+
+        let f = rpc.try_request_handle(&authorization, None, None).await;
+
+        assert!(matches!(f, Ok(OpenRequestResult::Busy)));
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_health_probe_trips() {
+        // nothing is listening on this port, so every probe fails
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let http_provider = connect_http(
+            format!("http://127.0.0.1:{}", port).parse().unwrap(),
+            None,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        let (tx, _) = watch::channel(None);
+
+        let rpc = Arc::new(Web3Rpc {
+            name: "probed".to_string(),
+            head_block: Some(tx),
+            http_provider: Some(http_provider),
+            health_probe: Some(HealthProbeConfig {
+                method: "eth_blockNumber".to_string(),
+                params: serde_json::json!([]),
+                interval_ms: 1_000,
+                failure_threshold: 2,
+            }),
+            peak_latency: Some(PeakEwmaLatency::spawn(
+                Duration::from_secs(1),
+                4,
+                Duration::from_secs(1),
+            )),
+            median_latency: Some(RollingQuantileLatency::spawn_median(1_000).await),
+            ..Default::default()
+        });
+
+        let (block_and_rpc_sender, mut block_and_rpc_receiver) = mpsc::unbounded_channel();
+        let block_map = BlocksByHashCache::new(100);
+
+        let now = chrono::Utc::now().timestamp();
+
+        let new_block = |number: u64| {
+            Arc::new(Block {
+                hash: Some(H256::random()),
+                number: Some(number.into()),
+                timestamp: now.into(),
+                ..Default::default()
+            })
+        };
+
+        // head tracking works fine
+        rpc.send_head_block_result(Ok(Some(new_block(1))), &block_and_rpc_sender, &block_map)
+            .await
+            .unwrap();
+
+        let (head_block, _) = block_and_rpc_receiver.recv().await.unwrap();
+        assert!(head_block.is_some());
+
+        // a single failure is below the threshold
+        assert!(!rpc.send_health_probe(None).await);
+        assert!(!rpc.health_probe_tripped());
+
+        assert!(rpc.send_health_probe(None).await);
+        assert!(rpc.health_probe_tripped());
+
+        // new heads are still tracked, but consensus is told the rpc has no head
+        rpc.send_head_block_result(Ok(Some(new_block(2))), &block_and_rpc_sender, &block_map)
+            .await
+            .unwrap();
+
+        let (head_block, _) = block_and_rpc_receiver.recv().await.unwrap();
+        assert!(head_block.is_none());
+
+        assert_eq!(
+            rpc.head_block
+                .as_ref()
+                .unwrap()
+                .borrow()
+                .as_ref()
+                .map(|x| *x.number()),
+            Some(2.into())
+        );
+    }
+
     /*
     // TODO: think about how to bring the concept of a "lagged" node back
     #[test]
@@ -1322,4 +2333,13 @@ mod tests {
         assert!(!x.has_block_data(&(head_block.number() + 1000)));
     }
     */
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let delays: Vec<_> = (0..8).map(|x| reconnect_backoff(x).as_secs()).collect();
+
+        assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30, 30]);
+
+        assert_eq!(reconnect_backoff(u32::MAX), Duration::from_secs(30));
+    }
 }
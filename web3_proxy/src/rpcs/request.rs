@@ -1,17 +1,19 @@
 use super::one::Web3Rpc;
 use crate::errors::{Web3ProxyErrorContext, Web3ProxyResult};
-use crate::frontend::authorization::{Authorization, AuthorizationType};
+use crate::frontend::authorization::{Authorization, AuthorizationType, RequestMetadata};
 use crate::globals::{global_db_conn, DB_CONN};
-use crate::jsonrpc::{JsonRpcParams, JsonRpcResultData};
+use crate::jsonrpc::{JsonRpcParams, JsonRpcResultData, StreamOrBuffered, StreamResponse};
 use chrono::Utc;
 use derive_more::From;
 use entities::revert_log;
 use entities::sea_orm_active_enums::Method;
-use ethers::providers::ProviderError;
+use ethers::providers::{HttpClientError, JsonRpcError, ProviderError};
 use ethers::types::{Address, Bytes};
+use http::header;
 use migration::sea_orm::{self, ActiveEnum, ActiveModelTrait};
 use nanorand::Rng;
 use serde_json::json;
+use serde_json::value::RawValue;
 use std::sync::atomic;
 use std::sync::Arc;
 use tokio::time::{Duration, Instant};
@@ -24,6 +26,8 @@ pub enum OpenRequestResult {
     RetryAt(Instant),
     /// Unable to start a request because no servers are synced
     NotReady,
+    /// Unable to start a request because the server already has `max_active_requests` in flight. Try another server
+    Busy,
 }
 
 /// Make RPC requests through this handle and drop it when you are done.
@@ -136,20 +140,20 @@ impl OpenRequestHandle {
         authorization: Arc<Authorization>,
         rpc: Arc<Web3Rpc>,
         error_handler: Option<RequestErrorHandler>,
-    ) -> Self {
+    ) -> Option<Self> {
         // TODO: take request_id as an argument?
         // TODO: attach a unique id to this? customer requests have one, but not internal queries
-        // TODO: what ordering?!
-        rpc.active_requests
-            .fetch_add(1, std::sync::atomic::Ordering::AcqRel);
+        if !rpc.try_inc_active_requests(authorization.is_internal()) {
+            return None;
+        }
 
         let error_handler = error_handler.unwrap_or_default();
 
-        Self {
+        Some(Self {
             authorization,
             error_handler,
             rpc,
-        }
+        })
     }
 
     pub fn connection_name(&self) -> String {
@@ -161,6 +165,223 @@ impl OpenRequestHandle {
         self.rpc.clone()
     }
 
+    /// count the request as external or internal. every request sent to the rpc is counted once
+    fn count_request(&self) {
+        match self.authorization.authorization_type {
+            AuthorizationType::Frontend => {
+                self.rpc
+                    .external_requests
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+            AuthorizationType::Internal => {
+                self.rpc
+                    .internal_requests
+                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// record how long the rpc took to answer. `answered` is false for connection errors and timeouts
+    fn record_answer(&self, latency: Duration, answered: bool) {
+        self.rpc.lifetime_stats.record_request(latency);
+
+        self.rpc.record_circuit_result(answered);
+
+        let rpc = self.rpc.clone();
+        tokio::spawn(async move {
+            rpc.peak_latency.as_ref().unwrap().report(latency);
+            rpc.median_latency.as_ref().unwrap().record(latency);
+        });
+    }
+
+    /// count, log, and maybe save an error response. rate limit errors hold the rpc back for a moment
+    async fn on_error<P: JsonRpcParams>(&self, method: &str, params: &P, err: &ProviderError) {
+        // only save reverts for some types of calls
+        // TODO: do something special for eth_sendRawTransaction too
+        let error_handler = if let RequestErrorHandler::Save = self.error_handler {
+            // TODO: should all these be Trace or Debug or a mix?
+            if !["eth_call", "eth_estimateGas"].contains(&method) {
+                // trace!(%method, "skipping save on revert");
+                RequestErrorHandler::TraceLevel
+            } else if DB_CONN.read().await.is_ok() {
+                let log_revert_chance = self.authorization.checks.log_revert_chance;
+
+                if log_revert_chance == 0 {
+                    // trace!(%method, "no chance. skipping save on revert");
+                    RequestErrorHandler::TraceLevel
+                } else if log_revert_chance == u16::MAX {
+                    // trace!(%method, "gaurenteed chance. SAVING on revert");
+                    self.error_handler
+                } else if nanorand::tls_rng().generate_range(0u16..u16::MAX) < log_revert_chance {
+                    // trace!(%method, "missed chance. skipping save on revert");
+                    RequestErrorHandler::TraceLevel
+                } else {
+                    // trace!("Saving on revert");
+                    // TODO: is always logging at debug level fine?
+                    self.error_handler
+                }
+            } else {
+                // trace!(%method, "no database. skipping save on revert");
+                RequestErrorHandler::TraceLevel
+            }
+        } else {
+            self.error_handler
+        };
+
+        // TODO: simple enum -> string derive?
+        // TODO: if ProviderError::UnsupportedRpc, we should retry on another server
+        #[derive(Debug)]
+        enum ResponseTypes {
+            Revert,
+            RateLimit,
+            Error,
+        }
+
+        // check for "execution reverted" here
+        // TODO: move this info a function on ResponseErrorType
+        let response_type = if let ProviderError::JsonRpcClientError(err) = err {
+            if let Some(_err) = err.as_serde_error() {
+                // this seems to pretty much always be a rate limit error
+                ResponseTypes::RateLimit
+            } else if let Some(err) = err.as_error_response() {
+                // JsonRpc and Application errors get rolled into the JsonRpcClientError
+                let msg = err.message.as_str();
+
+                trace!(%msg, "jsonrpc error message");
+
+                if msg.starts_with("execution reverted") {
+                    ResponseTypes::Revert
+                } else if msg.contains("limit") || msg.contains("request") {
+                    // TODO! THIS HAS TOO MANY FALSE POSITIVES! Theres another spot in the code that checks for things.
+                    ResponseTypes::RateLimit
+                } else {
+                    ResponseTypes::Error
+                }
+            } else {
+                // i don't think this is possible
+                warn!(?err, "unexpected error");
+                ResponseTypes::Error
+            }
+        } else {
+            ResponseTypes::Error
+        };
+
+        if !matches!(response_type, ResponseTypes::Revert) {
+            self.rpc.lifetime_stats.record_error();
+        }
+
+        if matches!(response_type, ResponseTypes::RateLimit) {
+            if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
+                // TODO: how long should we actually wait? different providers have different times
+                // TODO: if rate_limit_period_seconds is set, use that
+                // TODO: check response headers for rate limits too
+                let retry_at = Instant::now() + Duration::from_secs(1);
+
+                if self.rpc.backup {
+                    debug!(?retry_at, "rate limited on {}!", self.rpc);
+                } else {
+                    warn!(?retry_at, "rate limited on {}!", self.rpc);
+                }
+
+                hard_limit_until.send_replace(retry_at);
+            }
+        }
+
+        // TODO: think more about the method and param logs. those can be sensitive information
+        // we do **NOT** use self.error_handler here because it might have been modified
+        match error_handler {
+            RequestErrorHandler::DebugLevel => {
+                // TODO: think about this revert check more. sometimes we might want reverts logged so this needs a flag
+                if matches!(response_type, ResponseTypes::Revert) {
+                    trace!(
+                        rpc=%self.rpc,
+                        %method,
+                        ?params,
+                        ?err,
+                        "revert",
+                    );
+                } else {
+                    debug!(
+                        rpc=%self.rpc,
+                        %method,
+                        ?params,
+                        ?err,
+                        "bad response",
+                    );
+                }
+            }
+            RequestErrorHandler::InfoLevel => {
+                info!(
+                    rpc=%self.rpc,
+                    %method,
+                    ?params,
+                    ?err,
+                    "bad response",
+                );
+            }
+            RequestErrorHandler::TraceLevel => {
+                trace!(
+                    rpc=%self.rpc,
+                    %method,
+                    ?params,
+                    ?err,
+                    "bad response",
+                );
+            }
+            RequestErrorHandler::ErrorLevel => {
+                // TODO: only include params if not running in release mode
+                error!(
+                    rpc=%self.rpc,
+                    %method,
+                    ?params,
+                    ?err,
+                    "bad response",
+                );
+            }
+            RequestErrorHandler::WarnLevel => {
+                // TODO: only include params if not running in release mode
+                warn!(
+                    rpc=%self.rpc,
+                    %method,
+                    ?params,
+                    ?err,
+                    "bad response",
+                );
+            }
+            RequestErrorHandler::Save => {
+                trace!(
+                    rpc=%self.rpc,
+                    %method,
+                    ?params,
+                    ?err,
+                    "bad response",
+                );
+
+                // TODO: do not unwrap! (doesn't matter much since we check method as a string above)
+                let method: Method = Method::try_from_value(&method.to_string()).unwrap();
+
+                // TODO: i don't think this prsing is correct
+                match serde_json::from_value::<EthCallParams>(json!(params)) {
+                    Ok(params) => {
+                        // spawn saving to the database so we don't slow down the request
+                        // TODO: log if this errors
+                        let f = self.authorization.clone().save_revert(method, params.0 .0);
+
+                        tokio::spawn(f);
+                    }
+                    Err(err) => {
+                        warn!(
+                            %method,
+                            ?params,
+                            ?err,
+                            "failed parsing eth_call params. unable to save revert",
+                        );
+                    }
+                }
+            }
+        }
+    }
+
     /// Send a web3 request
     /// By having the request method here, we ensure that the rate limiter was called and connection counts were properly incremented
     /// depending on how things are locked, you might need to pass the provider in
@@ -175,18 +396,7 @@ impl OpenRequestHandle {
         // trace!(rpc=%self.rpc, %method, "request");
         trace!("requesting from {}", self.rpc);
 
-        match self.authorization.authorization_type {
-            AuthorizationType::Frontend => {
-                self.rpc
-                    .external_requests
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            }
-            AuthorizationType::Internal => {
-                self.rpc
-                    .internal_requests
-                    .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
-            }
-        }
+        self.count_request();
 
         // we used to fetch_add the active_request count here, but sometimes a request is made without going through this function (like with subscriptions)
 
@@ -194,7 +404,9 @@ impl OpenRequestHandle {
 
         // TODO: replace ethers-rs providers with our own that supports streaming the responses
         // TODO: replace ethers-rs providers with our own that handles "id" being null
-        let response: Result<R, _> = if let Some(ref p) = self.rpc.http_provider {
+        let response: Result<R, _> = if let Some(ref p) = self.rpc.signed_http_provider {
+            p.request(method, params).await
+        } else if let Some(ref p) = self.rpc.http_provider {
             p.request(method, params).await
         } else if let Some(p) = self.rpc.ws_provider.load().as_ref() {
             p.request(method, params).await
@@ -208,6 +420,17 @@ impl OpenRequestHandle {
         // originally i thought we wouldn't want errors, but I think it's a more accurate number including all requests
         let latency = start.elapsed();
 
+        // an error response is still an answer. only connection errors and timeouts count against the circuit
+        let answered = match &response {
+            Ok(_) => true,
+            Err(ProviderError::JsonRpcClientError(err)) => {
+                err.as_error_response().is_some() || err.as_serde_error().is_some()
+            }
+            Err(_) => false,
+        };
+
+        self.record_answer(latency, answered);
+
         // we used to fetch_sub the active_request count here, but sometimes the handle is dropped without request being called!
 
         trace!(
@@ -219,194 +442,127 @@ impl OpenRequestHandle {
         );
 
         if let Err(err) = &response {
-            // only save reverts for some types of calls
-            // TODO: do something special for eth_sendRawTransaction too
-            let error_handler = if let RequestErrorHandler::Save = self.error_handler {
-                // TODO: should all these be Trace or Debug or a mix?
-                if !["eth_call", "eth_estimateGas"].contains(&method) {
-                    // trace!(%method, "skipping save on revert");
-                    RequestErrorHandler::TraceLevel
-                } else if DB_CONN.read().await.is_ok() {
-                    let log_revert_chance = self.authorization.checks.log_revert_chance;
-
-                    if log_revert_chance == 0 {
-                        // trace!(%method, "no chance. skipping save on revert");
-                        RequestErrorHandler::TraceLevel
-                    } else if log_revert_chance == u16::MAX {
-                        // trace!(%method, "gaurenteed chance. SAVING on revert");
-                        self.error_handler
-                    } else if nanorand::tls_rng().generate_range(0u16..u16::MAX) < log_revert_chance
-                    {
-                        // trace!(%method, "missed chance. skipping save on revert");
-                        RequestErrorHandler::TraceLevel
-                    } else {
-                        // trace!("Saving on revert");
-                        // TODO: is always logging at debug level fine?
-                        self.error_handler
-                    }
-                } else {
-                    // trace!(%method, "no database. skipping save on revert");
-                    RequestErrorHandler::TraceLevel
-                }
-            } else {
-                self.error_handler
-            };
-
-            // TODO: simple enum -> string derive?
-            // TODO: if ProviderError::UnsupportedRpc, we should retry on another server
-            #[derive(Debug)]
-            enum ResponseTypes {
-                Revert,
-                RateLimit,
-                Error,
-            }
+            self.on_error(method, params, err).await;
+        }
 
-            // check for "execution reverted" here
-            // TODO: move this info a function on ResponseErrorType
-            let response_type = if let ProviderError::JsonRpcClientError(err) = err {
-                if let Some(_err) = err.as_serde_error() {
-                    // this seems to pretty much always be a rate limit error
-                    ResponseTypes::RateLimit
-                } else if let Some(err) = err.as_error_response() {
-                    // JsonRpc and Application errors get rolled into the JsonRpcClientError
-                    let msg = err.message.as_str();
-
-                    trace!(%msg, "jsonrpc error message");
-
-                    if msg.starts_with("execution reverted") {
-                        ResponseTypes::Revert
-                    } else if msg.contains("limit") || msg.contains("request") {
-                        // TODO! THIS HAS TOO MANY FALSE POSITIVES! Theres another spot in the code that checks for things.
-                        ResponseTypes::RateLimit
-                    } else {
-                        ResponseTypes::Error
-                    }
-                } else {
-                    // i don't think this is possible
-                    warn!(?err, "unexpected error");
-                    ResponseTypes::Error
-                }
-            } else {
-                ResponseTypes::Error
-            };
-
-            if matches!(response_type, ResponseTypes::RateLimit) {
-                if let Some(hard_limit_until) = self.rpc.hard_limit_until.as_ref() {
-                    // TODO: how long should we actually wait? different providers have different times
-                    // TODO: if rate_limit_period_seconds is set, use that
-                    // TODO: check response headers for rate limits too
-                    let retry_at = Instant::now() + Duration::from_secs(1);
-
-                    if self.rpc.backup {
-                        debug!(?retry_at, "rate limited on {}!", self.rpc);
-                    } else {
-                        warn!(?retry_at, "rate limited on {}!", self.rpc);
-                    }
+        response
+    }
 
-                    hard_limit_until.send_replace(retry_at);
-                }
+    /// Like `request`, but the response is only read into memory if it is smaller than `max_buffer_bytes`. Bigger responses are streamed.
+    /// The client's id is sent to the rpc so that a streamed body can be passed through unchanged.
+    /// Falls back to `request` if the rpc does not have a plain http connection.
+    pub async fn stream_request(
+        self,
+        id: &RawValue,
+        method: &str,
+        params: &serde_json::Value,
+        max_buffer_bytes: usize,
+        request_metadata: &Arc<RequestMetadata>,
+    ) -> Result<StreamOrBuffered<Box<RawValue>>, ProviderError> {
+        let (client, url, auth) = match self.rpc.stream_http.as_ref() {
+            Some(x) => (x.client.clone(), x.url.clone(), x.auth.clone()),
+            None => {
+                return self
+                    .request(method, params)
+                    .await
+                    .map(StreamOrBuffered::Buffered)
             }
+        };
 
-            // TODO: think more about the method and param logs. those can be sensitive information
-            // we do **NOT** use self.error_handler here because it might have been modified
-            match error_handler {
-                RequestErrorHandler::DebugLevel => {
-                    // TODO: think about this revert check more. sometimes we might want reverts logged so this needs a flag
-                    if matches!(response_type, ResponseTypes::Revert) {
-                        trace!(
-                            rpc=%self.rpc,
-                            %method,
-                            ?params,
-                            ?err,
-                            "revert",
-                        );
-                    } else {
-                        debug!(
-                            rpc=%self.rpc,
-                            %method,
-                            ?params,
-                            ?err,
-                            "bad response",
-                        );
-                    }
-                }
-                RequestErrorHandler::InfoLevel => {
-                    info!(
-                        rpc=%self.rpc,
-                        %method,
-                        ?params,
-                        ?err,
-                        "bad response",
-                    );
-                }
-                RequestErrorHandler::TraceLevel => {
-                    trace!(
-                        rpc=%self.rpc,
-                        %method,
-                        ?params,
-                        ?err,
-                        "bad response",
-                    );
-                }
-                RequestErrorHandler::ErrorLevel => {
-                    // TODO: only include params if not running in release mode
-                    error!(
-                        rpc=%self.rpc,
-                        %method,
-                        ?params,
-                        ?err,
-                        "bad response",
-                    );
-                }
-                RequestErrorHandler::WarnLevel => {
-                    // TODO: only include params if not running in release mode
-                    warn!(
-                        rpc=%self.rpc,
-                        %method,
-                        ?params,
-                        ?err,
-                        "bad response",
-                    );
-                }
-                RequestErrorHandler::Save => {
-                    trace!(
-                        rpc=%self.rpc,
-                        %method,
-                        ?params,
-                        ?err,
-                        "bad response",
-                    );
+        trace!("streaming from {}", self.rpc);
 
-                    // TODO: do not unwrap! (doesn't matter much since we check method as a string above)
-                    let method: Method = Method::try_from_value(&method.to_string()).unwrap();
-
-                    // TODO: i don't think this prsing is correct
-                    match serde_json::from_value::<EthCallParams>(json!(params)) {
-                        Ok(params) => {
-                            // spawn saving to the database so we don't slow down the request
-                            // TODO: log if this errors
-                            let f = self.authorization.clone().save_revert(method, params.0 .0);
-
-                            tokio::spawn(f);
-                        }
-                        Err(err) => {
-                            warn!(
-                                %method,
-                                ?params,
-                                ?err,
-                                "failed parsing eth_call params. unable to save revert",
-                            );
-                        }
+        self.count_request();
+
+        let body = serde_json::to_vec(&StreamRequest {
+            jsonrpc: "2.0",
+            id,
+            method,
+            params,
+        })?;
+
+        let mut request = client
+            .post(url)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body);
+
+        if let Some(auth) = auth {
+            request = request.header(header::AUTHORIZATION, auth.to_string());
+        }
+
+        let start = Instant::now();
+
+        let response = request.send().await.and_then(|x| x.error_for_status());
+
+        // this is the time until the headers arrived. a big body can take much longer
+        let latency = start.elapsed();
+
+        // an error status is still an answer
+        let answered = match &response {
+            Ok(_) => true,
+            Err(err) => err.is_status(),
+        };
+
+        self.record_answer(latency, answered);
+
+        let mut response = response.map_err(|err| {
+            self.rpc.lifetime_stats.record_error();
+            err
+        })?;
+
+        let mut buffer = vec![];
+
+        while buffer.len() < max_buffer_bytes {
+            match response.chunk().await? {
+                Some(chunk) => buffer.extend_from_slice(&chunk),
+                None => {
+                    // the whole response fit in the buffer. handle it like any other response
+                    let response: BufferedResponse = serde_json::from_slice(&buffer)?;
+
+                    if let Some(err) = response.error {
+                        let err = ProviderError::JsonRpcClientError(Box::new(
+                            HttpClientError::JsonRpcError(err),
+                        ));
+
+                        self.on_error(method, params, &err).await;
+
+                        return Err(err);
                     }
+
+                    // a null result is valid. it deserializes as None
+                    let result = match response.result {
+                        Some(x) => x,
+                        None => RawValue::from_string("null".to_string())?,
+                    };
+
+                    return Ok(StreamOrBuffered::Buffered(result));
                 }
             }
         }
 
-        tokio::spawn(async move {
-            self.rpc.peak_latency.as_ref().unwrap().report(latency);
-            self.rpc.median_latency.as_ref().unwrap().record(latency);
-        });
+        trace!(rpc=%self.rpc, %method, buffered=%buffer.len(), "streaming response");
 
-        response
+        Ok(StreamOrBuffered::Stream(StreamResponse {
+            buffer: buffer.into(),
+            response,
+            request_metadata: request_metadata.clone(),
+            handle: self,
+        }))
     }
 }
+
+/// the body of a streamed request. params are passed through unchanged and the id is kept raw
+#[derive(serde::Serialize)]
+struct StreamRequest<'a> {
+    jsonrpc: &'static str,
+    id: &'a RawValue,
+    method: &'a str,
+    params: &'a serde_json::Value,
+}
+
+/// a response to a streamed request that was small enough to read into memory
+#[derive(serde::Deserialize)]
+struct BufferedResponse {
+    #[serde(default)]
+    result: Option<Box<RawValue>>,
+    error: Option<JsonRpcError>,
+}
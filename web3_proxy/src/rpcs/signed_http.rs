@@ -0,0 +1,249 @@
+//! Http transport for rpcs that require every request to be signed with a shared secret.
+use super::provider::extract_auth;
+use crate::config::HmacConfig;
+use crate::errors::Web3ProxyResult;
+use async_trait::async_trait;
+use ethers::providers::{HttpClientError, JsonRpcClient, JsonRpcError, Provider};
+use ethers::utils::hex;
+use hmac::{Hmac, Mac};
+use http::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use sha2::Sha256;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use url::Url;
+
+pub type EthersSignedHttpProvider = Provider<SignedHttp>;
+
+pub struct SignedHttp {
+    client: reqwest::Client,
+    /// basic auth that was in the url
+    auth: Option<String>,
+    hmac: HmacConfig,
+    id: AtomicU64,
+    url: Url,
+}
+
+#[derive(Serialize)]
+struct Request<'a, T> {
+    id: u64,
+    jsonrpc: &'static str,
+    method: &'a str,
+    params: T,
+}
+
+#[derive(Deserialize)]
+struct Response<'a> {
+    #[serde(borrow)]
+    result: Option<&'a RawValue>,
+    error: Option<JsonRpcError>,
+}
+
+/// hex HMAC-SHA256 of `{timestamp}.{body}`
+pub fn sign(secret: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac keys can be any size");
+
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+
+    hex::encode(mac.finalize().into_bytes())
+}
+
+impl SignedHttp {
+    pub fn new(mut url: Url, client: Option<reqwest::Client>, hmac: HmacConfig) -> Self {
+        let auth = extract_auth(&mut url).map(|x| x.to_string());
+
+        Self {
+            client: client.unwrap_or_default(),
+            auth,
+            hmac,
+            id: AtomicU64::new(1),
+            url,
+        }
+    }
+}
+
+impl fmt::Debug for SignedHttp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never include the secret or the auth
+        f.debug_struct("SignedHttp")
+            .field("url", &self.url)
+            .finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl JsonRpcClient for SignedHttp {
+    type Error = HttpClientError;
+
+    async fn request<T, R>(&self, method: &str, params: T) -> Result<R, HttpClientError>
+    where
+        T: fmt::Debug + Serialize + Send + Sync,
+        R: DeserializeOwned + Send,
+    {
+        let id = self.id.fetch_add(1, Ordering::Relaxed);
+
+        let body = serde_json::to_vec(&Request {
+            id,
+            jsonrpc: "2.0",
+            method,
+            params,
+        })
+        .map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: format!("unable to serialize {} request", method),
+        })?;
+
+        let timestamp = chrono::Utc::now().timestamp();
+
+        let signature = sign(self.hmac.secret.expose().as_bytes(), timestamp, &body);
+
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .header(&self.hmac.timestamp_header, timestamp.to_string())
+            .header(&self.hmac.signature_header, signature);
+
+        if let Some(auth) = self.auth.as_ref() {
+            request = request.header(AUTHORIZATION, auth);
+        }
+
+        // like the ethers Http client, the status is ignored. jsonrpc errors can come with any status
+        let text = request.body(body).send().await?.text().await?;
+
+        let response: Response =
+            serde_json::from_str(&text).map_err(|err| HttpClientError::SerdeJson {
+                err,
+                text: text.clone(),
+            })?;
+
+        if let Some(err) = response.error {
+            return Err(HttpClientError::JsonRpcError(err));
+        }
+
+        let result = response.result.map(|x| x.get()).unwrap_or("null");
+
+        serde_json::from_str(result).map_err(|err| HttpClientError::SerdeJson {
+            err,
+            text: result.to_string(),
+        })
+    }
+}
+
+pub fn connect_signed_http(
+    url: Url,
+    http_client: Option<reqwest::Client>,
+    hmac: HmacConfig,
+    interval: Duration,
+) -> Web3ProxyResult<EthersSignedHttpProvider> {
+    if !url.scheme().starts_with("http") {
+        return Err(anyhow::anyhow!("only http servers are supported. cannot use {}", url).into());
+    }
+
+    let mut provider = Provider::new(SignedHttp::new(url, http_client, hmac));
+
+    provider.set_interval(interval);
+
+    Ok(provider)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+    use axum::http::{HeaderMap, StatusCode};
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use ethers::providers::Middleware;
+    use ethers::types::U64;
+    use serde_json::json;
+    use std::net::TcpListener;
+
+    const SECRET: &str = "hunter2";
+
+    /// an rpc that rejects requests without a valid signature
+    async fn mock_rpc(
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Json<serde_json::Value>, StatusCode> {
+        let timestamp: i64 = headers
+            .get("x-timestamp")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.parse().ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let signature = headers
+            .get("x-signature")
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| hex::decode(x).ok())
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(&body);
+
+        mac.verify_slice(&signature)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+        let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+        Ok(Json(json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": "0x1",
+        })))
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_signed_requests() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let url: Url = format!("http://{}", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+
+        let app = Router::new().route("/", post(mock_rpc));
+
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+
+        let hmac = HmacConfig {
+            secret: SECRET.to_string().into(),
+            signature_header: "x-signature".to_string(),
+            timestamp_header: "x-timestamp".to_string(),
+        };
+
+        let signed =
+            connect_signed_http(url.clone(), None, hmac.clone(), Duration::from_secs(1)).unwrap();
+
+        assert_eq!(signed.get_block_number().await.unwrap(), U64::from(1));
+
+        // the secret does not leak into debug output
+        assert!(!format!("{:?} {:?}", signed, hmac).contains(SECRET));
+
+        // a bad secret is rejected
+        let wrong_secret = HmacConfig {
+            secret: "wrong".to_string().into(),
+            ..hmac
+        };
+
+        let wrong =
+            connect_signed_http(url.clone(), None, wrong_secret, Duration::from_secs(1)).unwrap();
+
+        assert!(wrong.get_block_number().await.is_err());
+
+        // unsigned requests are rejected
+        let unsigned = Provider::new(ethers::providers::Http::new(url));
+
+        assert!(unsigned.get_block_number().await.is_err());
+    }
+}
@@ -0,0 +1,260 @@
+//! Share upstream subscriptions (`logs`, `newPendingTransactions`) between every client that subscribes to the same thing.
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use ethers::types::{Log, Transaction, TxHash};
+use futures::future::{AbortHandle, Abortable};
+use hashbrown::HashMap;
+use http::StatusCode;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Weak};
+use tokio::sync::broadcast;
+
+/// how many logs a slow client can fall behind before it starts missing some
+pub const LOGS_BUFFER: usize = 1024;
+
+/// how many transactions a slow client can fall behind before it starts missing some
+pub const PENDING_TX_BUFFER: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TxStatus {
+    /// an rpc announced this transaction for the first time
+    Pending(TxHash),
+    /// this transaction is in the new consensus head block
+    Confirmed(TxHash),
+}
+
+pub type LogsSender = broadcast::Sender<Arc<Log>>;
+pub type PendingTxSender = broadcast::Sender<TxStatus>;
+pub type PendingFullTxSender = broadcast::Sender<Arc<Transaction>>;
+
+/// keyed by the filter's json. clients with the same filter share an upstream
+pub type LogsSubscriptions = SharedSubscriptions<String, Arc<Log>>;
+/// there is only ever one of these upstreams, so the key is `()`
+pub type PendingTxSubscriptions = SharedSubscriptions<(), TxStatus>;
+/// each pending transaction is fetched once and then sent to every client
+pub type PendingFullTxSubscriptions = SharedSubscriptions<(), Arc<Transaction>>;
+
+pub struct SharedSubscriptions<K, T> {
+    /// the most upstream subscriptions that can be open at once
+    max_upstream: usize,
+    /// how many messages a slow client can fall behind before it starts missing some
+    buffer: usize,
+    /// Weak so that an upstream stops once its last client leaves
+    upstream: Mutex<HashMap<K, Weak<UpstreamSubscription<T>>>>,
+}
+
+/// a single upstream subscription. it is stopped when the last client drops its handle
+pub struct UpstreamSubscription<T> {
+    sender: broadcast::Sender<T>,
+    abort_handle: AbortHandle,
+}
+
+impl<T> UpstreamSubscription<T> {
+    pub fn subscribe(&self) -> broadcast::Receiver<T> {
+        self.sender.subscribe()
+    }
+}
+
+impl<T> Drop for UpstreamSubscription<T> {
+    fn drop(&mut self) {
+        self.abort_handle.abort();
+    }
+}
+
+impl<K: Eq + Hash, T: Clone> SharedSubscriptions<K, T> {
+    pub fn new(max_upstream: usize, buffer: usize) -> Self {
+        Self {
+            max_upstream,
+            buffer,
+            upstream: Default::default(),
+        }
+    }
+
+    /// the number of upstream subscriptions that still have clients
+    pub fn num_upstream(&self) -> usize {
+        self.upstream
+            .lock()
+            .values()
+            .filter(|x| x.strong_count() > 0)
+            .count()
+    }
+
+    /// join the upstream subscription for this key. if there isn't one, `start` is spawned to forward messages into the sender
+    pub fn subscribe<F, Fut>(
+        &self,
+        key: K,
+        start: F,
+    ) -> Web3ProxyResult<Arc<UpstreamSubscription<T>>>
+    where
+        F: FnOnce(broadcast::Sender<T>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut upstream = self.upstream.lock();
+
+        if let Some(x) = upstream.get(&key).and_then(Weak::upgrade) {
+            return Ok(x);
+        }
+
+        upstream.retain(|_, x| x.strong_count() > 0);
+
+        if upstream.len() >= self.max_upstream {
+            return Err(Web3ProxyError::StatusCode(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "too many upstream subscriptions. try again later or use a subscription that is already open".into(),
+                None,
+            ));
+        }
+
+        let (sender, _) = broadcast::channel(self.buffer);
+
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+
+        tokio::spawn(Abortable::new(start(sender.clone()), abort_registration));
+
+        let x = Arc::new(UpstreamSubscription {
+            sender,
+            abort_handle,
+        });
+
+        upstream.insert(key, Arc::downgrade(&x));
+
+        Ok(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        LogsSender, LogsSubscriptions, PendingTxSender, PendingTxSubscriptions, TxStatus,
+        LOGS_BUFFER,
+    };
+    use ethers::types::{Address, Log, TxHash};
+    use parking_lot::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[test_log::test(tokio::test)]
+    async fn test_shared_logs_subscription() {
+        let logs_subscriptions = LogsSubscriptions::new(2, LOGS_BUFFER);
+
+        let num_started = AtomicUsize::new(0);
+        let upstream_sender: Mutex<Option<LogsSender>> = Default::default();
+
+        // a fake upstream. the test sends the logs itself
+        let start = |sender: LogsSender| {
+            num_started.fetch_add(1, Ordering::SeqCst);
+            *upstream_sender.lock() = Some(sender);
+            futures::future::pending()
+        };
+
+        let clients: Vec<_> = (0..3)
+            .map(|_| {
+                logs_subscriptions
+                    .subscribe("a".to_string(), start)
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(num_started.load(Ordering::SeqCst), 1);
+        assert_eq!(logs_subscriptions.num_upstream(), 1);
+        assert!(clients.iter().all(|x| Arc::ptr_eq(x, &clients[0])));
+
+        let mut receivers: Vec<_> = clients.iter().map(|x| x.subscribe()).collect();
+
+        let log = Log {
+            address: Address::repeat_byte(1),
+            ..Default::default()
+        };
+
+        upstream_sender
+            .lock()
+            .as_ref()
+            .unwrap()
+            .send(Arc::new(log.clone()))
+            .unwrap();
+
+        for receiver in receivers.iter_mut() {
+            assert_eq!(*receiver.recv().await.unwrap(), log);
+        }
+
+        // a different key needs its own upstream
+        let other = logs_subscriptions
+            .subscribe("b".to_string(), start)
+            .unwrap();
+
+        assert_eq!(num_started.load(Ordering::SeqCst), 2);
+        assert_eq!(logs_subscriptions.num_upstream(), 2);
+
+        // the limit is reached
+        assert!(logs_subscriptions
+            .subscribe("c".to_string(), start)
+            .is_err());
+
+        // but existing keys can still be joined
+        let late = logs_subscriptions
+            .subscribe("a".to_string(), start)
+            .unwrap();
+        assert!(Arc::ptr_eq(&late, &clients[0]));
+
+        // once every client leaves, the upstream is freed up for another key
+        drop(clients);
+        drop(late);
+
+        assert_eq!(logs_subscriptions.num_upstream(), 1);
+
+        logs_subscriptions
+            .subscribe("c".to_string(), start)
+            .unwrap();
+
+        assert_eq!(num_started.load(Ordering::SeqCst), 3);
+
+        drop(other);
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_shared_pending_txs_subscription() {
+        let pending_txs_subscriptions = PendingTxSubscriptions::new(1, 16);
+
+        let num_started = AtomicUsize::new(0);
+        let upstream_sender: Mutex<Option<PendingTxSender>> = Default::default();
+
+        // a fake upstream. the test sends the transactions itself
+        let start = |sender: PendingTxSender| {
+            num_started.fetch_add(1, Ordering::SeqCst);
+            *upstream_sender.lock() = Some(sender);
+            futures::future::pending()
+        };
+
+        let clients: Vec<_> = (0..3)
+            .map(|_| pending_txs_subscriptions.subscribe((), start).unwrap())
+            .collect();
+
+        assert_eq!(num_started.load(Ordering::SeqCst), 1);
+        assert_eq!(pending_txs_subscriptions.num_upstream(), 1);
+
+        let mut receivers: Vec<_> = clients.iter().map(|x| x.subscribe()).collect();
+
+        let tx_status = TxStatus::Pending(TxHash::repeat_byte(1));
+
+        upstream_sender
+            .lock()
+            .as_ref()
+            .unwrap()
+            .send(tx_status)
+            .unwrap();
+
+        for receiver in receivers.iter_mut() {
+            assert_eq!(receiver.recv().await.unwrap(), tx_status);
+        }
+
+        // once every client leaves, the upstream stops. the next client starts a new one
+        drop(clients);
+
+        assert_eq!(pending_txs_subscriptions.num_upstream(), 0);
+
+        let _client = pending_txs_subscriptions.subscribe((), start).unwrap();
+
+        assert_eq!(num_started.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,149 @@
+//! Shut the app down one phase at a time. Each phase has its own deadline so that a stuck phase can't skip the ones after it.
+use crate::errors::{Web3ProxyError, Web3ProxyResult};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::future::Future;
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+use tracing::{error, info};
+
+/// phases run in the order they were added. a phase that errors or runs out of time does not stop the later phases.
+#[derive(Default)]
+pub struct ShutdownCoordinator {
+    phases: Vec<(
+        &'static str,
+        Duration,
+        BoxFuture<'static, Web3ProxyResult<()>>,
+    )>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn phase<F>(mut self, name: &'static str, max_wait: Duration, f: F) -> Self
+    where
+        F: Future<Output = Web3ProxyResult<()>> + Send + 'static,
+    {
+        self.phases.push((name, max_wait, f.boxed()));
+        self
+    }
+
+    /// returns the names of the phases that completed, in order. the first error is returned once every phase has run
+    pub async fn run(self) -> Web3ProxyResult<Vec<&'static str>> {
+        let start = Instant::now();
+
+        let mut completed = vec![];
+        let mut first_error = None;
+
+        for (name, max_wait, f) in self.phases {
+            match timeout(max_wait, f).await {
+                Ok(Ok(())) => {
+                    info!(phase = name, elapsed_ms = %start.elapsed().as_millis(), "shutdown phase complete");
+                    completed.push(name);
+                }
+                Ok(Err(err)) => {
+                    error!(phase = name, ?err, "shutdown phase failed");
+                    completed.push(name);
+                    first_error.get_or_insert(err);
+                }
+                Err(_) => {
+                    // keep going. later phases (like saving stats) still need to run
+                    error!(phase = name, ?max_wait, "shutdown phase deadline exceeded");
+                    first_error.get_or_insert(Web3ProxyError::Timeout(Some(max_wait)));
+                }
+            }
+        }
+
+        match first_error {
+            None => Ok(completed),
+            Some(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShutdownCoordinator;
+    use crate::errors::Web3ProxyError;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_phases_run_in_order() {
+        let finished = Arc::new(Mutex::new(vec![]));
+
+        let phase = |name: &'static str, ms: u64| {
+            let finished = finished.clone();
+
+            async move {
+                sleep(Duration::from_millis(ms)).await;
+                finished.lock().push(name);
+                Ok::<_, Web3ProxyError>(())
+            }
+        };
+
+        let start = Instant::now();
+
+        // later phases are faster. they still wait for the earlier ones
+        let max_wait = Duration::from_secs(10);
+
+        let completed = ShutdownCoordinator::new()
+            .phase("frontend", max_wait, phase("frontend", 400))
+            .phase("drain", max_wait, phase("drain", 300))
+            .phase("consensus", max_wait, phase("consensus", 200))
+            .phase("stats", max_wait, phase("stats", 100))
+            .run()
+            .await
+            .unwrap();
+
+        let expected = vec!["frontend", "drain", "consensus", "stats"];
+
+        assert_eq!(completed, expected);
+        assert_eq!(*finished.lock(), expected);
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_deadline() {
+        let start = Instant::now();
+
+        let stats_saved = Arc::new(Mutex::new(false));
+
+        let x = {
+            let stats_saved = stats_saved.clone();
+
+            ShutdownCoordinator::new()
+                .phase("frontend", Duration::from_secs(10), async { Ok(()) })
+                .phase("stuck", Duration::from_secs(10), futures::future::pending())
+                .phase("stats", Duration::from_secs(5), async move {
+                    // the stuck phase must not cost us the stats
+                    sleep(Duration::from_secs(1)).await;
+                    *stats_saved.lock() = true;
+                    Ok(())
+                })
+                .run()
+                .await
+        };
+
+        assert!(matches!(x, Err(Web3ProxyError::Timeout(_))));
+        assert!(*stats_saved.lock());
+        assert_eq!(start.elapsed(), Duration::from_secs(11));
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_first_error_is_returned() {
+        let x = ShutdownCoordinator::new()
+            .phase("consensus", Duration::from_secs(10), async {
+                Err(Web3ProxyError::NoServersSynced)
+            })
+            .phase("stuck", Duration::from_secs(10), futures::future::pending())
+            .run()
+            .await;
+
+        assert!(matches!(x, Err(Web3ProxyError::NoServersSynced)));
+    }
+}
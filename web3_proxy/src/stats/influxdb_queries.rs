@@ -3,7 +3,7 @@ use crate::errors::Web3ProxyErrorContext;
 use crate::globals::global_db_replica_conn;
 use crate::{
     app::Web3ProxyApp,
-    errors::{Web3ProxyError, Web3ProxyResponse},
+    errors::{Web3ProxyError, Web3ProxyResponse, Web3ProxyResult},
     http_params::{
         get_chain_id_from_params, get_query_start_from_params, get_query_stop_from_params,
         get_query_window_seconds_from_params,
@@ -129,30 +129,24 @@ pub async fn query_user_influx_stats<'a>(
             })
             .collect::<Vec<_>>();
 
-        // Fetch all rpc_keys where we are the subuser
+        // Fetch all rpc_keys where we are the subuser. collaborators can use a key but not see its stats
         let mut subuser_rpc_keys = secondary_user::Entity::find()
             .filter(secondary_user::Column::UserId.eq(user_id))
+            .filter(secondary_user::Column::Role.ne(Role::Collaborator))
             .find_also_related(rpc_key::Entity)
             .all(db_replica.as_ref())
             // TODO: Do a join with rpc-keys
             .await
             .web3_context("failed loading subuser keys")?
             .into_iter()
-            .flat_map(
-                |(subuser, wrapped_shared_rpc_key)| match wrapped_shared_rpc_key {
-                    Some(shared_rpc_key) => {
-                        if subuser.role == Role::Admin || subuser.role == Role::Owner {
-                            let key = shared_rpc_key.id.to_string();
-                            let val = Ulid::from(shared_rpc_key.secret_key);
-                            rpc_key_id_to_key.insert(key.clone(), val);
-                            Some(key)
-                        } else {
-                            None
-                        }
-                    }
-                    None => None,
-                },
-            )
+            .flat_map(|(_, wrapped_shared_rpc_key)| {
+                wrapped_shared_rpc_key.map(|shared_rpc_key| {
+                    let key = shared_rpc_key.id.to_string();
+                    let val = Ulid::from(shared_rpc_key.secret_key);
+                    rpc_key_id_to_key.insert(key.clone(), val);
+                    key
+                })
+            })
             .collect::<Vec<_>>();
 
         user_rpc_keys.append(&mut subuser_rpc_keys);
@@ -163,6 +157,22 @@ pub async fn query_user_influx_stats<'a>(
             ));
         }
 
+        // optionally narrow down to one of those keys
+        if let Some(rpc_key_id) = params.get("rpc_key_id") {
+            let rpc_key_id = rpc_key_id
+                .parse::<u64>()
+                .map_err(|_| Web3ProxyError::BadRequest("Unable to parse rpc_key_id".into()))?
+                .to_string();
+
+            if !user_rpc_keys.contains(&rpc_key_id) {
+                return Err(Web3ProxyError::AccessDenied(
+                    "rpc_key_id is not one of your keys".into(),
+                ));
+            }
+
+            user_rpc_keys = vec![rpc_key_id];
+        }
+
         // Iterate, pop and add to string
         let mut filter_subquery = "".to_string();
 
@@ -191,6 +201,20 @@ pub async fn query_user_influx_stats<'a>(
         filter_chain_id = f!(r#"|> filter(fn: (r) => r.chain_id == "{chain_id}")"#);
     }
 
+    let mut filter_method = "".to_string();
+    if let Some(method) = params.get("method") {
+        // the method goes into the flux query. only allow characters that method names actually use
+        if method.is_empty()
+            || !method
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(Web3ProxyError::BadRequest("Unable to parse method".into()));
+        }
+
+        filter_method = f!(r#"|> filter(fn: (r) => r.method == "{method}")"#);
+    }
+
     // Fetch and request for balance
 
     trace!(
@@ -238,6 +262,14 @@ pub async fn query_user_influx_stats<'a>(
         ]
     );
 
+    // percentiles can't be added up. each window gets the highest value of the aggregates inside it
+    let percentiles = f!(r#"
+            percentiles = base()
+                |> filter(fn: (r) => r._field == "p50_response_millis" or r._field == "p90_response_millis" or r._field == "p99_response_millis")
+                |> group(columns: {group_keys})
+                |> aggregateWindow(every: {query_window_seconds}s, fn: max, createEmpty: false)
+    "#);
+
     let query;
     if stat_response_type == StatType::Detailed
         || (stat_response_type == StatType::Aggregated && user_id != 0)
@@ -247,12 +279,17 @@ pub async fn query_user_influx_stats<'a>(
                 |> range(start: {query_start}, stop: {query_stop})
                 {rpc_key_filter}
                 {filter_chain_id}
+                {filter_method}
                 |> filter(fn: (r) => r._measurement == "{measurement}")
-                
-            cumsum = base()
+
+            sums = base()
                 |> filter(fn: (r) => r._field == "backend_requests" or r._field == "cache_hits" or r._field == "cache_misses" or r._field == "frontend_requests" or r._field == "no_servers" or r._field == "sum_incl_free_credits_used" or r._field == "sum_credits_used" or r._field == "sum_request_bytes" or r._field == "sum_response_bytes" or r._field == "sum_response_millis")
                 |> group(columns: {group_keys})
                 |> aggregateWindow(every: {query_window_seconds}s, fn: sum, createEmpty: false)
+
+            {percentiles}
+
+            cumsum = union(tables: [sums, percentiles])
                 |> drop(columns: ["_start", "_stop"])
                 |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
                 |> group()
@@ -272,13 +309,20 @@ pub async fn query_user_influx_stats<'a>(
         "#);
     } else if stat_response_type == StatType::Aggregated && user_id == 0 {
         query = f!(r#"
-            from(bucket: "{bucket}")
+            base = () => from(bucket: "{bucket}")
                 |> range(start: {query_start}, stop: {query_stop})
                 {filter_chain_id}
+                {filter_method}
                 |> filter(fn: (r) => r._measurement == "{measurement}")
-                |> filter(fn: (r) => r._field != "balance")
+
+            sums = base()
+                |> filter(fn: (r) => r._field == "backend_requests" or r._field == "cache_hits" or r._field == "cache_misses" or r._field == "frontend_requests" or r._field == "no_servers" or r._field == "sum_incl_free_credits_used" or r._field == "sum_credits_used" or r._field == "sum_request_bytes" or r._field == "sum_response_bytes" or r._field == "sum_response_millis")
                 |> group(columns: {group_keys})
                 |> aggregateWindow(every: {query_window_seconds}s, fn: sum, createEmpty: false)
+
+            {percentiles}
+
+            union(tables: [sums, percentiles])
                 |> drop(columns: ["_start", "_stop"])
                 |> pivot(rowKey: ["_time"], columnKey: ["_field"], valueColumn: "_value")
                 |> group()
@@ -479,6 +523,22 @@ pub async fn query_user_influx_stats<'a>(
                             error!("rpc_secret_key_id should always be a String!");
                         }
                     }
+                } else if let Some(percentile) = [
+                    "p50_response_millis",
+                    "p90_response_millis",
+                    "p99_response_millis",
+                ]
+                .into_iter()
+                .find(|x| *x == key)
+                {
+                    match value {
+                        influxdb2_structmap::value::Value::Long(inner) => {
+                            out.insert(percentile, serde_json::Value::Number(inner.into()));
+                        }
+                        _ => {
+                            error!("{} should always be a Long!", percentile);
+                        }
+                    }
                 } else if key == "sum_response_millis" {
                     match value {
                         influxdb2_structmap::value::Value::Long(inner) => {
@@ -605,3 +665,64 @@ pub async fn query_user_influx_stats<'a>(
 
     Ok(response)
 }
+
+/// How often each rpc was on the consensus head vs behind it between `query_start` and `query_stop`.
+/// This sums the `rpc_head_lag` points from `save_rpc_head_lag_loop`, so unlike `Web3Rpc::head_lag_counts` it survives restarts and reconnects.
+/// Counts from every instance on the chain are added together.
+pub async fn query_rpc_head_lag(
+    influxdb_client: &influxdb2::Client,
+    bucket: &str,
+    chain_id: u64,
+    query_start: i64,
+    query_stop: i64,
+) -> Web3ProxyResult<HashMap<String, (u64, u64)>> {
+    let query = f!(r#"
+        from(bucket: "{bucket}")
+            |> range(start: {query_start}, stop: {query_stop})
+            |> filter(fn: (r) => r._measurement == "rpc_head_lag")
+            |> filter(fn: (r) => r.chain_id == "{chain_id}")
+            |> filter(fn: (r) => r._field == "on_head" or r._field == "behind_head")
+            |> group(columns: ["rpc", "_field"])
+            |> sum()
+            |> pivot(rowKey: ["rpc"], columnKey: ["_field"], valueColumn: "_value")
+            |> group()
+    "#);
+
+    trace!("Raw query to db is: {:#}", query);
+    let query = Query::new(query);
+
+    let raw_influx_responses: Vec<FluxRecord> = influxdb_client
+        .query_raw(Some(query))
+        .await
+        .context("failed querying head lag stats")?;
+
+    let mut counts = HashMap::with_capacity(raw_influx_responses.len());
+
+    for record in raw_influx_responses {
+        let mut rpc = None;
+        let mut on_head = 0;
+        let mut behind_head = 0;
+
+        for (key, value) in record.values {
+            match (key.as_str(), value) {
+                ("rpc", influxdb2_structmap::value::Value::String(inner)) => rpc = Some(inner),
+                ("on_head", influxdb2_structmap::value::Value::Long(inner)) => {
+                    on_head = inner.max(0) as u64
+                }
+                ("behind_head", influxdb2_structmap::value::Value::Long(inner)) => {
+                    behind_head = inner.max(0) as u64
+                }
+                _ => {}
+            }
+        }
+
+        match rpc {
+            Some(rpc) => {
+                counts.insert(rpc, (on_head, behind_head));
+            }
+            None => error!("rpc_head_lag should always have an rpc tag!"),
+        }
+    }
+
+    Ok(counts)
+}
@@ -10,11 +10,15 @@ use crate::caches::{RpcSecretKeyCache, UserBalanceCache};
 use crate::compute_units::ComputeUnit;
 use crate::errors::{Web3ProxyError, Web3ProxyResult};
 use crate::frontend::authorization::{Authorization, RequestMetadata};
+use crate::globals::global_db_conn;
+use crate::rpcs::many::Web3Rpcs;
 use crate::rpcs::one::Web3Rpc;
 use anyhow::{anyhow, Context};
 use chrono::{DateTime, Months, TimeZone, Utc};
 use derive_more::From;
 use entities::{referee, referrer, rpc_accounting_v2};
+use hashbrown::HashMap;
+use hdrhistogram::Histogram;
 use influxdb2::models::DataPoint;
 use migration::sea_orm::prelude::Decimal;
 use migration::sea_orm::{
@@ -29,9 +33,127 @@ use std::mem;
 use std::num::NonZeroU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::select;
+use tokio::sync::broadcast;
+use tokio::time::interval;
 use tracing::{error, instrument, trace, warn};
 
-pub use stat_buffer::{SpawnedStatBuffer, StatBuffer};
+pub use stat_buffer::{SpawnedStatBuffer, StatBuffer, StatSender};
+
+/// the timeseries percentiles stop at 5 minutes
+const RESPONSE_MILLIS_HISTOGRAM_MAX: u64 = 5 * 60 * 1000;
+
+/// Periodically save how often each rpc was on the consensus head vs behind it.
+/// Each point only counts the heads since the previous point, so they can be summed over any range.
+pub async fn save_rpc_head_lag_loop(
+    rpcs: Arc<Web3Rpcs>,
+    chain_id: u64,
+    influxdb_client: influxdb2::Client,
+    influxdb_bucket: String,
+    instance: String,
+    period: Duration,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    let mut interval = interval(period);
+
+    // the rpcs' counts are cumulative since they connected. rpc name -> counts at the last point
+    let mut previous_counts: HashMap<String, (u64, u64)> = HashMap::new();
+
+    loop {
+        select! {
+            _ = interval.tick() => {}
+            _ = shutdown_receiver.recv() => return Ok(()),
+        }
+
+        let timestamp_ns = Utc::now().timestamp_nanos();
+
+        let connected: Vec<_> = rpcs.by_name.read().values().cloned().collect();
+
+        previous_counts.retain(|name, _| connected.iter().any(|x| &x.name == name));
+
+        let points: Vec<_> = connected
+            .iter()
+            .filter_map(|rpc| {
+                let counts = rpc.head_lag_counts();
+
+                let (on_head, behind_head) = match previous_counts.insert(rpc.name.clone(), counts)
+                {
+                    // a reconnected rpc starts counting from 0 again
+                    Some(previous) if counts.0 >= previous.0 && counts.1 >= previous.1 => {
+                        (counts.0 - previous.0, counts.1 - previous.1)
+                    }
+                    _ => counts,
+                };
+
+                match DataPoint::builder("rpc_head_lag")
+                    .tag("chain_id", chain_id.to_string())
+                    .tag("instance", &instance)
+                    .tag("rpc", &rpc.name)
+                    .field("on_head", on_head as i64)
+                    .field("behind_head", behind_head as i64)
+                    .field("clock_skew_s", rpc.clock_skew())
+                    .timestamp(timestamp_ns)
+                    .build()
+                {
+                    Ok(point) => Some(point),
+                    Err(err) => {
+                        error!(?err, %rpc, "unable to build head lag stat!");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        if points.is_empty() {
+            continue;
+        }
+
+        if let Err(err) = influxdb_client
+            .write(&influxdb_bucket, futures::stream::iter(points))
+            .await
+        {
+            error!(?err, "unable to save head lag stats!");
+        }
+    }
+}
+
+/// Periodically add each rpc's lifetime stats to the database so that they survive restarts.
+/// The database is checked every time because it might connect after the app starts.
+pub async fn save_rpc_lifetime_stats_loop(
+    rpcs: Arc<Web3Rpcs>,
+    chain_id: u64,
+    period: Duration,
+    mut shutdown_receiver: broadcast::Receiver<()>,
+) -> Web3ProxyResult<()> {
+    let mut interval = interval(period);
+
+    loop {
+        let shutting_down = select! {
+            _ = interval.tick() => false,
+            _ = shutdown_receiver.recv() => true,
+        };
+
+        // save one last time before stopping
+        if let Ok(db_conn) = global_db_conn().await {
+            let rpcs: Vec<_> = rpcs.by_name.read().values().cloned().collect();
+
+            for rpc in rpcs {
+                if let Err(err) = rpc
+                    .lifetime_stats()
+                    .save(chain_id, &rpc.name, &db_conn)
+                    .await
+                {
+                    error!(?err, %rpc, "unable to save lifetime stats!");
+                }
+            }
+        }
+
+        if shutting_down {
+            return Ok(());
+        }
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum StatType {
@@ -219,6 +341,16 @@ impl BufferedRpcQueryStats {
         trace!("added");
     }
 
+    /// responses slower than `RESPONSE_MILLIS_HISTOGRAM_MAX` are counted as that max
+    fn record_response_millis(&mut self, response_millis: u64) {
+        self.response_millis_histogram
+            .get_or_insert_with(|| {
+                Histogram::new_with_max(RESPONSE_MILLIS_HISTOGRAM_MAX, 2)
+                    .expect("histogram bounds are valid")
+            })
+            .saturating_record(response_millis);
+    }
+
     async fn _save_db_stats(
         &self,
         chain_id: u64,
@@ -498,6 +630,22 @@ impl BufferedRpcQueryStats {
                     .context("sum_credits_used is really (too) large")?,
             );
 
+        if let Some(histogram) = self.response_millis_histogram.as_ref() {
+            builder = builder
+                .field(
+                    "p50_response_millis",
+                    histogram.value_at_quantile(0.50) as i64,
+                )
+                .field(
+                    "p90_response_millis",
+                    histogram.value_at_quantile(0.90) as i64,
+                )
+                .field(
+                    "p99_response_millis",
+                    histogram.value_at_quantile(0.99) as i64,
+                );
+        }
+
         // TODO: set the rpc_secret_key_id tag to 0 when anon? will that make other queries easier?
         if let Some(rpc_secret_key_id) = key.rpc_secret_key_id {
             builder = builder.tag("rpc_secret_key_id", rpc_secret_key_id.to_string());
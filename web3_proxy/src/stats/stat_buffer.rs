@@ -8,12 +8,31 @@ use crate::stats::RpcQueryStats;
 use derive_more::From;
 use futures::stream;
 use hashbrown::HashMap;
+use hdrhistogram::Histogram;
+use influxdb2::models::DataPoint;
 use migration::sea_orm::prelude::Decimal;
+use std::fmt;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{broadcast, mpsc, oneshot};
-use tokio::time::{interval, sleep};
+use tokio::task::JoinHandle;
+use tokio::time::{interval, sleep, timeout};
 use tracing::{debug, error, info, trace, warn, Instrument};
 
+/// how many saves can be waiting on influx before new points are dropped
+const TSDB_WRITE_QUEUE: usize = 16;
+
+// TODO: put max_batch_size in config?
+// TODO: i think the real limit is the byte size of the http request. so, a simple line count won't work very well
+const TSDB_MAX_BATCH_SIZE: usize = 1000;
+
+/// how long to wait for influx to acknowledge an explicit flush or to finish its queue at shutdown
+const TSDB_WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// points for the tsdb writer. the sender is notified once the points have been written
+type TsdbWrite = (Vec<DataPoint>, Option<oneshot::Sender<()>>);
+
 #[derive(Debug, Default)]
 pub struct BufferedRpcQueryStats {
     pub frontend_requests: u64,
@@ -25,6 +44,8 @@ pub struct BufferedRpcQueryStats {
     pub sum_request_bytes: u64,
     pub sum_response_bytes: u64,
     pub sum_response_millis: u64,
+    /// only recorded for the timeseries aggregates. the relational tables don't have columns for percentiles
+    pub response_millis_histogram: Option<Histogram<u32>>,
     pub sum_credits_used: Decimal,
     pub sum_cu_used: Decimal,
     pub paid_credits_used: Decimal,
@@ -36,26 +57,131 @@ pub struct BufferedRpcQueryStats {
 
 #[derive(From)]
 pub struct SpawnedStatBuffer {
-    pub stat_sender: mpsc::UnboundedSender<AppStat>,
+    pub stat_sender: StatSender,
     /// these handles are important and must be allowed to finish
     pub background_handle: Web3ProxyJoinHandle<()>,
 }
 
+/// sends stats to the [`StatBuffer`].
+/// once the buffer has done its final flush, stats are saved straight to the relational db instead of being lost
+#[derive(Clone, Debug)]
+pub struct StatSender {
+    sender: mpsc::UnboundedSender<AppStat>,
+    late_stats: Arc<LateStatSaver>,
+}
+
+impl StatSender {
+    /// returns a handle to the direct save if the buffer is no longer receiving
+    pub fn send(&self, stat: AppStat) -> Option<JoinHandle<()>> {
+        let stat = match self.sender.send(stat) {
+            Ok(()) => return None,
+            Err(err) => err.0,
+        };
+
+        warn!("stat buffer is closed. saving the stat directly");
+
+        let late_stats = self.late_stats.clone();
+
+        tokio::runtime::Handle::try_current()
+            .ok()
+            .map(|handle| handle.spawn(async move { late_stats.save(stat).await }))
+    }
+}
+
+/// everything needed to save a stat without the buffer
+struct LateStatSaver {
+    billing_period_seconds: i64,
+    chain_id: u64,
+    rpc_secret_key_cache: RpcSecretKeyCache,
+    user_balance_cache: UserBalanceCache,
+}
+
+impl fmt::Debug for LateStatSaver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LateStatSaver")
+            .field("billing_period_seconds", &self.billing_period_seconds)
+            .field("chain_id", &self.chain_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl LateStatSaver {
+    /// timeseries stats are not saved. influx has already been closed and they aren't used for billing
+    async fn save(&self, stat: AppStat) {
+        let AppStat::RpcQuery(request_metadata) = stat;
+
+        // nothing else is going to give back this request's hold
+        request_metadata.release_paid_credits().await;
+
+        let stat = match RpcQueryStats::try_from_metadata(request_metadata) {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, "unable to convert late stat");
+                return;
+            }
+        };
+
+        let key = stat.accounting_key(self.billing_period_seconds);
+        if !key.is_registered() {
+            return;
+        }
+
+        let db_conn = match global_db_conn().await {
+            Ok(x) => x,
+            Err(err) => {
+                error!(?err, "no db to save late stat");
+                return;
+            }
+        };
+
+        let approximate_balance_remaining = stat
+            .authorization
+            .checks
+            .latest_balance
+            .read()
+            .await
+            .remaining();
+
+        let mut buffered = BufferedRpcQueryStats::default();
+        buffered.add(stat, approximate_balance_remaining).await;
+
+        if let Err(err) = buffered
+            .save_db(
+                self.chain_id,
+                &db_conn,
+                key,
+                &self.user_balance_cache,
+                &self.rpc_secret_key_cache,
+            )
+            .await
+        {
+            error!(?err, "unable to save late stat!");
+        }
+    }
+}
+
 pub struct StatBuffer {
     accounting_db_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
     billing_period_seconds: i64,
     chain_id: u64,
     db_save_interval_seconds: u32,
+    /// save early once any single aggregate has this many frontend requests
+    flush_frontend_requests: Option<u64>,
+    /// set when an accounting aggregate crosses `flush_frontend_requests`
+    flush_relational_early: bool,
+    /// set when a timeseries aggregate crosses `flush_frontend_requests`
+    flush_tsdb_early: bool,
     global_timeseries_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
-    influxdb_bucket: Option<String>,
-    influxdb_client: Option<influxdb2::Client>,
     /// a globally unique name
     /// instance names can be re-used but they MUST only ever be used by a single server at a time!
     instance: String,
     opt_in_timeseries_buffer: HashMap<RpcQueryKey, BufferedRpcQueryStats>,
     rpc_secret_key_cache: RpcSecretKeyCache,
     tsdb_save_interval_seconds: u32,
+    /// None if influx is not configured
+    tsdb_sender: Option<mpsc::Sender<TsdbWrite>>,
     tsdb_window: i64,
+    tsdb_writer: Option<JoinHandle<()>>,
     num_tsdb_windows: i64,
     user_balance_cache: UserBalanceCache,
 
@@ -68,8 +194,9 @@ impl StatBuffer {
         billing_period_seconds: i64,
         chain_id: u64,
         db_save_interval_seconds: u32,
+        flush_frontend_requests: Option<u64>,
         influxdb_bucket: Option<String>,
-        mut influxdb_client: Option<influxdb2::Client>,
+        influxdb_client: Option<influxdb2::Client>,
         rpc_secret_key_cache: RpcSecretKeyCache,
         user_balance_cache: UserBalanceCache,
         shutdown_receiver: broadcast::Receiver<()>,
@@ -78,12 +205,33 @@ impl StatBuffer {
         flush_receiver: mpsc::Receiver<oneshot::Sender<FlushedStats>>,
         instance: String,
     ) -> anyhow::Result<Option<SpawnedStatBuffer>> {
-        if influxdb_bucket.is_none() {
-            influxdb_client = None;
-        }
-
         let (stat_sender, stat_receiver) = mpsc::unbounded_channel();
 
+        let stat_sender = StatSender {
+            sender: stat_sender,
+            late_stats: Arc::new(LateStatSaver {
+                billing_period_seconds,
+                chain_id,
+                rpc_secret_key_cache: rpc_secret_key_cache.clone(),
+                user_balance_cache: user_balance_cache.clone(),
+            }),
+        };
+
+        let (tsdb_sender, tsdb_writer) = match (influxdb_bucket, influxdb_client) {
+            (Some(influxdb_bucket), Some(influxdb_client)) => {
+                let (tsdb_sender, tsdb_receiver) = mpsc::channel(TSDB_WRITE_QUEUE);
+
+                let tsdb_writer = tokio::spawn(tsdb_write_loop(
+                    influxdb_client,
+                    influxdb_bucket,
+                    tsdb_receiver,
+                ));
+
+                (Some(tsdb_sender), Some(tsdb_writer))
+            }
+            _ => (None, None),
+        };
+
         // TODO: get the frontend request timeout and add a minute buffer instead of hard coding `(5 + 1)`
         let num_tsdb_windows = ((5 + 1) * 60) / tsdb_save_interval_seconds as i64;
 
@@ -98,15 +246,18 @@ impl StatBuffer {
             billing_period_seconds,
             chain_id,
             db_save_interval_seconds,
+            flush_frontend_requests,
+            flush_relational_early: false,
+            flush_tsdb_early: false,
             global_timeseries_buffer: Default::default(),
-            influxdb_bucket,
-            influxdb_client,
             instance,
             num_tsdb_windows,
             opt_in_timeseries_buffer: Default::default(),
             rpc_secret_key_cache,
             tsdb_save_interval_seconds,
+            tsdb_sender,
             tsdb_window,
+            tsdb_writer,
             user_balance_cache,
 
             _flush_sender: flush_sender,
@@ -143,7 +294,27 @@ impl StatBuffer {
                     if let Some(stat) = stat {
                         total_frontend_requests += self._buffer_app_stat(stat).await?;
 
-                        // TODO: if buffers are big, flush now?
+                        // an aggregate got big. save that buffer now instead of waiting for its interval
+                        if self.flush_relational_early {
+                            self.flush_relational_early = false;
+
+                            let (count, new_frontend_requests) = self.save_relational_stats().await;
+                            if count > 0 {
+                                db_frontend_requests += new_frontend_requests;
+                                debug!("Early saved {} stats for {} requests to the relational db", count, new_frontend_requests);
+                            }
+                        }
+
+                        if self.flush_tsdb_early {
+                            self.flush_tsdb_early = false;
+
+                            // the save advances the window just like an interval tick. uniq stays bounded by num_tsdb_windows
+                            let (count, new_frontend_requests) = self.save_tsdb_stats(None).await;
+                            if count > 0 {
+                                tsdb_frontend_requests += new_frontend_requests;
+                                debug!("Early saved {} stats for {} requests to the tsdb @ {}/{}", count, new_frontend_requests, self.tsdb_window, self.num_tsdb_windows);
+                            }
+                        }
                     } else {
                         break;
                     }
@@ -159,7 +330,7 @@ impl StatBuffer {
                 }
                 _ = tsdb_save_interval.tick() => {
                     trace!("TSDB save internal tick");
-                    let (count, new_frontend_requests) = self.save_tsdb_stats().await;
+                    let (count, new_frontend_requests) = self.save_tsdb_stats(None).await;
                     if count > 0 {
                         tsdb_frontend_requests += new_frontend_requests;
                         debug!("Saved {} stats for {} requests to the tsdb @ {}/{}", count, new_frontend_requests, self.tsdb_window, self.num_tsdb_windows);
@@ -211,11 +382,32 @@ impl StatBuffer {
         //     sleep(Duration::from_millis(10)).await;
         // }
 
+        // stop accepting stats before the final flush. anything already queued is still drained,
+        // and a stat sent after this is saved straight to the db by `StatSender` instead of sitting in a channel that is about to be dropped
+        stat_receiver.close();
+
         let flushed_stats = self._flush(&mut stat_receiver).await?;
 
         tsdb_frontend_requests += flushed_stats.timeseries_frontend_requests;
         db_frontend_requests += flushed_stats.relational_frontend_requests;
 
+        // closing the channel lets the writer finish the points it already has and then exit
+        self.tsdb_sender.take();
+
+        if let Some(mut tsdb_writer) = self.tsdb_writer.take() {
+            match timeout(TSDB_WRITE_TIMEOUT, &mut tsdb_writer).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => error!(?err, "tsdb writer failed"),
+                Err(_) => {
+                    // a hung influx must not keep the app from exiting
+                    error!(
+                        "tsdb writer did not finish in time. dropping the remaining tsdb stats!"
+                    );
+                    tsdb_writer.abort();
+                }
+            }
+        }
+
         // TODO: if these totals don't match, something is wrong!
         info!(%total_frontend_requests, %tsdb_frontend_requests, %db_frontend_requests, "accounting and stat save loop complete");
 
@@ -234,6 +426,9 @@ impl StatBuffer {
         &mut self,
         request_metadata: RequestMetadata,
     ) -> Web3ProxyResult<u64> {
+        // give back the request's hold before anything that can fail. the db being down or a bad stat must not leak it
+        request_metadata.release_paid_credits().await;
+
         // we convert on this side of the channel so that we don't slow down the request
         let stat = RpcQueryStats::try_from_metadata(request_metadata)?;
 
@@ -259,7 +454,6 @@ impl StatBuffer {
 
                 // if paid_credits_used is true, then they were premium at the start of the request
                 if stat.authorization.checks.paid_credits_used {
-                    // TODO: this lets them get a negative remaining balance. we should clear if close to 0
                     user_balance.total_spent_paid_credits += stat.compute_unit_cost;
 
                     // check if they still have premium
@@ -303,28 +497,43 @@ impl StatBuffer {
                     key = tracing::field::debug(&accounting_key)
                 )
                 .or_current();
-                self.accounting_db_buffer
-                    .entry(accounting_key)
-                    .or_default()
+                let aggregate = self.accounting_db_buffer.entry(accounting_key).or_default();
+
+                aggregate
                     .add(stat.clone(), approximate_balance_remaining)
                     .instrument(span)
                     .await;
+
+                let frontend_requests = aggregate.frontend_requests;
+                if self.over_flush_threshold(frontend_requests) {
+                    self.flush_relational_early = true;
+                }
             }
         }
 
-        if self.influxdb_client.is_some() {
+        if self.tsdb_sender.is_some() {
             if let Some(opt_in_timeseries_key) = stat.owned_timeseries_key(active_premium) {
                 let span = tracing::trace_span!(
                     "owned_timeseries",
                     key = tracing::field::debug(&opt_in_timeseries_key)
                 )
                 .or_current();
-                self.opt_in_timeseries_buffer
+                let aggregate = self
+                    .opt_in_timeseries_buffer
                     .entry(opt_in_timeseries_key)
-                    .or_default()
+                    .or_default();
+
+                aggregate.record_response_millis(stat.response_millis);
+
+                aggregate
                     .add(stat.clone(), approximate_balance_remaining)
                     .instrument(span)
                     .await;
+
+                let frontend_requests = aggregate.frontend_requests;
+                if self.over_flush_threshold(frontend_requests) {
+                    self.flush_tsdb_early = true;
+                }
             }
 
             let global_timeseries_key = stat.global_timeseries_key();
@@ -333,17 +542,45 @@ impl StatBuffer {
                 key = tracing::field::debug(&global_timeseries_key)
             )
             .or_current();
-            self.global_timeseries_buffer
+            let aggregate = self
+                .global_timeseries_buffer
                 .entry(global_timeseries_key)
-                .or_default()
+                .or_default();
+
+            aggregate.record_response_millis(stat.response_millis);
+
+            aggregate
                 .add(stat, approximate_balance_remaining)
                 .instrument(span)
                 .await;
+
+            let frontend_requests = aggregate.frontend_requests;
+            if self.over_flush_threshold(frontend_requests) {
+                self.flush_tsdb_early = true;
+            }
         }
 
         Ok(1)
     }
 
+    fn over_flush_threshold(&self, frontend_requests: u64) -> bool {
+        self.flush_frontend_requests
+            .map(|threshold| frontend_requests >= threshold)
+            .unwrap_or(false)
+    }
+
+    /// every tsdb save (interval, early, or flush) gets a new window. this is used to ensure that stats don't overwrite others because the keys match
+    /// this has to be done carefully or cardinality becomes a problem! the window wraps at num_tsdb_windows so uniq stays small
+    /// https://docs.influxdata.com/influxdb/v2.0/write-data/best-practices/duplicate-points/
+    fn advance_tsdb_window(&mut self) -> i64 {
+        self.tsdb_window += 1;
+        if self.tsdb_window > self.num_tsdb_windows {
+            self.tsdb_window = 0;
+        }
+
+        self.tsdb_window
+    }
+
     async fn _flush(
         &mut self,
         stat_receiver: &mut mpsc::UnboundedReceiver<AppStat>,
@@ -357,9 +594,26 @@ impl StatBuffer {
 
         // flush the buffers
         // TODO: include frontend counts here
-        let (tsdb_count, tsdb_frontend_requests) = self.save_tsdb_stats().await;
+        let (tsdb_written_tx, tsdb_written_rx) = oneshot::channel();
+        let (tsdb_count, tsdb_frontend_requests) =
+            self.save_tsdb_stats(Some(tsdb_written_tx)).await;
         let (relational_count, relational_frontend_requests) = self.save_relational_stats().await;
 
+        // a flush isn't done until influx has the points
+        if tsdb_count > 0 {
+            match timeout(TSDB_WRITE_TIMEOUT, tsdb_written_rx).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => error!("tsdb writer exited before the flushed stats were written"),
+                Err(_) => {
+                    error!("timed out waiting for the flushed stats to be written to the tsdb")
+                }
+            }
+        }
+
+        // everything was just saved
+        self.flush_relational_early = false;
+        self.flush_tsdb_early = false;
+
         // notify
         let flushed_stats = FlushedStats {
             timeseries: tsdb_count,
@@ -405,24 +659,14 @@ impl StatBuffer {
         (count, frontend_requests)
     }
 
+    /// if `written` is set, the points are queued even if the writer is behind and `written` is notified once they are in influx
     // TODO: bucket should be an enum so that we don't risk typos
-    async fn save_tsdb_stats(&mut self) -> (usize, u64) {
+    async fn save_tsdb_stats(&mut self, written: Option<oneshot::Sender<()>>) -> (usize, u64) {
         let mut count = 0;
         let mut frontend_requests = 0;
 
-        if let Some(influxdb_client) = self.influxdb_client.as_ref() {
-            // every time we save, we increment the tsdb_window. this is used to ensure that stats don't overwrite others because the keys match
-            // this has to be done carefully or cardinality becomes a problem!
-            // https://docs.influxdata.com/influxdb/v2.0/write-data/best-practices/duplicate-points/
-            self.tsdb_window += 1;
-            if self.tsdb_window > self.num_tsdb_windows {
-                self.tsdb_window = 0;
-            }
-
-            let influxdb_bucket = self
-                .influxdb_bucket
-                .as_ref()
-                .expect("if client is set, bucket must be set");
+        if let Some(tsdb_sender) = self.tsdb_sender.clone() {
+            let uniq = self.advance_tsdb_window();
 
             // TODO: use stream::iter properly to avoid allocating this Vec
             let mut points = vec![];
@@ -437,7 +681,7 @@ impl StatBuffer {
                         self.chain_id,
                         key,
                         &self.instance,
-                        self.tsdb_window,
+                        uniq,
                     )
                     .await
                 {
@@ -460,7 +704,7 @@ impl StatBuffer {
                         self.chain_id,
                         key,
                         &self.instance,
-                        self.tsdb_window,
+                        uniq,
                     )
                     .await
                 {
@@ -477,34 +721,344 @@ impl StatBuffer {
             count = points.len();
 
             if count > 0 {
-                // TODO: put max_batch_size in config?
-                // TODO: i think the real limit is the byte size of the http request. so, a simple line count won't work very well
-                let max_batch_size = 1000;
+                // the write happens in the background. a slow or down influx must not hold up the relational stats
+                if written.is_some() {
+                    // an explicit flush waits for room instead of dropping points
+                    if let Err(err) = tsdb_sender.send((points, written)).await {
+                        error!(
+                            num_points = err.0 .0.len(),
+                            "tsdb writer exited. dropping tsdb stats!"
+                        );
+                    }
+                } else {
+                    match tsdb_sender.try_send((points, None)) {
+                        Ok(()) => {}
+                        Err(TrySendError::Full((points, _))) => {
+                            error!(
+                                num_points = points.len(),
+                                "influx is too far behind. dropping tsdb stats!"
+                            );
+                        }
+                        Err(TrySendError::Closed((points, _))) => {
+                            error!(
+                                num_points = points.len(),
+                                "tsdb writer exited. dropping tsdb stats!"
+                            );
+                        }
+                    }
+                }
+            }
+        }
 
-                let mut num_left = count;
+        (count, frontend_requests)
+    }
+}
 
-                while num_left > 0 {
-                    let batch_size = num_left.min(max_batch_size);
+/// write the timeseries points to influx in batches until the sender is dropped
+async fn tsdb_write_loop(
+    influxdb_client: influxdb2::Client,
+    influxdb_bucket: String,
+    mut tsdb_receiver: mpsc::Receiver<TsdbWrite>,
+) {
+    while let Some((mut points, written)) = tsdb_receiver.recv().await {
+        while !points.is_empty() {
+            let batch_size = points.len().min(TSDB_MAX_BATCH_SIZE);
 
-                    // TODO: there has to be a better way to chunk this up. chunk on the stream with the stream being an iter?
-                    let p = points.split_off(batch_size);
+            let batch: Vec<_> = points.drain(..batch_size).collect();
 
-                    num_left -= batch_size;
+            if let Err(err) = influxdb_client
+                .write(&influxdb_bucket, stream::iter(batch))
+                .await
+            {
+                // TODO: if this errors, we throw away some of the pending stats! retry any failures! (but not successes. it can have partial successes!)
+                error!(?err, batch_size, "unable to save tsdb stats!");
+            }
+        }
 
-                    if let Err(err) = influxdb_client
-                        .write(influxdb_bucket, stream::iter(points))
-                        .await
-                    {
-                        // TODO: if this errors, we throw away some of the pending stats! retry any failures! (but not successes. it can have partial successes!)
-                        error!(?err, batch_size, "unable to save tsdb stats!");
-                        // TODO: we should probably wait a second to give errors a chance to settle
-                    }
+        if let Some(written) = written {
+            // the flush might have timed out and stopped listening
+            let _ = written.send(());
+        }
+    }
+}
 
-                    points = p;
-                }
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balance::Balance;
+    use crate::frontend::authorization::{Authorization, AuthorizationChecks, AuthorizationType};
+    use moka::future::Cache;
+    use parking_lot::Mutex;
+    use std::num::NonZeroU64;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+    use tokio::sync::RwLock as AsyncRwLock;
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_flush_on_count() {
+        // nothing listens here. the saves will fail, but the buffers are still drained
+        let influxdb_client = influxdb2::Client::new("http://127.0.0.1:1", "org", "token");
+
+        let (_shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+        let (flush_sender, flush_receiver) = mpsc::channel(1);
+
+        // the intervals are far longer than this test runs
+        let spawned = StatBuffer::try_spawn(
+            86400,
+            999_001_999,
+            3600,
+            Some(5),
+            Some("bucket".to_string()),
+            Some(influxdb_client),
+            Cache::builder().build(),
+            Cache::builder().build().into(),
+            shutdown_receiver,
+            3600,
+            flush_sender.clone(),
+            flush_receiver,
+            "test".to_string(),
+        )
+        .unwrap()
+        .unwrap();
+
+        // let the first interval ticks pass
+        sleep(Duration::from_secs(1)).await;
+
+        for _ in 0..12 {
+            let mut request_metadata = RequestMetadata::default();
+
+            // same timestamp so that every stat lands in the same aggregate
+            request_metadata
+                .response_timestamp
+                .store(1_700_000_000, Ordering::Relaxed);
+
+            request_metadata.method = "eth_blockNumber".into();
+
+            assert!(spawned
+                .stat_sender
+                .send(AppStat::RpcQuery(request_metadata))
+                .is_none());
         }
 
-        (count, frontend_requests)
+        // give the buffer time to receive the stats. still far less than the save interval
+        sleep(Duration::from_secs(1)).await;
+
+        let (tx, rx) = oneshot::channel();
+        flush_sender.send(tx).await.unwrap();
+        let flushed = rx.await.unwrap();
+
+        // 10 of the requests were saved early. only the remainder was left for the flush
+        assert_eq!(flushed.timeseries_frontend_requests, 2);
+    }
+
+    #[test_log::test(tokio::test(start_paused = true))]
+    async fn test_late_stats_are_saved_directly() {
+        let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+        let (flush_sender, flush_receiver) = mpsc::channel(1);
+
+        let spawned = StatBuffer::try_spawn(
+            86400,
+            999_001_999,
+            3600,
+            None,
+            None,
+            None,
+            Cache::builder().build(),
+            Cache::builder().build().into(),
+            shutdown_receiver,
+            3600,
+            flush_sender,
+            flush_receiver,
+            "test".to_string(),
+        )
+        .unwrap()
+        .unwrap();
+
+        shutdown_sender.send(()).unwrap();
+
+        // stats that arrive while the buffer waits for stragglers are still accepted
+        sleep(Duration::from_secs(1)).await;
+
+        assert!(spawned
+            .stat_sender
+            .send(AppStat::RpcQuery(RequestMetadata::default()))
+            .is_none());
+
+        spawned.background_handle.await.unwrap().unwrap();
+
+        // once the final flush starts, stats skip the buffer instead of silently going nowhere
+        let late = spawned
+            .stat_sender
+            .send(AppStat::RpcQuery(RequestMetadata::default()))
+            .expect("late stats should be saved directly");
+
+        late.await.unwrap();
+    }
+
+    #[test_log::test(tokio::test)]
+    async fn test_holds_are_released_without_a_db() {
+        let (flush_sender, _flush_receiver) = mpsc::channel(1);
+
+        let mut stat_buffer = StatBuffer {
+            accounting_db_buffer: Default::default(),
+            billing_period_seconds: 86400,
+            chain_id: 999_001_999,
+            db_save_interval_seconds: 3600,
+            flush_frontend_requests: None,
+            flush_relational_early: false,
+            flush_tsdb_early: false,
+            global_timeseries_buffer: Default::default(),
+            instance: "test".to_string(),
+            opt_in_timeseries_buffer: Default::default(),
+            rpc_secret_key_cache: Cache::builder().build(),
+            tsdb_save_interval_seconds: 60,
+            tsdb_sender: None,
+            tsdb_window: 4,
+            tsdb_writer: None,
+            num_tsdb_windows: 4,
+            user_balance_cache: Cache::builder().build().into(),
+
+            _flush_sender: flush_sender,
+        };
+
+        // no db is configured in tests
+        assert!(global_db_conn().await.is_err());
+
+        let latest_balance = Arc::new(AsyncRwLock::new(Balance {
+            admin_deposits: 100.into(),
+            user_id: 1,
+            ..Default::default()
+        }));
+
+        let checks = AuthorizationChecks {
+            latest_balance: latest_balance.clone(),
+            paid_credits_used: true,
+            rpc_secret_key_id: NonZeroU64::new(1),
+            user_id: 1,
+            ..Default::default()
+        };
+
+        let authorization = Arc::new(
+            Authorization::try_new(
+                checks,
+                &"127.0.0.1".parse().unwrap(),
+                None,
+                None,
+                None,
+                AuthorizationType::Frontend,
+            )
+            .unwrap(),
+        );
+
+        assert!(latest_balance.write().await.try_reserve(10.into()));
+
+        let request_metadata = RequestMetadata {
+            authorization: Some(authorization.clone()),
+            reserved_paid_credits: Mutex::new(10.into()),
+            ..Default::default()
+        };
+
+        stat_buffer
+            ._buffer_request_metadata(request_metadata)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            latest_balance.read().await.reserved_paid_credits,
+            Decimal::from(0)
+        );
+
+        // a stat that can't reach the buffer still gives back its hold
+        assert!(latest_balance.write().await.try_reserve(10.into()));
+
+        let (sender, stat_receiver) = mpsc::unbounded_channel();
+        drop(stat_receiver);
+
+        let stat_sender = StatSender {
+            sender,
+            late_stats: Arc::new(LateStatSaver {
+                billing_period_seconds: 86400,
+                chain_id: 999_001_999,
+                rpc_secret_key_cache: Cache::builder().build(),
+                user_balance_cache: Cache::builder().build().into(),
+            }),
+        };
+
+        let request_metadata = RequestMetadata {
+            authorization: Some(authorization),
+            reserved_paid_credits: Mutex::new(10.into()),
+            ..Default::default()
+        };
+
+        stat_sender
+            .send(AppStat::RpcQuery(request_metadata))
+            .expect("the buffer is closed")
+            .await
+            .unwrap();
+
+        assert_eq!(
+            latest_balance.read().await.reserved_paid_credits,
+            Decimal::from(0)
+        );
+    }
+
+    #[test]
+    fn test_tsdb_uniq_is_bounded() {
+        let (flush_sender, _flush_receiver) = mpsc::channel(1);
+
+        let mut stat_buffer = StatBuffer {
+            accounting_db_buffer: Default::default(),
+            billing_period_seconds: 86400,
+            chain_id: 999_001_999,
+            db_save_interval_seconds: 3600,
+            flush_frontend_requests: Some(5),
+            flush_relational_early: false,
+            flush_tsdb_early: false,
+            global_timeseries_buffer: Default::default(),
+            instance: "test".to_string(),
+            opt_in_timeseries_buffer: Default::default(),
+            rpc_secret_key_cache: Cache::builder().build(),
+            tsdb_save_interval_seconds: 60,
+            tsdb_sender: None,
+            tsdb_window: 4,
+            tsdb_writer: None,
+            num_tsdb_windows: 4,
+            user_balance_cache: Cache::builder().build().into(),
+
+            _flush_sender: flush_sender,
+        };
+
+        // the first save wraps the window back to 0
+        assert_eq!(stat_buffer.advance_tsdb_window(), 0);
+
+        // a threshold that is far too low saves early over and over. uniq still only takes num_tsdb_windows + 1 values
+        let mut previous = 0;
+        for _ in 0..1000 {
+            let uniq = stat_buffer.advance_tsdb_window();
+
+            assert!((0..=stat_buffer.num_tsdb_windows).contains(&uniq));
+            assert_ne!(uniq, previous);
+
+            previous = uniq;
+        }
+    }
+
+    #[test]
+    fn test_response_millis_percentiles() {
+        let mut stats = BufferedRpcQueryStats::default();
+
+        for x in 1..=100 {
+            stats.record_response_millis(x);
+        }
+
+        // slower than the histogram's max
+        stats.record_response_millis(u64::MAX);
+
+        let histogram = stats.response_millis_histogram.unwrap();
+
+        assert_eq!(histogram.len(), 101);
+        assert!((50..=51).contains(&histogram.value_at_quantile(0.50)));
+        assert!((90..=92).contains(&histogram.value_at_quantile(0.90)));
+        assert!(histogram.max() >= crate::stats::RESPONSE_MILLIS_HISTOGRAM_MAX);
     }
 }
@@ -85,6 +85,7 @@ impl MigrateStatsToV2SubCommand {
             BILLING_PERIOD_SECONDS,
             top_config.app.chain_id,
             60,
+            None,
             top_config.app.influxdb_bucket.clone(),
             influxdb_client.clone(),
             rpc_secret_key_cache,
@@ -193,6 +194,8 @@ impl MigrateStatsToV2SubCommand {
                         error_response: x.error_response.into(),
                         // debug data is in kafka, not mysql or influx
                         kafka_debug_logger: None,
+                        // these requests were already served
+                        max_backend_attempts: None,
                         method: x
                             .method
                             .clone()
@@ -200,6 +203,7 @@ impl MigrateStatsToV2SubCommand {
                             .into(),
                         // This is not relevant in the new version
                         no_servers: 0.into(),
+                        provenance: Default::default(),
                         // Get the mean of all the request bytes
                         request_bytes: int_request_bytes as usize,
                         response_bytes: int_response_bytes.into(),
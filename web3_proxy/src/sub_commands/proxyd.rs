@@ -2,11 +2,11 @@ use crate::app::{flatten_handle, flatten_handles, Web3ProxyApp};
 use crate::compute_units::default_usd_per_cu;
 use crate::config::TopConfig;
 use crate::globals::global_db_conn;
+use crate::shutdown::ShutdownCoordinator;
 use crate::stats::FlushedStats;
 use crate::{frontend, prometheus};
 use argh::FromArgs;
 use futures::StreamExt;
-use num::Zero;
 use std::path::PathBuf;
 use std::sync::atomic::AtomicU16;
 use std::sync::Arc;
@@ -196,13 +196,17 @@ impl ProxydSubCommand {
         let mut exited_with_err = false;
         let mut frontend_exited = false;
         tokio::select! {
-            x = flatten_handles(spawned_app.app_handles) => {
+            x = spawned_app.app_handles.next() => {
                 match x {
-                    Ok(_) => info!("app_handle exited"),
-                    Err(e) => {
+                    Some(Ok(Ok(_))) | None => info!("app_handle exited"),
+                    Some(Ok(Err(e))) => {
                         error!("app_handle exited: {:#?}", e);
                         exited_with_err = true;
                     }
+                    Some(Err(e)) => {
+                        error!("app_handle panicked: {:#?}", e);
+                        exited_with_err = true;
+                    }
                 }
             }
             x = frontend_handle => {
@@ -262,49 +266,52 @@ impl ProxydSubCommand {
             }
         };
 
-        // TODO: This is also not there on the main branch
-        // if a future above completed, make sure the frontend knows to start turning off
-        if !frontend_exited {
-            if let Err(err) = frontend_shutdown_sender.send(()) {
-                // TODO: this is actually expected if the frontend is already shut down
-                warn!(?err, "shutdown sender");
-            };
-        }
-
-        // TODO: Also not there on main branch
-        // TODO: wait until the frontend completes
-        if let Err(err) = frontend_shutdown_complete_receiver.recv().await {
-            warn!(?err, "shutdown completition");
-        } else {
-            info!("frontend exited gracefully");
-        }
-
-        // now that the frontend is complete, tell all the other futures to finish
-        if let Err(err) = app_shutdown_sender.send(()) {
-            warn!(?err, "backend sender");
-        };
+        let app_handles = spawned_app.app_handles;
+        let background_handles = spawned_app.background_handles;
+        let consensus_shutdown = spawned_app.consensus_shutdown;
 
         info!(
             "waiting on {} important background tasks",
-            spawned_app.background_handles.len()
+            background_handles.len()
         );
-        let mut background_errors = 0;
-        while let Some(x) = spawned_app.background_handles.next().await {
-            match x {
-                Err(e) => {
-                    error!("{:?}", e);
-                    background_errors += 1;
-                }
-                Ok(Err(e)) => {
-                    error!("{:?}", e);
-                    background_errors += 1;
-                }
-                Ok(Ok(_)) => {
-                    // TODO: how can we know which handle exited?
-                    trace!("a background handle exited");
-                    continue;
+
+        let phase_max_wait = Duration::from_millis(top_config.app.shutdown_max_wait_ms);
+
+        let shutdown = ShutdownCoordinator::new()
+            .phase("frontend intake", phase_max_wait, async move {
+                // if a future above completed, make sure the frontend knows to start turning off
+                if !frontend_exited {
+                    // an error here means the frontend is already shut down
+                    let _ = frontend_shutdown_sender.send(());
                 }
-            }
+
+                Ok(())
+            })
+            .phase("in-flight requests", phase_max_wait, async move {
+                frontend_shutdown_complete_receiver
+                    .recv()
+                    .await
+                    .map_err(|err| anyhow::anyhow!("frontend shutdown completion: {:?}", err))?;
+
+                Ok(())
+            })
+            .phase("consensus", phase_max_wait, async move {
+                // an error here means every rpc group already stopped
+                let _ = consensus_shutdown.send(());
+
+                flatten_handles(app_handles).await
+            })
+            .phase("stats", phase_max_wait, async move {
+                // now that requests and consensus are done, tell the stat buffer to save everything
+                // an error here means the background tasks already stopped
+                let _ = app_shutdown_sender.send(());
+
+                flatten_handles(background_handles).await
+            });
+
+        if let Err(err) = shutdown.run().await {
+            error!(?err, "shutdown finished with errors");
+            exited_with_err = true;
         }
 
         // TODO: make sure this happens even if we exit with an error
@@ -319,7 +326,7 @@ impl ProxydSubCommand {
             db_conn.close().await?;
         }
 
-        if background_errors.is_zero() && !exited_with_err {
+        if !exited_with_err {
             info!("finished");
             Ok(())
         } else {
@@ -51,6 +51,71 @@ impl TestApp {
         db: Option<&TestMysql>,
         influx: Option<&TestInflux>,
         influx_id: Option<String>,
+    ) -> Self {
+        Self::spawn_with_app_config(anvil, db, influx, influx_id, json!({})).await
+    }
+
+    /// like `spawn`, but the keys in `app_config_overrides` replace the test's default app config
+    #[allow(unused)]
+    pub async fn spawn_with_app_config(
+        anvil: &TestAnvil,
+        db: Option<&TestMysql>,
+        influx: Option<&TestInflux>,
+        influx_id: Option<String>,
+        app_config_overrides: serde_json::Value,
+    ) -> Self {
+        Self::spawn_with_configs(
+            anvil,
+            db,
+            influx,
+            influx_id,
+            app_config_overrides,
+            json!({}),
+        )
+        .await
+    }
+
+    /// like `spawn_with_app_config`, but the keys in `rpc_config_overrides` also replace the anvil rpc's config
+    #[allow(unused)]
+    pub async fn spawn_with_configs(
+        anvil: &TestAnvil,
+        db: Option<&TestMysql>,
+        influx: Option<&TestInflux>,
+        influx_id: Option<String>,
+        app_config_overrides: serde_json::Value,
+        rpc_config_overrides: serde_json::Value,
+    ) -> Self {
+        let mut rpc_config = json!({
+            "http_url": anvil.instance.endpoint(),
+            "ws_url": anvil.instance.ws_endpoint(),
+        });
+
+        if let serde_json::Value::Object(overrides) = rpc_config_overrides {
+            for (k, v) in overrides {
+                rpc_config[k] = v;
+            }
+        }
+
+        Self::spawn_with_rpcs(
+            anvil,
+            db,
+            influx,
+            influx_id,
+            app_config_overrides,
+            HashMap::from([("anvil".to_string(), rpc_config)]),
+        )
+        .await
+    }
+
+    /// like `spawn_with_app_config`, but with these balanced rpcs instead of only anvil
+    #[allow(unused)]
+    pub async fn spawn_with_rpcs(
+        anvil: &TestAnvil,
+        db: Option<&TestMysql>,
+        influx: Option<&TestInflux>,
+        influx_id: Option<String>,
+        app_config_overrides: serde_json::Value,
+        rpc_configs: HashMap<String, serde_json::Value>,
     ) -> Self {
         let chain_id = anvil.instance.chain_id();
         let num_workers = 4;
@@ -75,7 +140,7 @@ impl TestApp {
         // make a test TopConfig
         // TODO: test influx
         // TODO: test redis
-        let app_config: AppConfig = serde_json::from_value(json!({
+        let mut app_config = json!({
             "chain_id": chain_id,
             "db_url": db_url,
             "influxdb_host": influx_host,
@@ -92,24 +157,30 @@ impl TestApp {
             "min_synced_rpcs": 1,
             "public_requests_per_period": Some(1_000_000),
             "response_cache_max_bytes": 10_u64.pow(7),
-        }))
-        .unwrap();
+        });
+
+        if let serde_json::Value::Object(overrides) = app_config_overrides {
+            for (k, v) in overrides {
+                app_config[k] = v;
+            }
+        }
+
+        let app_config: AppConfig = serde_json::from_value(app_config).unwrap();
 
         info!("App Config is: {:?}", app_config);
 
+        let balanced_rpcs = rpc_configs
+            .into_iter()
+            .map(|(k, v)| (k, serde_json::from_value::<Web3RpcConfig>(v).unwrap()))
+            .collect();
+
         let top_config = TopConfig {
             app: app_config,
-            balanced_rpcs: HashMap::from([(
-                "anvil".to_string(),
-                Web3RpcConfig {
-                    http_url: Some(anvil.instance.endpoint()),
-                    ws_url: Some(anvil.instance.ws_endpoint()),
-                    ..Default::default()
-                },
-            )]),
+            balanced_rpcs,
             // influxdb_client: influx.map(|x| x.client),
             private_rpcs: None,
             bundler_4337_rpcs: None,
+            rpc_groups: Default::default(),
             extra: Default::default(),
         };
 
@@ -7,6 +7,7 @@ pub mod create_provider_with_rpc_key;
 pub mod create_user;
 pub mod influx;
 pub mod mysql;
+pub mod redis;
 pub mod referral;
 pub mod rpc_key;
 pub mod stats_accounting;
@@ -16,3 +17,4 @@ pub use self::anvil::TestAnvil;
 pub use self::app::TestApp;
 pub use self::influx::TestInflux;
 pub use self::mysql::TestMysql;
+pub use self::redis::TestRedis;
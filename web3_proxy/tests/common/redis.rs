@@ -0,0 +1,136 @@
+use ethers::prelude::rand::{self, distributions::Alphanumeric, Rng};
+use redis_rate_limiter::{DeadpoolRuntime, RedisConfig, RedisPool};
+use std::process::Command as SyncCommand;
+use std::time::Duration;
+use tokio::{
+    net::TcpStream,
+    process::Command as AsyncCommand,
+    time::{sleep, Instant},
+};
+use tracing::{info, trace};
+
+/// on drop, the redis docker container will be shut down
+pub struct TestRedis {
+    pub url: String,
+    pub container_name: String,
+}
+
+impl TestRedis {
+    #[allow(unused)]
+    pub async fn spawn() -> Self {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let container_name = format!("web3-proxy-test-redis-{}", random);
+
+        info!(%container_name);
+
+        let _ = AsyncCommand::new("docker")
+            .args([
+                "run",
+                "--name",
+                &container_name,
+                "--rm",
+                "-d",
+                "-p",
+                "0:6379",
+                "redis:6.0-alpine",
+            ])
+            .output()
+            .await
+            .expect("failed to start redis");
+
+        // give redis a second to start
+        sleep(Duration::from_secs(1)).await;
+
+        let docker_inspect_output = AsyncCommand::new("docker")
+            .args(["inspect", &container_name])
+            .output()
+            .await
+            .unwrap();
+
+        let docker_inspect_json = String::from_utf8(docker_inspect_output.stdout).unwrap();
+
+        trace!(%docker_inspect_json);
+
+        let docker_inspect_json: serde_json::Value =
+            serde_json::from_str(&docker_inspect_json).unwrap();
+
+        let redis_ports = docker_inspect_json
+            .get(0)
+            .unwrap()
+            .get("NetworkSettings")
+            .unwrap()
+            .get("Ports")
+            .unwrap()
+            .get("6379/tcp")
+            .unwrap()
+            .get(0)
+            .unwrap();
+
+        let redis_port: u64 = redis_ports
+            .get("HostPort")
+            .expect("unable to determine redis port")
+            .as_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+
+        let redis_ip = redis_ports
+            .get("HostIp")
+            .and_then(|x| x.as_str())
+            .expect("unable to determine redis ip");
+
+        let url = format!("redis://{}:{}/", redis_ip, redis_port);
+
+        let test_redis = Self {
+            url,
+            container_name,
+        };
+
+        let start = Instant::now();
+        let max_wait = Duration::from_secs(30);
+        loop {
+            if start.elapsed() > max_wait {
+                panic!("redis container took too long to start");
+            }
+
+            if TcpStream::connect(format!("{}:{}", redis_ip, redis_port))
+                .await
+                .is_ok()
+            {
+                break;
+            };
+
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        info!(url=%test_redis.url, elapsed=%start.elapsed().as_secs_f32(), "redis is ready");
+
+        test_redis
+    }
+
+    #[allow(unused)]
+    pub fn pool(&self) -> RedisPool {
+        RedisConfig::from_url(&self.url)
+            .builder()
+            .unwrap()
+            .max_size(4)
+            .runtime(DeadpoolRuntime::Tokio1)
+            .build()
+            .unwrap()
+    }
+}
+
+impl Drop for TestRedis {
+    fn drop(&mut self) {
+        info!(%self.container_name, "killing redis");
+
+        let _ = SyncCommand::new("docker")
+            .args(["kill", "-s", "9", &self.container_name])
+            .output();
+    }
+}
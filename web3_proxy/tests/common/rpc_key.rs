@@ -22,6 +22,8 @@ pub struct RpcKey {
     pub allowed_origins: Option<serde_json::Value>,
     pub allowed_referers: Option<serde_json::Value>,
     pub allowed_user_agents: Option<serde_json::Value>,
+    pub allowed_methods: Option<serde_json::Value>,
+    pub denied_methods: Option<serde_json::Value>,
     pub description: Option<serde_json::Value>,
     pub id: u64,
     pub log_revert_chance: f64,
@@ -10,8 +10,11 @@ use crate::common::create_user::create_user;
 use crate::common::mysql::TestMysql;
 use crate::common::user_balance::user_get_balance;
 use crate::common::TestApp;
+use http::StatusCode;
 use migration::sea_orm::prelude::Decimal;
+use serde_json::json;
 use tracing::info;
+use web3_proxy::frontend::admin::AdminMaintenancePost;
 
 // #[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
 #[ignore = "under construction"]
@@ -65,6 +68,87 @@ async fn test_admin_grant_credits() {
     x.wait_for_stop();
 }
 
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_admin_maintenance() {
+    let a: TestAnvil = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let admin_wallet = a.wallet(1);
+
+    let admin_login_response = create_user_as_admin(&x, &db, &r, &admin_wallet).await;
+
+    let maintenance_url = format!("{}admin/maintenance", x.proxy_provider.url());
+    let status_url = format!("{}status", x.proxy_provider.url());
+
+    let block_number_request = json!({
+        "jsonrpc": "2.0",
+        "method": "eth_blockNumber",
+        "params": [],
+        "id": 1,
+    });
+
+    let maintenance_response = r
+        .post(&maintenance_url)
+        .json(&AdminMaintenancePost {
+            enabled: true,
+            message: Some("upgrading. back soon".to_string()),
+        })
+        .bearer_auth(admin_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(maintenance_response.status(), StatusCode::OK);
+
+    // rpc requests get the maintenance error
+    let rpc_response = r
+        .post(x.proxy_provider.url().as_str())
+        .json(&block_number_request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(rpc_response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    let rpc_response: serde_json::Value = rpc_response.json().await.unwrap();
+    info!(?rpc_response);
+    assert_eq!(rpc_response["error"]["message"], "upgrading. back soon");
+
+    // status still works
+    let status_response = r.get(&status_url).send().await.unwrap();
+    assert_eq!(status_response.status(), StatusCode::OK);
+
+    let maintenance_response = r
+        .post(&maintenance_url)
+        .json(&AdminMaintenancePost {
+            enabled: false,
+            message: None,
+        })
+        .bearer_auth(admin_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(maintenance_response.status(), StatusCode::OK);
+
+    // rpc requests work again
+    let rpc_response = r
+        .post(x.proxy_provider.url().as_str())
+        .json(&block_number_request)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(rpc_response.status(), StatusCode::OK);
+
+    x.wait_for_stop();
+}
+
 // #[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
 #[ignore = "under construction"]
 #[test_log::test(tokio::test)]
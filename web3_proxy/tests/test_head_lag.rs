@@ -0,0 +1,61 @@
+mod common;
+
+use crate::common::TestInflux;
+use chrono::Utc;
+use influxdb2::models::DataPoint;
+use web3_proxy::stats::influxdb_queries::query_rpc_head_lag;
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_head_lag_history() {
+    let i = TestInflux::spawn().await;
+
+    let chain_id = 999_001_999;
+    let now = Utc::now().timestamp();
+
+    // two windows from two instances, as save_rpc_head_lag_loop would write them. one is for another chain
+    let points: Vec<_> = [
+        ("a", "fast", chain_id, 3, 0, now - 120),
+        ("a", "slow", chain_id, 1, 2, now - 120),
+        ("a", "fast", chain_id, 2, 1, now - 60),
+        ("b", "slow", chain_id, 0, 4, now - 60),
+        ("a", "fast", 1, 100, 100, now - 60),
+    ]
+    .into_iter()
+    .map(
+        |(instance, rpc, chain_id, on_head, behind_head, timestamp)| {
+            DataPoint::builder("rpc_head_lag")
+                .tag("chain_id", chain_id.to_string())
+                .tag("instance", instance)
+                .tag("rpc", rpc)
+                .field("on_head", on_head as i64)
+                .field("behind_head", behind_head as i64)
+                .field("clock_skew_s", 0i64)
+                .timestamp(timestamp * 1_000_000_000)
+                .build()
+                .unwrap()
+        },
+    )
+    .collect();
+
+    i.client
+        .write(&i.bucket, futures::stream::iter(points))
+        .await
+        .unwrap();
+
+    let counts = query_rpc_head_lag(&i.client, &i.bucket, chain_id, now - 3600, now + 1)
+        .await
+        .unwrap();
+
+    assert_eq!(counts.len(), 2);
+    assert_eq!(counts["fast"], (5, 1));
+    assert_eq!(counts["slow"], (1, 6));
+
+    // only the later window
+    let counts = query_rpc_head_lag(&i.client, &i.bucket, chain_id, now - 90, now + 1)
+        .await
+        .unwrap();
+
+    assert_eq!(counts["fast"], (2, 1));
+    assert_eq!(counts["slow"], (0, 4));
+}
@@ -1,14 +1,36 @@
 mod common;
 
-use crate::common::{anvil::TestAnvil, mysql::TestMysql, TestApp};
-use ethers::prelude::U256;
+use crate::common::{anvil::TestAnvil, mysql::TestMysql, TestApp, TestRedis};
+use axum::body::Bytes;
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::{Json, Router};
+use ethers::prelude::{Address, TransactionReceipt, TransactionRequest, H256, U256, U64};
+use ethers::providers::{JsonRpcError, Middleware, Provider, ProviderError, RpcError, Ws};
+use ethers::types::transaction::eip2718::TypedTransaction;
+use futures::StreamExt;
+use hashbrown::HashMap;
+use http::header::CONTENT_TYPE;
 use http::StatusCode;
+use parking_lot::Mutex;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::{
     task::yield_now,
-    time::{sleep, Instant},
+    time::{sleep, timeout, Instant},
 };
-use web3_proxy::rpcs::blockchain::ArcBlock;
+use web3_proxy::app::APP_USER_AGENT;
+use web3_proxy::config::Web3RpcConfig;
+use web3_proxy::dead_letters::DeadLetters;
+use web3_proxy::errors::Web3ProxyError;
+use web3_proxy::frontend::authorization::{Authorization, RequestMetadata};
+use web3_proxy::rpcs::blockchain::{ArcBlock, BlocksByHashCache};
+use web3_proxy::rpcs::one::Web3Rpc;
+use web3_proxy::rpcs::request::OpenRequestResult;
 
 #[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
 #[test_log::test(tokio::test)]
@@ -107,3 +129,1205 @@ async fn it_starts_and_stops() {
     // most tests won't need to wait, but we should wait here to be sure all the shutdown logic works properly
     x.wait_for_stop();
 }
+
+#[test_log::test(tokio::test)]
+async fn it_answers_client_version() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let anvil_result: String = a.provider.request("web3_clientVersion", ()).await.unwrap();
+    let proxy_result: String = x
+        .proxy_provider
+        .request("web3_clientVersion", ())
+        .await
+        .unwrap();
+
+    // the proxy answers this itself instead of leaking the backend's client
+    assert_eq!(proxy_result, APP_USER_AGENT);
+    assert_ne!(anvil_result, proxy_result);
+}
+
+#[test_log::test(tokio::test)]
+async fn it_forwards_unknown_methods() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    // evm_snapshot is anvil-specific. permissive mode passes it through
+    let _: U256 = x.proxy_provider.request("evm_snapshot", ()).await.unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn it_rejects_unknown_methods() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn_with_app_config(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "reject_unknown_methods": true,
+        }),
+    )
+    .await;
+
+    match x
+        .proxy_provider
+        .request::<_, U256>("evm_snapshot", ())
+        .await
+    {
+        Err(ProviderError::JsonRpcClientError(err)) => {
+            let err: Option<&JsonRpcError> = err.as_error_response();
+
+            assert_eq!(err.unwrap().code, -32601);
+        }
+        x => panic!("expected method not found, got {:?}", x),
+    }
+
+    // known methods still work
+    let proxy_result: Option<ArcBlock> = x
+        .proxy_provider
+        .request("eth_getBlockByNumber", ("latest", false))
+        .await
+        .unwrap();
+
+    assert!(proxy_result.is_some());
+}
+
+#[test_log::test(tokio::test)]
+async fn it_rejects_archive_requests_without_archive_rpcs() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // anvil keeps every block, but a small block_data_limit makes the proxy treat it like a full node
+    let x = TestApp::spawn_with_configs(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "archive_depth": 4,
+            "reject_archive_requests_without_archive_rpcs": true,
+        }),
+        json!({
+            "block_data_limit": 4,
+        }),
+    )
+    .await;
+
+    let _: serde_json::Value = a.provider.request("anvil_mine", ("0x14",)).await.unwrap();
+
+    let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let start = Instant::now();
+    loop {
+        let proxy_block_num: U64 = x
+            .proxy_provider
+            .request("eth_blockNumber", ())
+            .await
+            .unwrap();
+
+        if proxy_block_num == head_block_num {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("took too long to sync!");
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    match x
+        .proxy_provider
+        .request::<_, U256>("eth_getBalance", (Address::zero(), U64::one()))
+        .await
+    {
+        Err(ProviderError::JsonRpcClientError(err)) => {
+            let err: &JsonRpcError = err.as_error_response().unwrap();
+
+            assert_eq!(err.code, -32043);
+            assert_eq!(err.message, "archive data not available");
+        }
+        x => panic!("expected archive data not available, got {:?}", x),
+    }
+
+    // recent blocks still work
+    let _: U256 = x
+        .proxy_provider
+        .request("eth_getBalance", (Address::zero(), head_block_num))
+        .await
+        .unwrap();
+}
+
+#[test_log::test(tokio::test)]
+async fn it_warms_the_cache_before_it_is_healthy() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn_with_app_config(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "warmup_requests": [
+                {"method": "eth_getBlockByNumber", "params": ["0x0", false]},
+                {"method": "eth_getBalance", "params": ["0x0000000000000000000000000000000000000000", "0x0"]},
+                // failures are logged, but they do not keep the proxy unhealthy
+                {"method": "llama_notARealMethod"},
+            ],
+        }),
+    )
+    .await;
+
+    let proxy_url = x.proxy_provider.url();
+
+    let start = Instant::now();
+    loop {
+        let health_response = reqwest::get(format!("{}health", proxy_url)).await.unwrap();
+
+        if health_response.status() == StatusCode::OK {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("never became healthy");
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let status: serde_json::Value = reqwest::get(format!("{}status", proxy_url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(status["warmed_up"], json!(true));
+
+    // the warmup requests were cache misses. so they are cached now
+    let misses = status["response_cache"]["misses"].as_u64().unwrap();
+    assert!(misses >= 2, "{:#}", status);
+
+    let _: Option<ArcBlock> = x
+        .proxy_provider
+        .request("eth_getBlockByNumber", ("0x0", false))
+        .await
+        .unwrap();
+
+    // the status page is cached for a second
+    sleep(Duration::from_millis(1100)).await;
+
+    let status: serde_json::Value = reqwest::get(format!("{}status", proxy_url))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert!(
+        status["response_cache"]["hits"].as_u64().unwrap() >= 1,
+        "{:#}",
+        status
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn it_caches_finalized_block_receipts() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    // mine enough blocks for the first one to be finalized
+    let _: serde_json::Value = a.provider.request("anvil_mine", ("0x14",)).await.unwrap();
+
+    let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let start = Instant::now();
+    loop {
+        let proxy_block_num: U64 = x
+            .proxy_provider
+            .request("eth_blockNumber", ())
+            .await
+            .unwrap();
+
+        if proxy_block_num == head_block_num {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("took too long to sync!");
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let client = reqwest::Client::new();
+
+    // the names of the backend rpcs that served the request. empty for a cache hit
+    let backend_rpcs = |block_num: U64| {
+        let client = client.clone();
+        let proxy_url = x.proxy_provider.url().clone();
+
+        async move {
+            let response = client
+                .post(proxy_url)
+                .json(&json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "eth_getBlockReceipts",
+                    "params": [block_num],
+                }))
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            response.headers()["X-W3P-BACKEND-RPCS"]
+                .to_str()
+                .unwrap()
+                .to_string()
+        }
+    };
+
+    let finalized_block_num = U64::one();
+
+    assert_ne!(backend_rpcs(finalized_block_num).await, "");
+    assert_eq!(backend_rpcs(finalized_block_num).await, "");
+
+    // the head block might still reorg. it goes to the backend every time
+    assert_ne!(backend_rpcs(head_block_num).await, "");
+    assert_ne!(backend_rpcs(head_block_num).await, "");
+}
+
+/// sends everything to anvil. while `lying` is set, eth_getBlockTransactionCountByHash gets a wrong answer
+async fn lying_rpc(
+    State((anvil_url, lying)): State<(String, Arc<AtomicBool>)>,
+    body: Bytes,
+) -> Response {
+    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    if lying.load(Ordering::Relaxed) && request["method"] == "eth_getBlockTransactionCountByHash" {
+        return Json(json!({
+            "jsonrpc": "2.0",
+            "id": request["id"],
+            "result": "0x539",
+        }))
+        .into_response();
+    }
+
+    let response = reqwest::Client::new()
+        .post(anvil_url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+
+    ([(CONTENT_TYPE, "application/json")], response).into_response()
+}
+
+#[test_log::test(tokio::test)]
+async fn it_caches_immutable_results_after_agreement() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let lying = Arc::new(AtomicBool::new(true));
+
+    let mock_server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+        Router::new()
+            .route("/", post(lying_rpc))
+            .with_state((a.instance.endpoint(), lying.clone()))
+            .into_make_service(),
+    );
+
+    let mock_url = format!("http://{}", mock_server.local_addr());
+
+    tokio::spawn(mock_server);
+
+    let x = TestApp::spawn_with_rpcs(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "cache_min_agreement": 2,
+        }),
+        HashMap::from([
+            (
+                "anvil".to_string(),
+                json!({
+                    "http_url": a.instance.endpoint(),
+                    "ws_url": a.instance.ws_endpoint(),
+                }),
+            ),
+            (
+                "liar".to_string(),
+                json!({
+                    "http_url": mock_url,
+                }),
+            ),
+        ]),
+    )
+    .await;
+
+    let proxy_url = x.proxy_provider.url();
+
+    // both rpcs need to be synced for them to be asked together
+    let start = Instant::now();
+    loop {
+        let status: serde_json::Value = reqwest::get(format!("{}status", proxy_url))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+
+        if status["balanced_rpcs"]["synced_connections"]["num_synced"] == json!(2) {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("took too long to sync! {:#}", status);
+        }
+
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let head_block: ArcBlock = a
+        .provider
+        .request("eth_getBlockByNumber", ("latest", false))
+        .await
+        .unwrap();
+    let head_block_hash = head_block.hash.unwrap();
+
+    let expected: U64 = a
+        .provider
+        .request("eth_getBlockTransactionCountByHash", (head_block_hash,))
+        .await
+        .unwrap();
+
+    assert_ne!(expected, U64::from(0x539));
+
+    let client = reqwest::Client::new();
+
+    // the result and the names of the backend rpcs that served the request. empty for a cache hit
+    let tx_count = || {
+        let client = client.clone();
+        let proxy_url = proxy_url.clone();
+
+        async move {
+            let response = client
+                .post(proxy_url)
+                .json(&json!({
+                    "jsonrpc": "2.0",
+                    "id": 1,
+                    "method": "eth_getBlockTransactionCountByHash",
+                    "params": [head_block_hash],
+                }))
+                .send()
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let backend_rpcs = response.headers()["X-W3P-BACKEND-RPCS"]
+                .to_str()
+                .unwrap()
+                .to_string();
+
+            let body: serde_json::Value = response.json().await.unwrap();
+
+            (body["result"].clone(), backend_rpcs)
+        }
+    };
+
+    // the rpcs disagree. whatever is served must not be cached
+    let (_, backend_rpcs) = tx_count().await;
+    assert_ne!(backend_rpcs, "");
+
+    let (_, backend_rpcs) = tx_count().await;
+    assert_ne!(backend_rpcs, "");
+
+    // now a second rpc agrees with anvil
+    lying.store(false, Ordering::Relaxed);
+
+    let (result, backend_rpcs) = tx_count().await;
+    assert_eq!(result, json!(expected));
+    assert_ne!(backend_rpcs, "");
+
+    let (result, backend_rpcs) = tx_count().await;
+    assert_eq!(result, json!(expected));
+    assert_eq!(backend_rpcs, "");
+}
+
+/// sends everything to anvil, but hides receipts for a while after each transaction like an rpc that is behind
+async fn lagging_rpc(
+    State((anvil_url, last_write)): State<(String, Arc<Mutex<Option<Instant>>>)>,
+    body: Bytes,
+) -> Response {
+    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    if request["method"] == "eth_sendRawTransaction" {
+        *last_write.lock() = Some(Instant::now());
+    } else if request["method"] == "eth_getTransactionReceipt" {
+        let last_write = *last_write.lock();
+
+        if last_write.map(|x| x.elapsed() < Duration::from_millis(500)) == Some(true) {
+            return Json(json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": null,
+            }))
+            .into_response();
+        }
+    }
+
+    let response = reqwest::Client::new()
+        .post(anvil_url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+
+    ([(CONTENT_TYPE, "application/json")], response).into_response()
+}
+
+#[test_log::test(tokio::test)]
+async fn it_waits_for_reads_after_writes() {
+    let chain_id = 31337;
+
+    let a = TestAnvil::spawn(chain_id).await;
+
+    let mock_server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+        Router::new()
+            .route("/", post(lagging_rpc))
+            .with_state((a.instance.endpoint(), Arc::new(Mutex::new(None))))
+            .into_make_service(),
+    );
+
+    let mock_url = format!("http://{}", mock_server.local_addr());
+
+    tokio::spawn(mock_server);
+
+    let x = TestApp::spawn_with_configs(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "read_after_write_wait_ms": {"eth_getTransactionReceipt": 2_000},
+        }),
+        json!({
+            "http_url": mock_url,
+            "ws_url": null,
+        }),
+    )
+    .await;
+
+    let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let start = Instant::now();
+    loop {
+        let proxy_block_num: Result<U64, _> = x.proxy_provider.request("eth_blockNumber", ()).await;
+
+        if proxy_block_num.ok() == Some(head_block_num) {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("took too long to sync!");
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let wallet = a.wallet(0);
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(Address::zero())
+        .value(1)
+        .nonce(0)
+        .gas(21_000)
+        .gas_price(10_000_000_000u64)
+        .chain_id(chain_id)
+        .into();
+
+    let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+    let tx_hash: H256 = x
+        .proxy_provider
+        .request("eth_sendRawTransaction", (tx.rlp_signed(&signature),))
+        .await
+        .unwrap();
+
+    // the receipt is hidden for a while after the write. the proxy waits for it instead of returning null
+    let start = Instant::now();
+
+    let receipt: Option<TransactionReceipt> = x
+        .proxy_provider
+        .request("eth_getTransactionReceipt", (tx_hash,))
+        .await
+        .unwrap();
+
+    let receipt = receipt.expect("the proxy should have waited for the receipt");
+
+    assert_eq!(receipt.transaction_hash, tx_hash);
+    assert!(start.elapsed() < Duration::from_millis(2_000));
+}
+
+#[test_log::test(tokio::test)]
+async fn it_does_not_rebroadcast_retried_transactions() {
+    let chain_id = 31337;
+
+    let a = TestAnvil::spawn(chain_id).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let start = Instant::now();
+    loop {
+        let proxy_block_num: Result<U64, _> = x.proxy_provider.request("eth_blockNumber", ()).await;
+
+        if proxy_block_num.ok() == Some(head_block_num) {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("took too long to sync!");
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let wallet = a.wallet(0);
+
+    let tx: TypedTransaction = TransactionRequest::new()
+        .to(Address::zero())
+        .value(1)
+        .nonce(0)
+        .gas(21_000)
+        .gas_price(10_000_000_000u64)
+        .chain_id(chain_id)
+        .into();
+
+    let signature = wallet.sign_transaction_sync(&tx).unwrap();
+
+    let raw_tx = tx.rlp_signed(&signature);
+
+    let tx_hash: H256 = x
+        .proxy_provider
+        .request("eth_sendRawTransaction", (&raw_tx,))
+        .await
+        .unwrap();
+
+    // anvil mined the transaction already. sending it to anvil again would error with "nonce too low"
+    let retry_hash: H256 = x
+        .proxy_provider
+        .request("eth_sendRawTransaction", (&raw_tx,))
+        .await
+        .unwrap();
+
+    assert_eq!(tx_hash, retry_hash);
+}
+
+#[test_log::test(tokio::test)]
+async fn it_returns_200_for_reverts() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    // init code that immediately reverts
+    let request = json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "eth_call",
+        "params": [{"data": "0x60006000fd"}, "latest"],
+    });
+
+    let response = reqwest::Client::new()
+        .post(x.proxy_provider.url().as_str())
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+
+    // the request worked. the error is in the jsonrpc response
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response: serde_json::Value = response.json().await.unwrap();
+
+    assert!(response["error"].is_object(), "{:#}", response);
+    assert!(response.get("result").is_none(), "{:#}", response);
+}
+
+#[test_log::test(tokio::test)]
+async fn it_echoes_huge_ids() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let client = reqwest::Client::new();
+
+    // way past i64 and u64. a serde_json::Value would turn these into floats
+    let huge_id = "123456789012345678901234567890";
+
+    for request in [
+        format!(
+            r#"{{"jsonrpc":"2.0","id":{},"method":"eth_blockNumber","params":[]}}"#,
+            huge_id
+        ),
+        format!(
+            r#"[{{"jsonrpc":"2.0","id":{},"method":"eth_blockNumber","params":[]}}]"#,
+            huge_id
+        ),
+    ] {
+        let response = client
+            .post(x.proxy_provider.url().as_str())
+            .header("content-type", "application/json")
+            .body(request)
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let response = response.text().await.unwrap();
+
+        assert!(
+            response.contains(&format!(r#""id":{},"#, huge_id)),
+            "{}",
+            response
+        );
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn it_returns_an_http_error_when_all_nodes_are_down() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let proxy_url = x.proxy_provider.url().clone();
+
+    // kill the only backend
+    drop(a);
+
+    let client = reqwest::Client::new();
+
+    let start = Instant::now();
+    let mut i = 0u64;
+    let status = loop {
+        // different params every time so that the response cache can't answer
+        let request = json!({
+            "jsonrpc": "2.0",
+            "id": i,
+            "method": "eth_getBalance",
+            "params": [format!("0x{:040x}", i), "latest"],
+        });
+
+        let response = client
+            .post(proxy_url.as_str())
+            .json(&request)
+            .send()
+            .await
+            .unwrap();
+
+        if response.status() != StatusCode::OK {
+            break response.status();
+        }
+
+        if start.elapsed() > Duration::from_secs(10) {
+            panic!("the proxy kept returning 200s");
+        }
+
+        i += 1;
+
+        sleep(Duration::from_millis(100)).await;
+    };
+
+    assert!(
+        status == StatusCode::BAD_GATEWAY || status == StatusCode::SERVICE_UNAVAILABLE,
+        "{}",
+        status
+    );
+}
+
+#[test_log::test(tokio::test)]
+async fn it_answers_account_methods_locally() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    // anvil has unlocked dev accounts. the proxy must never expose them
+    let anvil_accounts: Vec<Address> = a.provider.request("eth_accounts", ()).await.unwrap();
+    assert!(!anvil_accounts.is_empty());
+
+    let proxy_accounts: Vec<Address> = x.proxy_provider.request("eth_accounts", ()).await.unwrap();
+    assert!(proxy_accounts.is_empty());
+
+    let dev_account = anvil_accounts[0];
+
+    let tx = json!({
+        "from": dev_account,
+        "to": dev_account,
+        "value": "0x1",
+    });
+
+    let requests = [
+        ("eth_coinbase", json!([])),
+        ("eth_sign", json!([dev_account, "0xdeadbeef"])),
+        ("eth_signTransaction", json!([tx])),
+        ("eth_sendTransaction", json!([tx])),
+        ("eth_signTypedData_v4", json!([dev_account, {}])),
+        ("personal_sign", json!(["0xdeadbeef", dev_account])),
+    ];
+
+    for (method, params) in requests {
+        match x
+            .proxy_provider
+            .request::<_, serde_json::Value>(method, params)
+            .await
+        {
+            Err(ProviderError::JsonRpcClientError(err)) => {
+                let err: &JsonRpcError = err.as_error_response().unwrap();
+
+                assert_eq!(err.code, -32601, "{}", method);
+                assert!(err.message.contains("not supported"), "{}", method);
+            }
+            x => panic!("expected {} to not be supported, got {:?}", method, x),
+        }
+    }
+
+    // nothing was sent from the dev account
+    let nonce: U256 = a
+        .provider
+        .request("eth_getTransactionCount", (dev_account, "latest"))
+        .await
+        .unwrap();
+    assert_eq!(nonce, U256::zero());
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn it_lowers_a_hard_limit_at_runtime() {
+    let chain_id = 31337;
+
+    let a = TestAnvil::spawn(chain_id).await;
+    let redis = TestRedis::spawn().await;
+
+    let config = Web3RpcConfig {
+        http_url: Some(a.instance.endpoint()),
+        hard_limit: Some(100),
+        ..Default::default()
+    };
+
+    let (rpc, _handle) = Web3Rpc::spawn(
+        config,
+        "anvil".to_string(),
+        chain_id,
+        None,
+        Some(redis.pool()),
+        Duration::from_secs(1),
+        BlocksByHashCache::new(100),
+        None,
+        Duration::from_secs(60),
+    )
+    .await
+    .unwrap();
+
+    let authorization = Arc::new(Authorization::default());
+
+    for _ in 0..3 {
+        assert!(matches!(
+            rpc.try_request_handle(&authorization, None, None)
+                .await
+                .unwrap(),
+            OpenRequestResult::Handle(_)
+        ));
+    }
+
+    // a request that started before the change keeps its handle
+    let in_flight = rpc
+        .try_request_handle(&authorization, None, None)
+        .await
+        .unwrap();
+    assert!(matches!(in_flight, OpenRequestResult::Handle(_)));
+
+    rpc.set_hard_limit(chain_id, Some(1), Some(redis.pool()))
+        .unwrap();
+
+    assert_eq!(rpc.hard_limit(), Some(1));
+
+    // the count is shared with the old limiter, so the new limit binds right away.
+    // the count resets every minute, so allow one more request in case this crossed into a new period
+    let mut results = vec![];
+    for _ in 0..2 {
+        results.push(
+            rpc.try_request_handle(&authorization, None, None)
+                .await
+                .unwrap(),
+        );
+    }
+
+    assert!(
+        matches!(results.last(), Some(OpenRequestResult::RetryAt(_))),
+        "{:?}",
+        results
+    );
+
+    drop(in_flight);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn it_limits_methods_separately() {
+    let chain_id = 31337;
+
+    let a = TestAnvil::spawn(chain_id).await;
+    let redis = TestRedis::spawn().await;
+
+    let config = Web3RpcConfig {
+        http_url: Some(a.instance.endpoint()),
+        method_limits: HashMap::from_iter([("eth_getLogs".to_string(), 1)]),
+        ..Default::default()
+    };
+
+    let (rpc, _handle) = Web3Rpc::spawn(
+        config,
+        "anvil".to_string(),
+        chain_id,
+        None,
+        Some(redis.pool()),
+        Duration::from_secs(1),
+        BlocksByHashCache::new(100),
+        None,
+        Duration::from_secs(60),
+    )
+    .await
+    .unwrap();
+
+    let authorization = Arc::new(Authorization::default());
+
+    // the count resets every minute, so allow one more request in case this crossed into a new period
+    let mut results = vec![];
+    for _ in 0..3 {
+        results.push(
+            rpc.try_request_handle(&authorization, Some("eth_getLogs"), None)
+                .await
+                .unwrap(),
+        );
+    }
+
+    assert!(
+        matches!(results.last(), Some(OpenRequestResult::RetryAt(_))),
+        "{:?}",
+        results
+    );
+
+    drop(results);
+
+    // methods without their own limit are not held back by eth_getLogs
+    for method in [Some("eth_blockNumber"), None] {
+        assert!(matches!(
+            rpc.try_request_handle(&authorization, method, None)
+                .await
+                .unwrap(),
+            OpenRequestResult::Handle(_)
+        ));
+    }
+}
+
+#[test_log::test(tokio::test)]
+async fn it_counts_lifetime_stats_per_rpc() {
+    let chain_id = 31337;
+
+    let a = TestAnvil::spawn(chain_id).await;
+
+    let authorization = Arc::new(Authorization::default());
+
+    let mut rpcs = vec![];
+    for name in ["a", "b"] {
+        let config = Web3RpcConfig {
+            http_url: Some(a.instance.endpoint()),
+            ..Default::default()
+        };
+
+        let (rpc, _handle) = Web3Rpc::spawn(
+            config,
+            name.to_string(),
+            chain_id,
+            None,
+            None,
+            Duration::from_secs(1),
+            BlocksByHashCache::new(100),
+            None,
+            Duration::from_secs(60),
+        )
+        .await
+        .unwrap();
+
+        // connecting checks the chain id
+        let start = Instant::now();
+        while rpc.lifetime_stats().counts().requests == 0 {
+            if start.elapsed() > Duration::from_secs(5) {
+                panic!("took too long to connect!");
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        rpcs.push(rpc);
+    }
+
+    // "0x7a69" with quotes
+    let chain_id_bytes = 8;
+
+    for (rpc, num_ok, num_err) in [(&rpcs[0], 3, 1), (&rpcs[1], 2, 0)] {
+        for _ in 0..num_ok {
+            let handle = match rpc
+                .try_request_handle(&authorization, None, None)
+                .await
+                .unwrap()
+            {
+                OpenRequestResult::Handle(x) => x,
+                x => panic!("{:?}", x),
+            };
+
+            let x: U64 = handle.request("eth_chainId", &()).await.unwrap();
+            assert_eq!(x, U64::from(chain_id));
+        }
+
+        for _ in 0..num_err {
+            let handle = match rpc
+                .try_request_handle(&authorization, None, None)
+                .await
+                .unwrap()
+            {
+                OpenRequestResult::Handle(x) => x,
+                x => panic!("{:?}", x),
+            };
+
+            handle
+                .request::<_, U64>("eth_notARealMethod", &())
+                .await
+                .unwrap_err();
+        }
+    }
+
+    let a_counts = rpcs[0].lifetime_stats().counts();
+    assert_eq!(a_counts.requests, 5);
+    assert_eq!(a_counts.errors, 1);
+    assert_eq!(a_counts.response_bytes, 4 * chain_id_bytes);
+    assert_eq!(a_counts.consensus_heads, 0);
+    assert!(a_counts.avg_latency_ms() > 0.0);
+
+    let b_counts = rpcs[1].lifetime_stats().counts();
+    assert_eq!(b_counts.requests, 3);
+    assert_eq!(b_counts.errors, 0);
+    assert_eq!(b_counts.response_bytes, 3 * chain_id_bytes);
+    assert_eq!(b_counts.consensus_heads, 0);
+    assert!(b_counts.avg_latency_ms() > 0.0);
+
+    // the counts are shown on the status page
+    let status = serde_json::to_value(&*rpcs[0]).unwrap();
+    assert_eq!(status["lifetime"]["requests"], json!(5));
+    assert_eq!(status["lifetime"]["errors"], json!(1));
+}
+
+#[test_log::test(tokio::test)]
+async fn it_subscribes_to_new_heads() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let x = TestApp::spawn(&a, None, None, None).await;
+
+    let mut ws_url = x.proxy_provider.url().clone();
+    ws_url.set_scheme("ws").unwrap();
+
+    let ws_provider = Provider::<Ws>::connect(ws_url.as_str()).await.unwrap();
+
+    let mut new_heads = ws_provider.subscribe_blocks().await.unwrap();
+
+    let _: U256 = a.provider.request("evm_mine", ()).await.unwrap();
+
+    let mined_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    // the current head might be sent first
+    let new_head = timeout(Duration::from_secs(10), async {
+        loop {
+            let new_head = new_heads.next().await.expect("subscription closed early");
+
+            if new_head.number == Some(mined_block_num) {
+                break new_head;
+            }
+        }
+    })
+    .await
+    .expect("no new head");
+
+    assert!(new_head.hash.is_some());
+
+    assert!(new_heads.unsubscribe().await.unwrap());
+}
+
+#[test_log::test(tokio::test)]
+async fn it_polls_heads_over_http_while_the_websocket_is_down() {
+    let a = TestAnvil::spawn(31337).await;
+
+    // nothing listens on this port, so every websocket connection fails
+    let x = TestApp::spawn_with_configs(
+        &a,
+        None,
+        None,
+        None,
+        json!({}),
+        json!({
+            "ws_url": "ws://127.0.0.1:1",
+        }),
+    )
+    .await;
+
+    for _ in 0..2 {
+        let _: U256 = a.provider.request("evm_mine", ()).await.unwrap();
+
+        let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+        // heads still arrive because the rpc polls its http_url between reconnects
+        let start = Instant::now();
+        loop {
+            let proxy_block_num: Result<U64, _> =
+                x.proxy_provider.request("eth_blockNumber", ()).await;
+
+            if proxy_block_num.ok() == Some(head_block_num) {
+                break;
+            }
+
+            if start.elapsed() > Duration::from_secs(10) {
+                panic!("heads were not polled over http!");
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn it_saves_dead_letters_in_redis() {
+    let redis = TestRedis::spawn().await;
+
+    let dead_letters = DeadLetters::new(2, false, 31337, Some(redis.pool()));
+
+    // only requests that reached a backend rpc are dead letters
+    let request_metadata = RequestMetadata::default();
+    request_metadata
+        .backend_requests
+        .lock()
+        .push(Arc::new(Web3Rpc::default()));
+
+    // another instance (or this one after a restart) sees the same letters
+    let other_instance = DeadLetters::new(2, false, 31337, Some(redis.pool()));
+
+    // the letters are saved in the background. wait for each one so that their order is known
+    for method in ["eth_call", "eth_getBalance", "eth_getCode"] {
+        dead_letters.push(
+            method,
+            &json!([]),
+            &request_metadata,
+            &Web3ProxyError::NoServersSynced,
+        );
+
+        let start = Instant::now();
+        loop {
+            let letters = other_instance.recent().await;
+
+            if letters.last().map(|x| x.method.as_str()) == Some(method) {
+                break;
+            }
+
+            if start.elapsed() > Duration::from_secs(5) {
+                panic!("{} was not saved to redis! {:?}", method, letters);
+            }
+
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    let letters = other_instance.recent().await;
+
+    // the oldest was trimmed
+    let methods: Vec<_> = letters.iter().map(|x| x.method.as_str()).collect();
+    assert_eq!(methods, ["eth_getBalance", "eth_getCode"]);
+    assert!(letters.iter().all(|x| x.params.is_none()));
+
+    // the other instance didn't save anything itself
+    assert!(other_instance.to_vec().is_empty());
+
+    // chains are kept apart
+    let other_chain = DeadLetters::new(2, false, 1, Some(redis.pool()));
+    assert!(other_chain.recent().await.is_empty());
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn it_rate_limits_every_request_in_a_batch() {
+    let a = TestAnvil::spawn(31337).await;
+    let redis = TestRedis::spawn().await;
+
+    let x = TestApp::spawn_with_app_config(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "public_requests_per_period": 3,
+            "volatile_redis_url": redis.url,
+        }),
+    )
+    .await;
+
+    let batch: Vec<_> = (1..=5)
+        .map(|id| json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": [], "id": id}))
+        .collect();
+
+    // localhost is never rate limited. pretend to be someone else
+    let responses: Vec<serde_json::Value> = reqwest::Client::new()
+        .post(x.proxy_provider.url().clone())
+        .header("x-forwarded-for", "203.0.113.1")
+        .json(&batch)
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    assert_eq!(responses.len(), 5);
+
+    let ids: Vec<_> = responses.iter().map(|x| x["id"].clone()).collect();
+    assert_eq!(ids, [json!(1), json!(2), json!(3), json!(4), json!(5)]);
+
+    let num_limited = responses
+        .iter()
+        .filter(|x| x["error"]["code"] == json!(StatusCode::TOO_MANY_REQUESTS.as_u16()))
+        .count();
+
+    // the count resets every minute, so allow one more request in case this crossed into a new period
+    assert!((1..=2).contains(&num_limited), "{:?}", responses);
+
+    // everything else was answered
+    assert!(responses
+        .iter()
+        .filter(|x| x.get("error").is_none())
+        .all(|x| x["result"] == json!("0x7a69")));
+}
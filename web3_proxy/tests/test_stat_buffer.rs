@@ -1,9 +1,24 @@
 mod common;
 
-use crate::common::influx::TestInflux;
+use crate::common::{
+    create_user::create_user, influx::TestInflux, rpc_key::user_get_first_rpc_key, TestAnvil,
+    TestApp, TestMysql,
+};
+use entities::rpc_accounting_v2;
+use migration::sea_orm::{ColumnTrait, EntityTrait, QueryFilter};
 use moka::future::Cache;
+use std::num::NonZeroU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{broadcast, mpsc};
-use web3_proxy::{caches::UserBalanceCache, stats::StatBuffer};
+use web3_proxy::{
+    caches::UserBalanceCache,
+    frontend::authorization::{
+        Authorization, AuthorizationChecks, AuthorizationType, RequestMetadata,
+    },
+    stats::{AppStat, StatBuffer},
+};
 
 #[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
 #[test_log::test(tokio::test)]
@@ -13,6 +28,7 @@ async fn test_two_buffers() {
     let billing_period_seconds = 86400 * 7;
     let chain_id = 999_001_999;
     let db_save_interval_seconds = 60;
+    let flush_frontend_requests = None;
     let influxdb_bucket = Some(i.bucket.clone());
     let influxdb_client = Some(i.client.clone());
     let rpc_secret_key_cache = Cache::builder().build();
@@ -29,6 +45,7 @@ async fn test_two_buffers() {
         billing_period_seconds,
         chain_id,
         db_save_interval_seconds,
+        flush_frontend_requests,
         influxdb_bucket.clone(),
         influxdb_client.clone(),
         rpc_secret_key_cache.clone(),
@@ -46,6 +63,7 @@ async fn test_two_buffers() {
         billing_period_seconds,
         chain_id,
         db_save_interval_seconds,
+        flush_frontend_requests,
         influxdb_bucket,
         influxdb_client,
         rpc_secret_key_cache,
@@ -66,3 +84,91 @@ async fn test_two_buffers() {
     buffer_1.background_handle.await.unwrap().unwrap();
     buffer_2.background_handle.await.unwrap().unwrap();
 }
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_late_stats_are_saved() {
+    let a = TestAnvil::spawn(999_001_999).await;
+    let db = TestMysql::spawn().await;
+
+    // the app sets up the global db connection
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(0);
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+    let rpc_key = user_get_first_rpc_key(&x, &r, &user_login_response).await;
+
+    let (shutdown_sender, shutdown_receiver) = broadcast::channel(1);
+    let (flush_sender, flush_receiver) = mpsc::channel(1);
+
+    let spawned = StatBuffer::try_spawn(
+        86400,
+        999_001_999,
+        60,
+        None,
+        None,
+        None,
+        Cache::builder().build(),
+        Cache::builder().build().into(),
+        shutdown_receiver,
+        30,
+        flush_sender,
+        flush_receiver,
+        "late".to_string(),
+    )
+    .unwrap()
+    .unwrap();
+
+    shutdown_sender.send(()).unwrap();
+    spawned.background_handle.await.unwrap().unwrap();
+
+    let checks = AuthorizationChecks {
+        rpc_secret_key_id: NonZeroU64::new(rpc_key.id),
+        user_id: rpc_key.user_id,
+        ..Default::default()
+    };
+
+    let authorization = Authorization::try_new(
+        checks,
+        &"127.0.0.1".parse().unwrap(),
+        None,
+        None,
+        None,
+        AuthorizationType::Frontend,
+    )
+    .unwrap();
+
+    let request_metadata = RequestMetadata {
+        authorization: Some(Arc::new(authorization)),
+        method: "eth_blockNumber".into(),
+        ..Default::default()
+    };
+
+    request_metadata
+        .response_timestamp
+        .store(1_700_000_000, Ordering::Relaxed);
+
+    // the buffer has done its final flush. the stat has to skip it
+    spawned
+        .stat_sender
+        .send(AppStat::RpcQuery(request_metadata))
+        .expect("the buffer is closed")
+        .await
+        .unwrap();
+
+    let saved = rpc_accounting_v2::Entity::find()
+        .filter(rpc_accounting_v2::Column::RpcKeyId.eq(rpc_key.id))
+        .all(&db.conn().await)
+        .await
+        .unwrap();
+
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].frontend_requests, 1);
+
+    x.stop().unwrap();
+}
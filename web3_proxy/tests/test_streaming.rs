@@ -0,0 +1,203 @@
+//! This is its own test binary so that the counting allocator only measures this test.
+mod common;
+
+use crate::common::{anvil::TestAnvil, TestApp};
+use axum::body::{Bytes, StreamBody};
+use axum::extract::State;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use ethers::prelude::U64;
+use http::header::CONTENT_TYPE;
+use http::StatusCode;
+use serde_json::json;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::time::{sleep, Instant};
+
+/// counts the bytes allocated by the whole process. that includes the proxy since TestApp runs it in a thread
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+
+            PEAK_ALLOCATED.fetch_max(allocated, Ordering::Relaxed);
+        }
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const NUM_LOGS: usize = 250_000;
+
+/// the body of a big eth_getLogs response. it is made in pieces so that neither the mock rpc nor the test holds all of it
+fn big_logs_chunks(id: &str) -> impl Iterator<Item = Bytes> {
+    let start = format!(r#"{{"jsonrpc":"2.0","id":{},"result":["#, id);
+
+    let logs = (0..NUM_LOGS).map(|i| {
+        let separator = if i == 0 { "" } else { "," };
+
+        format!(
+            r#"{}{{"logIndex":"0x{:x}","data":"0x{:0>128x}"}}"#,
+            separator, i, i
+        )
+    });
+
+    std::iter::once(start)
+        .chain(logs)
+        .chain(std::iter::once("]}".to_string()))
+        .map(Bytes::from)
+}
+
+/// answers eth_getLogs with a huge streamed body. everything else is sent to anvil
+async fn mock_rpc(State(anvil_url): State<String>, body: Bytes) -> Response {
+    let request: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+    if request["method"] == "eth_getLogs" {
+        let id = request["id"].to_string();
+
+        let body = futures::stream::iter(big_logs_chunks(&id).map(Ok::<_, Infallible>));
+
+        return ([(CONTENT_TYPE, "application/json")], StreamBody::new(body)).into_response();
+    }
+
+    let response = reqwest::Client::new()
+        .post(anvil_url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .unwrap()
+        .bytes()
+        .await
+        .unwrap();
+
+    ([(CONTENT_TYPE, "application/json")], response).into_response()
+}
+
+#[test_log::test(tokio::test)]
+async fn it_streams_big_responses() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let mock_server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(
+        Router::new()
+            .route("/", post(mock_rpc))
+            .with_state(a.instance.endpoint())
+            .into_make_service(),
+    );
+
+    let mock_url = format!("http://{}", mock_server.local_addr());
+
+    tokio::spawn(mock_server);
+
+    let x = TestApp::spawn_with_configs(
+        &a,
+        None,
+        None,
+        None,
+        json!({
+            "stream_methods": ["eth_getLogs"],
+            "stream_response_min_bytes": 1_000_000,
+        }),
+        json!({
+            "http_url": mock_url,
+            "ws_url": null,
+        }),
+    )
+    .await;
+
+    let head_block_num: U64 = a.provider.request("eth_blockNumber", ()).await.unwrap();
+
+    let start = Instant::now();
+    loop {
+        let proxy_block_num: Result<U64, _> = x.proxy_provider.request("eth_blockNumber", ()).await;
+
+        if proxy_block_num.ok() == Some(head_block_num) {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_secs(5) {
+            panic!("took too long to sync!");
+        }
+
+        sleep(Duration::from_millis(10)).await;
+    }
+
+    let total_bytes: usize = big_logs_chunks("1").map(|x| x.len()).sum();
+
+    let max_extra_bytes = 16_000_000;
+
+    assert!(
+        total_bytes > max_extra_bytes * 2,
+        "the body is too small to test anything"
+    );
+
+    let client = reqwest::Client::new();
+
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK_ALLOCATED.store(baseline, Ordering::Relaxed);
+
+    let mut response = client
+        .post(x.proxy_provider.url().as_str())
+        .json(&json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": "0x0", "toBlock": "0x0"}],
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // compare the body as it arrives. collecting it would use as much memory as the proxy buffering it
+    let mut expected = big_logs_chunks("1");
+    let mut expected_buffer = vec![];
+    let mut received_bytes = 0;
+
+    while let Some(chunk) = response.chunk().await.unwrap() {
+        while expected_buffer.len() < chunk.len() {
+            expected_buffer.extend_from_slice(&expected.next().expect("the body is too long"));
+        }
+
+        assert_eq!(&expected_buffer[..chunk.len()], &chunk[..]);
+
+        expected_buffer.drain(..chunk.len());
+        received_bytes += chunk.len();
+    }
+
+    assert!(expected_buffer.is_empty());
+    assert!(expected.next().is_none(), "the body is too short");
+    assert_eq!(received_bytes, total_bytes);
+
+    let peak_extra_bytes = PEAK_ALLOCATED
+        .load(Ordering::Relaxed)
+        .saturating_sub(baseline);
+
+    assert!(
+        peak_extra_bytes < max_extra_bytes,
+        "{} extra bytes were allocated for a {} byte body",
+        peak_extra_bytes,
+        total_bytes
+    );
+}
@@ -5,6 +5,7 @@ use crate::common::admin_increases_balance::admin_increase_balance;
 use crate::common::anvil::TestAnvil;
 use crate::common::create_admin::create_user_as_admin;
 use crate::common::create_user::create_user;
+use crate::common::influx::TestInflux;
 use crate::common::mysql::TestMysql;
 use crate::common::referral::{
     get_referral_code, get_shared_referral_codes, get_used_referral_codes, UserSharedReferralInfo,
@@ -13,10 +14,14 @@ use crate::common::referral::{
 use crate::common::rpc_key::{user_get_first_rpc_key, RpcKey};
 use crate::common::user_balance::user_get_balance;
 use crate::common::TestApp;
+use entities::user_tier;
 use ethers::prelude::{Http, Provider};
 use ethers::{signers::Signer, types::Signature};
+use http::{header::ORIGIN, StatusCode};
 use migration::sea_orm::prelude::Decimal;
+use migration::sea_orm::EntityTrait;
 use serde::Deserialize;
+use serde_json::json;
 use std::str::FromStr;
 use std::time::Duration;
 use tracing::{debug, info, trace};
@@ -754,3 +759,396 @@ async fn test_referral_bonus_concurrent_referrer_and_user() {
     // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
     drop(x);
 }
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_rpc_key_allowed_origins() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(1);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+
+    let rpc_key: RpcKey = user_get_first_rpc_key(&x, &r, &user_login_response).await;
+
+    // a browser key. only the dapp's origin can use it
+    let update_key_response = r
+        .put(format!("{}user/keys", x.proxy_provider.url()))
+        .bearer_auth(user_login_response.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "allowed_origins": "https://allowed.example",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(update_key_response.status(), StatusCode::OK);
+
+    let rpc_url = format!("{}rpc/{}", x.proxy_provider.url(), rpc_key.secret_key);
+
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+
+    let allowed = r
+        .post(&rpc_url)
+        .header(ORIGIN, "https://allowed.example")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(allowed.status(), StatusCode::OK);
+
+    let disallowed = r
+        .post(&rpc_url)
+        .header(ORIGIN, "https://stolen.example")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(disallowed.status(), StatusCode::FORBIDDEN);
+
+    let disallowed_text = disallowed.text().await.unwrap();
+    assert!(
+        disallowed_text.contains("https://stolen.example"),
+        "{}",
+        disallowed_text
+    );
+
+    // server-side requests don't send an origin. a restricted key needs one
+    let no_origin = r.post(&rpc_url).json(&request).send().await.unwrap();
+
+    assert_eq!(no_origin.status(), StatusCode::BAD_REQUEST);
+
+    // removing the restriction makes it a server-side key again. any origin (or none) works
+    let update_key_response = r
+        .put(format!("{}user/keys", x.proxy_provider.url()))
+        .bearer_auth(user_login_response.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "allowed_origins": "",
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(update_key_response.status(), StatusCode::OK);
+
+    let any_origin = r
+        .post(&rpc_url)
+        .header(ORIGIN, "https://stolen.example")
+        .json(&request)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(any_origin.status(), StatusCode::OK);
+
+    let no_origin = r.post(&rpc_url).json(&request).send().await.unwrap();
+
+    assert_eq!(no_origin.status(), StatusCode::OK);
+
+    // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
+    drop(x);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_rpc_key_permissions() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(1);
+    let other_wallet = a.wallet(2);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+    let other_login_response = create_user(&x, &r, &other_wallet, None).await;
+
+    let rpc_key: RpcKey = user_get_first_rpc_key(&x, &r, &user_login_response).await;
+
+    let update_key_response = r
+        .put(format!("{}user/keys", x.proxy_provider.url()))
+        .bearer_auth(user_login_response.bearer_token)
+        .json(&json!({
+            "key_id": rpc_key.id,
+            "allowed_origins": "https://allowed.example",
+            "denied_methods": "debug_*",
+            "private_txs": true,
+        }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(update_key_response.status(), StatusCode::OK);
+
+    let permissions_url = format!(
+        "{}user/key/{}/permissions",
+        x.proxy_provider.url(),
+        rpc_key.id
+    );
+
+    let permissions_response = r
+        .get(&permissions_url)
+        .bearer_auth(user_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(permissions_response.status(), StatusCode::OK);
+
+    let permissions: serde_json::Value = permissions_response.json().await.unwrap();
+    info!(?permissions);
+
+    assert_eq!(permissions["id"], json!(rpc_key.id));
+    assert_eq!(permissions["active"], json!(true));
+
+    // the test app forwards unknown methods. admin and signing methods are always denied, then the key's own list
+    assert_eq!(permissions["methods"]["allowed"], json!(null));
+    assert_eq!(permissions["methods"]["key_allowed"], json!(null));
+
+    let denied = permissions["methods"]["denied"].as_array().unwrap();
+
+    assert_eq!(denied.first(), Some(&json!("admin_*")));
+    assert!(denied.contains(&json!("eth_sign")));
+    assert_eq!(denied.last(), Some(&json!("debug_*")));
+
+    assert_eq!(
+        permissions["restrictions"]["allowed_origins"],
+        json!("https://allowed.example")
+    );
+    assert_eq!(permissions["restrictions"]["allowed_ips"], json!(null));
+    assert_eq!(permissions["restrictions"]["private_txs"], json!(true));
+
+    let user_tier = user_tier::Entity::find_by_id(user_login_response.user.user_tier_id)
+        .one(&db.conn().await)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(
+        permissions["tier"]["max_requests_per_period"],
+        json!(user_tier.max_requests_per_period)
+    );
+    assert_eq!(
+        permissions["tier"]["max_concurrent_requests"],
+        json!(user_tier.max_concurrent_requests)
+    );
+
+    // another user's bearer token can't see this key
+    let other_permissions_response = r
+        .get(&permissions_url)
+        .bearer_auth(other_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(other_permissions_response.status(), StatusCode::NOT_FOUND);
+
+    // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
+    drop(x);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_rpc_key_rotate() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), None, None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap();
+
+    let user_wallet = a.wallet(1);
+    let other_wallet = a.wallet(2);
+
+    let user_login_response = create_user(&x, &r, &user_wallet, None).await;
+    let other_login_response = create_user(&x, &r, &other_wallet, None).await;
+
+    let rpc_key: RpcKey = user_get_first_rpc_key(&x, &r, &user_login_response).await;
+
+    let request = json!({"jsonrpc": "2.0", "id": 1, "method": "eth_blockNumber", "params": []});
+
+    let old_rpc_url = format!("{}rpc/{}", x.proxy_provider.url(), rpc_key.secret_key);
+
+    // use the key so that its checks are cached
+    let old_key_response = r.post(&old_rpc_url).json(&request).send().await.unwrap();
+
+    assert_eq!(old_key_response.status(), StatusCode::OK);
+
+    let rotate_url = format!("{}user/key/{}/rotate", x.proxy_provider.url(), rpc_key.id);
+
+    // another user's bearer token can't rotate this key
+    let other_rotate_response = r
+        .post(&rotate_url)
+        .bearer_auth(other_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(other_rotate_response.status(), StatusCode::NOT_FOUND);
+
+    let rotate_response = r
+        .post(&rotate_url)
+        .bearer_auth(user_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(rotate_response.status(), StatusCode::OK);
+
+    let rotated: serde_json::Value = rotate_response.json().await.unwrap();
+
+    // same row, new secret
+    assert_eq!(rotated["id"], json!(rpc_key.id));
+
+    let new_secret_key: Ulid = serde_json::from_value(rotated["secret_key"].clone()).unwrap();
+
+    assert_ne!(new_secret_key, rpc_key.secret_key);
+
+    let new_rpc_url = format!("{}rpc/{}", x.proxy_provider.url(), new_secret_key);
+
+    let new_key_response = r.post(&new_rpc_url).json(&request).send().await.unwrap();
+
+    assert_eq!(new_key_response.status(), StatusCode::OK);
+
+    // the leaked secret is no longer tied to the account. unknown keys only get the public limits
+    let rotated_key: RpcKey = user_get_first_rpc_key(&x, &r, &user_login_response).await;
+
+    assert_eq!(rotated_key.id, rpc_key.id);
+    assert_eq!(rotated_key.secret_key, new_secret_key);
+
+    // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
+    drop(x);
+}
+
+#[cfg_attr(not(feature = "tests-needing-docker"), ignore)]
+#[test_log::test(tokio::test)]
+async fn test_user_stats_scoping() {
+    let a = TestAnvil::spawn(31337).await;
+
+    let db = TestMysql::spawn().await;
+
+    let influx = TestInflux::spawn().await;
+
+    let x = TestApp::spawn(&a, Some(&db), Some(&influx), None).await;
+
+    let r = reqwest::Client::builder()
+        .timeout(Duration::from_secs(20))
+        .build()
+        .unwrap();
+
+    let owner_wallet = a.wallet(1);
+    let subuser_wallet = a.wallet(2);
+
+    let owner_login_response = create_user(&x, &r, &owner_wallet, None).await;
+    let subuser_login_response = create_user(&x, &r, &subuser_wallet, None).await;
+
+    let rpc_key: RpcKey = user_get_first_rpc_key(&x, &r, &owner_login_response).await;
+
+    let now = chrono::Utc::now().timestamp();
+
+    let stats_url = format!(
+        "{}user/stats?rpc_key_id={}&query_start={}",
+        x.proxy_provider.url(),
+        rpc_key.id,
+        now - 3600,
+    );
+
+    let owner_stats_response = r
+        .get(&stats_url)
+        .bearer_auth(owner_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(owner_stats_response.status(), StatusCode::OK);
+
+    // an unrelated user can't read this key's stats
+    let subuser_stats_response = r
+        .get(&stats_url)
+        .bearer_auth(subuser_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(subuser_stats_response.status(), StatusCode::FORBIDDEN);
+
+    let share_key = |role: &'static str| {
+        r.post(format!("{}user/subuser", x.proxy_provider.url()))
+            .bearer_auth(owner_login_response.bearer_token)
+            .query(&[
+                ("key_id", rpc_key.id.to_string()),
+                ("subuser_address", format!("{:?}", subuser_wallet.address())),
+                ("new_status", "upsert".to_string()),
+                ("new_role", role.to_string()),
+            ])
+            .send()
+    };
+
+    // collaborators can use the key, but they still can't read its stats
+    assert_eq!(
+        share_key("collaborator").await.unwrap().status(),
+        StatusCode::OK
+    );
+
+    let subuser_stats_response = r
+        .get(&stats_url)
+        .bearer_auth(subuser_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(subuser_stats_response.status(), StatusCode::FORBIDDEN);
+
+    // admins can
+    assert_eq!(share_key("admin").await.unwrap().status(), StatusCode::OK);
+
+    let subuser_stats_response = r
+        .get(&stats_url)
+        .bearer_auth(subuser_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(subuser_stats_response.status(), StatusCode::OK);
+
+    // the range is limited to 90 days
+    let too_long_response = r
+        .get(format!(
+            "{}user/stats?query_start={}&query_stop={}",
+            x.proxy_provider.url(),
+            now - 91 * 86400,
+            now,
+        ))
+        .bearer_auth(owner_login_response.bearer_token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(too_long_response.status(), StatusCode::BAD_REQUEST);
+
+    // drop x first to avoid spurious warnings about anvil/influx/mysql shutting down before the app
+    drop(x);
+}